@@ -0,0 +1,1061 @@
+// 2A03 Audio Processing Unit
+// $4000-$4003 Pulse 1, $4004-$4007 Pulse 2, $4008-$400B Triangle,
+// $400C-$400F Noise, $4010-$4013 DMC, $4015 Status, $4017 Frame counter
+// Timers run off the CPU clock: the pulses every other cycle, the rest every cycle.
+// Envelopes and linear counters step on quarter frames, length counters and
+// sweeps on half frames.
+
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0], // 12.5%
+    [0, 1, 1, 0, 0, 0, 0, 0], // 25%
+    [0, 1, 1, 1, 1, 0, 0, 0], // 50%
+    [1, 0, 0, 1, 1, 1, 1, 1], // 25% negated
+];
+
+use crate::region::Region;
+use crate::savestate::State;
+
+const TRIANGLE_TABLE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+pub struct LengthCounter {
+    enabled: bool,
+    pub halt: bool,
+    pub value: u8,
+}
+
+impl LengthCounter {
+    fn new() -> Self {
+        Self { enabled: false, halt: false, value: 0 }
+    }
+
+    pub fn sync_state(&mut self, state: &mut State) {
+        state.bool(&mut self.enabled);
+        state.bool(&mut self.halt);
+        state.u8(&mut self.value);
+    }
+
+    // Disabling a channel through $4015 clears its counter immediately
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.value = 0;
+        }
+    }
+
+    fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.value = LENGTH_TABLE[index as usize];
+        }
+    }
+
+    fn clock(&mut self) {
+        if !self.halt && self.value > 0 {
+            self.value -= 1;
+        }
+    }
+}
+
+pub struct Envelope {
+    start: bool,
+    looping: bool,
+    constant: bool,
+    volume: u8, // Constant volume, or the divider period
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    fn new() -> Self {
+        Self { start: false, looping: false, constant: false, volume: 0, divider: 0, decay: 0 }
+    }
+
+    fn sync_state(&mut self, state: &mut State) {
+        state.bool(&mut self.start);
+        state.bool(&mut self.looping);
+        state.bool(&mut self.constant);
+        state.u8(&mut self.volume);
+        state.u8(&mut self.divider);
+        state.u8(&mut self.decay);
+    }
+
+    fn write(&mut self, data: u8) {
+        self.looping = data & 0x20 != 0;
+        self.constant = data & 0x10 != 0;
+        self.volume = data & 0x0F;
+    }
+
+    fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.constant { self.volume } else { self.decay }
+    }
+}
+
+pub struct Sweep {
+    enabled: bool,
+    period: u8,
+    negate: bool,
+    shift: u8,
+    reload: bool,
+    divider: u8,
+}
+
+impl Sweep {
+    fn new() -> Self {
+        Self { enabled: false, period: 0, negate: false, shift: 0, reload: false, divider: 0 }
+    }
+
+    fn sync_state(&mut self, state: &mut State) {
+        state.bool(&mut self.enabled);
+        state.u8(&mut self.period);
+        state.bool(&mut self.negate);
+        state.u8(&mut self.shift);
+        state.bool(&mut self.reload);
+        state.u8(&mut self.divider);
+    }
+
+    fn write(&mut self, data: u8) {
+        self.enabled = data & 0x80 != 0;
+        self.period = (data >> 4) & 0x07;
+        self.negate = data & 0x08 != 0;
+        self.shift = data & 0x07;
+        self.reload = true;
+    }
+}
+
+pub struct Pulse {
+    ones_complement: bool, // Pulse 1 negates with ones' complement, pulse 2 with two's
+    duty: u8,
+    sequence_pos: u8,
+    timer_period: u16,
+    timer: u16,
+    envelope: Envelope,
+    sweep: Sweep,
+    pub length: LengthCounter,
+}
+
+impl Pulse {
+    pub fn new(ones_complement: bool) -> Self {
+        Self {
+            ones_complement,
+            duty: 0,
+            sequence_pos: 0,
+            timer_period: 0,
+            timer: 0,
+            envelope: Envelope::new(),
+            sweep: Sweep::new(),
+            length: LengthCounter::new(),
+        }
+    }
+
+    pub fn sync_state(&mut self, state: &mut State) {
+        state.u8(&mut self.duty);
+        state.u8(&mut self.sequence_pos);
+        state.u16(&mut self.timer_period);
+        state.u16(&mut self.timer);
+        self.envelope.sync_state(state);
+        self.sweep.sync_state(state);
+        self.length.sync_state(state);
+    }
+
+    // reg is the register offset 0-3
+    pub fn write(&mut self, reg: usize, data: u8) {
+        match reg {
+            0 => {
+                self.duty = data >> 6;
+                self.length.halt = data & 0x20 != 0;
+                self.envelope.write(data);
+            }
+            1 => self.sweep.write(data),
+            2 => self.timer_period = (self.timer_period & 0x0700) | data as u16,
+            _ => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((data as u16 & 0x07) << 8);
+                self.length.load(data >> 3);
+                self.sequence_pos = 0;
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    // Period the sweep unit would move to, computed continuously
+    fn target_period(&self) -> u16 {
+        let change = self.timer_period >> self.sweep.shift;
+        if self.sweep.negate {
+            let change = change + self.ones_complement as u16;
+            self.timer_period.saturating_sub(change)
+        } else {
+            self.timer_period + change
+        }
+    }
+
+    // Short periods and sweep overflow silence the channel even if the sweep is disabled
+    fn sweep_muted(&self) -> bool {
+        self.timer_period < 8 || self.target_period() > 0x7FF
+    }
+
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.sequence_pos = (self.sequence_pos + 1) & 0x07;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        if self.sweep.divider == 0 && self.sweep.enabled && self.sweep.shift > 0 && !self.sweep_muted() {
+            self.timer_period = self.target_period();
+        }
+        if self.sweep.divider == 0 || self.sweep.reload {
+            self.sweep.divider = self.sweep.period;
+            self.sweep.reload = false;
+        } else {
+            self.sweep.divider -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.sweep_muted() || self.length.value == 0 || DUTY_TABLE[self.duty as usize][self.sequence_pos as usize] == 0 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+pub struct Triangle {
+    control: bool, // Also halts the length counter
+    linear_reload_value: u8,
+    linear_reload: bool,
+    linear_counter: u8,
+    timer_period: u16,
+    timer: u16,
+    sequence_pos: u8,
+    pub length: LengthCounter,
+    pub silence_ultrasonic: bool, // Freeze the sequencer on periods too high to hear
+}
+
+impl Triangle {
+    pub fn new() -> Self {
+        Self {
+            control: false,
+            linear_reload_value: 0,
+            linear_reload: false,
+            linear_counter: 0,
+            timer_period: 0,
+            timer: 0,
+            sequence_pos: 0,
+            length: LengthCounter::new(),
+            silence_ultrasonic: false,
+        }
+    }
+
+    // silence_ultrasonic is a setting and stays as it is
+    pub fn sync_state(&mut self, state: &mut State) {
+        state.bool(&mut self.control);
+        state.u8(&mut self.linear_reload_value);
+        state.bool(&mut self.linear_reload);
+        state.u8(&mut self.linear_counter);
+        state.u16(&mut self.timer_period);
+        state.u16(&mut self.timer);
+        state.u8(&mut self.sequence_pos);
+        self.length.sync_state(state);
+    }
+
+    // reg is the register offset 0-3, offset 1 is unused
+    pub fn write(&mut self, reg: usize, data: u8) {
+        match reg {
+            0 => {
+                self.control = data & 0x80 != 0;
+                self.length.halt = self.control;
+                self.linear_reload_value = data & 0x7F;
+            }
+            2 => self.timer_period = (self.timer_period & 0x0700) | data as u16,
+            3 => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((data as u16 & 0x07) << 8);
+                self.length.load(data >> 3);
+                self.linear_reload = true;
+            }
+            _ => {}
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            let ultrasonic = self.silence_ultrasonic && self.timer_period < 2;
+            if self.length.value > 0 && self.linear_counter > 0 && !ultrasonic {
+                self.sequence_pos = (self.sequence_pos + 1) & 0x1F;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_linear(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    // Silencing only stops the sequencer, so the output holds its last step
+    pub fn output(&self) -> u8 {
+        TRIANGLE_TABLE[self.sequence_pos as usize]
+    }
+}
+
+pub struct Noise {
+    mode: bool, // Short mode taps bit 6 instead of bit 1
+    shift_register: u16,
+    timer_period: u16,
+    timer: u16,
+    envelope: Envelope,
+    pub length: LengthCounter,
+}
+
+impl Noise {
+    pub fn new() -> Self {
+        Self {
+            mode: false,
+            shift_register: 1,
+            timer_period: 0,
+            timer: 0,
+            envelope: Envelope::new(),
+            length: LengthCounter::new(),
+        }
+    }
+
+    pub fn sync_state(&mut self, state: &mut State) {
+        state.bool(&mut self.mode);
+        state.u16(&mut self.shift_register);
+        state.u16(&mut self.timer_period);
+        state.u16(&mut self.timer);
+        self.envelope.sync_state(state);
+        self.length.sync_state(state);
+    }
+
+    // reg is the register offset 0-3, offset 1 is unused
+    pub fn write(&mut self, reg: usize, data: u8, region: Region) {
+        match reg {
+            0 => {
+                self.length.halt = data & 0x20 != 0;
+                self.envelope.write(data);
+            }
+            2 => {
+                self.mode = data & 0x80 != 0;
+                self.timer_period = region.noise_periods()[(data & 0x0F) as usize] - 1;
+            }
+            3 => {
+                self.length.load(data >> 3);
+                self.envelope.start = true;
+            }
+            _ => {}
+        }
+    }
+
+    // Steps the 15 bit LFSR
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            let tap = if self.mode { 6 } else { 1 };
+            let feedback = (self.shift_register ^ (self.shift_register >> tap)) & 0x01;
+            self.shift_register = (self.shift_register >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.shift_register & 0x01 != 0 || self.length.value == 0 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+// Delta modulation channel
+// Plays 1 bit delta samples fetched from $C000-$FFFF. The fetches are DMA
+// reads done by the console, so the channel only raises dma_request.
+pub struct Dmc {
+    irq_enabled: bool,
+    looping: bool,
+    timer_period: u16,
+    timer: u16,
+    output_level: u8,
+
+    sample_address: u16,
+    sample_length: u16,
+    current_address: u16,
+    pub bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+    pub dma_request: Option<u16>, // Address waiting to be fetched into the sample buffer
+
+    shift_register: u8,
+    bits_remaining: u8,
+    silence: bool,
+    pub irq: bool,
+}
+
+impl Dmc {
+    pub fn new() -> Self {
+        Self {
+            irq_enabled: false,
+            looping: false,
+            timer_period: 0,
+            timer: 0,
+            output_level: 0,
+
+            sample_address: 0xC000,
+            sample_length: 1,
+            current_address: 0xC000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            dma_request: None,
+
+            shift_register: 0,
+            bits_remaining: 8,
+            silence: true,
+            irq: false,
+        }
+    }
+
+    pub fn sync_state(&mut self, state: &mut State) {
+        state.bool(&mut self.irq_enabled);
+        state.bool(&mut self.looping);
+        state.u16(&mut self.timer_period);
+        state.u16(&mut self.timer);
+        state.u8(&mut self.output_level);
+        state.u16(&mut self.sample_address);
+        state.u16(&mut self.sample_length);
+        state.u16(&mut self.current_address);
+        state.u16(&mut self.bytes_remaining);
+        state.option(&mut self.sample_buffer, State::u8);
+        state.option(&mut self.dma_request, State::u16);
+        state.u8(&mut self.shift_register);
+        state.u8(&mut self.bits_remaining);
+        state.bool(&mut self.silence);
+        state.bool(&mut self.irq);
+    }
+
+    // reg is the register offset 0-3
+    pub fn write(&mut self, reg: usize, data: u8, region: Region) {
+        match reg {
+            0 => {
+                self.irq_enabled = data & 0x80 != 0;
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+                self.looping = data & 0x40 != 0;
+                self.timer_period = region.dmc_rates()[(data & 0x0F) as usize] - 1;
+            }
+            // Direct load of the 7 bit output level
+            1 => self.output_level = data & 0x7F,
+            2 => self.sample_address = 0xC000 + data as u16 * 64,
+            _ => self.sample_length = data as u16 * 16 + 1,
+        }
+    }
+
+    // $4015 bit 4: starts the sample if it has finished, or stops it
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+            self.request_fetch();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    fn request_fetch(&mut self) {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 && self.dma_request.is_none() {
+            self.dma_request = Some(self.current_address);
+        }
+    }
+
+    // Completes a DMA fetch started by dma_request
+    pub fn fill_buffer(&mut self, data: u8) {
+        self.sample_buffer = Some(data);
+        // Address wraps from $FFFF to $8000
+        self.current_address = self.current_address.checked_add(1).unwrap_or(0x8000);
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.timer_period;
+
+        // Output unit: each bit moves the level by 2 unless it would leave 0-127
+        if !self.silence {
+            if self.shift_register & 0x01 != 0 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+        self.bits_remaining -= 1;
+
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(data) => {
+                    self.silence = false;
+                    self.shift_register = data;
+                    self.request_fetch();
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        self.output_level
+    }
+}
+
+// Frame sequencer driving the quarter and half frame clocks and the frame IRQ
+pub struct FrameCounter {
+    five_step: bool,
+    irq_inhibit: bool,
+    cycle: usize,
+    pending_write: Option<(u8, usize)>, // $4017 value and the CPU cycles until the sequencer restarts
+    pending_inhibit: Option<(bool, usize)>, // IRQ inhibit and the CPU cycles until the write lands
+    last_write: u8,                  // Rewritten on reset
+    pub irq: bool,
+}
+
+impl FrameCounter {
+    fn new() -> Self {
+        Self {
+            five_step: false,
+            irq_inhibit: false,
+            cycle: 0,
+            pending_write: None,
+            pending_inhibit: None,
+            last_write: 0,
+            irq: false,
+        }
+    }
+
+    pub fn sync_state(&mut self, state: &mut State) {
+        state.bool(&mut self.five_step);
+        state.bool(&mut self.irq_inhibit);
+        state.usize(&mut self.cycle);
+        state.option(&mut self.pending_write, |state, (data, delay)| {
+            state.u8(data);
+            state.usize(delay);
+        });
+        state.option(&mut self.pending_inhibit, |state, (inhibit, delay)| {
+            state.bool(inhibit);
+            state.usize(delay);
+        });
+        state.u8(&mut self.last_write);
+        state.bool(&mut self.irq);
+    }
+
+    // A write landing delay CPU cycles from now. Inhibit applies as it lands,
+    // the mode only when the sequencer restarts 3 or 4 cycles after that,
+    // depending on whether it landed on an APU cycle
+    fn write(&mut self, data: u8, odd_cycle: bool, delay: usize) {
+        self.last_write = data;
+        self.pending_inhibit = Some((data & 0x40 != 0, delay));
+        if delay == 0 {
+            self.land_inhibit();
+        }
+        let restart = if odd_cycle { 4 } else { 3 };
+        self.pending_write = Some((data, delay + restart));
+    }
+
+    fn land_inhibit(&mut self) {
+        if let Some((inhibit, _)) = self.pending_inhibit.take() {
+            self.irq_inhibit = inhibit;
+            if inhibit {
+                self.irq = false;
+            }
+        }
+    }
+
+    // Returns which of the quarter and half frame clocks fire this cycle
+    fn clock(&mut self, region: Region) -> (bool, bool) {
+        let clocks = self.clock_sequencer(region);
+        // The write lands after this cycle's sequencer step
+        match self.pending_inhibit {
+            Some((inhibit, delay)) if delay > 1 => self.pending_inhibit = Some((inhibit, delay - 1)),
+            Some(_) => self.land_inhibit(),
+            None => {}
+        }
+        clocks
+    }
+
+    fn clock_sequencer(&mut self, region: Region) -> (bool, bool) {
+        if let Some((data, delay)) = self.pending_write {
+            if delay > 1 {
+                self.pending_write = Some((data, delay - 1));
+            } else {
+                self.pending_write = None;
+                self.five_step = data & 0x80 != 0;
+                self.cycle = 0;
+                // 5-step mode clocks both units as soon as it takes effect
+                return (self.five_step, self.five_step);
+            }
+        }
+
+        self.cycle += 1;
+        let steps = region.frame_counter_steps(self.five_step);
+        let step = steps.iter().position(|&step| step == self.cycle);
+        let raise_irq = !self.five_step && !self.irq_inhibit;
+        match step {
+            Some(0) | Some(2) => (true, false),
+            Some(1) => (true, true),
+            Some(3) => {
+                self.irq |= raise_irq;
+                (false, false)
+            }
+            Some(4) => {
+                self.irq |= raise_irq;
+                (true, true)
+            }
+            Some(5) => {
+                self.irq |= raise_irq;
+                self.cycle = 0;
+                (false, false)
+            }
+            _ => (false, false),
+        }
+    }
+}
+
+// Raw channel levels for the current CPU cycle, 0-15 except the DMC at 0-127
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ChannelOutputs {
+    pub pulse1: u8,
+    pub pulse2: u8,
+    pub triangle: u8,
+    pub noise: u8,
+    pub dmc: u8,
+}
+
+pub struct Apu {
+    pub pulse1: Pulse,
+    pub pulse2: Pulse,
+    pub triangle: Triangle,
+    pub noise: Noise,
+    pub dmc: Dmc,
+    pub frame_counter: FrameCounter,
+    pub region: Region,
+    cycle: usize,
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        Self {
+            pulse1: Pulse::new(true),
+            pulse2: Pulse::new(false),
+            triangle: Triangle::new(),
+            noise: Noise::new(),
+            dmc: Dmc::new(),
+            frame_counter: FrameCounter::new(),
+            region: Region::Ntsc,
+            cycle: 0,
+        }
+    }
+
+    pub fn sync_state(&mut self, state: &mut State) {
+        self.pulse1.sync_state(state);
+        self.pulse2.sync_state(state);
+        self.triangle.sync_state(state);
+        self.noise.sync_state(state);
+        self.dmc.sync_state(state);
+        self.frame_counter.sync_state(state);
+        state.usize(&mut self.cycle);
+    }
+
+    pub fn cpu_write(&mut self, addr: usize, data: u8) {
+        self.cpu_write_at(addr, data, 0);
+    }
+
+    // A write landing delay CPU cycles from now, as instructions run whole
+    // on their first cycle but write on their last. Only the frame counter,
+    // whose timing depends on the cycle, waits for it
+    pub fn cpu_write_at(&mut self, addr: usize, data: u8, delay: usize) {
+        match addr {
+            0x4000..=0x4003 => self.pulse1.write(addr - 0x4000, data),
+            0x4004..=0x4007 => self.pulse2.write(addr - 0x4004, data),
+            0x4008..=0x400B => self.triangle.write(addr - 0x4008, data),
+            0x400C..=0x400F => self.noise.write(addr - 0x400C, data, self.region),
+            0x4010..=0x4013 => self.dmc.write(addr - 0x4010, data, self.region),
+            0x4015 => {
+                self.pulse1.length.set_enabled(data & 0x01 != 0);
+                self.pulse2.length.set_enabled(data & 0x02 != 0);
+                self.triangle.length.set_enabled(data & 0x04 != 0);
+                self.noise.length.set_enabled(data & 0x08 != 0);
+                self.dmc.set_enabled(data & 0x10 != 0);
+            }
+            0x4017 => self.frame_counter.write(data, (self.cycle + delay) % 2 == 1, delay),
+            _ => {}
+        }
+    }
+
+    // $4015 status: length counters, DMC activity and both IRQ flags
+    // Reading acknowledges the frame IRQ but not the DMC one. Bit 5 is left
+    // clear, the bus fills it with open bus
+    pub fn read_status(&mut self) -> u8 {
+        let mut status = 0;
+        status |= (self.pulse1.length.value > 0) as u8;
+        status |= ((self.pulse2.length.value > 0) as u8) << 1;
+        status |= ((self.triangle.length.value > 0) as u8) << 2;
+        status |= ((self.noise.length.value > 0) as u8) << 3;
+        status |= ((self.dmc.bytes_remaining > 0) as u8) << 4;
+        status |= (self.frame_counter.irq as u8) << 6;
+        status |= (self.dmc.irq as u8) << 7;
+        self.frame_counter.irq = false;
+        status
+    }
+
+    // Level of the APU's contribution to the CPU IRQ line
+    pub fn irq(&self) -> bool {
+        self.frame_counter.irq || self.dmc.irq
+    }
+
+    // Reset silences every channel and replays the last $4017 write
+    pub fn reset(&mut self) {
+        self.cpu_write(0x4015, 0x00);
+        self.cpu_write(0x4017, self.frame_counter.last_write);
+    }
+
+    // Envelopes and the triangle linear counter
+    pub fn quarter_frame(&mut self) {
+        self.pulse1.envelope.clock();
+        self.pulse2.envelope.clock();
+        self.noise.envelope.clock();
+        self.triangle.clock_linear();
+    }
+
+    // Length counters and sweep units
+    pub fn half_frame(&mut self) {
+        self.pulse1.length.clock();
+        self.pulse2.length.clock();
+        self.triangle.length.clock();
+        self.noise.length.clock();
+        self.pulse1.clock_sweep();
+        self.pulse2.clock_sweep();
+    }
+
+    // Advances the frame counter and channel timers by one CPU cycle
+    pub fn clock(&mut self) {
+        let (quarter, half) = self.frame_counter.clock(self.region);
+        if quarter {
+            self.quarter_frame();
+        }
+        if half {
+            self.half_frame();
+        }
+
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        if self.cycle % 2 == 1 {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        self.cycle += 1;
+    }
+
+    pub fn outputs(&self) -> ChannelOutputs {
+        ChannelOutputs {
+            pulse1: self.pulse1.output(),
+            pulse2: self.pulse2.output(),
+            triangle: self.triangle.output(),
+            noise: self.noise.output(),
+            dmc: self.dmc.output(),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    #[test]
+    fn sweep_negate() {
+        use crate::apu::*;
+        let mut apu = Apu::new();
+
+        // Same period and shift, negated: pulse 1 lands one lower than pulse 2
+        apu.cpu_write(0x4001, 0b1000_1001);
+        apu.cpu_write(0x4002, 0x00);
+        apu.cpu_write(0x4003, 0x01);
+        apu.cpu_write(0x4005, 0b1000_1001);
+        apu.cpu_write(0x4006, 0x00);
+        apu.cpu_write(0x4007, 0x01);
+        assert_eq!(apu.pulse1.target_period(), 0x100 - 0x80 - 1);
+        assert_eq!(apu.pulse2.target_period(), 0x100 - 0x80);
+
+        // Divider starts expired, so the first half frame sweeps
+        apu.half_frame();
+        assert_eq!(apu.pulse1.timer_period, 0x7F);
+        assert_eq!(apu.pulse2.timer_period, 0x80);
+    }
+
+    #[test]
+    fn pulse_envelope_and_length() {
+        use crate::apu::*;
+        let mut apu = Apu::new();
+        apu.pulse1.length.set_enabled(true);
+
+        // 50% duty, decaying envelope with period 0, length index 1 (254)
+        apu.cpu_write(0x4000, 0b1000_0000);
+        apu.cpu_write(0x4002, 0x40);
+        apu.cpu_write(0x4003, 0b0000_1000);
+        assert_eq!(apu.pulse1.length.value, 254);
+
+        apu.quarter_frame();
+        let mut levels = Vec::new();
+        for _ in 0..(0x41 * 2 * 8) {
+            apu.clock();
+            levels.push(apu.outputs().pulse1);
+        }
+        assert_eq!(levels.iter().max(), Some(&15));
+        assert_eq!(levels.iter().filter(|&&level| level == 0).count(), levels.len() / 2);
+
+        apu.quarter_frame();
+        assert_eq!(apu.pulse1.envelope.output(), 14);
+
+        // Disabled channel drops its length counter and goes silent
+        apu.pulse1.length.set_enabled(false);
+        apu.clock();
+        assert_eq!(apu.outputs().pulse1, 0);
+    }
+
+    #[test]
+    fn triangle_linear_counter() {
+        use crate::apu::*;
+        let mut apu = Apu::new();
+        apu.triangle.length.set_enabled(true);
+
+        // Linear counter of 2 lets the sequencer run for two quarter frames
+        apu.cpu_write(0x4008, 0x02);
+        apu.cpu_write(0x400A, 0x00);
+        apu.cpu_write(0x400B, 0x08);
+        apu.quarter_frame();
+        apu.clock();
+        assert_eq!(apu.outputs().triangle, 14);
+        apu.quarter_frame();
+        apu.quarter_frame();
+        let held = apu.outputs().triangle;
+        for _ in 0..10 {
+            apu.clock();
+        }
+        assert_eq!(apu.outputs().triangle, held);
+
+        // Ultrasonic periods freeze the sequencer when silencing is on
+        apu.triangle.silence_ultrasonic = true;
+        apu.cpu_write(0x4008, 0x7F);
+        apu.cpu_write(0x400B, 0x08);
+        apu.quarter_frame();
+        for _ in 0..10 {
+            apu.clock();
+        }
+        assert_eq!(apu.outputs().triangle, held);
+    }
+
+    #[test]
+    fn noise_lfsr_period() {
+        use crate::apu::*;
+
+        // Long mode repeats every 32767 steps, short mode every 93
+        for (mode, period) in [(0x00, 32767), (0x80, 93)] {
+            let mut noise = Noise::new();
+            noise.write(2, mode, Region::Ntsc);
+            for _ in 0..100 {
+                noise.timer = 0;
+                noise.clock_timer();
+            }
+            let start = noise.shift_register;
+            let mut steps = 0;
+            loop {
+                noise.timer = 0;
+                noise.clock_timer();
+                steps += 1;
+                if noise.shift_register == start {
+                    break;
+                }
+            }
+            assert_eq!(steps, period);
+        }
+    }
+
+    #[test]
+    fn dmc_sample_playback() {
+        use crate::apu::*;
+        let mut apu = Apu::new();
+
+        // Fastest rate, IRQ on, one byte sample at $C000, level starts at 64
+        apu.cpu_write(0x4010, 0x8F);
+        apu.cpu_write(0x4011, 0x40);
+        apu.cpu_write(0x4012, 0x00);
+        apu.cpu_write(0x4013, 0x00);
+        apu.dmc.set_enabled(true);
+        assert_eq!(apu.dmc.dma_request.take(), Some(0xC000));
+        apu.dmc.fill_buffer(0b0000_1111);
+        assert!(apu.dmc.irq);
+
+        // The first 8 bits drain the silent shift register, then the sample plays
+        let mut levels = Vec::new();
+        for _ in 0..54 * 16 {
+            apu.clock();
+            levels.push(apu.outputs().dmc);
+        }
+        assert_eq!(levels[54 * 8 - 1], 0x40);
+        assert_eq!(*levels.iter().max().unwrap(), 0x40 + 8);
+        assert_eq!(*levels.last().unwrap(), 0x40);
+        assert_eq!(apu.dmc.dma_request, None);
+    }
+
+    #[test]
+    fn frame_irq() {
+        use crate::apu::*;
+        let mut apu = Apu::new();
+
+        // The $4017 write lands 3 cycles later on an even cycle
+        apu.cpu_write(0x4017, 0x00);
+        for _ in 0..3 + 29827 {
+            apu.clock();
+        }
+        assert!(!apu.irq());
+        apu.clock();
+        assert!(apu.irq());
+
+        // Reading $4015 acknowledges it, but the next two steps raise it again
+        assert_eq!(apu.read_status() & 0x40, 0x40);
+        assert_eq!(apu.read_status() & 0x40, 0x00);
+        apu.clock();
+        assert!(apu.irq());
+
+        // Setting the inhibit flag clears it and keeps it clear
+        apu.cpu_write(0x4017, 0x40);
+        assert!(!apu.irq());
+        for _ in 0..40000 {
+            apu.clock();
+        }
+        assert!(!apu.irq());
+    }
+
+    #[test]
+    fn status_open_bus() {
+        use crate::bus::Bus;
+        let mut bus = Bus::new();
+        bus.write(0x0000, 0xFF);
+        bus.write(0x4015, 0x01);
+        bus.write(0x4003, 0x08);
+
+        // Bit 5 keeps whatever was last on the bus
+        bus.read(0x0000);
+        assert_eq!(bus.read(0x4015), 0x21);
+        bus.read(0x0001);
+        assert_eq!(bus.read(0x4015), 0x01);
+    }
+
+    #[test]
+    fn delayed_frame_counter_write() {
+        use crate::apu::*;
+        let mut apu = Apu::new();
+
+        // STA $4017 starting on cycle 0 writes on cycle 3, an odd one, so the
+        // sequencer restarts 4 cycles after that
+        apu.cpu_write_at(0x4017, 0x00, 3);
+        for _ in 0..3 + 4 + 29827 {
+            apu.clock();
+        }
+        assert!(!apu.irq());
+        apu.clock();
+        assert!(apu.irq());
+
+        // Inhibit clears the flag when the write lands, not before
+        apu.cpu_write_at(0x4017, 0x40, 3);
+        apu.clock();
+        apu.clock();
+        assert!(apu.irq());
+        apu.clock();
+        assert!(!apu.irq());
+    }
+
+    #[test]
+    fn five_step_mode() {
+        use crate::apu::*;
+        let mut apu = Apu::new();
+
+        // Load a length counter, then switch to 5-step mode with an odd write cycle
+        apu.cpu_write(0x4015, 0x01);
+        apu.cpu_write(0x4000, 0x00);
+        apu.cpu_write(0x4003, 0x18); // Length index 3 loads 2
+        assert_eq!(apu.read_status() & 0x01, 0x01);
+        apu.clock();
+        apu.cpu_write(0x4017, 0x80);
+        for _ in 0..3 {
+            apu.clock();
+        }
+        assert_eq!(apu.pulse1.length.value, 2);
+
+        // The half frame clock fires as the write takes effect
+        apu.clock();
+        assert_eq!(apu.pulse1.length.value, 1);
+        for _ in 0..37282 {
+            apu.clock();
+        }
+        assert_eq!(apu.pulse1.length.value, 0);
+        assert_eq!(apu.read_status() & 0x01, 0x00);
+        assert!(!apu.irq());
+
+        // Disabling the DMC acknowledges its IRQ
+        apu.dmc.irq = true;
+        apu.cpu_write(0x4015, 0x00);
+        assert!(!apu.irq());
+    }
+}
@@ -0,0 +1,341 @@
+// APU output stage: nonlinear mixing, resampling to the host rate and the
+// console's analog filters
+// The mixer level is fed in once per CPU cycle. Each change in level is added
+// to the output as a band-limited step (a windowed sinc impulse, integrated on
+// read), so nothing above the host Nyquist frequency aliases back down.
+
+use crate::apu::ChannelOutputs;
+use crate::expansion::{ExpansionAudio, ExpansionChip, EXPANSION_CHIPS};
+
+// Impulse resolution: sub-sample positions and taps per impulse
+const PHASES: usize = 32;
+const TAPS: usize = 16;
+// Passband as a fraction of the host sample rate
+const CUTOFF: f64 = 0.45;
+
+// First order filters in the NES output path
+const HIGH_PASS_1_HZ: f32 = 90.0;
+const HIGH_PASS_2_HZ: f32 = 440.0;
+const LOW_PASS_HZ: f32 = 14_000.0;
+
+// A single voice that can be muted or soloed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    Dmc,
+    Expansion(usize), // Channel of the cartridge's sound chip
+}
+
+impl Channel {
+    // 2A03 channel names, or exp0, exp1, ... for the cartridge chip
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "pulse1" => Some(Channel::Pulse1),
+            "pulse2" => Some(Channel::Pulse2),
+            "triangle" => Some(Channel::Triangle),
+            "noise" => Some(Channel::Noise),
+            "dmc" => Some(Channel::Dmc),
+            name => name.strip_prefix("exp")?.parse().ok().map(Channel::Expansion),
+        }
+    }
+}
+
+// Lookup tables from the NESdev wiki (APU Mixer)
+pub struct Mixer {
+    pulse_table: [f32; 31],
+    tnd_table: [f32; 203],
+    muted: Vec<Channel>,
+    soloed: Vec<Channel>, // When not empty only these channels play
+    chip_volume: [f32; EXPANSION_CHIPS.len()],
+}
+
+impl Mixer {
+    pub fn new() -> Self {
+        let mut pulse_table = [0.0; 31];
+        for (n, level) in pulse_table.iter_mut().enumerate().skip(1) {
+            *level = 95.52 / (8128.0 / n as f32 + 100.0);
+        }
+        let mut tnd_table = [0.0; 203];
+        for (n, level) in tnd_table.iter_mut().enumerate().skip(1) {
+            *level = 163.67 / (24329.0 / n as f32 + 100.0);
+        }
+        Self {
+            pulse_table,
+            tnd_table,
+            muted: Vec::new(),
+            soloed: Vec::new(),
+            chip_volume: [1.0; EXPANSION_CHIPS.len()],
+        }
+    }
+
+    pub fn set_muted(&mut self, channel: Channel, muted: bool) {
+        self.muted.retain(|&c| c != channel);
+        if muted {
+            self.muted.push(channel);
+        }
+    }
+
+    pub fn set_solo(&mut self, channel: Channel, solo: bool) {
+        self.soloed.retain(|&c| c != channel);
+        if solo {
+            self.soloed.push(channel);
+        }
+    }
+
+    // User gain on top of the chip's calibrated level, 1.0 by default
+    pub fn set_chip_volume(&mut self, chip: ExpansionChip, volume: f32) {
+        self.chip_volume[chip.index()] = volume;
+    }
+
+    fn audible(&self, channel: Channel) -> bool {
+        !self.muted.contains(&channel) && (self.soloed.is_empty() || self.soloed.contains(&channel))
+    }
+
+    // 0.0 to about 1.0 for the 2A03 alone, expansion chips add on top
+    pub fn mix(&self, outputs: &ChannelOutputs, expansion: Option<&dyn ExpansionAudio>) -> f32 {
+        let level = |channel, value| if self.audible(channel) { value as usize } else { 0 };
+        let pulse = level(Channel::Pulse1, outputs.pulse1) + level(Channel::Pulse2, outputs.pulse2);
+        let tnd = 3 * level(Channel::Triangle, outputs.triangle)
+            + 2 * level(Channel::Noise, outputs.noise)
+            + level(Channel::Dmc, outputs.dmc);
+        let mut mix = self.pulse_table[pulse] + self.tnd_table[tnd];
+
+        if let Some(chip) = expansion {
+            // Scaled against one full volume pulse
+            let gain = self.pulse_table[15] * chip.chip().relative_volume() * self.chip_volume[chip.chip().index()];
+            for channel in 0..chip.channel_count() {
+                if self.audible(Channel::Expansion(channel)) {
+                    mix += chip.output(channel) * gain;
+                }
+            }
+        }
+        mix
+    }
+}
+
+// First order RC filter, high-pass or low-pass
+struct Filter {
+    high_pass: bool,
+    alpha: f32,
+    prev_in: f32,
+    prev_out: f32,
+}
+
+impl Filter {
+    fn new(high_pass: bool, cutoff_hz: f32, sample_rate: f32) -> Self {
+        let rc = 1.0 / (2.0 * std::f32::consts::PI * cutoff_hz);
+        let dt = 1.0 / sample_rate;
+        let alpha = if high_pass { rc / (rc + dt) } else { dt / (rc + dt) };
+        Self { high_pass, alpha, prev_in: 0.0, prev_out: 0.0 }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let output = if self.high_pass {
+            self.alpha * (self.prev_out + input - self.prev_in)
+        } else {
+            self.prev_out + self.alpha * (input - self.prev_out)
+        };
+        self.prev_in = input;
+        self.prev_out = output;
+        output
+    }
+}
+
+pub struct Resampler {
+    sample_rate: usize,
+    step: f64,                        // Output samples per input clock
+    time: f64,                        // Output samples since the buffer start
+    level: f32,                       // Last input level
+    integrator: f32,                  // Running sum of the deltas read so far
+    deltas: Vec<f32>,                 // Band-limited level changes, one slot per output sample
+    kernel: Vec<[f32; TAPS]>,         // Impulse for each sub-sample phase
+    filters: Vec<Filter>,
+}
+
+impl Resampler {
+    pub fn new(clock_hz: f64, sample_rate: usize) -> Self {
+        // Blackman windowed sinc, each phase normalised so a step settles at its full height
+        let mut kernel = Vec::with_capacity(PHASES);
+        for phase in 0..PHASES {
+            let mut taps = [0.0; TAPS];
+            for (tap, value) in taps.iter_mut().enumerate() {
+                let x = tap as f64 - (TAPS / 2) as f64 + 1.0 - phase as f64 / PHASES as f64;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    let angle = std::f64::consts::PI * 2.0 * CUTOFF * x;
+                    angle.sin() / angle
+                };
+                let w = std::f64::consts::PI * (x + (TAPS / 2) as f64) / TAPS as f64;
+                let window = 0.42 - 0.5 * (2.0 * w).cos() + 0.08 * (4.0 * w).cos();
+                *value = (sinc * window) as f32;
+            }
+            let sum: f32 = taps.iter().sum();
+            taps.iter_mut().for_each(|value| *value /= sum);
+            kernel.push(taps);
+        }
+
+        let rate = sample_rate as f32;
+        let filters = vec![
+            Filter::new(true, HIGH_PASS_1_HZ, rate),
+            Filter::new(true, HIGH_PASS_2_HZ, rate),
+            Filter::new(false, LOW_PASS_HZ, rate),
+        ];
+
+        Self {
+            sample_rate,
+            step: sample_rate as f64 / clock_hz,
+            time: 0.0,
+            level: 0.0,
+            integrator: 0.0,
+            deltas: vec![0.0; TAPS],
+            kernel,
+            filters,
+        }
+    }
+
+    pub fn sample_rate(&self) -> usize {
+        self.sample_rate
+    }
+
+    pub fn buffered(&self) -> usize {
+        self.deltas.len()
+    }
+
+    // Takes the input level for one clock
+    pub fn clock(&mut self, level: f32) {
+        let delta = level - self.level;
+        if delta != 0.0 {
+            self.level = level;
+            let index = self.time as usize;
+            let phase = ((self.time - index as f64) * PHASES as f64) as usize;
+            if self.deltas.len() < index + TAPS {
+                self.deltas.resize(index + TAPS, 0.0);
+            }
+            for (slot, tap) in self.deltas[index..index + TAPS].iter_mut().zip(self.kernel[phase]) {
+                *slot += delta * tap;
+            }
+        }
+        self.time += self.step;
+    }
+
+    // Drains every output sample that no longer depends on future input
+    pub fn read_samples(&mut self) -> Vec<i16> {
+        let count = self.time as usize;
+        if self.deltas.len() < count + TAPS {
+            self.deltas.resize(count + TAPS, 0.0);
+        }
+
+        let mut samples = Vec::with_capacity(count);
+        for delta in self.deltas.drain(..count) {
+            self.integrator += delta;
+            let mut sample = self.integrator;
+            for filter in self.filters.iter_mut() {
+                sample = filter.process(sample);
+            }
+            samples.push((sample * 32767.0).clamp(-32768.0, 32767.0) as i16);
+        }
+        self.time -= count as f64;
+        samples
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    #[test]
+    fn mixer_tables() {
+        use crate::apu::ChannelOutputs;
+        use crate::audio::*;
+        let mixer = Mixer::new();
+
+        let silent = ChannelOutputs { pulse1: 0, pulse2: 0, triangle: 0, noise: 0, dmc: 0 };
+        assert_eq!(mixer.mix(&silent, None), 0.0);
+
+        // Two pulses at 15 are louder than one, but not twice as loud
+        let one = ChannelOutputs { pulse1: 15, ..silent };
+        let two = ChannelOutputs { pulse1: 15, pulse2: 15, ..silent };
+        assert!((mixer.mix(&one, None) - 0.1494).abs() < 0.001);
+        assert!(mixer.mix(&two, None) < 2.0 * mixer.mix(&one, None));
+
+        let full = ChannelOutputs { pulse1: 15, pulse2: 15, triangle: 15, noise: 15, dmc: 127 };
+        assert!((mixer.mix(&full, None) - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn expansion_mute_and_solo() {
+        use crate::apu::ChannelOutputs;
+        use crate::audio::*;
+
+        struct FullVolume;
+        impl ExpansionAudio for FullVolume {
+            fn chip(&self) -> ExpansionChip {
+                ExpansionChip::Fds
+            }
+            fn write(&mut self, _addr: u16, _data: u8) {}
+            fn clock(&mut self) {}
+            fn channel_count(&self) -> usize {
+                1
+            }
+            fn output(&self, _channel: usize) -> f32 {
+                1.0
+            }
+        }
+
+        let mut mixer = Mixer::new();
+        let pulse = ChannelOutputs { pulse1: 15, pulse2: 0, triangle: 0, noise: 0, dmc: 0 };
+        let pulse_level = mixer.mix(&pulse, None);
+
+        // The FDS sits at 2.4 times a full pulse
+        let both = mixer.mix(&pulse, Some(&FullVolume));
+        assert!((both - pulse_level * 3.4).abs() < 0.001);
+        mixer.set_chip_volume(ExpansionChip::Fds, 0.5);
+        let both = mixer.mix(&pulse, Some(&FullVolume));
+        assert!((both - pulse_level * 2.2).abs() < 0.001);
+
+        mixer.set_muted(Channel::Expansion(0), true);
+        assert_eq!(mixer.mix(&pulse, Some(&FullVolume)), pulse_level);
+        mixer.set_muted(Channel::Expansion(0), false);
+
+        // Soloing a silent channel silences everything else
+        mixer.set_solo(Channel::Triangle, true);
+        assert_eq!(mixer.mix(&pulse, Some(&FullVolume)), 0.0);
+        mixer.set_solo(Channel::Pulse1, true);
+        assert_eq!(mixer.mix(&pulse, Some(&FullVolume)), pulse_level);
+
+        assert_eq!(Channel::parse("exp3"), Some(Channel::Expansion(3)));
+        assert_eq!(Channel::parse("Noise"), Some(Channel::Noise));
+        assert_eq!(Channel::parse("exp"), None);
+    }
+
+    #[test]
+    fn resampled_square_wave() {
+        use crate::audio::*;
+        let clock_hz = 1_789_773.0;
+        let mut resampler = Resampler::new(clock_hz, 44_100);
+
+        // One second of a 1 kHz square wave
+        let half_period = (clock_hz / 2000.0) as usize;
+        for cycle in 0..clock_hz as usize {
+            let level = if (cycle / half_period).is_multiple_of(2) { 0.5 } else { 0.0 };
+            resampler.clock(level);
+        }
+        let samples = resampler.read_samples();
+        assert_eq!(samples.len(), 44_100);
+
+        // The high-pass filters centre the wave around zero
+        let tail = &samples[22_050..];
+        let max = *tail.iter().max().unwrap();
+        let min = *tail.iter().min().unwrap();
+        assert!(max > 10_000 && min < -10_000);
+        let mean = tail.iter().map(|&s| s as i64).sum::<i64>() / tail.len() as i64;
+        assert!(mean.abs() < 500);
+
+        // Band limiting keeps the edges from overshooting much past the plateaus
+        assert!((max as i32 + min as i32).abs() < 3000);
+    }
+}
@@ -1,7 +1,7 @@
 // Add memory map guards based on addressing
 
 // $0000–$07FF 	$0800 	2 KB internal RAM
-// $0800–$0FFF 	$0800 	
+// $0800–$0FFF 	$0800
 // $1000–$17FF 	$0800   Mirrors of $0000–$07FF
 // $1800–$1FFF 	$0800
 // $2000–$2007 	$0008 	NES PPU registers
@@ -10,36 +10,212 @@
 // $4018–$401F 	$0008 	APU and I/O functionality that is normally disabled. See CPU Test Mode.
 // $4020–$FFFF 	$BFE0 	Cartridge space: PRG ROM, PRG RAM, and mapper registers
 
+use crate::apu::Apu;
+use crate::cdl::{self, CodeDataLog};
+use crate::controller::Controller;
+use crate::debugger::Access;
+use crate::input::{InputDevice, Unplugged};
+use crate::ppu::Ppu;
 use crate::ram::Ram;
+use crate::rom::Rom;
+use crate::savestate::State;
+use crate::symbols::SymbolTable;
+use crate::watch::{Space, Watcher};
 
 pub struct Bus {
+    pub ram: Ram,
+    pub ppu: Ppu,
+    pub apu: Apu,
+    pub rom: Rom,
+    pub ports: [Box<dyn InputDevice>; 2], // Controller ports 1 and 2
+    pub expansion_port: Box<dyn InputDevice>,
+    pub microphone: bool, // Famicom controller 2 microphone, $4016 D2
+    pub oam_dma: Option<u8>, // Page written to $4014, waiting to be copied
+    pub last_read: usize,    // Address of the most recent CPU read
+    pub access_delay: usize, // CPU cycles until the running instruction's last cycle
+    pub ppu_catch_up: usize, // Dots the PPU may run ahead before a register access
+    pub ppu_ahead: usize,    // Dots already run ahead, which Nes::clock skips
+    open_bus: u8,            // Last value on the data bus, seen in undriven bits
+    pub watch: Option<Box<Watcher>>, // Watchpoints, None keeps reads and writes free of them
+    pub cdl: Option<Box<CodeDataLog>>, // Code/data logging, None keeps reads free of it
+    pub symbols: SymbolTable,          // Names for traces and the debugger
 }
 
 impl Bus {
     pub fn new() -> Self {
         Self {
+            ram: Ram::new(),
+            ppu: Ppu::new(),
+            apu: Apu::new(),
+            rom: Rom::new(),
+            ports: [Box::new(Controller::new()), Box::new(Controller::new())],
+            expansion_port: Box::new(Unplugged),
+            microphone: false,
+            oam_dma: None,
+            last_read: 0,
+            access_delay: 0,
+            ppu_catch_up: 0,
+            ppu_ahead: 0,
+            open_bus: 0,
+            watch: None,
+            cdl: None,
+            symbols: SymbolTable::new(),
         }
     }
 
+    // Debugging state and the microphone, which is input, stay as they are
+    pub fn sync_state(&mut self, state: &mut State) {
+        self.ram.sync_state(state);
+        self.ppu.sync_state(state);
+        self.apu.sync_state(state);
+        self.rom.sync_state(state);
+        self.ports.iter_mut().for_each(|device| device.sync_state(state));
+        self.expansion_port.sync_state(state);
+        state.option(&mut self.oam_dma, State::u8);
+        state.u8(&mut self.open_bus);
+    }
+
+    pub fn read(&mut self, addr: usize) -> u8 {
+        if self.watch.is_some() || self.cdl.is_some() {
+            return self.instrumented_read(addr);
+        }
+        self.read_memory(addr)
+    }
+
     // Update naming conventions to reflect broader addressing
-    pub fn read(&self, ram: &Ram, addr: usize) -> u8 {
-        match addr {
-            // Finish building full address range with temp panics for each function
-            0x0000..=0x1FFF => ram.read(addr & 0x7FF),
+    fn read_memory(&mut self, addr: usize) -> u8 {
+        self.last_read = addr;
+        let data = match addr {
+            0x0000..=0x1FFF => self.ram.read(addr & 0x7FF),
+            0x2000..=0x3FFF => {
+                self.catch_up_ppu();
+                self.ppu.cpu_read(addr & 0x2007, &self.rom)
+            }
+            // Bit 5 isn't driven
+            0x4015 => (self.open_bus & 0x20) | (self.apu.read_status() & !0x20),
+            // Only the low bits are driven, the rest keep the last value on the
+            // bus, which for LDA $4016 is the $40 of the operand
+            0x4016 | 0x4017 => {
+                let port = addr - 0x4016;
+                let mut bits = self.ports[port].read(port, &self.ppu) | self.expansion_port.read(port, &self.ppu);
+                if port == 0 && self.microphone {
+                    bits |= 0x04;
+                }
+                (self.open_bus & 0xE0) | bits
+            }
+            0x4020..=0xFFFF => self.rom.cpu_read(addr),
+            // Write only and disabled registers don't drive the bus
+            0x4000..=0x401F => self.open_bus,
             _ => panic!("Address {:?} outside valid read range", addr)
+        };
+        self.open_bus = data;
+        data
+    }
+
+    // Reads without side effects, for trace logs and debuggers
+    // Registers aren't read since that changes them, they show as $FF
+    pub fn peek(&self, addr: usize) -> u8 {
+        match addr {
+            0x0000..=0x1FFF => self.ram.read(addr & 0x7FF),
+            0x4020..=0xFFFF => self.rom.cpu_read(addr),
+            _ => 0xFF,
         }
     }
 
-    pub fn read_u16 (&self, ram: &Ram, addr: usize) -> u16 {
-        let addr_lo = ram.read(addr as usize);
-        let addr_hi = ram.read((addr + 1) as usize);
+    pub fn read_u16 (&mut self, addr: usize) -> u16 {
+        let addr_lo = self.read(addr);
+        let addr_hi = self.read((addr + 1) & 0xFFFF);
         (addr_hi as u16) << 8 | addr_lo as u16
     }
 
-    pub fn write(&self, ram: &mut Ram, addr: usize, data: u8) {
+    pub fn write(&mut self, addr: usize, data: u8) {
+        if self.watch.is_some() {
+            return self.watched_write(addr, data);
+        }
+        self.write_memory(addr, data);
+    }
+
+    fn write_memory(&mut self, addr: usize, data: u8) {
+        self.open_bus = data;
         match addr {
-            0x0000..=0x1FFF => ram.write(addr & 0x7FF, data),
+            0x0000..=0x1FFF => self.ram.write(addr & 0x7FF, data),
+            0x2000..=0x3FFF => {
+                self.catch_up_ppu();
+                self.ppu.cpu_write(addr & 0x2007, data, &mut self.rom)
+            }
+            0x4014 => self.oam_dma = Some(data),
+            0x4016 => {
+                self.ports.iter_mut().for_each(|device| device.write(data));
+                self.expansion_port.write(data);
+            }
+            0x4000..=0x4013 | 0x4015 | 0x4017 => self.apu.cpu_write_at(addr, data, self.access_delay),
+            0x4020..=0xFFFF => self.rom.cpu_write(addr, data),
+            0x4018..=0x401F => {}
             _ => panic!("Address {:?} outside valid write range", addr)
         }
     }
-}
\ No newline at end of file
+
+    // Instructions run whole on their first cycle but reach PPU registers on
+    // their last, so the PPU is clocked up to that cycle first
+    fn catch_up_ppu(&mut self) {
+        while self.ppu_catch_up > 0 {
+            self.ppu_catch_up -= 1;
+            self.ppu_ahead += 1;
+            self.ppu.clock(&self.rom, self.cdl.as_deref_mut());
+        }
+    }
+
+    // PPU memory or OAM reached through a $2004 or $2007 access
+    fn ppu_target(&self, addr: usize) -> Option<(Space, u16)> {
+        match addr {
+            0x2000..=0x3FFF if addr & 0x2007 == 0x2004 => Some((Space::Oam, self.ppu.oam_addr() as u16)),
+            0x2000..=0x3FFF if addr & 0x2007 == 0x2007 => Some((Space::Ppu, self.ppu.vram_addr())),
+            _ => None,
+        }
+    }
+
+    // Reads with watchpoints or the code/data logger installed
+    #[inline(never)]
+    fn instrumented_read(&mut self, addr: usize) -> u8 {
+        if let Some(cdl) = self.cdl.as_mut() {
+            match addr {
+                0x8000..=0xFFFF => cdl.log_read(&self.rom, addr as u16),
+                0x2000..=0x3FFF if addr & 0x2007 == 0x2007 => {
+                    cdl.log_chr(&self.rom, (self.ppu.vram_addr() & 0x3FFF) as usize, cdl::READ)
+                }
+                _ => {}
+            }
+            if self.watch.is_none() {
+                return self.read_memory(addr);
+            }
+        }
+        // The byte $2007 fetches from PPU memory, rather than the buffered one returned
+        let target = self.ppu_target(addr).map(|(space, target)| match space {
+            Space::Ppu => (space, target, self.ppu.ppu_read(target as usize, &self.rom)),
+            _ => (space, target, self.ppu.oam[target as usize]),
+        });
+        let data = self.read_memory(addr);
+        let watch = self.watch.as_mut().unwrap();
+        watch.check(Space::Cpu, Access::Read, addr as u16, data);
+        if let Some((space, target, value)) = target {
+            watch.check(space, Access::Read, target, value);
+        }
+        data
+    }
+
+    #[inline(never)]
+    fn watched_write(&mut self, addr: usize, data: u8) {
+        let target = self.ppu_target(addr);
+        self.write_memory(addr, data);
+        let watch = self.watch.as_mut().unwrap();
+        watch.check(Space::Cpu, Access::Write, addr as u16, data);
+        if let Some((space, target)) = target {
+            watch.check(space, Access::Write, target, data);
+        }
+    }
+
+    // Whether a watchpoint has stopped emulation
+    pub fn halted(&self) -> bool {
+        self.watch.as_ref().is_some_and(|watch| watch.halted.is_some())
+    }
+}
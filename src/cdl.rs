@@ -0,0 +1,199 @@
+// Code/Data Logger: which PRG ROM bytes ran as code, were read as data or
+// played as DMC samples, and which CHR ROM bytes were rendered or read
+// through $2007. Installed on the bus like the watcher, so a run without one
+// pays a single None check.
+//
+// Logs are saved in the FCEUX .cdl layout, a flag byte for every PRG ROM byte
+// followed by one for every CHR ROM byte (none with CHR RAM).
+//   PRG xPdcAADC: C code, D data, AA the 8 KB CPU window ($8000, $A000, $C000
+//       or $E000) it was read through, c indirect code (JMP ($nnnn) targets),
+//       d indirect data (through ($nn,X) and ($nn),Y), P DMC sample
+//   CHR xxxxxxRD: D rendered, R read through $2007
+// FCEUX logs operands as code too, so opcodes are told apart by bit 7, which
+// the format leaves unused. It is cleared on save and lost on load.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::disasm::{AddrMode, Instruction, OPCODES};
+use crate::rom::Rom;
+
+pub const CODE: u8 = 0x01;
+pub const DATA: u8 = 0x02;
+pub const INDIRECT_CODE: u8 = 0x10;
+pub const INDIRECT_DATA: u8 = 0x20;
+pub const PCM: u8 = 0x40;
+pub const OPCODE: u8 = 0x80;
+
+pub const RENDERED: u8 = 0x01;
+pub const READ: u8 = 0x02;
+
+pub struct CodeDataLog {
+    pub prg: Vec<u8>,
+    pub chr: Vec<u8>,
+    instruction: (u16, u16), // pc and length of the running instruction, whose own bytes aren't data
+    data_flags: u8,          // How the running instruction's reads are logged
+    indirect_jump: bool,     // The running instruction is JMP ($nnnn)
+}
+
+impl CodeDataLog {
+    pub fn new(rom: &Rom) -> Self {
+        Self {
+            prg: vec![0; rom.prg_rom.len()],
+            chr: vec![0; rom.chr_rom_len()],
+            instruction: (0, 0),
+            data_flags: DATA,
+            indirect_jump: false,
+        }
+    }
+
+    // Continues a saved log, which must be sized for this ROM
+    pub fn load(path: &Path, rom: &Rom) -> io::Result<Self> {
+        let data = fs::read(path)?;
+        let mut log = Self::new(rom);
+        if data.len() != log.prg.len() + log.chr.len() {
+            let message = format!("{} bytes, expected {} for this ROM", data.len(), log.prg.len() + log.chr.len());
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
+        let (prg, chr) = data.split_at(log.prg.len());
+        log.prg.copy_from_slice(prg);
+        log.chr.copy_from_slice(chr);
+        Ok(log)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut data: Vec<u8> = self.prg.iter().map(|flags| flags & !OPCODE).collect();
+        data.extend_from_slice(&self.chr);
+        fs::write(path, data)
+    }
+
+    fn log_prg(&mut self, rom: &Rom, addr: u16, flags: u8) {
+        if let Some(byte) = rom.prg_offset(addr as usize).and_then(|offset| self.prg.get_mut(offset)) {
+            *byte |= flags | ((addr >> 11) & 0x0C) as u8;
+        }
+    }
+
+    // Called at each instruction boundary before the instruction at pc runs
+    pub fn begin_instruction(&mut self, rom: &Rom, pc: u16, opcode: u8) {
+        let opcode = &OPCODES[opcode as usize];
+        if self.indirect_jump {
+            self.log_prg(rom, pc, INDIRECT_CODE);
+        }
+        self.indirect_jump = opcode.instruction == Instruction::JMP && opcode.mode == AddrMode::IND;
+        self.instruction = (pc, opcode.bytes as u16);
+        self.data_flags = match opcode.mode {
+            AddrMode::INX | AddrMode::INY => DATA | INDIRECT_DATA,
+            _ => DATA,
+        };
+        self.log_prg(rom, pc, CODE | OPCODE);
+        for offset in 1..opcode.bytes as u16 {
+            self.log_prg(rom, pc.wrapping_add(offset), CODE);
+        }
+    }
+
+    // Called instead when the boundary enters an interrupt handler, whose
+    // vector fetch is a plain data read
+    pub fn begin_interrupt(&mut self) {
+        self.instruction = (0, 0);
+        self.data_flags = DATA;
+        self.indirect_jump = false;
+    }
+
+    // CPU read of cartridge space
+    pub fn log_read(&mut self, rom: &Rom, addr: u16) {
+        let (pc, bytes) = self.instruction;
+        if addr.wrapping_sub(pc) >= bytes {
+            self.log_prg(rom, addr, self.data_flags);
+        }
+    }
+
+    pub fn log_sample(&mut self, rom: &Rom, addr: u16) {
+        self.log_prg(rom, addr, PCM);
+    }
+
+    // Pattern table access at a PPU address
+    pub fn log_chr(&mut self, rom: &Rom, addr: usize, flags: u8) {
+        if let Some(byte) = rom.chr_offset(addr).and_then(|offset| self.chr.get_mut(offset)) {
+            *byte |= flags;
+        }
+    }
+
+    // "PRG 1234 code, 567 data, 0 both, 14583 unused of 16384; CHR 2048 rendered, 0 read, 6144 unused of 8192"
+    pub fn summary(&self) -> String {
+        let count = |log: &[u8], test: &dyn Fn(u8) -> bool| log.iter().filter(|&&flags| test(flags)).count();
+        let mut text = format!(
+            "PRG {} code, {} data, {} both, {} unused of {}",
+            count(&self.prg, &|flags| flags & CODE != 0 && flags & (DATA | PCM) == 0),
+            count(&self.prg, &|flags| flags & CODE == 0 && flags & (DATA | PCM) != 0),
+            count(&self.prg, &|flags| flags & CODE != 0 && flags & (DATA | PCM) != 0),
+            count(&self.prg, &|flags| flags & (CODE | DATA | PCM) == 0),
+            self.prg.len()
+        );
+        if !self.chr.is_empty() {
+            text += &format!(
+                "; CHR {} rendered, {} read, {} unused of {}",
+                count(&self.chr, &|flags| flags & RENDERED != 0),
+                count(&self.chr, &|flags| flags & READ != 0),
+                count(&self.chr, &|flags| flags == 0),
+                self.chr.len()
+            );
+        }
+        text
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    // LDX #$00 / LDA $C010,X / LDA ($00),Y / JMP ($C014), with $C014 pointing
+    // at $C020: JMP $C020
+    fn load_program() -> crate::nes::Nes {
+        let mut nes = crate::nes::Nes::new();
+        let mut prg_rom = vec![0xEA; 0x4000];
+        let program = [0xA2, 0x00, 0xBD, 0x10, 0xC0, 0xB1, 0x00, 0x6C, 0x14, 0xC0];
+        prg_rom[..program.len()].copy_from_slice(&program);
+        prg_rom[0x14..0x16].copy_from_slice(&[0x20, 0xC0]);
+        prg_rom[0x20..0x23].copy_from_slice(&[0x4C, 0x20, 0xC0]);
+        prg_rom[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0xC0]);
+        nes.bus.rom.prg_rom = prg_rom;
+        // ($00) points at $C012
+        nes.bus.ram.write(0x00, 0x12);
+        nes.bus.ram.write(0x01, 0xC0);
+        nes.reset();
+        nes
+    }
+
+    #[test]
+    fn logs_code_and_data() {
+        use crate::cdl::*;
+        let mut nes = load_program();
+        nes.bus.cdl = Some(Box::new(CodeDataLog::new(&nes.bus.rom)));
+        for _ in 0..5 {
+            nes.step();
+        }
+        let log = nes.bus.cdl.take().unwrap();
+        // Everything ran through the $C000 window, bank bits 10
+        assert_eq!(log.prg[0x00], OPCODE | CODE | 0x08);
+        assert_eq!(log.prg[0x01], CODE | 0x08);
+        assert_eq!(log.prg[0x10], DATA | 0x08);
+        assert_eq!(log.prg[0x12], DATA | INDIRECT_DATA | 0x08);
+        assert_eq!(log.prg[0x14], DATA | 0x08);
+        assert_eq!(log.prg[0x20], OPCODE | CODE | INDIRECT_CODE | 0x08);
+        assert_eq!(log.prg[0x0A], 0);
+        // The reset vector was read as data before logging started
+        assert_eq!(log.prg[0x3FFC], 0);
+        assert_eq!(log.summary(), "PRG 13 code, 4 data, 0 both, 16367 unused of 16384");
+
+        let path = std::env::temp_dir().join("nebulous_cdl_test.cdl");
+        log.save(&path).unwrap();
+        let saved = std::fs::read(&path).unwrap();
+        assert_eq!((saved.len(), saved[0x00], saved[0x20]), (0x4000, CODE | 0x08, CODE | INDIRECT_CODE | 0x08));
+        let loaded = CodeDataLog::load(&path, &nes.bus.rom).unwrap();
+        assert_eq!(loaded.prg[0x12], log.prg[0x12]);
+        nes.bus.rom.prg_rom.truncate(0x2000);
+        assert!(CodeDataLog::load(&path, &nes.bus.rom).is_err());
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,186 @@
+// Standard joypad
+// Writing 1 to $4016 holds the shift register in reload; writing 0 latches the
+// buttons. Each read of $4016/$4017 then returns the next button in bit 0, in
+// the order A, B, Select, Start, Up, Down, Left, Right, and 1 once all eight
+// have been read.
+
+use std::any::Any;
+
+use crate::input::InputDevice;
+use crate::ppu::Ppu;
+use crate::savestate::State;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Button {
+    A = 1 << 0,
+    B = 1 << 1,
+    Select = 1 << 2,
+    Start = 1 << 3,
+    Up = 1 << 4,
+    Down = 1 << 5,
+    Left = 1 << 6,
+    Right = 1 << 7,
+}
+
+pub struct Controller {
+    buttons: u8, // Held buttons, one bit per Button
+    shift: u8,
+    strobe: bool,
+    pub allow_opposite: bool, // Let Left+Right and Up+Down through, which a real pad can't press
+}
+
+impl Controller {
+    pub fn new() -> Self {
+        Self {
+            buttons: 0,
+            shift: 0,
+            strobe: false,
+            allow_opposite: false,
+        }
+    }
+
+    // Held buttons are input and stay as they are
+    pub fn sync_state(&mut self, state: &mut State) {
+        state.u8(&mut self.shift);
+        state.bool(&mut self.strobe);
+    }
+
+    // Replaces the held buttons, usually once per frame
+    pub fn set_buttons(&mut self, buttons: u8) {
+        self.buttons = buttons;
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        if pressed {
+            self.buttons |= button as u8;
+        } else {
+            self.buttons &= !(button as u8);
+        }
+    }
+
+    pub fn buttons(&self) -> u8 {
+        self.buttons
+    }
+
+    // Buttons as the game will see them, opposite directions cancel out unless allowed
+    fn latched_buttons(&self) -> u8 {
+        let mut buttons = self.buttons;
+        if !self.allow_opposite {
+            for pair in [Button::Up as u8 | Button::Down as u8, Button::Left as u8 | Button::Right as u8] {
+                if buttons & pair == pair {
+                    buttons &= !pair;
+                }
+            }
+        }
+        buttons
+    }
+
+    // $4016 bit 0
+    pub fn write(&mut self, data: u8) {
+        self.strobe = data & 0x01 != 0;
+        if self.strobe {
+            self.shift = self.latched_buttons();
+        }
+    }
+
+    // Bit 0 only, the caller fills in the open bus bits
+    pub fn read(&mut self) -> u8 {
+        if self.strobe {
+            return self.latched_buttons() & 0x01;
+        }
+        let bit = self.shift & 0x01;
+        // Official pads shift in 1s behind the last button
+        self.shift = (self.shift >> 1) | 0x80;
+        bit
+    }
+}
+
+impl InputDevice for Controller {
+    fn write(&mut self, data: u8) {
+        Controller::write(self, data);
+    }
+
+    fn read(&mut self, _port: usize, _ppu: &Ppu) -> u8 {
+        Controller::read(self)
+    }
+
+    fn set_buttons(&mut self, slot: usize, buttons: u8) {
+        if slot == 0 {
+            Controller::set_buttons(self, buttons);
+        }
+    }
+
+    fn set_allow_opposite(&mut self, allow: bool) {
+        self.allow_opposite = allow;
+    }
+
+    fn sync_state(&mut self, state: &mut State) {
+        Controller::sync_state(self, state);
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    #[test]
+    fn shift_register() {
+        use crate::controller::*;
+        let mut controller = Controller::new();
+        controller.set_button(Button::A, true);
+        controller.set_button(Button::Start, true);
+        controller.set_button(Button::Left, true);
+
+        // Strobe high keeps returning A
+        controller.write(1);
+        assert_eq!(controller.read(), 1);
+        assert_eq!(controller.read(), 1);
+        controller.write(0);
+
+        let bits: Vec<u8> = (0..10).map(|_| controller.read()).collect();
+        assert_eq!(bits, vec![1, 0, 0, 1, 0, 0, 1, 0, 1, 1]);
+    }
+
+    #[test]
+    fn opposite_directions() {
+        use crate::controller::*;
+        let mut controller = Controller::new();
+        controller.set_buttons(Button::Left as u8 | Button::Right as u8 | Button::Up as u8);
+
+        controller.write(1);
+        controller.write(0);
+        let bits: Vec<u8> = (0..8).map(|_| controller.read()).collect();
+        assert_eq!(bits, vec![0, 0, 0, 0, 1, 0, 0, 0]);
+
+        controller.allow_opposite = true;
+        controller.write(1);
+        controller.write(0);
+        let bits: Vec<u8> = (0..8).map(|_| controller.read()).collect();
+        assert_eq!(bits, vec![0, 0, 0, 0, 1, 0, 1, 1]);
+    }
+
+    #[test]
+    fn open_bus_bits() {
+        use crate::bus::Bus;
+        use crate::controller::*;
+        let mut bus = Bus::new();
+        let mut controller = Controller::new();
+        controller.set_button(Button::A, true);
+        bus.ports[1] = Box::new(controller);
+        bus.write(0x4016, 1);
+        bus.write(0x4016, 0);
+
+        // The upper bits keep whatever was last on the bus, here the high
+        // byte of an absolute operand
+        bus.write(0x0000, 0x40);
+        bus.read(0x0000);
+        assert_eq!(bus.read(0x4016), 0x40);
+        bus.read(0x0000);
+        assert_eq!(bus.read(0x4017), 0x41);
+        assert_eq!(bus.read(0x4017), 0x41 & 0xE0);
+    }
+}
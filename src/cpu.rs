@@ -1,95 +1,21 @@
-// TODO:
-// Set PC (0xFFFC?)
-//     and expand functions to allow fetch of first opcode from live ROM
-// Build out address resolution functions
+// 2A03 CPU: a 6502 without decimal mode
+// Every official and unofficial opcode is decoded through disasm::OPCODES.
+// Instructions execute whole on their first cycle and add their cycle count,
+// including page crossing and branch penalties, for Nes to count down.
+
+use std::io::Write;
 
 use crate::bus::Bus;
-use crate::ram::Ram;
+use crate::disasm::{self, AddrMode, Instruction, LabelLookup, OPCODES};
+use crate::savestate::State;
 
 enum ProgramCounter {
     Next,
     Skip,
-    Jump,
-}
-
-// May use later for disassembler
-// enum Instruction {
-//     None, // No Instruction
-//     ADC, // Add with Carry
-//     AND, // Logical AND
-//     ASL, // Arithmetic Shift Left
-//     BCC, // Branch if Carry Clear
-//     BCS, // Branch if Carry Set
-//     BEQ, // Branch if Equal
-//     BIT, // Bit Test
-//     BMI, // Branch if Minus
-//     BNE, // Branch if Not Equal
-//     BPL, // Branch if Positive
-//     BRK, // Force Interrupt
-//     BVC, // Branch if Overflow Clear
-//     BVS, // Branch if Overflow Set
-//     CLC, // Clear Carry Flag
-//     CLD, // Clear Decimal Mode
-//     CLI, // Clear Interrupt Disable
-//     CLV, // Clear Oveerflow Flag
-//     CMP, // Compare
-//     CPX, // Compare X Register
-//     CPY, // Compare Y Register
-//     DEC, // Decrement Memory
-//     DEX, // Decrement X Register
-//     DEY, // Decrement Y Register
-//     EOR, // Exclusive OR
-//     INC, // Increment Memory
-//     INX, // Increment X Register
-//     INY, // Increment Y Register
-//     JMP, // Jump
-//     JSR, // Jump to Subroutine
-//     LDA, // Load Accumulator
-//     LDX, // Load X Register
-//     LDY, // Load Y Register
-//     LSR, // Logical Shift Right
-//     NOP, // No Operation
-//     ORA, // Logical Inclusive OR
-//     PHA, // Push Accumulator
-//     PHP, // Push Processor Status
-//     PLA, // Pull Accumulator
-//     PLP, // Pull Processor Status
-//     ROL, // Rotate Left
-//     ROR, // Rotate Right
-//     RTI, // Return from Interrupt
-//     RTS, // Return from Subroutine
-//     SBC, // Subtract with Carry
-//     SEC, // Set Carry Flag
-//     SED, // Set Decimal Flag
-//     SEI, // Set Interrupt Disable
-//     STA, // Store Accumulator
-//     STX, // Store X Register
-//     STY, // Store Y Register
-//     TAX, // Transfer Accumulator to X
-//     TAY, // Transfer Accumulator to Y
-//     TSX, // Transfer Stack Pointer to X
-//     TXA, // Transfer X to Accumulator
-//     TXS, // Transfer X to Stack Pointer
-//     TYA, // Transfer Y to Accumulator
-// }
-
-#[derive(Debug)]
-enum AddrMode {
-    ZPX, // Zero Page Indexed X
-    ZPY, // Zero Page Indexed Y
-    ABX, // Absolute Indexed X
-    ABY, // Absolute Indexed Y
-    INX, // Indirect Indexed X
-    INY, // Indirect Indexed Y
-    IMP, // Implicit
-    ACC, // Accumulator
-    IMM, // Immediate
-    ZPG, // ZeroPage
-    ABS, // Absolute
-    REL, // Relative
-    IND, // Indirect
+    Jump(u16),
 }
 
+#[allow(dead_code)]
 enum CpuFlag {
     C = 1 << 0, // Carry
     Z = 1 << 1, // Zero
@@ -101,6 +27,17 @@ enum CpuFlag {
     N = 1 << 7, // Negative
 }
 
+// Register file as seen by debuggers
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Registers {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub pc: u16,
+    pub sp: u8,
+    pub p: u8,
+}
+
 pub struct Cpu {
     a: u8,   // Accumulator
     pub x: u8,   // X Index; Temp pub for testing
@@ -109,8 +46,13 @@ pub struct Cpu {
     sp: u8,  // Stack Pointer
     p: u8,   // Status Flags
 
-    pub cycles: usize,
+    pub cycles: usize,       // Cycles left before the next instruction
+    pub total_cycles: usize, // Cycles since power on
+    pub stall_cycles: usize, // Cycles lost to DMA since power on
     page_crossed: bool,
+    pub nmi: bool, // NMI line, serviced before the next opcode fetch
+    pub irq: bool, // IRQ line level, serviced while I is clear
+    pub tracer: Option<Box<dyn Write>>, // Gets a nestest.log line per instruction
 }
 
 impl Cpu {
@@ -124,25 +66,128 @@ impl Cpu {
             p: 0x00,
 
             cycles: 0,
+            total_cycles: 0,
+            stall_cycles: 0,
             page_crossed: false,
+            nmi: false,
+            irq: false,
+            tracer: None,
         }
     }
 
-    pub fn debug_print(&self) {
-        println!(" A: {:02X?}", self.a);
-        println!(" X: {:02X?}", self.x);
-        println!(" Y: {:02X?}", self.y);
-        println!("PC: {:02X?}", self.pc);
-        println!("SP: {:02X?}", self.sp);
-        println!(" P: {:08b}", self.p);
-        // println!("Cycles: {:?}", self.cycles);
+    // The tracer is a setting and stays as it is
+    pub fn sync_state(&mut self, state: &mut State) {
+        state.u8(&mut self.a);
+        state.u8(&mut self.x);
+        state.u8(&mut self.y);
+        state.u16(&mut self.pc);
+        state.u8(&mut self.sp);
+        state.u8(&mut self.p);
+        state.usize(&mut self.cycles);
+        state.usize(&mut self.total_cycles);
+        state.usize(&mut self.stall_cycles);
+        state.bool(&mut self.page_crossed);
+        state.bool(&mut self.nmi);
+        state.bool(&mut self.irq);
+    }
+
+    // Loads pc from the reset vector at $FFFC
+    pub fn reset(&mut self, bus: &mut Bus) {
+        self.pc = bus.read_u16(0xFFFC);
+        self.sp = 0xFD;
+        self.p = CpuFlag::I as u8 | CpuFlag::U as u8;
+        self.cycles = 7;
+    }
+
+    // Clears the registers and lines, then runs the reset sequence
+    // The cycle counts keep running
+    pub fn power_on(&mut self, bus: &mut Bus) {
+        self.a = 0;
+        self.x = 0;
+        self.y = 0;
+        self.nmi = false;
+        self.irq = false;
+        self.reset(bus);
+    }
+
+    // The instruction about to run and the state before it, as nestest.log has it:
+    // C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+    pub fn trace_line(&self, bus: &Bus) -> String {
+        let opcode = &OPCODES[bus.peek(self.pc as usize) as usize];
+        let bytes: Vec<String> = (0..opcode.bytes as u16)
+            .map(|offset| format!("{:02X}", bus.peek(self.pc.wrapping_add(offset) as usize)))
+            .collect();
+        let peek = |addr: u16| bus.peek(addr as usize);
+        let labels = bus.symbols.labels(&bus.rom);
+        let labels = (!bus.symbols.is_empty()).then_some(&labels as &dyn LabelLookup);
+        let text = disasm::format_instruction(&peek, self.pc, Some((self.x, self.y)), labels);
+        format!(
+            "{:04X}  {:<8} {}{:<31} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}",
+            self.pc,
+            bytes.join(" "),
+            if opcode.official { ' ' } else { '*' },
+            text,
+            self.a,
+            self.x,
+            self.y,
+            self.p,
+            self.sp,
+            bus.ppu.scanline,
+            bus.ppu.dot,
+            self.total_cycles
+        )
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers { a: self.a, x: self.x, y: self.y, pc: self.pc, sp: self.sp, p: self.p }
+    }
+
+    pub fn set_registers(&mut self, registers: Registers) {
+        self.a = registers.a;
+        self.x = registers.x;
+        self.y = registers.y;
+        self.pc = registers.pc;
+        self.sp = registers.sp;
+        self.p = registers.p;
+    }
+
+    // Whether the next clock at an instruction boundary enters a handler
+    // instead of running an instruction
+    pub fn interrupt_pending(&self, nmi: bool, irq: bool) -> bool {
+        self.nmi || nmi || (irq && self.p & CpuFlag::I as u8 == 0)
+    }
+
+    // Continues from addr, as test ROMs run without a reset vector need
+    pub fn jump(&mut self, addr: u16) {
+        self.set_pc(ProgramCounter::Jump(addr));
+    }
+
+    // Enters a subroutine as if a JSR had been made that returns to return_to,
+    // for players that drive code from outside (NSF INIT and PLAY)
+    pub fn call(&mut self, bus: &mut Bus, addr: u16, return_to: u16, a: u8, x: u8) {
+        let return_addr = return_to.wrapping_sub(1);
+        self.push(bus, (return_addr >> 8) as u8);
+        self.push(bus, return_addr as u8);
+        self.pc = addr;
+        self.a = a;
+        self.x = x;
+    }
+
+    // Holds the CPU off the bus for cycles taken by DMA
+    pub fn stall(&mut self, cycles: usize) {
+        self.cycles += cycles;
+        self.stall_cycles += cycles;
     }
 
     fn set_pc (&mut self, pc_addr: ProgramCounter) {
         self.pc = match pc_addr {
-            ProgramCounter::Next => self.pc + 1,
-            ProgramCounter::Skip => self.pc + 2,
-            ProgramCounter::Jump => panic!("PC Jump not implemented yet."),
+            ProgramCounter::Next => self.pc.wrapping_add(1),
+            ProgramCounter::Skip => self.pc.wrapping_add(2),
+            ProgramCounter::Jump(addr) => addr,
         }
     }
 
@@ -154,28 +199,30 @@ impl Cpu {
         }
     }
 
+    fn flag(&self, flag: CpuFlag) -> bool {
+        self.p & flag as u8 != 0
+    }
+
     fn set_flag_negative_zero (&mut self, value: u8) {
         self.set_flag(CpuFlag::N, (value & 0b1000_0000) != 0);
         self.set_flag(CpuFlag::Z, value == 0);
     }
 
-    fn fetch_addr(&mut self, addr: AddrMode, bus: &Bus, ram: &Ram) -> u16 {
+    fn fetch_addr(&mut self, addr: AddrMode, bus: &mut Bus) -> u16 {
         self.page_crossed = false;
         match addr {
-            // AddrMode::IMP => self.addr_imp(), // Implicit
-            // AddrMode::ACC => self.addr_acc(), // Accumulator
             AddrMode::IMM => self.addr_imm(), // Immediate
-            AddrMode::ZPG => self.addr_zpg(bus, ram), // Zero Page
-            AddrMode::ZPX => self.addr_zpx(bus, ram), // Zero Page, X
-            AddrMode::ZPY => self.addr_zpy(bus, ram), // Zero Page, Y
-            // AddrMode::REL => self.addr_rel(), // Relative
-            AddrMode::ABS => self.addr_abs(bus, ram), // Absolute
-            AddrMode::ABX => self.addr_abx(bus, ram), // Absolute, X
-            AddrMode::ABY => self.addr_aby(bus, ram), // Absolute, Y
-            // AddrMode::IND => self.addr_ind(), // Indirect
-            AddrMode::INX => self.addr_inx(bus, ram), // Indirect, X
-            AddrMode::INY => self.addr_iny(bus, ram), // Indirect, Y
-            _ => panic!("{:?} is an invalid addressing mode.", addr)
+            AddrMode::ZPG => self.addr_zpg(bus), // Zero Page
+            AddrMode::ZPX => self.addr_zpx(bus), // Zero Page, X
+            AddrMode::ZPY => self.addr_zpy(bus), // Zero Page, Y
+            AddrMode::REL => self.addr_rel(bus), // Relative
+            AddrMode::ABS => self.addr_abs(bus), // Absolute
+            AddrMode::ABX => self.addr_abx(bus), // Absolute, X
+            AddrMode::ABY => self.addr_aby(bus), // Absolute, Y
+            AddrMode::IND => self.addr_ind(bus), // Indirect
+            AddrMode::INX => self.addr_inx(bus), // Indirect, X
+            AddrMode::INY => self.addr_iny(bus), // Indirect, Y
+            AddrMode::IMP | AddrMode::ACC => panic!("{:?} has no operand address.", addr),
         }
     }
 
@@ -183,147 +230,596 @@ impl Cpu {
     fn addr_imm(&mut self) -> u16 {
         let addr = self.pc;
         self.set_pc(ProgramCounter::Next);
-        println!("Current Addr: {:04X}", addr);
         addr
     }
 
     // Sets addr hi byte to 00 and lo byte to data at current pc
-    fn addr_zpg(&mut self, bus: &Bus, ram: &Ram) -> u16 {
-        let addr_lo = bus.read(ram, self.pc as usize);
+    fn addr_zpg(&mut self, bus: &mut Bus) -> u16 {
+        let addr_lo = bus.read(self.pc as usize);
         self.set_pc(ProgramCounter::Next);
-        let addr = (addr_lo as u16) & 0x00FF;
-        println!("Current Addr: {:04X}", addr);
-        addr
+        (addr_lo as u16) & 0x00FF
     }
 
     // Sets addr hi byte to 00 and lo byte to data at current pc + x reg
-    fn addr_zpx(&mut self, bus: &Bus, ram: &Ram) -> u16 {
-        let addr_lo = bus.read(ram, self.pc as usize).wrapping_add(self.x);
+    fn addr_zpx(&mut self, bus: &mut Bus) -> u16 {
+        let addr_lo = bus.read(self.pc as usize).wrapping_add(self.x);
         self.set_pc(ProgramCounter::Next);
-        let addr = (addr_lo as u16) & 0x00FF;
-        println!("Current Addr: {:04X}", addr);
-        addr
+        (addr_lo as u16) & 0x00FF
     }
 
     // Sets addr hi byte to 00 and lo byte to data at current pc + y reg
-    fn addr_zpy(&mut self, bus: &Bus, ram: &Ram) -> u16 {
-        let addr_lo = bus.read(ram, self.pc as usize).wrapping_add(self.y);
+    fn addr_zpy(&mut self, bus: &mut Bus) -> u16 {
+        let addr_lo = bus.read(self.pc as usize).wrapping_add(self.y);
+        self.set_pc(ProgramCounter::Next);
+        (addr_lo as u16) & 0x00FF
+    }
+
+    // Sets addr to the branch target, pc after the operand + signed offset
+    fn addr_rel(&mut self, bus: &mut Bus) -> u16 {
+        let offset = bus.read(self.pc as usize) as i8;
         self.set_pc(ProgramCounter::Next);
-        let addr = (addr_lo as u16) & 0x00FF;
-        println!("Current Addr: {:04X}", addr);
+        let addr = self.pc.wrapping_add(offset as u16);
+        self.page_crossed = (addr & 0xFF00) != (self.pc & 0xFF00);
         addr
     }
 
     // Sets addr hi byte to pc + 1 and addr lo byte to pc
-    fn addr_abs(&mut self, bus: &Bus, ram: &Ram) -> u16 {
-        let addr = bus.read_u16(ram, self.pc as usize);
+    fn addr_abs(&mut self, bus: &mut Bus) -> u16 {
+        let addr = bus.read_u16(self.pc as usize);
         self.set_pc(ProgramCounter::Skip);
-        println!("Current Addr: {:04X}", addr);
         addr
     }
 
     // Sets addr to abs addr + x reg
-    fn addr_abx(&mut self, bus: &Bus, ram: &Ram) -> u16 {
-        let base_addr = bus.read_u16(ram, self.pc as usize);
+    fn addr_abx(&mut self, bus: &mut Bus) -> u16 {
+        let base_addr = bus.read_u16(self.pc as usize);
         let addr = base_addr.wrapping_add(self.x as u16);
         self.set_pc(ProgramCounter::Skip);
-        println!("Current Addr: {:04X}", addr);
-        if (addr & 0xFF00) > (base_addr & 0xFF00) {
-            self.page_crossed = true;
-        }
+        self.page_crossed = (addr & 0xFF00) != (base_addr & 0xFF00);
         addr
     }
 
     // Sets addr to abs addr + y reg
-    fn addr_aby(&mut self, bus: &Bus, ram: &Ram) -> u16 {
-        let base_addr = bus.read_u16(ram, self.pc as usize);
+    fn addr_aby(&mut self, bus: &mut Bus) -> u16 {
+        let base_addr = bus.read_u16(self.pc as usize);
         let addr = base_addr.wrapping_add(self.y as u16);
         self.set_pc(ProgramCounter::Skip);
-        println!("Current Addr: {:04X}", addr);
-        if (addr & 0xFF00) > (base_addr & 0xFF00) {
-            self.page_crossed = true;
-        }
+        self.page_crossed = (addr & 0xFF00) != (base_addr & 0xFF00);
         addr
     }
 
+    // Sets addr to the addr held at the abs addr at pc (JMP only)
+    // The hi byte doesn't carry into the next page, JMP ($10FF) reads $10FF and $1000
+    fn addr_ind(&mut self, bus: &mut Bus) -> u16 {
+        let pointer = bus.read_u16(self.pc as usize);
+        self.set_pc(ProgramCounter::Skip);
+        let addr_lo = bus.read(pointer as usize);
+        let addr_hi = bus.read(((pointer & 0xFF00) | (pointer.wrapping_add(1) & 0x00FF)) as usize);
+        (addr_hi as u16) << 8 | addr_lo as u16
+    }
+
     // Sets addr to the addr held at the zpg redirected to by addr_lo at pc + x reg
-    fn addr_inx(&mut self, bus: &Bus, ram: &Ram) -> u16 {
-        let addr_lo = bus.read(ram, self.pc as usize).wrapping_add(self.x);
+    fn addr_inx(&mut self, bus: &mut Bus) -> u16 {
+        let addr_lo = bus.read(self.pc as usize).wrapping_add(self.x);
         self.set_pc(ProgramCounter::Next);
-        let zpg_addr = (addr_lo as u16) & 0x00FF;
-        println!("Zero PG Addr: {:04X}", zpg_addr);
-        let addr = bus.read_u16(ram, zpg_addr as usize);
-        println!("Current Addr: {:04X}", addr);
-        addr
+        self.read_zpg_u16(addr_lo, bus)
     }
 
-    // Sets addr to the y reg + addr held at the zpg redirected from addr_lo at pc 
-    fn addr_iny(&mut self, bus: &Bus, ram: &Ram) -> u16 {
-        let addr_lo = bus.read(ram, self.pc as usize);
+    // Sets addr to the y reg + addr held at the zpg redirected from addr_lo at pc
+    fn addr_iny(&mut self, bus: &mut Bus) -> u16 {
+        let addr_lo = bus.read(self.pc as usize);
         self.set_pc(ProgramCounter::Next);
-        let zpg_addr = (addr_lo as u16) & 0x00FF;
-        println!("Zero PG Addr: {:04X}", zpg_addr);
-        let base_addr = bus.read_u16(ram, zpg_addr as usize);
-        let addr = base_addr + (self.y as u16);
-        println!("Current Addr: {:04X}", addr);
-        if (addr & 0xFF00) > (base_addr & 0xFF00) {
-            self.page_crossed = true;
-        }
+        let base_addr = self.read_zpg_u16(addr_lo, bus);
+        let addr = base_addr.wrapping_add(self.y as u16);
+        self.page_crossed = (addr & 0xFF00) != (base_addr & 0xFF00);
         addr
     }
 
-    pub fn clock(&mut self, bus: &Bus, ram: &Ram) {
-        let current_opcode = self.fetch_opcode(bus, ram);
-        println!("Current Opcode: {:02X}", current_opcode);
+    // Pointers in zero page wrap around, ($FF) reads $FF and $00
+    fn read_zpg_u16(&self, zpg_addr: u8, bus: &mut Bus) -> u16 {
+        let addr_lo = bus.read(zpg_addr as usize);
+        let addr_hi = bus.read(zpg_addr.wrapping_add(1) as usize);
+        (addr_hi as u16) << 8 | addr_lo as u16
+    }
+
+    // Reads the operand of an instruction that only reads memory, which
+    // takes a cycle longer when indexing crosses a page
+    fn read_operand(&mut self, addr: AddrMode, bus: &mut Bus) -> u8 {
+        let current_addr = self.fetch_addr(addr, bus);
+        if self.page_crossed {
+            self.cycles += 1;
+        }
+        bus.read(current_addr as usize)
+    }
+
+    // Read-modify-write on A or memory, returning the new value
+    // Memory gets the unmodified value written back first, as the 6502 does
+    fn modify(&mut self, addr: AddrMode, bus: &mut Bus, operation: fn(&mut Self, u8) -> u8) -> u8 {
+        if let AddrMode::ACC = addr {
+            self.a = operation(self, self.a);
+            return self.a;
+        }
+        let current_addr = self.fetch_addr(addr, bus) as usize;
+        let data = bus.read(current_addr);
+        bus.write(current_addr, data);
+        let result = operation(self, data);
+        bus.write(current_addr, result);
+        result
+    }
+
+    fn push(&mut self, bus: &mut Bus, data: u8) {
+        bus.write(0x0100 + self.sp as usize, data);
+        self.sp = self.sp.wrapping_sub(1);
+    }
+
+    fn pull(&mut self, bus: &mut Bus) -> u8 {
+        self.sp = self.sp.wrapping_add(1);
+        bus.read(0x0100 + self.sp as usize)
+    }
+
+    fn push_u16(&mut self, bus: &mut Bus, data: u16) {
+        self.push(bus, (data >> 8) as u8);
+        self.push(bus, data as u8);
+    }
+
+    fn pull_u16(&mut self, bus: &mut Bus) -> u16 {
+        let data_lo = self.pull(bus);
+        let data_hi = self.pull(bus);
+        (data_hi as u16) << 8 | data_lo as u16
+    }
+
+    // Pushes pc and status, then jumps through the vector
+    // NMI uses $FFFA, IRQ uses $FFFE
+    fn interrupt(&mut self, bus: &mut Bus, vector: usize) {
+        self.push_u16(bus, self.pc);
+        self.push(bus, (self.p | CpuFlag::U as u8) & !(CpuFlag::B as u8));
+        self.set_flag(CpuFlag::I, true);
+        self.pc = bus.read_u16(vector);
+        self.cycles += 7;
+    }
+
+    pub fn clock(&mut self, bus: &mut Bus) {
+        if self.nmi {
+            self.nmi = false;
+            self.interrupt(bus, 0xFFFA);
+            return;
+        }
+        if self.irq && self.p & CpuFlag::I as u8 == 0 {
+            self.interrupt(bus, 0xFFFE);
+            return;
+        }
+        if self.tracer.is_some() {
+            let line = self.trace_line(bus);
+            // Stop tracing rather than fail the emulation
+            if writeln!(self.tracer.as_mut().unwrap(), "{}", line).is_err() {
+                self.tracer = None;
+            }
+        }
+        let current_opcode = self.fetch_opcode(bus);
         self.set_pc(ProgramCounter::Next);
-        self.execute_opcode(current_opcode, bus, ram);
+        self.execute_opcode(current_opcode, bus);
     }
 
-    // For now, fetches u8 opcode from ram at pc
-    fn fetch_opcode(&self, bus: &Bus, ram: &Ram) -> u8 {
-        bus.read(ram, self.pc as usize)
+    // Fetches u8 opcode from the bus at pc
+    fn fetch_opcode(&self, bus: &mut Bus) -> u8 {
+        bus.read(self.pc as usize)
     }
-    
-    // Work in progress opcode mapping/handling
-    fn execute_opcode(&mut self, current_opcode: u8, bus: &Bus, ram: &Ram) {
-        match current_opcode {
-            // Work in progress opcode table
 
+    // Decodes through the opcode table, each handler adds its own cycles
+    fn execute_opcode(&mut self, current_opcode: u8, bus: &mut Bus) {
+        let opcode = &OPCODES[current_opcode as usize];
+        let (addr, cycles) = (opcode.mode, opcode.cycles);
+        match opcode.instruction {
             // Load/Store Operations
-            0xA9 => self.opcode_lda(AddrMode::IMM, 2, bus, ram),
-            0xA5 => self.opcode_lda(AddrMode::ZPG, 3, bus, ram),
-            0xB5 => self.opcode_lda(AddrMode::ZPX, 4, bus, ram),
-            0xAD => self.opcode_lda(AddrMode::ABS, 4, bus, ram),
-            0xBD => self.opcode_lda(AddrMode::ABX, 4, bus, ram),
-            0xB9 => self.opcode_lda(AddrMode::ABY, 4, bus, ram),
-            0xA1 => self.opcode_lda(AddrMode::INX, 6, bus, ram),
-            0xB1 => self.opcode_lda(AddrMode::INY, 5, bus, ram),
-            0xA2 => self.opcode_ldx(AddrMode::IMM, 2, bus, ram),
-
-            _ => panic!("Unkown opcode {:X?} at PC {:X?}", current_opcode, self.pc),
+            Instruction::LDA => self.opcode_lda(addr, cycles, bus),
+            Instruction::LDX => self.opcode_ldx(addr, cycles, bus),
+            Instruction::LDY => self.opcode_ldy(addr, cycles, bus),
+            Instruction::STA => self.opcode_store(addr, cycles, bus, self.a),
+            Instruction::STX => self.opcode_store(addr, cycles, bus, self.x),
+            Instruction::STY => self.opcode_store(addr, cycles, bus, self.y),
+
+            // Register Transfers
+            Instruction::TAX => self.x = self.opcode_transfer(cycles, self.a),
+            Instruction::TAY => self.y = self.opcode_transfer(cycles, self.a),
+            Instruction::TXA => self.a = self.opcode_transfer(cycles, self.x),
+            Instruction::TYA => self.a = self.opcode_transfer(cycles, self.y),
+            Instruction::TSX => self.x = self.opcode_transfer(cycles, self.sp),
+            Instruction::TXS => {
+                self.sp = self.x;
+                self.cycles += cycles as usize;
+            }
+
+            // Stack Operations
+            Instruction::PHA => self.opcode_push(cycles, bus, self.a),
+            Instruction::PHP => self.opcode_push(cycles, bus, self.p | CpuFlag::B as u8 | CpuFlag::U as u8),
+            Instruction::PLA => self.opcode_pla(cycles, bus),
+            Instruction::PLP => self.opcode_plp(cycles, bus),
+
+            // Logical and Arithmetic
+            Instruction::AND => self.opcode_logic(addr, cycles, bus, |a, data| a & data),
+            Instruction::EOR => self.opcode_logic(addr, cycles, bus, |a, data| a ^ data),
+            Instruction::ORA => self.opcode_logic(addr, cycles, bus, |a, data| a | data),
+            Instruction::BIT => self.opcode_bit(addr, cycles, bus),
+            Instruction::ADC => self.opcode_adc(addr, cycles, bus),
+            Instruction::SBC => self.opcode_sbc(addr, cycles, bus),
+            Instruction::CMP => self.opcode_compare(addr, cycles, bus, self.a),
+            Instruction::CPX => self.opcode_compare(addr, cycles, bus, self.x),
+            Instruction::CPY => self.opcode_compare(addr, cycles, bus, self.y),
+
+            // Increments, Decrements and Shifts
+            Instruction::INC => self.opcode_modify(addr, cycles, bus, Self::increment),
+            Instruction::DEC => self.opcode_modify(addr, cycles, bus, Self::decrement),
+            Instruction::ASL => self.opcode_modify(addr, cycles, bus, Self::shift_left),
+            Instruction::LSR => self.opcode_modify(addr, cycles, bus, Self::shift_right),
+            Instruction::ROL => self.opcode_modify(addr, cycles, bus, Self::rotate_left),
+            Instruction::ROR => self.opcode_modify(addr, cycles, bus, Self::rotate_right),
+            Instruction::INX => self.x = self.opcode_transfer(cycles, self.x.wrapping_add(1)),
+            Instruction::INY => self.y = self.opcode_transfer(cycles, self.y.wrapping_add(1)),
+            Instruction::DEX => self.x = self.opcode_transfer(cycles, self.x.wrapping_sub(1)),
+            Instruction::DEY => self.y = self.opcode_transfer(cycles, self.y.wrapping_sub(1)),
+
+            // Jumps, Calls and Branches
+            Instruction::JMP => self.opcode_jmp(addr, cycles, bus),
+            Instruction::JSR => self.opcode_jsr(cycles, bus),
+            Instruction::RTS => self.opcode_rts(cycles, bus),
+            Instruction::BCC => self.opcode_branch(cycles, bus, !self.flag(CpuFlag::C)),
+            Instruction::BCS => self.opcode_branch(cycles, bus, self.flag(CpuFlag::C)),
+            Instruction::BNE => self.opcode_branch(cycles, bus, !self.flag(CpuFlag::Z)),
+            Instruction::BEQ => self.opcode_branch(cycles, bus, self.flag(CpuFlag::Z)),
+            Instruction::BPL => self.opcode_branch(cycles, bus, !self.flag(CpuFlag::N)),
+            Instruction::BMI => self.opcode_branch(cycles, bus, self.flag(CpuFlag::N)),
+            Instruction::BVC => self.opcode_branch(cycles, bus, !self.flag(CpuFlag::V)),
+            Instruction::BVS => self.opcode_branch(cycles, bus, self.flag(CpuFlag::V)),
+
+            // Status Flag Changes
+            Instruction::CLC => self.opcode_flag(cycles, CpuFlag::C, false),
+            Instruction::CLD => self.opcode_flag(cycles, CpuFlag::D, false),
+            Instruction::CLI => self.opcode_flag(cycles, CpuFlag::I, false),
+            Instruction::CLV => self.opcode_flag(cycles, CpuFlag::V, false),
+            Instruction::SEC => self.opcode_flag(cycles, CpuFlag::C, true),
+            Instruction::SED => self.opcode_flag(cycles, CpuFlag::D, true),
+            Instruction::SEI => self.opcode_flag(cycles, CpuFlag::I, true),
+
+            // System Functions
+            Instruction::BRK => self.opcode_brk(cycles, bus),
+            Instruction::RTI => self.opcode_rti(cycles, bus),
+            Instruction::NOP => self.opcode_nop(addr, cycles, bus),
+
+            // Unofficial
+            Instruction::LAX => self.opcode_lax(addr, cycles, bus),
+            Instruction::SAX => self.opcode_store(addr, cycles, bus, self.a & self.x),
+            Instruction::DCP => self.opcode_combined(addr, cycles, bus, Self::decrement, Self::compare_a),
+            Instruction::ISB => self.opcode_combined(addr, cycles, bus, Self::increment, Self::subtract),
+            Instruction::SLO => self.opcode_combined(addr, cycles, bus, Self::shift_left, |cpu, data| cpu.logic(data, |a, data| a | data)),
+            Instruction::RLA => self.opcode_combined(addr, cycles, bus, Self::rotate_left, |cpu, data| cpu.logic(data, |a, data| a & data)),
+            Instruction::SRE => self.opcode_combined(addr, cycles, bus, Self::shift_right, |cpu, data| cpu.logic(data, |a, data| a ^ data)),
+            Instruction::RRA => self.opcode_combined(addr, cycles, bus, Self::rotate_right, Self::add),
+            Instruction::ANC => self.opcode_anc(addr, cycles, bus),
+            Instruction::ALR => self.opcode_alr(addr, cycles, bus),
+            Instruction::ARR => self.opcode_arr(addr, cycles, bus),
+            Instruction::AXS => self.opcode_axs(addr, cycles, bus),
+            Instruction::LAS => self.opcode_las(addr, cycles, bus),
+            Instruction::XAA => self.opcode_xaa(addr, cycles, bus),
+            Instruction::SHX => self.opcode_store_high(addr, cycles, bus, self.x, self.y),
+            Instruction::SHY => self.opcode_store_high(addr, cycles, bus, self.y, self.x),
+            Instruction::AHX => self.opcode_store_high(addr, cycles, bus, self.a & self.x, self.y),
+            Instruction::TAS => {
+                self.sp = self.a & self.x;
+                self.opcode_store_high(addr, cycles, bus, self.sp, self.y);
+            }
+            Instruction::KIL => self.opcode_kil(cycles),
         }
     }
 
     // Load data from supplied address into register A
-    fn opcode_lda(&mut self, addr: AddrMode, cycles: u8, bus: &Bus, ram: &Ram) {
-        let current_addr = self.fetch_addr(addr, bus, ram);
-        self.a = bus.read(ram, current_addr as usize);
+    fn opcode_lda(&mut self, addr: AddrMode, cycles: u8, bus: &mut Bus) {
+        self.a = self.read_operand(addr, bus);
         self.set_flag_negative_zero(self.a);
         self.cycles += cycles as usize;
-        if self.page_crossed {
-            self.cycles += 1;
-        }
     }
 
     // Load data from supplied address into register X
-    fn opcode_ldx(&mut self, addr: AddrMode, cycles: u8, bus: &Bus, ram: &Ram) {
-        let current_addr = self.fetch_addr(addr, bus, ram);
-        self.x = bus.read(ram, current_addr as usize);
+    fn opcode_ldx(&mut self, addr: AddrMode, cycles: u8, bus: &mut Bus) {
+        self.x = self.read_operand(addr, bus);
         self.set_flag_negative_zero(self.x);
         self.cycles += cycles as usize;
+    }
+
+    // Load data from supplied address into register Y
+    fn opcode_ldy(&mut self, addr: AddrMode, cycles: u8, bus: &mut Bus) {
+        self.y = self.read_operand(addr, bus);
+        self.set_flag_negative_zero(self.y);
+        self.cycles += cycles as usize;
+    }
+
+    // Store a register (or A & X for SAX) at supplied address
+    // Indexed stores always take the extra cycle, it's in their base count
+    fn opcode_store(&mut self, addr: AddrMode, cycles: u8, bus: &mut Bus, data: u8) {
+        let current_addr = self.fetch_addr(addr, bus);
+        bus.write(current_addr as usize, data);
+        self.cycles += cycles as usize;
+    }
+
+    // Register to register operations, returns data after setting N and Z from it
+    fn opcode_transfer(&mut self, cycles: u8, data: u8) -> u8 {
+        self.set_flag_negative_zero(data);
+        self.cycles += cycles as usize;
+        data
+    }
+
+    fn opcode_push(&mut self, cycles: u8, bus: &mut Bus, data: u8) {
+        self.push(bus, data);
+        self.cycles += cycles as usize;
+    }
+
+    fn opcode_pla(&mut self, cycles: u8, bus: &mut Bus) {
+        self.a = self.pull(bus);
+        self.set_flag_negative_zero(self.a);
+        self.cycles += cycles as usize;
+    }
+
+    // B only exists on the stack and U always reads as set
+    fn opcode_plp(&mut self, cycles: u8, bus: &mut Bus) {
+        let data = self.pull(bus);
+        self.p = (data & !(CpuFlag::B as u8)) | CpuFlag::U as u8;
+        self.cycles += cycles as usize;
+    }
+
+    fn logic(&mut self, data: u8, operation: fn(u8, u8) -> u8) {
+        self.a = operation(self.a, data);
+        self.set_flag_negative_zero(self.a);
+    }
+
+    // AND, EOR and ORA with A
+    fn opcode_logic(&mut self, addr: AddrMode, cycles: u8, bus: &mut Bus, operation: fn(u8, u8) -> u8) {
+        let data = self.read_operand(addr, bus);
+        self.logic(data, operation);
+        self.cycles += cycles as usize;
+    }
+
+    // Z from A & data, N and V copied from bits 7 and 6 of data
+    fn opcode_bit(&mut self, addr: AddrMode, cycles: u8, bus: &mut Bus) {
+        let data = self.read_operand(addr, bus);
+        self.set_flag(CpuFlag::Z, self.a & data == 0);
+        self.set_flag(CpuFlag::N, data & 0x80 != 0);
+        self.set_flag(CpuFlag::V, data & 0x40 != 0);
+        self.cycles += cycles as usize;
+    }
+
+    // Binary add with carry, the 2A03 ignores the decimal flag
+    fn add(&mut self, data: u8) {
+        let sum = self.a as u16 + data as u16 + self.flag(CpuFlag::C) as u16;
+        let result = sum as u8;
+        self.set_flag(CpuFlag::C, sum > 0xFF);
+        self.set_flag(CpuFlag::V, (self.a ^ result) & (data ^ result) & 0x80 != 0);
+        self.a = result;
+        self.set_flag_negative_zero(self.a);
+    }
+
+    fn subtract(&mut self, data: u8) {
+        self.add(!data);
+    }
+
+    fn opcode_adc(&mut self, addr: AddrMode, cycles: u8, bus: &mut Bus) {
+        let data = self.read_operand(addr, bus);
+        self.add(data);
+        self.cycles += cycles as usize;
+    }
+
+    fn opcode_sbc(&mut self, addr: AddrMode, cycles: u8, bus: &mut Bus) {
+        let data = self.read_operand(addr, bus);
+        self.subtract(data);
+        self.cycles += cycles as usize;
+    }
+
+    fn compare(&mut self, register: u8, data: u8) {
+        self.set_flag(CpuFlag::C, register >= data);
+        self.set_flag_negative_zero(register.wrapping_sub(data));
+    }
+
+    fn compare_a(&mut self, data: u8) {
+        self.compare(self.a, data);
+    }
+
+    fn opcode_compare(&mut self, addr: AddrMode, cycles: u8, bus: &mut Bus, register: u8) {
+        let data = self.read_operand(addr, bus);
+        self.compare(register, data);
+        self.cycles += cycles as usize;
+    }
+
+    fn increment(&mut self, data: u8) -> u8 {
+        let result = data.wrapping_add(1);
+        self.set_flag_negative_zero(result);
+        result
+    }
+
+    fn decrement(&mut self, data: u8) -> u8 {
+        let result = data.wrapping_sub(1);
+        self.set_flag_negative_zero(result);
+        result
+    }
+
+    fn shift_left(&mut self, data: u8) -> u8 {
+        self.set_flag(CpuFlag::C, data & 0x80 != 0);
+        let result = data << 1;
+        self.set_flag_negative_zero(result);
+        result
+    }
+
+    fn shift_right(&mut self, data: u8) -> u8 {
+        self.set_flag(CpuFlag::C, data & 0x01 != 0);
+        let result = data >> 1;
+        self.set_flag_negative_zero(result);
+        result
+    }
+
+    fn rotate_left(&mut self, data: u8) -> u8 {
+        let result = data << 1 | self.flag(CpuFlag::C) as u8;
+        self.set_flag(CpuFlag::C, data & 0x80 != 0);
+        self.set_flag_negative_zero(result);
+        result
+    }
+
+    fn rotate_right(&mut self, data: u8) -> u8 {
+        let result = data >> 1 | (self.flag(CpuFlag::C) as u8) << 7;
+        self.set_flag(CpuFlag::C, data & 0x01 != 0);
+        self.set_flag_negative_zero(result);
+        result
+    }
+
+    // INC, DEC and the shifts, on A or memory
+    fn opcode_modify(&mut self, addr: AddrMode, cycles: u8, bus: &mut Bus, operation: fn(&mut Self, u8) -> u8) {
+        self.modify(addr, bus, operation);
+        self.cycles += cycles as usize;
+    }
+
+    // Unofficial read-modify-write then an operation on A with the result
+    fn opcode_combined(
+        &mut self,
+        addr: AddrMode,
+        cycles: u8,
+        bus: &mut Bus,
+        operation: fn(&mut Self, u8) -> u8,
+        then: fn(&mut Self, u8),
+    ) {
+        let result = self.modify(addr, bus, operation);
+        then(self, result);
+        self.cycles += cycles as usize;
+    }
+
+    fn opcode_jmp(&mut self, addr: AddrMode, cycles: u8, bus: &mut Bus) {
+        let current_addr = self.fetch_addr(addr, bus);
+        self.set_pc(ProgramCounter::Jump(current_addr));
+        self.cycles += cycles as usize;
+    }
+
+    // Pushes the address of the JSR's last byte, RTS adds the 1 back
+    fn opcode_jsr(&mut self, cycles: u8, bus: &mut Bus) {
+        let current_addr = self.fetch_addr(AddrMode::ABS, bus);
+        self.push_u16(bus, self.pc.wrapping_sub(1));
+        self.set_pc(ProgramCounter::Jump(current_addr));
+        self.cycles += cycles as usize;
+    }
+
+    fn opcode_rts(&mut self, cycles: u8, bus: &mut Bus) {
+        let return_addr = self.pull_u16(bus);
+        self.set_pc(ProgramCounter::Jump(return_addr.wrapping_add(1)));
+        self.cycles += cycles as usize;
+    }
+
+    // One cycle more when taken, and another when the target is on a new page
+    fn opcode_branch(&mut self, cycles: u8, bus: &mut Bus, condition: bool) {
+        let current_addr = self.fetch_addr(AddrMode::REL, bus);
+        self.cycles += cycles as usize;
+        if condition {
+            self.cycles += 1 + self.page_crossed as usize;
+            self.set_pc(ProgramCounter::Jump(current_addr));
+        }
+    }
+
+    fn opcode_flag(&mut self, cycles: u8, flag: CpuFlag, flag_set: bool) {
+        self.set_flag(flag, flag_set);
+        self.cycles += cycles as usize;
+    }
+
+    // Software interrupt through $FFFE, skipping a padding byte
+    fn opcode_brk(&mut self, cycles: u8, bus: &mut Bus) {
+        self.push_u16(bus, self.pc.wrapping_add(1));
+        self.push(bus, self.p | CpuFlag::B as u8 | CpuFlag::U as u8);
+        self.set_flag(CpuFlag::I, true);
+        self.pc = bus.read_u16(0xFFFE);
+        self.cycles += cycles as usize;
+    }
+
+    fn opcode_rti(&mut self, cycles: u8, bus: &mut Bus) {
+        let data = self.pull(bus);
+        self.p = (data & !(CpuFlag::B as u8)) | CpuFlag::U as u8;
+        self.pc = self.pull_u16(bus);
+        self.cycles += cycles as usize;
+    }
+
+    // Unofficial NOPs still read their operand
+    fn opcode_nop(&mut self, addr: AddrMode, cycles: u8, bus: &mut Bus) {
+        if !matches!(addr, AddrMode::IMP) {
+            self.read_operand(addr, bus);
+        }
+        self.cycles += cycles as usize;
+    }
+
+    fn opcode_lax(&mut self, addr: AddrMode, cycles: u8, bus: &mut Bus) {
+        self.a = self.read_operand(addr, bus);
+        self.x = self.a;
+        self.set_flag_negative_zero(self.a);
+        self.cycles += cycles as usize;
+    }
+
+    // AND, then C from bit 7 like an ASL would
+    fn opcode_anc(&mut self, addr: AddrMode, cycles: u8, bus: &mut Bus) {
+        let data = self.read_operand(addr, bus);
+        self.logic(data, |a, data| a & data);
+        self.set_flag(CpuFlag::C, self.a & 0x80 != 0);
+        self.cycles += cycles as usize;
+    }
+
+    fn opcode_alr(&mut self, addr: AddrMode, cycles: u8, bus: &mut Bus) {
+        let data = self.read_operand(addr, bus);
+        self.a = self.shift_right(self.a & data);
+        self.cycles += cycles as usize;
+    }
+
+    // AND then ROR, with C from bit 6 and V from bit 6 ^ bit 5
+    fn opcode_arr(&mut self, addr: AddrMode, cycles: u8, bus: &mut Bus) {
+        let data = self.read_operand(addr, bus);
+        self.a = (self.a & data) >> 1 | (self.flag(CpuFlag::C) as u8) << 7;
+        self.set_flag_negative_zero(self.a);
+        self.set_flag(CpuFlag::C, self.a & 0x40 != 0);
+        self.set_flag(CpuFlag::V, ((self.a >> 6) ^ (self.a >> 5)) & 0x01 != 0);
+        self.cycles += cycles as usize;
+    }
+
+    // X = (A & X) - data, setting flags like CMP
+    fn opcode_axs(&mut self, addr: AddrMode, cycles: u8, bus: &mut Bus) {
+        let data = self.read_operand(addr, bus);
+        let register = self.a & self.x;
+        self.compare(register, data);
+        self.x = register.wrapping_sub(data);
+        self.cycles += cycles as usize;
+    }
+
+    fn opcode_las(&mut self, addr: AddrMode, cycles: u8, bus: &mut Bus) {
+        let data = self.read_operand(addr, bus) & self.sp;
+        self.a = data;
+        self.x = data;
+        self.sp = data;
+        self.set_flag_negative_zero(data);
+        self.cycles += cycles as usize;
+    }
+
+    // Unstable on hardware, $EE is the most commonly seen magic constant
+    fn opcode_xaa(&mut self, addr: AddrMode, cycles: u8, bus: &mut Bus) {
+        let data = self.read_operand(addr, bus);
+        self.a = (self.a | 0xEE) & self.x & data;
+        self.set_flag_negative_zero(self.a);
+        self.cycles += cycles as usize;
+    }
+
+    // SHX, SHY, AHX and TAS store data & (hi byte of the base address + 1)
+    // When indexing crosses a page that value also replaces the hi byte
+    fn opcode_store_high(&mut self, addr: AddrMode, cycles: u8, bus: &mut Bus, data: u8, index: u8) {
+        let mut current_addr = self.fetch_addr(addr, bus);
+        let base_hi = (current_addr.wrapping_sub(index as u16) >> 8) as u8;
+        let result = data & base_hi.wrapping_add(1);
         if self.page_crossed {
-            self.cycles += 1;
+            current_addr = (result as u16) << 8 | (current_addr & 0x00FF);
         }
+        bus.write(current_addr as usize, result);
+        self.cycles += cycles as usize;
+    }
+
+    // Locks up until reset, fetching the same opcode forever
+    fn opcode_kil(&mut self, cycles: u8) {
+        self.set_pc(ProgramCounter::Jump(self.pc.wrapping_sub(1)));
+        self.cycles += cycles as usize;
     }
 }
 
@@ -333,10 +829,28 @@ impl Cpu {
 #[cfg(test)]
 mod tests {
 
+    // Builds an NROM image from a program placed at $C000, ready to run it
+    fn load_program(program: &[u8]) -> crate::nes::Nes {
+        let mut nes = crate::nes::Nes::new();
+        let mut prg_rom = vec![0xEA; 0x4000];
+        prg_rom[..program.len()].copy_from_slice(program);
+        prg_rom[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0xC0]);
+        nes.bus.rom.prg_rom = prg_rom;
+        nes.reset();
+        nes.finish_instruction();
+        nes
+    }
+
+    // Runs one instruction and returns the cycles it took
+    fn step(nes: &mut crate::nes::Nes) -> usize {
+        let start = nes.cpu.total_cycles;
+        nes.step();
+        nes.cpu.total_cycles - start
+    }
+
     #[test]
     fn set_flag() {
-        use crate::*;
-        use cpu::*;
+        use crate::cpu::*;
 
         // Test Set Flag Z and N as true and false
         let mut cpu = Cpu::new();
@@ -352,158 +866,327 @@ mod tests {
 
     #[test]
     fn addr_zpy() {
-        use crate::*;
+        use crate::bus::Bus;
+        use crate::cpu::*;
         let mut cpu = Cpu::new();
-        let bus = Bus::new();
-        let mut ram = Ram::new();
+        let mut bus = Bus::new();
 
         // Test ZPY without wrapping add
-        bus.write(&mut ram, 0x0000, 0x2B);
+        bus.write(0x0000, 0x2B);
         cpu.y = 0x01;
-        let addr = cpu.addr_zpy(&bus, &ram);
+        let addr = cpu.addr_zpy(&mut bus);
         assert_eq!(addr, 0x002C);
         assert_eq!(cpu.pc, 0x0001);
 
         // Test ZPY with wrapping add
-        bus.write(&mut ram, 0x0001, 0xFF);
+        bus.write(0x0001, 0xFF);
         cpu.y = 0xA1;
-        let addr = cpu.addr_zpy(&bus, &ram);
+        let addr = cpu.addr_zpy(&mut bus);
         assert_eq!(addr, 0x00A0);
         assert_eq!(cpu.pc, 0x0002);
 
     }
 
+    #[test]
+    fn addr_abs_wraps() {
+        use crate::bus::Bus;
+        use crate::cpu::*;
+        let mut cpu = Cpu::new();
+        let mut bus = Bus::new();
+
+        // An operand at $FFFF takes its high byte from $0000
+        bus.rom.prg_rom = vec![0x00; 0x4000];
+        bus.rom.prg_rom[0x3FFF] = 0x34;
+        bus.write(0x0000, 0x12);
+        cpu.pc = 0xFFFF;
+        assert_eq!(cpu.addr_abs(&mut bus), 0x1234);
+    }
+
     // Rework LDA test to be more succinct when done building
     #[test]
     fn lda() {
-        use crate::*;
-        use cpu::*;
+        use crate::bus::Bus;
+        use crate::cpu::*;
         let mut cpu = Cpu::new();
-        let bus = Bus::new();
-        let mut ram = Ram::new();
+        let mut bus = Bus::new();
 
         // Test LDA with positive non zero data at IMM addr
-        bus.write(&mut ram, 0x0000, 0x2B);
-        cpu.opcode_lda(AddrMode::IMM, 2, &bus, &ram);
+        bus.write(0x0000, 0x2B);
+        cpu.opcode_lda(AddrMode::IMM, 2, &mut bus);
         assert_eq!(cpu.a, 0x2B);
         assert_eq!(cpu.p, 0b0000_0000);
         assert_eq!(cpu.cycles, 2);
-        assert_eq!(cpu.page_crossed, false);
+        assert!(!cpu.page_crossed);
 
         // Test LDA with negative non zero data at IMM addr
-        bus.write(&mut ram, 0x0001, 0xA0);
-        cpu.opcode_lda(AddrMode::IMM, 4, &bus, &ram);
+        bus.write(0x0001, 0xA0);
+        cpu.opcode_lda(AddrMode::IMM, 4, &mut bus);
         assert_eq!(cpu.a, 0xA0);
         assert_eq!(cpu.p, 0b1000_0000);
         assert_eq!(cpu.cycles, 6);
 
         // Test LDA with non negative zero data at IMM addr
-        bus.write(&mut ram, 0x0002, 0x00);
-        cpu.opcode_lda(AddrMode::IMM, 4, &bus, &ram);
+        bus.write(0x0002, 0x00);
+        cpu.opcode_lda(AddrMode::IMM, 4, &mut bus);
         assert_eq!(cpu.a, 0x00);
         assert_eq!(cpu.p, 0b0000_0010);
         assert_eq!(cpu.cycles, 10);
 
         // Test LDA with positive non zero data at ZPG addr
-        bus.write(&mut ram, 0x0003, 0x1F);
-        bus.write(&mut ram, 0x001F, 0x24);
-        cpu.opcode_lda(AddrMode::ZPG, 3, &bus, &ram);
+        bus.write(0x0003, 0x1F);
+        bus.write(0x001F, 0x24);
+        cpu.opcode_lda(AddrMode::ZPG, 3, &mut bus);
         assert_eq!(cpu.a, 0x24);
         assert_eq!(cpu.p, 0b0000_0000);
         assert_eq!(cpu.cycles, 13);
 
         // Test LDA with positive non zero data at ZPX addr
-        bus.write(&mut ram, 0x0004, 0x2F);
+        bus.write(0x0004, 0x2F);
         cpu.x = 0x12;
-        bus.write(&mut ram, 0x0041, 0x36);
-        cpu.opcode_lda(AddrMode::ZPX, 4, &bus, &ram);
+        bus.write(0x0041, 0x36);
+        cpu.opcode_lda(AddrMode::ZPX, 4, &mut bus);
         assert_eq!(cpu.a, 0x36);
         assert_eq!(cpu.p, 0b0000_0000);
         assert_eq!(cpu.cycles, 17);
 
         // Test LDA with positive non zero data at ZPX addr with wrapping add
-        bus.write(&mut ram, 0x0005, 0xFF);
+        bus.write(0x0005, 0xFF);
         cpu.x = 0x10;
-        bus.write(&mut ram, 0x000F, 0x04);
-        cpu.opcode_lda(AddrMode::ZPX, 4, &bus, &ram);
+        bus.write(0x000F, 0x04);
+        cpu.opcode_lda(AddrMode::ZPX, 4, &mut bus);
         assert_eq!(cpu.a, 0x04);
         assert_eq!(cpu.p, 0b0000_0000);
         assert_eq!(cpu.cycles, 21);
 
         // Test LDA with ABS address
-        bus.write(&mut ram, 0x0006, 0x05);
-        bus.write(&mut ram, 0x0007, 0x04);
-        bus.write(&mut ram, 0x0405, 0x46);
-        cpu.opcode_lda(AddrMode::ABS, 4, &bus, &ram);
+        bus.write(0x0006, 0x05);
+        bus.write(0x0007, 0x04);
+        bus.write(0x0405, 0x46);
+        cpu.opcode_lda(AddrMode::ABS, 4, &mut bus);
         assert_eq!(cpu.a, 0x46);
 
         // Test LDA with ABX address
-        bus.write(&mut ram, 0x0008, 0x05);
-        bus.write(&mut ram, 0x0009, 0x06);
+        bus.write(0x0008, 0x05);
+        bus.write(0x0009, 0x06);
         cpu.x = 0x05;
-        bus.write(&mut ram, 0x060A, 0x38);
-        cpu.opcode_lda(AddrMode::ABX, 4, &bus, &ram);
+        bus.write(0x060A, 0x38);
+        cpu.opcode_lda(AddrMode::ABX, 4, &mut bus);
         assert_eq!(cpu.a, 0x38);
-        assert_eq!(cpu.page_crossed, false);
+        assert!(!cpu.page_crossed);
 
         // Test LDA with ABY address
-        bus.write(&mut ram, 0x000A, 0x06);
-        bus.write(&mut ram, 0x000B, 0x07);
+        bus.write(0x000A, 0x06);
+        bus.write(0x000B, 0x07);
         cpu.y = 0x06;
-        bus.write(&mut ram, 0x070C, 0x28);
-        cpu.opcode_lda(AddrMode::ABY, 4, &bus, &ram);
+        bus.write(0x070C, 0x28);
+        cpu.opcode_lda(AddrMode::ABY, 4, &mut bus);
         assert_eq!(cpu.a, 0x28);
-        assert_eq!(cpu.page_crossed, false);
+        assert!(!cpu.page_crossed);
 
         // Test LDA with ABX address and page cross
-        bus.write(&mut ram, 0x000C, 0x05);
-        bus.write(&mut ram, 0x000D, 0x08);
+        bus.write(0x000C, 0x05);
+        bus.write(0x000D, 0x08);
         cpu.x = 0xFF;
-        bus.write(&mut ram, 0x0904, 0xAA);
-        cpu.opcode_lda(AddrMode::ABX, 4, &bus, &ram);
+        bus.write(0x0904, 0xAA);
+        cpu.opcode_lda(AddrMode::ABX, 4, &mut bus);
         assert_eq!(cpu.a, 0xAA);
         assert_eq!(cpu.cycles, 38);
-        assert_eq!(cpu.page_crossed, true);
+        assert!(cpu.page_crossed);
 
         // Test LDA with ABY address and page cross
-        bus.write(&mut ram, 0x000E, 0x06);
-        bus.write(&mut ram, 0x000F, 0x09);
+        bus.write(0x000E, 0x06);
+        bus.write(0x000F, 0x09);
         cpu.y = 0xFF;
-        bus.write(&mut ram, 0x0A05, 0x26);
-        cpu.opcode_lda(AddrMode::ABY, 4, &bus, &ram);
+        bus.write(0x0A05, 0x26);
+        cpu.opcode_lda(AddrMode::ABY, 4, &mut bus);
         assert_eq!(cpu.a, 0x26);
         assert_eq!(cpu.cycles, 43);
-        assert_eq!(cpu.page_crossed, true);
+        assert!(cpu.page_crossed);
 
         // Test LDA with INX address
-        bus.write(&mut ram, 0x0010, 0xA4);
+        bus.write(0x0010, 0xA4);
         cpu.x = 0x10;
-        bus.write(&mut ram, 0x00B4, 0x04);
-        bus.write(&mut ram, 0x00B5, 0x03);
-        bus.write(&mut ram, 0x0304, 0x38);
-        cpu.opcode_lda(AddrMode::INX, 6, &bus, &ram);
+        bus.write(0x00B4, 0x04);
+        bus.write(0x00B5, 0x03);
+        bus.write(0x0304, 0x38);
+        cpu.opcode_lda(AddrMode::INX, 6, &mut bus);
         assert_eq!(cpu.a, 0x38);
 
         // Test LDA with INY address
-        bus.write(&mut ram, 0x0011, 0xA7);
+        bus.write(0x0011, 0xA7);
         cpu.y = 0x02;
-        bus.write(&mut ram, 0x00A7, 0x08);
-        bus.write(&mut ram, 0x00A8, 0x02);
-        bus.write(&mut ram, 0x020A, 0x19);
-        cpu.opcode_lda(AddrMode::INY, 5, &bus, &ram);
+        bus.write(0x00A7, 0x08);
+        bus.write(0x00A8, 0x02);
+        bus.write(0x020A, 0x19);
+        cpu.opcode_lda(AddrMode::INY, 5, &mut bus);
         assert_eq!(cpu.a, 0x19);
-        assert_eq!(cpu.page_crossed, false);
+        assert!(!cpu.page_crossed);
         assert_eq!(cpu.cycles, 54);
 
         // Test LDA with INY address and page cross
-        bus.write(&mut ram, 0x0012, 0xA9);
+        bus.write(0x0012, 0xA9);
         cpu.y = 0xFF;
-        bus.write(&mut ram, 0x00A9, 0x08);
-        bus.write(&mut ram, 0x00AA, 0x02);
-        bus.write(&mut ram, 0x0307, 0x29);
-        cpu.opcode_lda(AddrMode::INY, 5, &bus, &ram);
+        bus.write(0x00A9, 0x08);
+        bus.write(0x00AA, 0x02);
+        bus.write(0x0307, 0x29);
+        cpu.opcode_lda(AddrMode::INY, 5, &mut bus);
         assert_eq!(cpu.a, 0x29);
-        assert_eq!(cpu.page_crossed, true);
+        assert!(cpu.page_crossed);
         assert_eq!(cpu.cycles, 60);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn adc_sbc_flags() {
+        let mut nes = load_program(&[
+            0x18, // CLC
+            0xA9, 0x7F, // LDA #$7F
+            0x69, 0x01, // ADC #$01, signed overflow
+            0x69, 0x80, // ADC #$80, carry out and overflow to zero
+            0x38, // SEC
+            0xA9, 0x00, // LDA #$00
+            0xE9, 0x01, // SBC #$01, borrows
+            0x38, // SEC
+            0xA9, 0x80, // LDA #$80
+            0xE9, 0x01, // SBC #$01, signed overflow without a borrow
+            0xF8, // SED
+            0x18, // CLC
+            0xA9, 0x09, // LDA #$09
+            0x69, 0x01, // ADC #$01, binary even in decimal mode
+        ]);
+        let mut run = |steps: usize| {
+            (0..steps).for_each(|_| {
+                step(&mut nes);
+            });
+            (nes.cpu.a, nes.cpu.p)
+        };
+        assert_eq!(run(3), (0x80, 0xE4));
+        assert_eq!(run(1), (0x00, 0x67));
+        assert_eq!(run(3), (0xFF, 0xA4));
+        assert_eq!(run(3), (0x7F, 0x65));
+        assert_eq!(run(4), (0x0A, 0x2C));
+    }
+
+    #[test]
+    fn branch_cycles() {
+        let mut program = vec![0xEA; 0x100];
+        program[..8].copy_from_slice(&[
+            0xA2, 0x02, // LDX #$02
+            0xCA, // DEX
+            0xD0, 0xFD, // BNE $C002
+            0x4C, 0xFB, 0xC0, // JMP $C0FB
+        ]);
+        program[0xFB..0xFF].copy_from_slice(&[
+            0xA9, 0x00, // LDA #$00
+            0xF0, 0x10, // BEQ $C10F, across a page
+        ]);
+        let mut nes = load_program(&program);
+        let cycles: Vec<usize> = (0..8).map(|_| step(&mut nes)).collect();
+        // Not taken 2, taken 3, taken into another page 4
+        assert_eq!(cycles, vec![2, 2, 3, 2, 2, 3, 2, 4]);
+        assert_eq!(nes.cpu.pc, 0xC10F);
+    }
+
+    #[test]
+    fn stack() {
+        let mut program = vec![0xEA; 0x11];
+        program[..11].copy_from_slice(&[
+            0xA9, 0x42, // LDA #$42
+            0x48, // PHA
+            0x08, // PHP
+            0xA9, 0x00, // LDA #$00
+            0x28, // PLP
+            0x68, // PLA
+            0x20, 0x10, 0xC0, // JSR $C010
+        ]);
+        program[0x10] = 0x60; // RTS
+        let mut nes = load_program(&program);
+        step(&mut nes);
+        assert_eq!(step(&mut nes), 3);
+        assert_eq!((nes.cpu.sp, nes.bus.peek(0x01FD)), (0xFC, 0x42));
+        // PHP pushes B and the unused bit set, PLP leaves B out
+        assert_eq!(step(&mut nes), 3);
+        assert_eq!(nes.bus.peek(0x01FC), 0x34);
+        step(&mut nes);
+        assert_eq!(step(&mut nes), 4);
+        assert_eq!(nes.cpu.p, 0x24);
+        assert_eq!(step(&mut nes), 4);
+        assert_eq!((nes.cpu.a, nes.cpu.sp), (0x42, 0xFD));
+
+        // JSR pushes the address of its last byte
+        assert_eq!(step(&mut nes), 6);
+        assert_eq!((nes.cpu.pc, nes.cpu.sp), (0xC010, 0xFB));
+        assert_eq!((nes.bus.peek(0x01FD), nes.bus.peek(0x01FC)), (0xC0, 0x0A));
+        assert_eq!(step(&mut nes), 6);
+        assert_eq!((nes.cpu.pc, nes.cpu.sp), (0xC00B, 0xFD));
+    }
+
+    #[test]
+    fn read_modify_write() {
+        let mut nes = load_program(&[
+            0x06, 0x10, // ASL $10
+            0x26, 0x10, // ROL $10
+            0x66, 0x10, // ROR $10
+            0x46, 0x10, // LSR $10
+            0xC6, 0x10, // DEC $10
+            0xC6, 0x10, // DEC $10
+            0xA2, 0x01, // LDX #$01
+            0xFE, 0xFF, 0x01, // INC $01FF,X
+        ]);
+        nes.bus.write(0x0010, 0x81);
+        nes.bus.write(0x0200, 0x7F);
+        let mut run = || {
+            let cycles = step(&mut nes);
+            (nes.bus.peek(0x0010), nes.cpu.p & 0x83, cycles)
+        };
+        assert_eq!(run(), (0x02, 0x01, 5));
+        assert_eq!(run(), (0x05, 0x00, 5));
+        assert_eq!(run(), (0x02, 0x01, 5));
+        assert_eq!(run(), (0x01, 0x00, 5));
+        assert_eq!(run(), (0x00, 0x02, 5));
+        assert_eq!(run(), (0xFF, 0x80, 5));
+        step(&mut nes);
+        // Indexed RMW always takes the extra cycle
+        assert_eq!(step(&mut nes), 7);
+        assert_eq!((nes.bus.peek(0x0200), nes.cpu.p & 0x82), (0x80, 0x80));
+    }
+
+    #[test]
+    fn unofficial() {
+        let mut nes = load_program(&[
+            0xA7, 0x10, // LAX $10
+            0xA2, 0x3C, // LDX #$3C
+            0x87, 0x12, // SAX $12
+            0xC7, 0x10, // DCP $10
+            0xE7, 0x10, // ISB $10
+            0x07, 0x11, // SLO $11
+            0x47, 0x13, // SRE $13
+            0x04, 0x10, // NOP $10
+            0x1A, // NOP
+            0x0C, 0x00, 0x02, // NOP $0200
+        ]);
+        nes.bus.write(0x0010, 0x0F);
+        nes.bus.write(0x0011, 0x80);
+        nes.bus.write(0x0013, 0x03);
+        step(&mut nes);
+        assert_eq!((nes.cpu.a, nes.cpu.x), (0x0F, 0x0F));
+        step(&mut nes);
+        step(&mut nes);
+        assert_eq!(nes.bus.peek(0x0012), 0x0C);
+
+        // DCP decrements then compares, ISB increments then subtracts
+        step(&mut nes);
+        assert_eq!((nes.bus.peek(0x0010), nes.cpu.p & 0x03), (0x0E, 0x01));
+        step(&mut nes);
+        assert_eq!((nes.bus.peek(0x0010), nes.cpu.a, nes.cpu.p & 0x03), (0x0F, 0x00, 0x03));
+
+        // SLO shifts left then ORs, SRE shifts right then EORs
+        step(&mut nes);
+        assert_eq!((nes.bus.peek(0x0011), nes.cpu.a, nes.cpu.p & 0x03), (0x00, 0x00, 0x03));
+        step(&mut nes);
+        assert_eq!((nes.bus.peek(0x0013), nes.cpu.a, nes.cpu.p & 0x03), (0x01, 0x01, 0x01));
+
+        let cycles: Vec<usize> = (0..3).map(|_| step(&mut nes)).collect();
+        assert_eq!(cycles, vec![3, 2, 4]);
+        assert_eq!(nes.cpu.pc, 0xC014);
+    }
+}
@@ -0,0 +1,740 @@
+// Debug adapter protocol server, for source-level debugging of cc65/ca65
+// programs from VS Code and other editors. Messages are JSON with a
+// Content-Length header, over stdio or a TCP connection. Source lines come
+// from ld65 debug info; code without it is still reachable through the
+// disassembly view and instruction breakpoints.
+//
+// Launch and attach take optional "debugInfo" (a .dbg path) and
+// "stopOnEntry" arguments; the ROM is the one given on the command line.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use crate::dbginfo::DebugInfo;
+use crate::debugger::{self, Access, Breakpoint, CallStack, Debugger, FrameKind, Stop};
+use crate::disasm::{self, Labels, OPCODES};
+use crate::expression::Expression;
+use crate::json::Json;
+use crate::nes::Nes;
+
+const THREAD_ID: i64 = 1;
+const REGISTERS_REFERENCE: i64 = 1;
+const SYMBOLS_REFERENCE: i64 = 2;
+
+// Instructions run between checks for new requests
+const REQUEST_CHECK: usize = 10_000;
+
+// A source line, as (file index, line)
+type LineKey = Option<(usize, u32)>;
+
+// What a resumed CPU runs until, besides breakpoints
+#[derive(Clone, Copy)]
+enum Resume {
+    Continue,
+    StepIn { line: LineKey, by_line: bool },
+    Next { depth: usize, line: LineKey, by_line: bool },
+    StepOut { depth: usize },
+}
+
+pub struct DebugAdapter {
+    pub debugger: Debugger,
+    info: Option<DebugInfo>,
+    source_root: PathBuf, // Source paths in the debug info are relative to this
+    labels: Labels,
+    source_breakpoints: Vec<(usize, Breakpoint)>, // With the file they were set in
+    instruction_breakpoints: Vec<Breakpoint>,
+    stop_on_entry: bool,
+    resume: Option<Resume>,
+    events: Vec<(&'static str, Json)>, // Sent after the current response
+    seq: i64,
+}
+
+fn address_reference(addr: u16) -> Json {
+    Json::from(format!("0x{:04X}", addr))
+}
+
+fn parse_reference(text: &str) -> Option<u16> {
+    let text = text.trim();
+    match text.strip_prefix("0x").or(text.strip_prefix("0X")).or(text.strip_prefix('$')) {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+// Reads Content-Length framed messages on a thread of their own, so requests
+// such as pause arrive while the CPU runs
+fn read_messages(input: impl Read + Send + 'static) -> Receiver<Json> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut reader = BufReader::new(input);
+        while let Ok(Some(body)) = read_message(&mut reader) {
+            // Malformed messages can't be answered without a seq, so are dropped
+            if let Ok(message) = Json::parse(&body) {
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+    receiver
+}
+
+fn read_message(reader: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            if length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let mut body = vec![0; length.unwrap_or(0)];
+    reader.read_exact(&mut body)?;
+    Ok(Some(String::from_utf8_lossy(&body).into_owned()))
+}
+
+fn write_message(output: &mut dyn Write, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+impl DebugAdapter {
+    pub fn new(info: Option<DebugInfo>, source_root: &Path) -> Self {
+        let mut adapter = Self {
+            debugger: Debugger::new(),
+            info: None,
+            source_root: source_root.to_path_buf(),
+            labels: Labels::new(),
+            source_breakpoints: Vec::new(),
+            instruction_breakpoints: Vec::new(),
+            stop_on_entry: false,
+            resume: None,
+            events: Vec::new(),
+            seq: 0,
+        };
+        adapter.set_info(info);
+        adapter
+    }
+
+    fn set_info(&mut self, info: Option<DebugInfo>) {
+        self.labels = info
+            .iter()
+            .flat_map(|info| &info.symbols)
+            .filter(|symbol| symbol.label)
+            .map(|symbol| (symbol.value, symbol.name.clone()))
+            .collect();
+        self.info = info;
+    }
+
+    // Serves requests until the client disconnects or closes the stream
+    pub fn serve(&mut self, nes: &mut Nes, input: impl Read + Send + 'static, output: &mut dyn Write) -> io::Result<()> {
+        let requests = read_messages(input);
+        // Let the reset sequence finish so the first stop is at an instruction boundary
+        nes.finish_instruction();
+        loop {
+            let request = match self.resume {
+                Some(_) => match requests.try_recv() {
+                    Ok(request) => Some(request),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => return Ok(()),
+                },
+                None => match requests.recv() {
+                    Ok(request) => Some(request),
+                    Err(_) => return Ok(()),
+                },
+            };
+            if let Some(request) = request {
+                if !self.respond(nes, &request, output)? {
+                    return Ok(());
+                }
+            }
+            if let Some(resume) = self.resume {
+                if let Some(reason) = self.run(nes, resume) {
+                    self.resume = None;
+                    self.events.push(("stopped", Self::stopped(reason)));
+                }
+            }
+            for (event, body) in std::mem::take(&mut self.events) {
+                self.seq += 1;
+                let message = Json::object(vec![
+                    ("seq", Json::from(self.seq)),
+                    ("type", Json::from("event")),
+                    ("event", Json::from(event)),
+                    ("body", body),
+                ]);
+                write_message(output, &message)?;
+            }
+        }
+    }
+
+    fn stopped(reason: &str) -> Json {
+        let mut body = vec![
+            ("reason", Json::from(reason)),
+            ("threadId", Json::from(THREAD_ID)),
+            ("allThreadsStopped", Json::from(true)),
+        ];
+        if reason == "exception" {
+            body.push(("description", Json::from("CPU jammed by KIL")));
+        }
+        Json::object(body)
+    }
+
+    // Answers one request, false once the client disconnects
+    fn respond(&mut self, nes: &mut Nes, request: &Json, output: &mut dyn Write) -> io::Result<bool> {
+        let command = request.get("command").and_then(Json::as_str).unwrap_or_default().to_string();
+        let arguments = request.get("arguments").cloned().unwrap_or(Json::Object(Vec::new()));
+        let result = self.handle(nes, &command, &arguments);
+
+        self.seq += 1;
+        let mut response = vec![
+            ("seq", Json::from(self.seq)),
+            ("type", Json::from("response")),
+            ("request_seq", request.get("seq").cloned().unwrap_or(Json::Null)),
+            ("success", Json::from(result.is_ok())),
+            ("command", Json::from(command.as_str())),
+        ];
+        match result {
+            Ok(body) => response.push(("body", body)),
+            Err(message) => response.push(("message", Json::from(message))),
+        }
+        write_message(output, &Json::object(response))?;
+        Ok(command != "disconnect")
+    }
+
+    fn handle(&mut self, nes: &mut Nes, command: &str, arguments: &Json) -> Result<Json, String> {
+        let body = match command {
+            "initialize" => {
+                self.events.push(("initialized", Json::Object(Vec::new())));
+                Json::object(vec![
+                    ("supportsConfigurationDoneRequest", Json::from(true)),
+                    ("supportsConditionalBreakpoints", Json::from(true)),
+                    ("supportsInstructionBreakpoints", Json::from(true)),
+                    ("supportsDisassembleRequest", Json::from(true)),
+                    ("supportsSteppingGranularity", Json::from(true)),
+                    ("supportsEvaluateForHovers", Json::from(true)),
+                ])
+            }
+            "launch" | "attach" => {
+                if let Some(path) = arguments.get("debugInfo").and_then(Json::as_str) {
+                    let info = DebugInfo::load(Path::new(path)).map_err(|err| format!("Failed to load {}: {}", path, err))?;
+                    self.source_root = Path::new(path).parent().unwrap_or(Path::new("")).to_path_buf();
+                    self.set_info(Some(info));
+                }
+                self.stop_on_entry = arguments.get("stopOnEntry").and_then(Json::as_bool).unwrap_or(false);
+                Json::Object(Vec::new())
+            }
+            "configurationDone" => {
+                if self.stop_on_entry {
+                    self.events.push(("stopped", Self::stopped("entry")));
+                } else {
+                    self.resume = Some(Resume::Continue);
+                }
+                Json::Object(Vec::new())
+            }
+            "setBreakpoints" => self.set_source_breakpoints(arguments)?,
+            "setInstructionBreakpoints" => self.set_instruction_breakpoints(arguments),
+            "setExceptionBreakpoints" => Json::object(vec![("breakpoints", Json::Array(Vec::new()))]),
+            "threads" => {
+                let thread = Json::object(vec![("id", Json::from(THREAD_ID)), ("name", Json::from("6502"))]);
+                Json::object(vec![("threads", Json::from(vec![thread]))])
+            }
+            "stackTrace" => self.stack_trace(nes),
+            "scopes" => {
+                let scope = |name: &str, reference: i64| {
+                    Json::object(vec![
+                        ("name", Json::from(name)),
+                        ("variablesReference", Json::from(reference)),
+                        ("expensive", Json::from(false)),
+                    ])
+                };
+                let mut scopes = vec![scope("Registers", REGISTERS_REFERENCE)];
+                if self.info.is_some() {
+                    scopes.push(scope("Symbols", SYMBOLS_REFERENCE));
+                }
+                Json::object(vec![("scopes", Json::from(scopes))])
+            }
+            "variables" => {
+                let reference = arguments.get("variablesReference").and_then(Json::as_i64).unwrap_or(0);
+                Json::object(vec![("variables", Json::from(self.variables(nes, reference)))])
+            }
+            "evaluate" => {
+                let expression = arguments.get("expression").and_then(Json::as_str).unwrap_or_default();
+                let value = self.evaluate(nes, expression)?;
+                Json::object(vec![("result", Json::from(value)), ("variablesReference", Json::from(0))])
+            }
+            "continue" => {
+                self.resume = Some(Resume::Continue);
+                Json::object(vec![("allThreadsContinued", Json::from(true))])
+            }
+            "next" | "stepIn" | "stepOut" => {
+                let by_line = self.info.is_some() && arguments.get("granularity").and_then(Json::as_str) != Some("instruction");
+                let line = self.line_key(nes.cpu.pc());
+                let depth = self.debugger.call_stack.frames.len();
+                self.resume = Some(match command {
+                    "next" => Resume::Next { depth, line, by_line },
+                    "stepIn" => Resume::StepIn { line, by_line },
+                    _ if depth == 0 => return Err(String::from("Not inside a routine")),
+                    _ => Resume::StepOut { depth },
+                });
+                Json::Object(Vec::new())
+            }
+            "pause" => {
+                if self.resume.take().is_some() {
+                    self.events.push(("stopped", Self::stopped("pause")));
+                }
+                Json::Object(Vec::new())
+            }
+            "disassemble" => self.disassemble(nes, arguments)?,
+            "disconnect" | "terminate" => {
+                self.resume = None;
+                Json::Object(Vec::new())
+            }
+            _ => return Err(format!("Unsupported request {}", command)),
+        };
+        Ok(body)
+    }
+
+    fn line_key(&self, addr: u16) -> LineKey {
+        let span = self.info.as_ref()?.line_at(addr)?;
+        Some((span.file, span.line))
+    }
+
+    // Runs a slice of the resumed execution, with the stop reason once it stops
+    fn run(&mut self, nes: &mut Nes, resume: Resume) -> Option<&'static str> {
+        let mut left = REQUEST_CHECK;
+        let mut finished = false;
+        let info = self.info.as_ref();
+        let line_key = |addr: u16| info.and_then(|info| info.line_at(addr)).map(|span| (span.file, span.line));
+        let stop = self.debugger.run(nes, |nes, call_stack: &CallStack| {
+            let depth = call_stack.frames.len();
+            let line = || line_key(nes.cpu.pc());
+            finished = match resume {
+                Resume::Continue => false,
+                Resume::StepIn { line: start, by_line } => !by_line || line() != start,
+                Resume::Next { depth: start_depth, line: start, by_line } => {
+                    depth <= start_depth && (!by_line || line() != start)
+                }
+                Resume::StepOut { depth: start_depth } => depth < start_depth,
+            };
+            left -= 1;
+            finished || left == 0
+        });
+        match stop {
+            Stop::Done if finished => Some("step"),
+            Stop::Done => None,
+            Stop::Breakpoint(_) => Some("breakpoint"),
+            Stop::Watchpoint(_) => Some("data breakpoint"),
+            Stop::Jammed => Some("exception"),
+        }
+    }
+
+    fn rebuild_breakpoints(&mut self) {
+        let source = self.source_breakpoints.iter().map(|(_, breakpoint)| breakpoint.clone());
+        self.debugger.breakpoints = source.chain(self.instruction_breakpoints.iter().cloned()).collect();
+    }
+
+    fn parse_condition(breakpoint: &Json) -> Result<Option<(String, Expression)>, String> {
+        match breakpoint.get("condition").and_then(Json::as_str).filter(|text| !text.trim().is_empty()) {
+            Some(text) => Ok(Some((text.to_string(), Expression::parse(text)?))),
+            None => Ok(None),
+        }
+    }
+
+    fn set_source_breakpoints(&mut self, arguments: &Json) -> Result<Json, String> {
+        let path = arguments.get("source").and_then(|source| source.get("path")).and_then(Json::as_str).unwrap_or_default();
+        let requested = arguments.get("breakpoints").and_then(Json::as_array).unwrap_or_default();
+        let file = self.info.as_ref().and_then(|info| info.find_file(path));
+        if let Some(file) = file {
+            self.source_breakpoints.retain(|(breakpoint_file, _)| *breakpoint_file != file);
+        }
+
+        let mut results = Vec::new();
+        for breakpoint in requested {
+            let line = breakpoint.get("line").and_then(Json::as_i64).unwrap_or(0);
+            let placed = match (&self.info, file) {
+                (Some(info), Some(file)) => info.line_addresses(file, line.max(0) as u32).ok_or("No code at or after this line"),
+                (Some(_), None) => Err("Source file isn't in the debug info"),
+                (None, _) => Err("No debug info loaded"),
+            };
+            let result = match (placed, Self::parse_condition(breakpoint)) {
+                (Ok((line, addresses)), Ok(condition)) => {
+                    for addr in addresses {
+                        let breakpoint = Breakpoint { access: Access::Execute, start: addr, end: addr, condition: condition.clone() };
+                        self.source_breakpoints.push((file.unwrap(), breakpoint));
+                    }
+                    vec![("verified", Json::from(true)), ("line", Json::from(line as i64))]
+                }
+                (Err(message), _) => vec![("verified", Json::from(false)), ("message", Json::from(message))],
+                (_, Err(message)) => vec![("verified", Json::from(false)), ("message", Json::from(message))],
+            };
+            results.push(Json::object(result));
+        }
+        self.rebuild_breakpoints();
+        Ok(Json::object(vec![("breakpoints", Json::from(results))]))
+    }
+
+    fn set_instruction_breakpoints(&mut self, arguments: &Json) -> Json {
+        self.instruction_breakpoints.clear();
+        let mut results = Vec::new();
+        for breakpoint in arguments.get("breakpoints").and_then(Json::as_array).unwrap_or_default() {
+            let reference = breakpoint.get("instructionReference").and_then(Json::as_str).and_then(parse_reference);
+            let offset = breakpoint.get("offset").and_then(Json::as_i64).unwrap_or(0);
+            let result = match (reference, Self::parse_condition(breakpoint)) {
+                (Some(addr), Ok(condition)) => {
+                    let addr = addr.wrapping_add(offset as u16);
+                    self.instruction_breakpoints.push(Breakpoint { access: Access::Execute, start: addr, end: addr, condition });
+                    vec![("verified", Json::from(true)), ("instructionReference", address_reference(addr))]
+                }
+                (None, _) => vec![("verified", Json::from(false)), ("message", Json::from("Invalid instruction reference"))],
+                (_, Err(message)) => vec![("verified", Json::from(false)), ("message", Json::from(message))],
+            };
+            results.push(Json::object(result));
+        }
+        self.rebuild_breakpoints();
+        Json::object(vec![("breakpoints", Json::from(results))])
+    }
+
+    // Source reference and line for addr, when the debug info covers it
+    fn location(&self, addr: u16) -> Vec<(&'static str, Json)> {
+        let Some((info, span)) = self.info.as_ref().and_then(|info| Some((info, info.line_at(addr)?))) else {
+            return vec![("line", Json::from(0)), ("column", Json::from(0))];
+        };
+        let name = &info.files[span.file];
+        let path = self.source_root.join(name);
+        let file_name = Path::new(name).file_name().map_or(name.clone(), |name| name.to_string_lossy().into_owned());
+        let source = Json::object(vec![("name", Json::from(file_name)), ("path", Json::from(path.to_string_lossy().into_owned()))]);
+        vec![("source", source), ("line", Json::from(span.line as i64)), ("column", Json::from(1))]
+    }
+
+    // Nearest label at or before addr
+    fn routine_name(&self, addr: u16) -> String {
+        let label = self.labels.iter().filter(|(&value, _)| value <= addr).max_by_key(|(&value, _)| value);
+        match label {
+            Some((&value, name)) if value == addr => name.clone(),
+            Some((&value, name)) => format!("{}+{}", name, addr - value),
+            None => format!("${:04X}", addr),
+        }
+    }
+
+    // The current pc, then each caller, innermost first
+    fn stack_trace(&self, nes: &Nes) -> Json {
+        let frames = &self.debugger.call_stack.frames;
+        let mut locations = vec![(nes.cpu.pc(), frames.last().map(|frame| (frame.entry, frame.kind)))];
+        for (i, frame) in frames.iter().enumerate().rev() {
+            let routine = i.checked_sub(1).map(|outer| (frames[outer].entry, frames[outer].kind));
+            locations.push((frame.caller, routine));
+        }
+
+        let stack_frames: Vec<Json> = locations
+            .iter()
+            .enumerate()
+            .map(|(id, &(addr, routine))| {
+                let name = match routine {
+                    Some((entry, FrameKind::Nmi)) => format!("{} (NMI)", self.routine_name(entry)),
+                    Some((entry, FrameKind::Irq)) => format!("{} (IRQ)", self.routine_name(entry)),
+                    Some((entry, FrameKind::Subroutine)) => self.routine_name(entry),
+                    None => self.routine_name(addr),
+                };
+                let mut frame = vec![
+                    ("id", Json::from(id as i64)),
+                    ("name", Json::from(name)),
+                    ("instructionPointerReference", address_reference(addr)),
+                ];
+                frame.extend(self.location(addr));
+                Json::object(frame)
+            })
+            .collect();
+        let total = stack_frames.len() as i64;
+        Json::object(vec![("stackFrames", Json::from(stack_frames)), ("totalFrames", Json::from(total))])
+    }
+
+    fn variables(&self, nes: &Nes, reference: i64) -> Vec<Json> {
+        let variable = |name: &str, value: String, addr: Option<u16>| {
+            let mut pairs = vec![
+                ("name", Json::from(name)),
+                ("value", Json::from(value)),
+                ("variablesReference", Json::from(0)),
+            ];
+            if let Some(addr) = addr {
+                pairs.push(("memoryReference", address_reference(addr)));
+            }
+            Json::object(pairs)
+        };
+        match reference {
+            REGISTERS_REFERENCE => {
+                let registers = nes.cpu.registers();
+                vec![
+                    variable("A", format!("${:02X}", registers.a), None),
+                    variable("X", format!("${:02X}", registers.x), None),
+                    variable("Y", format!("${:02X}", registers.y), None),
+                    variable("SP", format!("${:02X}", registers.sp), None),
+                    variable("PC", format!("${:04X}", registers.pc), None),
+                    variable("P", format!("${:02X} {}", registers.p, debugger::flag_names(registers.p)), None),
+                ]
+            }
+            // Labels in RAM, as bytes or little endian words
+            SYMBOLS_REFERENCE => {
+                let Some(info) = &self.info else {
+                    return Vec::new();
+                };
+                let mut symbols: Vec<_> = info
+                    .symbols
+                    .iter()
+                    .filter(|symbol| symbol.label && (symbol.value < 0x0800 || (0x6000..0x8000).contains(&symbol.value)))
+                    .collect();
+                symbols.sort_by_key(|symbol| symbol.value);
+                symbols
+                    .iter()
+                    .map(|symbol| variable(&symbol.name, self.memory_value(nes, symbol.value, symbol.size), Some(symbol.value)))
+                    .collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn memory_value(&self, nes: &Nes, addr: u16, size: u16) -> String {
+        let peek = |addr: u16| nes.bus.peek(addr as usize);
+        match size {
+            2 => format!("${:04X}", u16::from_le_bytes([peek(addr), peek(addr.wrapping_add(1))])),
+            _ => format!("${:02X}", peek(addr)),
+        }
+    }
+
+    // A symbol's value, otherwise a debugger expression
+    fn evaluate(&self, nes: &Nes, text: &str) -> Result<String, String> {
+        let symbol = self.info.as_ref().and_then(|info| info.symbol(text.trim()));
+        match symbol {
+            Some(symbol) if symbol.label => Ok(self.memory_value(nes, symbol.value, symbol.size)),
+            Some(symbol) => Ok(format!("${:04X}", symbol.value)),
+            None => Expression::parse(text).map(|expression| format!("${:02X}", expression.evaluate(nes))),
+        }
+    }
+
+    fn disassemble(&self, nes: &Nes, arguments: &Json) -> Result<Json, String> {
+        let peek = |addr: u16| nes.bus.peek(addr as usize);
+        let reference = arguments.get("memoryReference").and_then(Json::as_str).and_then(parse_reference);
+        let base = reference.ok_or("Invalid memory reference")?;
+        let base = base.wrapping_add(arguments.get("offset").and_then(Json::as_i64).unwrap_or(0) as u16);
+        let offset = arguments.get("instructionOffset").and_then(Json::as_i64).unwrap_or(0);
+        let count = arguments.get("instructionCount").and_then(Json::as_i64).unwrap_or(0).clamp(0, 0x10000);
+
+        let next = |addr: u16| addr.wrapping_add(OPCODES[peek(addr) as usize].bytes as u16);
+        let mut addr = base;
+        if offset < 0 {
+            let back = offset.unsigned_abs() as u16;
+            addr = instructions_before(&peek, base, back);
+        } else {
+            for _ in 0..offset {
+                addr = next(addr);
+            }
+        }
+
+        let mut instructions = Vec::new();
+        for _ in 0..count {
+            let opcode = &OPCODES[peek(addr) as usize];
+            let bytes: Vec<String> = (0..opcode.bytes as u16).map(|i| format!("{:02X}", peek(addr.wrapping_add(i)))).collect();
+            let text = disasm::format_instruction(&peek, addr, None, Some(&self.labels));
+            let mut instruction = vec![
+                ("address", address_reference(addr)),
+                ("instructionBytes", Json::from(bytes.join(" "))),
+                ("instruction", Json::from(if opcode.official { text } else { format!("*{}", text) })),
+            ];
+            if let Some(label) = self.labels.get(&addr) {
+                instruction.push(("symbol", Json::from(label.as_str())));
+            }
+            if self.line_key(addr).is_some() {
+                instruction.extend(self.location(addr).into_iter().filter(|(key, _)| *key != "column"));
+            }
+            instructions.push(Json::object(instruction));
+            addr = next(addr);
+        }
+        Ok(Json::object(vec![("instructions", Json::from(instructions))]))
+    }
+}
+
+// Start of the instruction count instructions before addr. Code can't be
+// decoded backwards, so this takes the furthest start that decodes forwards
+// onto addr, falling back to one byte per instruction.
+fn instructions_before(peek: &dyn Fn(u16) -> u8, addr: u16, count: u16) -> u16 {
+    for back in (count..=count.saturating_mul(3)).rev() {
+        let mut starts = Vec::new();
+        let mut current = addr.wrapping_sub(back);
+        while current != addr && starts.len() <= back as usize {
+            starts.push(current);
+            current = current.wrapping_add(OPCODES[peek(current) as usize].bytes as u16);
+            // Overshot addr
+            if addr.wrapping_sub(current) > back {
+                break;
+            }
+        }
+        if current == addr && starts.len() >= count as usize {
+            return starts[starts.len() - count as usize];
+        }
+    }
+    addr.wrapping_sub(count)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    // JSR sub / LDA #$10 / STA result / KIL, with sub doing INX / RTS
+    const DEBUG_INFO: &str = "\
+version\tmajor=2,minor=0
+file\tid=0,name=\"src/main.s\",size=400,mtime=0x6512AB00,mod=0
+seg\tid=0,name=\"BSS\",start=0x000300,size=0x0001,addrsize=absolute,type=rw
+seg\tid=1,name=\"CODE\",start=0x00C000,size=0x0012,addrsize=absolute,type=ro,oname=\"game.nes\",ooffs=16
+span\tid=0,seg=1,start=0,size=3
+span\tid=1,seg=1,start=3,size=2
+span\tid=2,seg=1,start=5,size=3
+span\tid=3,seg=1,start=8,size=1
+span\tid=4,seg=1,start=16,size=1
+span\tid=5,seg=1,start=17,size=1
+line\tid=0,file=0,line=10,span=0
+line\tid=1,file=0,line=11,span=1
+line\tid=2,file=0,line=12,span=2
+line\tid=3,file=0,line=13,span=3
+line\tid=4,file=0,line=20,span=4
+line\tid=5,file=0,line=21,span=5
+sym\tid=0,name=\"reset\",addrsize=absolute,scope=0,def=0,val=0xC000,seg=1,type=lab
+sym\tid=1,name=\"sub\",addrsize=absolute,scope=0,def=1,val=0xC010,seg=1,type=lab
+sym\tid=2,name=\"result\",addrsize=absolute,size=1,scope=0,def=2,val=0x0300,seg=0,type=lab
+";
+
+    struct Client {
+        stream: std::net::TcpStream,
+        reader: std::io::BufReader<std::net::TcpStream>,
+        seq: i64,
+    }
+
+    impl Client {
+        fn receive(&mut self) -> crate::json::Json {
+            let body = crate::dap::read_message(&mut self.reader).unwrap().unwrap();
+            crate::json::Json::parse(&body).unwrap()
+        }
+
+        // Sends a request and returns its response body
+        fn request(&mut self, command: &str, arguments: &str) -> crate::json::Json {
+            use std::io::Write;
+            self.seq += 1;
+            let body = format!(r#"{{"seq":{},"type":"request","command":"{}","arguments":{}}}"#, self.seq, command, arguments);
+            write!(self.stream, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+            loop {
+                let message = self.receive();
+                if message.get("type").and_then(|kind| kind.as_str()) == Some("response") {
+                    assert_eq!(message.get("request_seq").and_then(|seq| seq.as_i64()), Some(self.seq));
+                    assert_eq!(message.get("success").and_then(|success| success.as_bool()), Some(true), "{}", message);
+                    return message.get("body").cloned().unwrap();
+                }
+            }
+        }
+
+        // Waits for a stopped event, returning its reason
+        fn stopped(&mut self) -> String {
+            loop {
+                let message = self.receive();
+                if message.get("event").and_then(|event| event.as_str()) == Some("stopped") {
+                    return message.get("body").unwrap().get("reason").unwrap().as_str().unwrap().to_string();
+                }
+            }
+        }
+
+        // (name, line) of each stack frame
+        fn stack(&mut self) -> Vec<(String, i64)> {
+            let trace = self.request("stackTrace", r#"{"threadId":1}"#);
+            let frames = trace.get("stackFrames").unwrap().as_array().unwrap().to_vec();
+            frames
+                .iter()
+                .map(|frame| (frame.get("name").unwrap().as_str().unwrap().to_string(), frame.get("line").unwrap().as_i64().unwrap()))
+                .collect()
+        }
+    }
+
+    #[test]
+    fn source_level_session() {
+        use crate::dap::*;
+        use std::net::{TcpListener, TcpStream};
+
+        let mut nes = Nes::new();
+        let mut prg_rom = vec![0xEA; 0x4000];
+        prg_rom[..9].copy_from_slice(&[0x20, 0x10, 0xC0, 0xA9, 0x10, 0x8D, 0x00, 0x03, 0x02]);
+        prg_rom[0x10..0x12].copy_from_slice(&[0xE8, 0x60]);
+        prg_rom[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0xC0]);
+        nes.bus.rom.prg_rom = prg_rom;
+        nes.reset();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let stream = TcpStream::connect(addr).unwrap();
+            let reader = BufReader::new(stream.try_clone().unwrap());
+            let mut client = Client { stream, reader, seq: 0 };
+
+            let capabilities = client.request("initialize", r#"{"adapterID":"nebulous"}"#);
+            assert_eq!(capabilities.get("supportsDisassembleRequest"), Some(&Json::Bool(true)));
+            client.request("launch", r#"{"stopOnEntry":true}"#);
+            let placed = client.request(
+                "setBreakpoints",
+                r#"{"source":{"path":"/home/dev/game/src/main.s"},"breakpoints":[{"line":11},{"line":30}]}"#,
+            );
+            let placed = placed.get("breakpoints").unwrap().as_array().unwrap().to_vec();
+            assert_eq!(placed[0].get("verified"), Some(&Json::Bool(true)));
+            assert_eq!(placed[1].get("verified"), Some(&Json::Bool(false)));
+            client.request("configurationDone", "{}");
+            assert_eq!(client.stopped(), "entry");
+            assert_eq!(client.stack(), [(String::from("reset"), 10)]);
+
+            client.request("stepIn", r#"{"threadId":1}"#);
+            assert_eq!(client.stopped(), "step");
+            assert_eq!(client.stack(), [(String::from("sub"), 20), (String::from("reset"), 10)]);
+            client.request("stepOut", r#"{"threadId":1}"#);
+            assert_eq!(client.stopped(), "step");
+            assert_eq!(client.stack(), [(String::from("reset+3"), 11)]);
+
+            let registers = client.request("variables", r#"{"variablesReference":1}"#);
+            let x = &registers.get("variables").unwrap().as_array().unwrap()[1];
+            assert_eq!(x.get("value").and_then(Json::as_str), Some("$01"));
+
+            client.request("next", r#"{"threadId":1}"#);
+            assert_eq!(client.stopped(), "step");
+            client.request("continue", r#"{"threadId":1}"#);
+            assert_eq!(client.stopped(), "exception");
+            let result = client.request("evaluate", r#"{"expression":"result"}"#);
+            assert_eq!(result.get("result").and_then(Json::as_str), Some("$10"));
+            let result = client.request("evaluate", r#"{"expression":"A == #$10"}"#);
+            assert_eq!(result.get("result").and_then(Json::as_str), Some("$01"));
+
+            let listing = client.request(
+                "disassemble",
+                r#"{"memoryReference":"0xC003","instructionOffset":-1,"instructionCount":3}"#,
+            );
+            let listing = listing.get("instructions").unwrap().as_array().unwrap().to_vec();
+            assert_eq!(listing[0].get("address").and_then(Json::as_str), Some("0xC000"));
+            assert_eq!(listing[0].get("instruction").and_then(Json::as_str), Some("JSR sub"));
+            assert_eq!(listing[0].get("line").and_then(Json::as_i64), Some(10));
+            assert_eq!(listing[2].get("instruction").and_then(Json::as_str), Some("STA result"));
+            client.request("disconnect", "{}");
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let info = crate::dbginfo::DebugInfo::parse(DEBUG_INFO).unwrap();
+        let mut adapter = DebugAdapter::new(Some(info), Path::new("/home/dev/game"));
+        let mut output = stream.try_clone().unwrap();
+        adapter.serve(&mut nes, stream, &mut output).unwrap();
+        client.join().unwrap();
+    }
+}
@@ -0,0 +1,292 @@
+// ld65 debug info files (ld65 --dbgfile), which map addresses to source lines
+// and symbols. Each line is a record type, a tab, then key=value pairs:
+//   file  id=0,name="main.s",size=1402,mtime=0x6512AB00,mod=0
+//   seg   id=1,name="CODE",start=0x00C000,size=0x0123,addrsize=absolute,type=ro,oname="game.nes",ooffs=16
+//   span  id=4,seg=1,start=3,size=2
+//   line  id=7,file=0,line=25,span=4
+//   sym   id=2,name="reset",addrsize=absolute,scope=0,def=5,val=0xC000,seg=1,type=lab
+// Line types are 0 for assembly, 1 for C source passed through from cc65 and
+// 2 for lines inside a macro expansion.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub const LINE_ASSEMBLY: u8 = 0;
+pub const LINE_C: u8 = 1;
+pub const LINE_MACRO: u8 = 2;
+
+pub struct Segment {
+    pub name: String,
+    pub start: u16,
+    pub size: u16,
+    pub rom_offset: Option<usize>, // Offset in the output file, for PRG segments
+}
+
+// A run of bytes generated by one source line
+pub struct LineSpan {
+    pub file: usize,
+    pub line: u32,
+    pub kind: u8,
+    pub segment: usize,
+    pub start: u16,
+    pub size: u16,
+}
+
+impl LineSpan {
+    pub fn contains(&self, addr: u16) -> bool {
+        addr.wrapping_sub(self.start) < self.size
+    }
+}
+
+pub struct Symbol {
+    pub name: String,
+    pub value: u16,
+    pub size: u16,      // Bytes reserved, 0 when ld65 doesn't know
+    pub label: bool,    // An address rather than an equate
+    pub segment: Option<usize>,
+}
+
+pub struct DebugInfo {
+    pub files: Vec<String>,
+    pub segments: Vec<Segment>,
+    pub lines: Vec<LineSpan>,
+    pub symbols: Vec<Symbol>,
+}
+
+fn parse_value(text: &str) -> Option<usize> {
+    match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+// key=value pairs split on commas outside quotes, with the quotes removed
+fn parse_fields(text: &str) -> HashMap<&str, &str> {
+    let mut fields = HashMap::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        let Some((key, value)) = rest.split_once('=') else {
+            break;
+        };
+        let (value, next) = match value.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"').unwrap_or(quoted.len());
+                (&quoted[..end], quoted.get(end + 1..).unwrap_or_default())
+            }
+            None => value.split_once(',').map_or((value, ""), |(value, next)| (value, next)),
+        };
+        fields.insert(key.trim(), value);
+        rest = next.strip_prefix(',').unwrap_or(next);
+    }
+    fields
+}
+
+// Lower is preferred when several lines cover an address: C, then assembly,
+// then macro bodies
+fn line_priority(kind: u8) -> u8 {
+    match kind {
+        LINE_C => 0,
+        LINE_ASSEMBLY => 1,
+        _ => 2,
+    }
+}
+
+impl DebugInfo {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        Self::parse(&text).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut files = HashMap::new();
+        let mut segments = HashMap::new();
+        let mut spans = HashMap::new();
+        let mut lines = Vec::new();
+        let mut symbols = Vec::new();
+
+        for (number, record) in text.lines().enumerate() {
+            let Some((kind, fields)) = record.split_once(char::is_whitespace) else {
+                continue;
+            };
+            let fields = parse_fields(fields.trim());
+            let invalid = || format!("Invalid {} record on line {}", kind, number + 1);
+            let get = |key: &str| fields.get(key).and_then(|value| parse_value(value));
+            match kind {
+                "version" if get("major") != Some(2) => return Err(String::from("Only version 2 debug info is supported")),
+                "file" => {
+                    files.insert(get("id").ok_or_else(invalid)?, fields.get("name").ok_or_else(invalid)?.to_string());
+                }
+                "seg" => {
+                    let segment = Segment {
+                        name: fields.get("name").ok_or_else(invalid)?.to_string(),
+                        start: get("start").ok_or_else(invalid)? as u16,
+                        size: get("size").ok_or_else(invalid)? as u16,
+                        rom_offset: get("ooffs"),
+                    };
+                    segments.insert(get("id").ok_or_else(invalid)?, segment);
+                }
+                "span" => {
+                    let span = (get("seg").ok_or_else(invalid)?, get("start").ok_or_else(invalid)?, get("size").ok_or_else(invalid)?);
+                    spans.insert(get("id").ok_or_else(invalid)?, span);
+                }
+                // Spans are resolved once every record is read
+                "line" => {
+                    let Some(span_ids) = fields.get("span") else {
+                        continue;
+                    };
+                    let file = get("file").ok_or_else(invalid)?;
+                    let line = get("line").ok_or_else(invalid)? as u32;
+                    let kind = get("type").unwrap_or(0) as u8;
+                    for id in span_ids.split('+') {
+                        lines.push((file, line, kind, parse_value(id).ok_or_else(invalid)?));
+                    }
+                }
+                "sym" => {
+                    let label = match fields.get("type") {
+                        Some(&"lab") => true,
+                        Some(&"equ") => false,
+                        _ => continue,
+                    };
+                    let Some(value) = get("val") else {
+                        continue;
+                    };
+                    symbols.push(Symbol {
+                        name: fields.get("name").ok_or_else(invalid)?.to_string(),
+                        value: value as u16,
+                        size: get("size").unwrap_or(0) as u16,
+                        label,
+                        segment: get("seg"),
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        // Ids are dense in ld65 output, so they become indexes
+        let mut segment_ids: Vec<usize> = segments.keys().copied().collect();
+        segment_ids.sort();
+        let segment_index: HashMap<usize, usize> = segment_ids.iter().enumerate().map(|(index, &id)| (id, index)).collect();
+        let file_count = files.keys().max().map_or(0, |&max| max + 1);
+        let mut file_names = vec![String::new(); file_count];
+        for (id, name) in files {
+            file_names[id] = name;
+        }
+
+        let mut line_spans = Vec::new();
+        for (file, line, kind, span_id) in lines {
+            let &(segment_id, offset, size) = spans.get(&span_id).ok_or(format!("Line refers to missing span {}", span_id))?;
+            let segment = *segment_index.get(&segment_id).ok_or(format!("Span refers to missing segment {}", segment_id))?;
+            let start = segments[&segment_id].start.wrapping_add(offset as u16);
+            line_spans.push(LineSpan { file, line, kind, segment, start, size: size as u16 });
+        }
+        for symbol in &mut symbols {
+            symbol.segment = symbol.segment.and_then(|id| segment_index.get(&id).copied());
+        }
+        let mut segments: Vec<(usize, Segment)> = segments.into_iter().collect();
+        segments.sort_by_key(|(id, _)| *id);
+
+        Ok(Self {
+            files: file_names,
+            segments: segments.into_iter().map(|(_, segment)| segment).collect(),
+            lines: line_spans,
+            symbols,
+        })
+    }
+
+    // Source line that generated the byte at addr
+    pub fn line_at(&self, addr: u16) -> Option<&LineSpan> {
+        self.lines
+            .iter()
+            .filter(|span| span.contains(addr))
+            .min_by_key(|span| (line_priority(span.kind), span.size))
+    }
+
+    // Index of the file a path refers to, matching on whole path components
+    // since ld65 records paths as given on its command line
+    pub fn find_file(&self, path: &str) -> Option<usize> {
+        let normalize = |path: &str| path.replace('\\', "/");
+        let path = normalize(path);
+        self.files.iter().position(|name| {
+            let name = normalize(name);
+            !name.is_empty() && (path == name || path.ends_with(&format!("/{}", name.trim_start_matches("./"))))
+        })
+    }
+
+    // Where a breakpoint on line ends up: the first line at or after it that
+    // generated code, with the addresses its code starts at
+    pub fn line_addresses(&self, file: usize, line: u32) -> Option<(u32, Vec<u16>)> {
+        let code_line = self
+            .lines
+            .iter()
+            .filter(|span| span.file == file && span.line >= line && span.kind != LINE_MACRO && span.size > 0)
+            .map(|span| span.line)
+            .min()?;
+        let addresses = self
+            .lines
+            .iter()
+            .filter(|span| span.file == file && span.line == code_line && span.kind != LINE_MACRO && span.size > 0)
+            .map(|span| span.start)
+            .collect();
+        Some((code_line, addresses))
+    }
+
+    // Label placed exactly at addr
+    pub fn label_at(&self, addr: u16) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.label && symbol.value == addr)
+    }
+
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    const SAMPLE: &str = "\
+version\tmajor=2,minor=0
+info\tcsym=0,file=2,lib=0,line=4,mod=1,scope=1,seg=2,span=4,sym=3,type=0
+file\tid=0,name=\"src/main.s\",size=400,mtime=0x6512AB00,mod=0
+file\tid=1,name=\"src/macros.inc\",size=90,mtime=0x6512AB00,mod=0
+seg\tid=0,name=\"ZEROPAGE\",start=0x000000,size=0x0002,addrsize=zeropage,type=rw
+seg\tid=1,name=\"CODE\",start=0x00C000,size=0x0010,addrsize=absolute,type=ro,oname=\"game.nes\",ooffs=16
+span\tid=0,seg=1,start=0,size=3
+span\tid=1,seg=1,start=3,size=2
+span\tid=2,seg=1,start=5,size=3
+span\tid=3,seg=1,start=3,size=5
+line\tid=0,file=0,line=10,span=0
+line\tid=1,file=0,line=12,span=3
+line\tid=2,file=1,line=3,type=2,count=1,span=1+2
+line\tid=3,file=0,line=11
+sym\tid=0,name=\"reset\",addrsize=absolute,scope=0,def=0,val=0xC000,seg=1,type=lab
+sym\tid=1,name=\"score\",addrsize=zeropage,size=2,scope=0,def=1,val=0x00,seg=0,type=lab
+sym\tid=2,name=\"PPUCTRL\",addrsize=absolute,scope=0,def=2,val=0x2000,type=equ
+";
+
+    #[test]
+    fn lines_and_symbols() {
+        use crate::dbginfo::*;
+        let info = DebugInfo::parse(SAMPLE).unwrap();
+        assert_eq!(info.files, ["src/main.s", "src/macros.inc"]);
+        assert_eq!(info.segments[1].rom_offset, Some(16));
+
+        let line = info.line_at(0xC001).unwrap();
+        assert_eq!((info.files[line.file].as_str(), line.line), ("src/main.s", 10));
+        // The macro invocation wins over the lines inside the macro
+        assert_eq!(info.line_at(0xC006).unwrap().line, 12);
+        assert!(info.line_at(0xC00A).is_none());
+
+        let file = info.find_file("/home/dev/game/src/main.s").unwrap();
+        assert_eq!(info.find_file("/home/dev/game/main.s"), None);
+        assert_eq!(info.line_addresses(file, 11), Some((12, vec![0xC003])));
+        assert_eq!(info.line_addresses(file, 13), None);
+
+        assert_eq!(info.label_at(0xC000).unwrap().name, "reset");
+        assert_eq!(info.symbol("score").unwrap().size, 2);
+        assert!(!info.symbol("PPUCTRL").unwrap().label);
+        assert!(DebugInfo::parse("version\tmajor=3,minor=0\n").is_err());
+    }
+}
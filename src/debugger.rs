@@ -0,0 +1,622 @@
+// Interactive debugger for --debug, driven one command line at a time.
+// Addresses and counts are hex, with or without $:
+//   s, step [N]                  run N instructions
+//   n, next                      step, running over a JSR or an interrupt
+//   finish                       run until the current routine returns
+//   c, continue                  run until a breakpoint
+//   frame [N]                    run N frames
+//   b, break ADDR [if COND]      stop before executing ADDR
+//   rb, wb ADDR[-END] [if COND]  stop before an instruction reads or writes ADDR-END
+//   delete N, breaks             remove or list breakpoints
+//   watch EXPR, unwatch N        expressions shown at every stop
+//   wp [SPEC], unwp N            add or list bus watchpoints (see watch.rs), remove one
+//   r, regs                      registers and flags
+//   set REG VALUE                A X Y SP PC P, or a flag N V D I Z C
+//   m, mem ADDR [LEN]            hex dump, without side effects
+//   poke ADDR VALUE...           write memory through the bus
+//   d, disasm [ADDR] [N]         disassemble, around pc by default
+//   bt, stack                    routines entered through JSR and interrupts
+// An empty line repeats the last command. Conditions and watches use the
+// syntax in expression.rs.
+
+use crate::disasm::{self, Instruction, LabelLookup, OPCODES};
+use crate::expression::Expression;
+use crate::nes::Nes;
+use crate::watch::{Hit, Watcher, Watchpoint};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Execute,
+    Read,
+    Write,
+}
+
+#[derive(Clone)]
+pub struct Breakpoint {
+    pub access: Access,
+    pub start: u16,
+    pub end: u16,
+    pub condition: Option<(String, Expression)>,
+}
+
+impl Breakpoint {
+    fn describe(&self) -> String {
+        let kind = match self.access {
+            Access::Execute => "exec",
+            Access::Read => "read",
+            Access::Write => "write",
+        };
+        let mut text = format!("{} ${:04X}", kind, self.start);
+        if self.end != self.start {
+            text += &format!("-${:04X}", self.end);
+        }
+        if let Some((source, _)) = &self.condition {
+            text += &format!(" if {}", source);
+        }
+        text
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrameKind {
+    Subroutine,
+    Nmi,
+    Irq,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Frame {
+    pub kind: FrameKind,
+    pub caller: u16, // The JSR, or the instruction the interrupt came before
+    pub entry: u16,  // First instruction of the routine
+}
+
+// Routines entered and not yet left, tracked from JSR/RTS and interrupt entry/RTI
+pub struct CallStack {
+    pub frames: Vec<Frame>,
+}
+
+impl CallStack {
+    pub fn new() -> Self {
+        Self { frames: Vec::new() }
+    }
+
+    // Steps one instruction, keeping track of calls and returns
+    pub fn step(&mut self, nes: &mut Nes) {
+        nes.finish_instruction();
+        let pc = nes.cpu.pc();
+        let (entered, returned) = if nes.interrupt_pending() {
+            (Some(if nes.cpu.nmi || nes.bus.ppu.nmi { FrameKind::Nmi } else { FrameKind::Irq }), false)
+        } else {
+            match OPCODES[nes.bus.peek(pc as usize) as usize].instruction {
+                Instruction::JSR => (Some(FrameKind::Subroutine), false),
+                Instruction::RTS | Instruction::RTI => (None, true),
+                _ => (None, false),
+            }
+        };
+        let cycle = nes.cpu.total_cycles;
+        nes.step();
+        // Held before the instruction by an execute watchpoint
+        if nes.cpu.total_cycles == cycle {
+            return;
+        }
+        // Games that return through a pushed address leave nothing to pop
+        if returned {
+            self.frames.pop();
+        }
+        if let Some(kind) = entered {
+            self.frames.push(Frame { kind, caller: pc, entry: nes.cpu.pc() });
+        }
+    }
+}
+
+// Why a run stopped
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stop {
+    Done,
+    Breakpoint(usize),
+    Watchpoint(Hit), // A halting bus watchpoint
+    Jammed, // Executing KIL, nothing more will happen until reset
+}
+
+pub struct Debugger {
+    pub breakpoints: Vec<Breakpoint>,
+    pub watches: Vec<(String, Expression)>,
+    pub call_stack: CallStack,
+    last_command: String,
+}
+
+fn parse_hex(text: &str) -> Result<u16, String> {
+    u16::from_str_radix(text.trim_start_matches('#').trim_start_matches('$'), 16)
+        .map_err(|_| format!("Invalid hex number {}", text))
+}
+
+fn parse_count(text: Option<&str>, default: u16) -> Result<u16, String> {
+    text.map_or(Ok(default), parse_hex)
+}
+
+// A symbol name, with the bytes it covers, or a hex address
+fn parse_address(nes: &Nes, text: &str) -> Result<(u16, u16), String> {
+    match nes.bus.symbols.lookup(text, &nes.bus.rom) {
+        Some(symbol) => Ok(symbol),
+        None => parse_hex(text).map(|addr| (addr, 1)),
+    }
+}
+
+fn parse_expression(nes: &Nes, text: &str) -> Result<Expression, String> {
+    Expression::parse_with_symbols(text, &|name| nes.bus.symbols.lookup(name, &nes.bus.rom).map(|(addr, _)| addr))
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self {
+            breakpoints: Vec::new(),
+            watches: Vec::new(),
+            call_stack: CallStack::new(),
+            last_command: String::new(),
+        }
+    }
+
+    // The next instruction with the registers, then the watches
+    pub fn status(&self, nes: &Nes) -> String {
+        let mut text = nes.cpu.trace_line(&nes.bus);
+        for (i, (source, expression)) in self.watches.iter().enumerate() {
+            text += &format!("\n  {}: {} = ${:02X}", i + 1, source, expression.evaluate(nes));
+        }
+        text
+    }
+
+    // Index of a breakpoint the instruction at pc triggers
+    pub fn breakpoint_hit(&self, nes: &Nes) -> Option<usize> {
+        if nes.interrupt_pending() {
+            return None;
+        }
+        let registers = nes.cpu.registers();
+        let peek = |addr: u16| nes.bus.peek(addr as usize);
+        let (reads, writes) = OPCODES[peek(registers.pc) as usize].instruction.data_access();
+        let target = disasm::effective_address(&peek, registers.pc, registers.x, registers.y);
+        self.breakpoints.iter().position(|breakpoint| {
+            let addr = match breakpoint.access {
+                Access::Execute => Some(registers.pc),
+                Access::Read if reads => target,
+                Access::Write if writes => target,
+                _ => None,
+            };
+            addr.is_some_and(|addr| (breakpoint.start..=breakpoint.end).contains(&addr))
+                && breakpoint.condition.as_ref().is_none_or(|(_, condition)| condition.is_true(nes))
+        })
+    }
+
+    // Steps until done returns true or a breakpoint hits. The instruction at
+    // the starting pc is always run, so continuing leaves a breakpoint.
+    pub fn run(&mut self, nes: &mut Nes, mut done: impl FnMut(&Nes, &CallStack) -> bool) -> Stop {
+        if let Some(watch) = nes.bus.watch.as_mut() {
+            watch.resume();
+        }
+        loop {
+            self.call_stack.step(nes);
+            if let Some(hit) = nes.bus.watch.as_ref().and_then(|watch| watch.halted) {
+                return Stop::Watchpoint(hit);
+            }
+            if done(nes, &self.call_stack) {
+                return Stop::Done;
+            }
+            if let Some(index) = self.breakpoint_hit(nes) {
+                return Stop::Breakpoint(index);
+            }
+            if nes.cpu.cycles == 0 && OPCODES[nes.bus.peek(nes.cpu.pc() as usize) as usize].instruction == Instruction::KIL {
+                return Stop::Jammed;
+            }
+        }
+    }
+
+    fn report(&self, nes: &Nes, stop: Stop) -> String {
+        match stop {
+            Stop::Done => self.status(nes),
+            Stop::Breakpoint(index) => {
+                format!("Breakpoint {}, {}\n{}", index + 1, self.breakpoints[index].describe(), self.status(nes))
+            }
+            Stop::Watchpoint(hit) => {
+                let watchpoint = nes.bus.watch.as_ref().unwrap().watchpoints[hit.watchpoint].describe();
+                format!("Watchpoint {}, {}\n{}\n{}", hit.watchpoint + 1, watchpoint, hit.report(&nes.bus), self.status(nes))
+            }
+            Stop::Jammed => format!("CPU jammed\n{}", self.status(nes)),
+        }
+    }
+
+    // Runs one command line, None once the user quits
+    pub fn execute(&mut self, nes: &mut Nes, line: &str) -> Option<String> {
+        let line = if line.trim().is_empty() { self.last_command.clone() } else { line.trim().to_string() };
+        self.last_command = line.clone();
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            return Some(String::new());
+        };
+        let args: Vec<&str> = words.collect();
+        let rest = line[command.len()..].trim();
+
+        let result = match command {
+            "q" | "quit" => return None,
+            "h" | "help" => Ok(String::from(HELP)),
+            "s" | "step" => parse_count(args.first().copied(), 1).map(|count| {
+                let mut left = count.max(1);
+                let stop = self.run(nes, |_, _| {
+                    left -= 1;
+                    left == 0
+                });
+                self.report(nes, stop)
+            }),
+            "n" | "next" => {
+                let depth = self.call_stack.frames.len();
+                let stop = self.run(nes, |_, call_stack| call_stack.frames.len() <= depth);
+                Ok(self.report(nes, stop))
+            }
+            "finish" => match self.call_stack.frames.len() {
+                0 => Err(String::from("Not inside a routine")),
+                depth => {
+                    let stop = self.run(nes, |_, call_stack| call_stack.frames.len() < depth);
+                    Ok(self.report(nes, stop))
+                }
+            },
+            "c" | "continue" => {
+                let stop = self.run(nes, |_, _| false);
+                Ok(self.report(nes, stop))
+            }
+            "frame" => parse_count(args.first().copied(), 1).map(|count| {
+                let target = nes.frame_count() + count as usize;
+                let stop = self.run(nes, |nes, _| nes.frame_count() >= target);
+                self.report(nes, stop)
+            }),
+            "b" | "break" => self.add_breakpoint(nes, Access::Execute, rest),
+            "rb" => self.add_breakpoint(nes, Access::Read, rest),
+            "wb" => self.add_breakpoint(nes, Access::Write, rest),
+            "delete" => args.first().ok_or(String::from("delete needs a breakpoint number")).and_then(|&text| {
+                let index = text.parse::<usize>().ok().filter(|&i| i >= 1 && i <= self.breakpoints.len());
+                let index = index.ok_or(format!("No breakpoint {}", text))?;
+                self.breakpoints.remove(index - 1);
+                Ok(String::new())
+            }),
+            "breaks" => Ok(self
+                .breakpoints
+                .iter()
+                .enumerate()
+                .map(|(i, breakpoint)| format!("{}: {}", i + 1, breakpoint.describe()))
+                .collect::<Vec<_>>()
+                .join("\n")),
+            "watch" => parse_expression(nes, rest).map(|expression| {
+                self.watches.push((rest.to_string(), expression));
+                self.status(nes)
+            }),
+            "unwatch" => args.first().ok_or(String::from("unwatch needs a watch number")).and_then(|&text| {
+                let index = text.parse::<usize>().ok().filter(|&i| i >= 1 && i <= self.watches.len());
+                let index = index.ok_or(format!("No watch {}", text))?;
+                self.watches.remove(index - 1);
+                Ok(String::new())
+            }),
+            "wp" => match rest {
+                "" => Ok(nes
+                    .bus
+                    .watch
+                    .iter()
+                    .flat_map(|watch| watch.watchpoints.iter().enumerate())
+                    .map(|(i, watchpoint)| format!("{}: {}", i + 1, watchpoint.describe()))
+                    .collect::<Vec<_>>()
+                    .join("\n")),
+                spec => Watchpoint::parse(spec).map(|watchpoint| {
+                    let watch = nes.bus.watch.get_or_insert_with(|| Box::new(Watcher::new()));
+                    watch.watchpoints.push(watchpoint);
+                    format!("Watchpoint {}, {}", watch.watchpoints.len(), watch.watchpoints.last().unwrap().describe())
+                }),
+            },
+            "unwp" => {
+                let count = nes.bus.watch.as_ref().map_or(0, |watch| watch.watchpoints.len());
+                let index = args.first().and_then(|text| text.parse::<usize>().ok()).filter(|&i| i >= 1 && i <= count);
+                match index {
+                    Some(index) => {
+                        let watch = nes.bus.watch.as_mut().unwrap();
+                        watch.watchpoints.remove(index - 1);
+                        // Without watchpoints the bus goes back to its fast path
+                        if watch.watchpoints.is_empty() {
+                            nes.bus.watch = None;
+                        }
+                        Ok(String::new())
+                    }
+                    None => Err(format!("No watchpoint {}", rest)),
+                }
+            }
+            "r" | "regs" => Ok(registers(nes)),
+            "set" => self.set_register(nes, &args).map(|_| registers(nes)),
+            "m" | "mem" => self.dump_memory(nes, &args),
+            "poke" => self.poke(nes, &args),
+            "d" | "disasm" => self.disassemble(nes, &args),
+            "bt" | "stack" => Ok(self.backtrace(nes)),
+            _ => Err(format!("Unknown command {}, try help", command)),
+        };
+        Some(result.unwrap_or_else(|err| err))
+    }
+
+    // ADDR[-END] [if COND], where a symbol covers all of its bytes
+    fn add_breakpoint(&mut self, nes: &Nes, access: Access, text: &str) -> Result<String, String> {
+        let (range, condition) = match text.split_once(" if ") {
+            Some((range, condition)) => (range.trim(), Some(condition.trim())),
+            None => (text.trim(), None),
+        };
+        if range.is_empty() {
+            return Err(String::from("Breakpoints need an address"));
+        }
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (parse_address(nes, start)?.0, parse_address(nes, end)?.0),
+            None => {
+                let (start, size) = parse_address(nes, range)?;
+                (start, start.wrapping_add(size - 1))
+            }
+        };
+        if end < start {
+            return Err(format!("Empty range {}", range));
+        }
+        let condition = match condition {
+            Some(source) => Some((source.to_string(), parse_expression(nes, source)?)),
+            None => None,
+        };
+        self.breakpoints.push(Breakpoint { access, start, end, condition });
+        Ok(format!("Breakpoint {}, {}", self.breakpoints.len(), self.breakpoints.last().unwrap().describe()))
+    }
+
+    fn set_register(&mut self, nes: &mut Nes, args: &[&str]) -> Result<(), String> {
+        let [name, value] = args else {
+            return Err(String::from("set needs a register and a value"));
+        };
+        let value = parse_address(nes, value)?.0;
+        let mut registers = nes.cpu.registers();
+        let flag = "CZIDB-VN".find(name.to_uppercase().as_str()).filter(|_| name.len() == 1 && *name != "-");
+        match name.to_uppercase().as_str() {
+            "A" => registers.a = value as u8,
+            "X" => registers.x = value as u8,
+            "Y" => registers.y = value as u8,
+            "SP" | "S" => registers.sp = value as u8,
+            "PC" => registers.pc = value,
+            "P" => registers.p = value as u8,
+            _ => {
+                let bit = flag.ok_or(format!("Unknown register {}", name))?;
+                registers.p = (registers.p & !(1 << bit)) | ((value != 0) as u8) << bit;
+            }
+        }
+        nes.cpu.set_registers(registers);
+        Ok(())
+    }
+
+    fn dump_memory(&self, nes: &Nes, args: &[&str]) -> Result<String, String> {
+        let start = parse_address(nes, args.first().ok_or(String::from("mem needs an address"))?)?.0;
+        let length = parse_count(args.get(1).copied(), 0x40)?;
+        let mut lines = Vec::new();
+        for row in (0..length as usize).step_by(16) {
+            let addr = start.wrapping_add(row as u16);
+            let count = 16.min(length as usize - row);
+            let bytes: Vec<String> = (0..count)
+                .map(|i| format!("{:02X}", nes.bus.peek(addr.wrapping_add(i as u16) as usize)))
+                .collect();
+            // Names of the symbols starting in the row, such as "player_x@0324"
+            let names: Vec<String> = (0..count as u16)
+                .map(|i| addr.wrapping_add(i))
+                .filter_map(|addr| nes.bus.symbols.name(addr, &nes.bus.rom).map(|name| format!("{}@{:04X}", name, addr)))
+                .collect();
+            let mut line = format!("{:04X}  {}", addr, bytes.join(" "));
+            if !names.is_empty() {
+                line += &format!("{:width$}  {}", "", names.join(" "), width = (16 - count) * 3);
+            }
+            lines.push(line);
+        }
+        Ok(lines.join("\n"))
+    }
+
+    fn poke(&self, nes: &mut Nes, args: &[&str]) -> Result<String, String> {
+        let [addr, values @ ..] = args else {
+            return Err(String::from("poke needs an address and values"));
+        };
+        let addr = parse_address(nes, addr)?.0;
+        if values.is_empty() {
+            return Err(String::from("poke needs an address and values"));
+        }
+        for (offset, value) in values.iter().enumerate() {
+            nes.bus.write(addr.wrapping_add(offset as u16) as usize, parse_hex(value)? as u8);
+        }
+        self.dump_memory(nes, &[&format!("{:X}", addr), &format!("{:X}", values.len())])
+    }
+
+    // Lists N instructions from ADDR, or from a few before pc
+    fn disassemble(&self, nes: &Nes, args: &[&str]) -> Result<String, String> {
+        let pc = nes.cpu.pc();
+        let peek = |addr: u16| nes.bus.peek(addr as usize);
+        let count = parse_count(args.get(1).copied(), 10)?;
+        let start = match args.first() {
+            Some(addr) => parse_address(nes, addr)?.0,
+            None => {
+                // Code can't be decoded backwards reliably, so take the
+                // furthest start within 3 instructions that lands on pc
+                let lands_on_pc = |start: u16| {
+                    let mut addr = start;
+                    for _ in 0..3 {
+                        addr = addr.wrapping_add(OPCODES[peek(addr) as usize].bytes as u16);
+                        if addr == pc {
+                            return true;
+                        }
+                    }
+                    false
+                };
+                (1..=9).rev().map(|back| pc.wrapping_sub(back)).find(|&start| lands_on_pc(start)).unwrap_or(pc)
+            }
+        };
+
+        let labels = nes.bus.symbols.labels(&nes.bus.rom);
+        let mut lines = Vec::new();
+        let mut addr = start;
+        for _ in 0..count {
+            if let Some(label) = labels.label(addr) {
+                lines.push(format!("{}:", label));
+            }
+            let opcode = &OPCODES[peek(addr) as usize];
+            let marker = if addr == pc { '>' } else { ' ' };
+            let text = disasm::format_instruction(&peek, addr, None, Some(&labels));
+            lines.push(format!("{} {:04X}  {}{}", marker, addr, if opcode.official { ' ' } else { '*' }, text));
+            addr = addr.wrapping_add(opcode.bytes as u16);
+        }
+        Ok(lines.join("\n"))
+    }
+
+    // Innermost first
+    fn backtrace(&self, nes: &Nes) -> String {
+        let mut lines = vec![format!("#0  ${:04X}", nes.cpu.pc())];
+        for (depth, frame) in self.call_stack.frames.iter().rev().enumerate() {
+            let how = match frame.kind {
+                FrameKind::Subroutine => "called from",
+                FrameKind::Nmi => "NMI at",
+                FrameKind::Irq => "IRQ at",
+            };
+            let entry = match nes.bus.symbols.name(frame.entry, &nes.bus.rom) {
+                Some(name) => format!("${:04X} {}", frame.entry, name),
+                None => format!("${:04X}", frame.entry),
+            };
+            lines.push(format!("#{}  {}  {} ${:04X}", depth + 1, entry, how, frame.caller));
+        }
+        lines.join("\n")
+    }
+}
+
+// nv-bdIzc, with set flags in capitals
+pub fn flag_names(p: u8) -> String {
+    "nv-bdizc"
+        .chars()
+        .enumerate()
+        .map(|(i, name)| if p & (0x80 >> i) != 0 { name.to_ascii_uppercase() } else { name })
+        .collect()
+}
+
+// A:00 X:00 Y:00 SP:FD PC:C000 P:24 nv-bdIzc
+fn registers(nes: &Nes) -> String {
+    let registers = nes.cpu.registers();
+    format!(
+        "A:{:02X} X:{:02X} Y:{:02X} SP:{:02X} PC:{:04X} P:{:02X} {}",
+        registers.a, registers.x, registers.y, registers.sp, registers.pc, registers.p, flag_names(registers.p)
+    )
+}
+
+const HELP: &str = "\
+s, step [N]                  run N instructions
+n, next                      step, running over a JSR or an interrupt
+finish                       run until the current routine returns
+c, continue                  run until a breakpoint
+frame [N]                    run N frames
+b, break ADDR [if COND]      stop before executing ADDR
+rb, wb ADDR[-END] [if COND]  stop before an instruction reads or writes ADDR-END
+delete N, breaks             remove or list breakpoints
+watch EXPR, unwatch N        expressions shown at every stop
+wp [SPEC], unwp N            bus watchpoint such as ppu:w:3F00-3F1F=0F, or list them
+r, regs                      registers and flags
+set REG VALUE                A X Y SP PC P, or a flag N V D I Z C
+m, mem ADDR [LEN]            hex dump
+poke ADDR VALUE...           write memory
+d, disasm [ADDR] [N]         disassemble, around pc by default
+bt, stack                    call stack
+q, quit
+Numbers are hex, and symbol names work anywhere an address does.
+Conditions look like A == #$10 && $0324 >= #3 or player_x >= #3";
+
+
+#[cfg(test)]
+mod tests {
+
+    // JSR $C010 / LDA #$10 / STA $0300 / KIL, with the subroutine at $C010
+    // doing INX / RTS
+    fn load_program() -> crate::nes::Nes {
+        let mut nes = crate::nes::Nes::new();
+        let mut prg_rom = vec![0xEA; 0x4000];
+        prg_rom[..10].copy_from_slice(&[0x20, 0x10, 0xC0, 0xA9, 0x10, 0x8D, 0x00, 0x03, 0x02, 0xEA]);
+        prg_rom[0x10..0x12].copy_from_slice(&[0xE8, 0x60]);
+        prg_rom[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0xC0]);
+        nes.bus.rom.prg_rom = prg_rom;
+        nes.reset();
+        nes
+    }
+
+    #[test]
+    fn audio_stays_bounded() {
+        use crate::debugger::*;
+        // JMP $C000 with pulse 1 sounding, so the level keeps changing
+        let mut nes = load_program();
+        nes.bus.rom.prg_rom[..3].copy_from_slice(&[0x4C, 0x00, 0xC0]);
+        nes.reset();
+        for (addr, data) in [(0x4015, 0x01), (0x4000, 0xBF), (0x4002, 0x40), (0x4003, 0x00)] {
+            nes.bus.write(addr, data);
+        }
+        // Nothing drains the audio while debugging
+        let mut debugger = Debugger::new();
+        debugger.run(&mut nes, |nes, _| nes.frame_count() >= 120);
+        assert!(nes.audio_buffered() < 100, "{} samples buffered", nes.audio_buffered());
+    }
+
+    #[test]
+    fn stepping_and_call_stack() {
+        use crate::debugger::*;
+        let mut nes = load_program();
+        let mut debugger = Debugger::new();
+        assert!(debugger.execute(&mut nes, "step").unwrap().starts_with("C010  E8"));
+        assert_eq!(debugger.call_stack.frames.len(), 1);
+        assert!(debugger.execute(&mut nes, "bt").unwrap().contains("$C010  called from $C000"));
+        assert!(debugger.execute(&mut nes, "finish").unwrap().starts_with("C003  A9 10"));
+        assert_eq!(nes.cpu.x, 1);
+
+        // Step over runs the whole subroutine
+        nes.cpu.jump(0xC000);
+        assert!(debugger.execute(&mut nes, "n").unwrap().starts_with("C003"));
+        assert_eq!(nes.cpu.x, 2);
+        assert!(debugger.execute(&mut nes, "").unwrap().starts_with("C005"));
+        assert!(debugger.execute(&mut nes, "c").unwrap().starts_with("CPU jammed"));
+        assert!(debugger.execute(&mut nes, "quit").is_none());
+    }
+
+    #[test]
+    fn breakpoints_and_registers() {
+        use crate::debugger::*;
+        let mut nes = load_program();
+        let mut debugger = Debugger::new();
+        debugger.execute(&mut nes, "b C003 if X == #2");
+        debugger.execute(&mut nes, "wb $0300-$03FF");
+        debugger.execute(&mut nes, "watch $0300");
+
+        // X is 1 the first time through
+        let stop = debugger.execute(&mut nes, "c").unwrap();
+        assert!(stop.starts_with("Breakpoint 2, write $0300-$03FF\nC005  8D 00 03  STA $0300 = 00"));
+        assert!(stop.ends_with("1: $0300 = $00"));
+
+        nes.cpu.jump(0xC000);
+        debugger.execute(&mut nes, "set C 1");
+        assert_eq!(debugger.execute(&mut nes, "set A 7F").unwrap(), "A:7F X:01 Y:00 SP:FD PC:C000 P:25 nv-bdIzC");
+        assert!(debugger.execute(&mut nes, "c").unwrap().starts_with("Breakpoint 1, exec $C003 if X == #2"));
+
+        debugger.execute(&mut nes, "poke 0300 12 34");
+        assert_eq!(debugger.execute(&mut nes, "m 300 3").unwrap(), "0300  12 34 00");
+        let listing = debugger.execute(&mut nes, "d").unwrap();
+        assert!(listing.contains("  C000   JSR $C010\n> C003   LDA #$10\n"));
+        assert!(debugger.execute(&mut nes, "set Q 1").unwrap().contains("Unknown register"));
+    }
+
+    #[test]
+    fn symbolic_names() {
+        use crate::debugger::*;
+        use crate::symbols::Location;
+        let mut nes = load_program();
+        nes.bus.symbols.add("add_one", Location::Prg(0x10), 1);
+        nes.bus.symbols.add("buffer", Location::Cpu(0x0300), 2);
+        let mut debugger = Debugger::new();
+        assert_eq!(debugger.execute(&mut nes, "wb buffer if buffer == #0").unwrap(), "Breakpoint 1, write $0300-$0301 if buffer == #0");
+        assert!(debugger.execute(&mut nes, "d C000 1").unwrap().ends_with("C000   JSR add_one"));
+        assert_eq!(debugger.execute(&mut nes, "d add_one 1").unwrap(), "add_one:\n  C010   INX");
+
+        debugger.execute(&mut nes, "step");
+        assert!(debugger.execute(&mut nes, "bt").unwrap().contains("$C010 add_one  called from $C000"));
+        let stop = debugger.execute(&mut nes, "c").unwrap();
+        assert!(stop.starts_with("Breakpoint 1, write $0300-$0301 if buffer == #0\nC005  8D 00 03  STA buffer = 00"));
+        assert_eq!(debugger.execute(&mut nes, "m buffer 2").unwrap(), format!("0300  00 00{:42}  buffer@0300", ""));
+        assert!(debugger.execute(&mut nes, "b missing").unwrap().contains("Invalid hex number"));
+    }
+}
@@ -0,0 +1,409 @@
+// 6502 opcode table and disassembler
+// Instructions are written in standard syntax ("LDA ($24),Y"). Given the X and
+// Y registers, the effective address and the value found there are resolved
+// the same way nestest.log shows them, which the CPU trace builds on.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Names for addresses, shown in place of the number
+pub type Labels = HashMap<u16, String>;
+
+// Where the disassembler gets names from, a fixed map or a symbol table
+// resolved through the current banks
+pub trait LabelLookup {
+    fn label(&self, addr: u16) -> Option<&str>;
+}
+
+impl LabelLookup for Labels {
+    fn label(&self, addr: u16) -> Option<&str> {
+        self.get(&addr).map(String::as_str)
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Instruction {
+    ADC, // Add with Carry
+    AND, // Logical AND
+    ASL, // Arithmetic Shift Left
+    BCC, // Branch if Carry Clear
+    BCS, // Branch if Carry Set
+    BEQ, // Branch if Equal
+    BIT, // Bit Test
+    BMI, // Branch if Minus
+    BNE, // Branch if Not Equal
+    BPL, // Branch if Positive
+    BRK, // Force Interrupt
+    BVC, // Branch if Overflow Clear
+    BVS, // Branch if Overflow Set
+    CLC, // Clear Carry Flag
+    CLD, // Clear Decimal Mode
+    CLI, // Clear Interrupt Disable
+    CLV, // Clear Overflow Flag
+    CMP, // Compare
+    CPX, // Compare X Register
+    CPY, // Compare Y Register
+    DEC, // Decrement Memory
+    DEX, // Decrement X Register
+    DEY, // Decrement Y Register
+    EOR, // Exclusive OR
+    INC, // Increment Memory
+    INX, // Increment X Register
+    INY, // Increment Y Register
+    JMP, // Jump
+    JSR, // Jump to Subroutine
+    LDA, // Load Accumulator
+    LDX, // Load X Register
+    LDY, // Load Y Register
+    LSR, // Logical Shift Right
+    NOP, // No Operation
+    ORA, // Logical Inclusive OR
+    PHA, // Push Accumulator
+    PHP, // Push Processor Status
+    PLA, // Pull Accumulator
+    PLP, // Pull Processor Status
+    ROL, // Rotate Left
+    ROR, // Rotate Right
+    RTI, // Return from Interrupt
+    RTS, // Return from Subroutine
+    SBC, // Subtract with Carry
+    SEC, // Set Carry Flag
+    SED, // Set Decimal Flag
+    SEI, // Set Interrupt Disable
+    STA, // Store Accumulator
+    STX, // Store X Register
+    STY, // Store Y Register
+    TAX, // Transfer Accumulator to X
+    TAY, // Transfer Accumulator to Y
+    TSX, // Transfer Stack Pointer to X
+    TXA, // Transfer X to Accumulator
+    TXS, // Transfer X to Stack Pointer
+    TYA, // Transfer Y to Accumulator
+
+    // Unofficial, named as in nestest.log
+    AHX, // Store A & X & (high byte + 1)
+    ALR, // AND then LSR
+    ANC, // AND, copying N into C
+    ARR, // AND then ROR
+    AXS, // X = (A & X) - operand
+    DCP, // DEC then CMP
+    ISB, // INC then SBC
+    KIL, // Halt the CPU
+    LAS, // A, X and SP = memory & SP
+    LAX, // LDA and LDX
+    RLA, // ROL then AND
+    RRA, // ROR then ADC
+    SAX, // Store A & X
+    SHX, // Store X & (high byte + 1)
+    SHY, // Store Y & (high byte + 1)
+    SLO, // ASL then ORA
+    SRE, // LSR then EOR
+    TAS, // SP = A & X, then AHX
+    XAA, // Unstable A = (A | magic) & X & operand
+}
+
+impl Instruction {
+    // Whether a memory operand is (read, written)
+    pub fn data_access(self) -> (bool, bool) {
+        use Instruction::*;
+        match self {
+            STA | STX | STY | SAX | SHX | SHY | AHX | TAS => (false, true),
+            ASL | LSR | ROL | ROR | INC | DEC | SLO | RLA | SRE | RRA | DCP | ISB => (true, true),
+            JMP | JSR => (false, false),
+            _ => (true, false),
+        }
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AddrMode {
+    ZPX, // Zero Page Indexed X
+    ZPY, // Zero Page Indexed Y
+    ABX, // Absolute Indexed X
+    ABY, // Absolute Indexed Y
+    INX, // Indirect Indexed X
+    INY, // Indirect Indexed Y
+    IMP, // Implicit
+    ACC, // Accumulator
+    IMM, // Immediate
+    ZPG, // ZeroPage
+    ABS, // Absolute
+    REL, // Relative
+    IND, // Indirect
+}
+
+impl AddrMode {
+    // Instruction length including the opcode
+    pub const fn bytes(self) -> u8 {
+        match self {
+            AddrMode::IMP | AddrMode::ACC => 1,
+            AddrMode::ABS | AddrMode::ABX | AddrMode::ABY | AddrMode::IND => 3,
+            _ => 2,
+        }
+    }
+}
+
+pub struct Opcode {
+    pub instruction: Instruction,
+    pub mode: AddrMode,
+    pub bytes: u8,
+    pub cycles: u8,     // Before page crossing and branch penalties
+    pub official: bool,
+}
+
+const fn op(instruction: Instruction, mode: AddrMode, cycles: u8, official: bool) -> Opcode {
+    Opcode { instruction, mode, bytes: mode.bytes(), cycles, official }
+}
+
+pub const OPCODES: [Opcode; 256] = {
+    use Instruction::*;
+    use AddrMode as M;
+    [
+        // 0x00
+        op(BRK, M::IMP, 7, true), op(ORA, M::INX, 6, true), op(KIL, M::IMP, 2, false), op(SLO, M::INX, 8, false),
+        op(NOP, M::ZPG, 3, false), op(ORA, M::ZPG, 3, true), op(ASL, M::ZPG, 5, true), op(SLO, M::ZPG, 5, false),
+        op(PHP, M::IMP, 3, true), op(ORA, M::IMM, 2, true), op(ASL, M::ACC, 2, true), op(ANC, M::IMM, 2, false),
+        op(NOP, M::ABS, 4, false), op(ORA, M::ABS, 4, true), op(ASL, M::ABS, 6, true), op(SLO, M::ABS, 6, false),
+        // 0x10
+        op(BPL, M::REL, 2, true), op(ORA, M::INY, 5, true), op(KIL, M::IMP, 2, false), op(SLO, M::INY, 8, false),
+        op(NOP, M::ZPX, 4, false), op(ORA, M::ZPX, 4, true), op(ASL, M::ZPX, 6, true), op(SLO, M::ZPX, 6, false),
+        op(CLC, M::IMP, 2, true), op(ORA, M::ABY, 4, true), op(NOP, M::IMP, 2, false), op(SLO, M::ABY, 7, false),
+        op(NOP, M::ABX, 4, false), op(ORA, M::ABX, 4, true), op(ASL, M::ABX, 7, true), op(SLO, M::ABX, 7, false),
+        // 0x20
+        op(JSR, M::ABS, 6, true), op(AND, M::INX, 6, true), op(KIL, M::IMP, 2, false), op(RLA, M::INX, 8, false),
+        op(BIT, M::ZPG, 3, true), op(AND, M::ZPG, 3, true), op(ROL, M::ZPG, 5, true), op(RLA, M::ZPG, 5, false),
+        op(PLP, M::IMP, 4, true), op(AND, M::IMM, 2, true), op(ROL, M::ACC, 2, true), op(ANC, M::IMM, 2, false),
+        op(BIT, M::ABS, 4, true), op(AND, M::ABS, 4, true), op(ROL, M::ABS, 6, true), op(RLA, M::ABS, 6, false),
+        // 0x30
+        op(BMI, M::REL, 2, true), op(AND, M::INY, 5, true), op(KIL, M::IMP, 2, false), op(RLA, M::INY, 8, false),
+        op(NOP, M::ZPX, 4, false), op(AND, M::ZPX, 4, true), op(ROL, M::ZPX, 6, true), op(RLA, M::ZPX, 6, false),
+        op(SEC, M::IMP, 2, true), op(AND, M::ABY, 4, true), op(NOP, M::IMP, 2, false), op(RLA, M::ABY, 7, false),
+        op(NOP, M::ABX, 4, false), op(AND, M::ABX, 4, true), op(ROL, M::ABX, 7, true), op(RLA, M::ABX, 7, false),
+        // 0x40
+        op(RTI, M::IMP, 6, true), op(EOR, M::INX, 6, true), op(KIL, M::IMP, 2, false), op(SRE, M::INX, 8, false),
+        op(NOP, M::ZPG, 3, false), op(EOR, M::ZPG, 3, true), op(LSR, M::ZPG, 5, true), op(SRE, M::ZPG, 5, false),
+        op(PHA, M::IMP, 3, true), op(EOR, M::IMM, 2, true), op(LSR, M::ACC, 2, true), op(ALR, M::IMM, 2, false),
+        op(JMP, M::ABS, 3, true), op(EOR, M::ABS, 4, true), op(LSR, M::ABS, 6, true), op(SRE, M::ABS, 6, false),
+        // 0x50
+        op(BVC, M::REL, 2, true), op(EOR, M::INY, 5, true), op(KIL, M::IMP, 2, false), op(SRE, M::INY, 8, false),
+        op(NOP, M::ZPX, 4, false), op(EOR, M::ZPX, 4, true), op(LSR, M::ZPX, 6, true), op(SRE, M::ZPX, 6, false),
+        op(CLI, M::IMP, 2, true), op(EOR, M::ABY, 4, true), op(NOP, M::IMP, 2, false), op(SRE, M::ABY, 7, false),
+        op(NOP, M::ABX, 4, false), op(EOR, M::ABX, 4, true), op(LSR, M::ABX, 7, true), op(SRE, M::ABX, 7, false),
+        // 0x60
+        op(RTS, M::IMP, 6, true), op(ADC, M::INX, 6, true), op(KIL, M::IMP, 2, false), op(RRA, M::INX, 8, false),
+        op(NOP, M::ZPG, 3, false), op(ADC, M::ZPG, 3, true), op(ROR, M::ZPG, 5, true), op(RRA, M::ZPG, 5, false),
+        op(PLA, M::IMP, 4, true), op(ADC, M::IMM, 2, true), op(ROR, M::ACC, 2, true), op(ARR, M::IMM, 2, false),
+        op(JMP, M::IND, 5, true), op(ADC, M::ABS, 4, true), op(ROR, M::ABS, 6, true), op(RRA, M::ABS, 6, false),
+        // 0x70
+        op(BVS, M::REL, 2, true), op(ADC, M::INY, 5, true), op(KIL, M::IMP, 2, false), op(RRA, M::INY, 8, false),
+        op(NOP, M::ZPX, 4, false), op(ADC, M::ZPX, 4, true), op(ROR, M::ZPX, 6, true), op(RRA, M::ZPX, 6, false),
+        op(SEI, M::IMP, 2, true), op(ADC, M::ABY, 4, true), op(NOP, M::IMP, 2, false), op(RRA, M::ABY, 7, false),
+        op(NOP, M::ABX, 4, false), op(ADC, M::ABX, 4, true), op(ROR, M::ABX, 7, true), op(RRA, M::ABX, 7, false),
+        // 0x80
+        op(NOP, M::IMM, 2, false), op(STA, M::INX, 6, true), op(NOP, M::IMM, 2, false), op(SAX, M::INX, 6, false),
+        op(STY, M::ZPG, 3, true), op(STA, M::ZPG, 3, true), op(STX, M::ZPG, 3, true), op(SAX, M::ZPG, 3, false),
+        op(DEY, M::IMP, 2, true), op(NOP, M::IMM, 2, false), op(TXA, M::IMP, 2, true), op(XAA, M::IMM, 2, false),
+        op(STY, M::ABS, 4, true), op(STA, M::ABS, 4, true), op(STX, M::ABS, 4, true), op(SAX, M::ABS, 4, false),
+        // 0x90
+        op(BCC, M::REL, 2, true), op(STA, M::INY, 6, true), op(KIL, M::IMP, 2, false), op(AHX, M::INY, 6, false),
+        op(STY, M::ZPX, 4, true), op(STA, M::ZPX, 4, true), op(STX, M::ZPY, 4, true), op(SAX, M::ZPY, 4, false),
+        op(TYA, M::IMP, 2, true), op(STA, M::ABY, 5, true), op(TXS, M::IMP, 2, true), op(TAS, M::ABY, 5, false),
+        op(SHY, M::ABX, 5, false), op(STA, M::ABX, 5, true), op(SHX, M::ABY, 5, false), op(AHX, M::ABY, 5, false),
+        // 0xA0
+        op(LDY, M::IMM, 2, true), op(LDA, M::INX, 6, true), op(LDX, M::IMM, 2, true), op(LAX, M::INX, 6, false),
+        op(LDY, M::ZPG, 3, true), op(LDA, M::ZPG, 3, true), op(LDX, M::ZPG, 3, true), op(LAX, M::ZPG, 3, false),
+        op(TAY, M::IMP, 2, true), op(LDA, M::IMM, 2, true), op(TAX, M::IMP, 2, true), op(LAX, M::IMM, 2, false),
+        op(LDY, M::ABS, 4, true), op(LDA, M::ABS, 4, true), op(LDX, M::ABS, 4, true), op(LAX, M::ABS, 4, false),
+        // 0xB0
+        op(BCS, M::REL, 2, true), op(LDA, M::INY, 5, true), op(KIL, M::IMP, 2, false), op(LAX, M::INY, 5, false),
+        op(LDY, M::ZPX, 4, true), op(LDA, M::ZPX, 4, true), op(LDX, M::ZPY, 4, true), op(LAX, M::ZPY, 4, false),
+        op(CLV, M::IMP, 2, true), op(LDA, M::ABY, 4, true), op(TSX, M::IMP, 2, true), op(LAS, M::ABY, 4, false),
+        op(LDY, M::ABX, 4, true), op(LDA, M::ABX, 4, true), op(LDX, M::ABY, 4, true), op(LAX, M::ABY, 4, false),
+        // 0xC0
+        op(CPY, M::IMM, 2, true), op(CMP, M::INX, 6, true), op(NOP, M::IMM, 2, false), op(DCP, M::INX, 8, false),
+        op(CPY, M::ZPG, 3, true), op(CMP, M::ZPG, 3, true), op(DEC, M::ZPG, 5, true), op(DCP, M::ZPG, 5, false),
+        op(INY, M::IMP, 2, true), op(CMP, M::IMM, 2, true), op(DEX, M::IMP, 2, true), op(AXS, M::IMM, 2, false),
+        op(CPY, M::ABS, 4, true), op(CMP, M::ABS, 4, true), op(DEC, M::ABS, 6, true), op(DCP, M::ABS, 6, false),
+        // 0xD0
+        op(BNE, M::REL, 2, true), op(CMP, M::INY, 5, true), op(KIL, M::IMP, 2, false), op(DCP, M::INY, 8, false),
+        op(NOP, M::ZPX, 4, false), op(CMP, M::ZPX, 4, true), op(DEC, M::ZPX, 6, true), op(DCP, M::ZPX, 6, false),
+        op(CLD, M::IMP, 2, true), op(CMP, M::ABY, 4, true), op(NOP, M::IMP, 2, false), op(DCP, M::ABY, 7, false),
+        op(NOP, M::ABX, 4, false), op(CMP, M::ABX, 4, true), op(DEC, M::ABX, 7, true), op(DCP, M::ABX, 7, false),
+        // 0xE0
+        op(CPX, M::IMM, 2, true), op(SBC, M::INX, 6, true), op(NOP, M::IMM, 2, false), op(ISB, M::INX, 8, false),
+        op(CPX, M::ZPG, 3, true), op(SBC, M::ZPG, 3, true), op(INC, M::ZPG, 5, true), op(ISB, M::ZPG, 5, false),
+        op(INX, M::IMP, 2, true), op(SBC, M::IMM, 2, true), op(NOP, M::IMP, 2, true), op(SBC, M::IMM, 2, false),
+        op(CPX, M::ABS, 4, true), op(SBC, M::ABS, 4, true), op(INC, M::ABS, 6, true), op(ISB, M::ABS, 6, false),
+        // 0xF0
+        op(BEQ, M::REL, 2, true), op(SBC, M::INY, 5, true), op(KIL, M::IMP, 2, false), op(ISB, M::INY, 8, false),
+        op(NOP, M::ZPX, 4, false), op(SBC, M::ZPX, 4, true), op(INC, M::ZPX, 6, true), op(ISB, M::ZPX, 6, false),
+        op(SED, M::IMP, 2, true), op(SBC, M::ABY, 4, true), op(NOP, M::IMP, 2, false), op(ISB, M::ABY, 7, false),
+        op(NOP, M::ABX, 4, false), op(SBC, M::ABX, 4, true), op(INC, M::ABX, 7, true), op(ISB, M::ABX, 7, false),
+    ]
+};
+
+// Formats the instruction at addr, reading memory through peek
+// With index as Some((x, y)) the operand is resolved, e.g.
+// "LDA ($24),Y = 0300 @ 0302 = 5B". Labels replace operand addresses.
+pub fn format_instruction(peek: &dyn Fn(u16) -> u8, addr: u16, index: Option<(u8, u8)>, labels: Option<&dyn LabelLookup>) -> String {
+    let opcode = &OPCODES[peek(addr) as usize];
+    let lo = peek(addr.wrapping_add(1));
+    let word = u16::from_le_bytes([lo, peek(addr.wrapping_add(2))]);
+
+    let label = |target: u16| labels.and_then(|labels| labels.label(target)).map(String::from);
+    let zp = |target: u8| label(target as u16).unwrap_or(format!("${:02X}", target));
+    let abs = |target: u16| label(target).unwrap_or(format!("${:04X}", target));
+    let operand = match opcode.mode {
+        AddrMode::IMP => String::new(),
+        AddrMode::ACC => String::from("A"),
+        AddrMode::IMM => format!("#${:02X}", lo),
+        AddrMode::ZPG => zp(lo),
+        AddrMode::ZPX => format!("{},X", zp(lo)),
+        AddrMode::ZPY => format!("{},Y", zp(lo)),
+        AddrMode::ABS => abs(word),
+        AddrMode::ABX => format!("{},X", abs(word)),
+        AddrMode::ABY => format!("{},Y", abs(word)),
+        AddrMode::IND => format!("({})", abs(word)),
+        AddrMode::INX => format!("({},X)", zp(lo)),
+        AddrMode::INY => format!("({}),Y", zp(lo)),
+        AddrMode::REL => abs(addr.wrapping_add(2).wrapping_add(lo as i8 as u16)),
+    };
+    let mut text = format!("{:?}", opcode.instruction);
+    if !operand.is_empty() {
+        text.push(' ');
+        text += &operand;
+    }
+
+    let Some((x, y)) = index else {
+        return text;
+    };
+    let value = |target: u16| format!(" = {:02X}", peek(target));
+    let zp_word = |pointer: u8| u16::from_le_bytes([peek(pointer as u16), peek(pointer.wrapping_add(1) as u16)]);
+    text += &match opcode.mode {
+        AddrMode::ZPG => value(lo as u16),
+        AddrMode::ZPX => format!(" @ {:02X}{}", lo.wrapping_add(x), value(lo.wrapping_add(x) as u16)),
+        AddrMode::ZPY => format!(" @ {:02X}{}", lo.wrapping_add(y), value(lo.wrapping_add(y) as u16)),
+        AddrMode::ABS if !matches!(opcode.instruction, Instruction::JMP | Instruction::JSR) => value(word),
+        AddrMode::ABX => format!(" @ {:04X}{}", word.wrapping_add(x as u16), value(word.wrapping_add(x as u16))),
+        AddrMode::ABY => format!(" @ {:04X}{}", word.wrapping_add(y as u16), value(word.wrapping_add(y as u16))),
+        AddrMode::IND => {
+            // The high byte comes from the same page when the pointer is at $xxFF
+            let hi_addr = (word & 0xFF00) | (word.wrapping_add(1) & 0x00FF);
+            format!(" = {:04X}", u16::from_le_bytes([peek(word), peek(hi_addr)]))
+        }
+        AddrMode::INX => {
+            let target = zp_word(lo.wrapping_add(x));
+            format!(" @ {:02X} = {:04X}{}", lo.wrapping_add(x), target, value(target))
+        }
+        AddrMode::INY => {
+            let base = zp_word(lo);
+            let target = base.wrapping_add(y as u16);
+            format!(" = {:04X} @ {:04X}{}", base, target, value(target))
+        }
+        _ => String::new(),
+    };
+    text
+}
+
+// Address of the data the instruction at addr accesses, None when it has no
+// memory operand (and for JMP indirect, which only reads its pointer)
+pub fn effective_address(peek: &dyn Fn(u16) -> u8, addr: u16, x: u8, y: u8) -> Option<u16> {
+    let opcode = &OPCODES[peek(addr) as usize];
+    let lo = peek(addr.wrapping_add(1));
+    let word = u16::from_le_bytes([lo, peek(addr.wrapping_add(2))]);
+    let zp_word = |pointer: u8| u16::from_le_bytes([peek(pointer as u16), peek(pointer.wrapping_add(1) as u16)]);
+    match opcode.mode {
+        AddrMode::ZPG => Some(lo as u16),
+        AddrMode::ZPX => Some(lo.wrapping_add(x) as u16),
+        AddrMode::ZPY => Some(lo.wrapping_add(y) as u16),
+        AddrMode::ABS => Some(word),
+        AddrMode::ABX => Some(word.wrapping_add(x as u16)),
+        AddrMode::ABY => Some(word.wrapping_add(y as u16)),
+        AddrMode::INX => Some(zp_word(lo.wrapping_add(x))),
+        AddrMode::INY => Some(zp_word(lo).wrapping_add(y as u16)),
+        _ => None,
+    }
+}
+
+// Linear listing of a PRG bank mapped at base, one instruction per line:
+// "C000  4C F5 C5  JMP $C5F5". Unofficial opcodes are marked with *, and an
+// instruction cut off by the end of the bank is listed as .byte
+pub fn disassemble_bank(data: &[u8], base: u16, labels: Option<&dyn LabelLookup>) -> String {
+    let peek = |addr: u16| data.get(addr.wrapping_sub(base) as usize).copied().unwrap_or(0);
+    let mut listing = String::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let addr = base.wrapping_add(offset as u16);
+        if let Some(label) = labels.and_then(|labels| labels.label(addr)) {
+            listing += &format!("{}:\n", label);
+        }
+
+        let opcode = &OPCODES[data[offset] as usize];
+        let length = opcode.bytes as usize;
+        if offset + length > data.len() {
+            for &byte in &data[offset..] {
+                listing += &format!("{:04X}  {:02X}        .byte ${:02X}\n", base.wrapping_add(offset as u16), byte, byte);
+                offset += 1;
+            }
+            break;
+        }
+        let bytes: Vec<String> = data[offset..offset + length].iter().map(|b| format!("{:02X}", b)).collect();
+        let marker = if opcode.official { ' ' } else { '*' };
+        listing += &format!("{:04X}  {:<8} {}{}\n", addr, bytes.join(" "), marker, format_instruction(&peek, addr, None, labels));
+        offset += length;
+    }
+    listing
+}
+
+pub fn save_bank(path: &Path, data: &[u8], base: u16, labels: Option<&dyn LabelLookup>) -> io::Result<()> {
+    fs::write(path, disassemble_bank(data, base, labels))
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    #[test]
+    fn opcode_table() {
+        use crate::disasm::*;
+        assert_eq!(OPCODES.iter().filter(|op| op.official).count(), 151);
+        let lda = &OPCODES[0xB1];
+        assert_eq!((lda.instruction, lda.mode, lda.bytes, lda.cycles), (Instruction::LDA, AddrMode::INY, 2, 5));
+        assert_eq!(OPCODES[0x6C].mode, AddrMode::IND);
+        assert!(!OPCODES[0xEB].official);
+    }
+
+    #[test]
+    fn format_modes() {
+        use crate::disasm::*;
+        let mut memory = [0u8; 0x10000];
+        let program = [0xB1, 0x24, 0xA1, 0x80, 0xBD, 0x00, 0x03, 0x6C, 0xFF, 0x02, 0x10, 0xFC, 0x20, 0x00, 0x90];
+        memory[0xC000..0xC000 + program.len()].copy_from_slice(&program);
+        memory[0x24..0x26].copy_from_slice(&[0x00, 0x03]);
+        memory[0x82..0x84].copy_from_slice(&[0x02, 0x03]);
+        memory[0x0302] = 0x5B;
+        memory[0x02FF] = 0x7E;
+        memory[0x0200] = 0xDB;
+        let peek = |addr: u16| memory[addr as usize];
+
+        assert_eq!(format_instruction(&peek, 0xC000, None, None), "LDA ($24),Y");
+        assert_eq!(format_instruction(&peek, 0xC000, Some((2, 2)), None), "LDA ($24),Y = 0300 @ 0302 = 5B");
+        assert_eq!(format_instruction(&peek, 0xC002, Some((2, 2)), None), "LDA ($80,X) @ 82 = 0302 = 5B");
+        assert_eq!(format_instruction(&peek, 0xC004, Some((2, 2)), None), "LDA $0300,X @ 0302 = 5B");
+        assert_eq!(format_instruction(&peek, 0xC007, Some((2, 2)), None), "JMP ($02FF) = DB7E");
+        assert_eq!(format_instruction(&peek, 0xC00A, Some((2, 2)), None), "BPL $C008");
+
+        let mut labels = Labels::new();
+        labels.insert(0x9000, String::from("UpdateSound"));
+        assert_eq!(format_instruction(&peek, 0xC00C, Some((2, 2)), Some(&labels)), "JSR UpdateSound");
+
+        let listing = disassemble_bank(&program, 0xC000, None);
+        assert!(listing.starts_with("C000  B1 24     LDA ($24),Y\n"));
+        assert!(listing.ends_with("C00C  20 00 90  JSR $9000\n"));
+        assert_eq!(disassemble_bank(&[0xEA, 0x4C, 0x00], 0x8000, None), "8000  EA        NOP\n8001  4C        .byte $4C\n8002  00        .byte $00\n");
+    }
+}
@@ -0,0 +1,83 @@
+// Cartridge sound chips
+// A cartridge with its own audio hardware installs it as Rom::expansion_audio,
+// which sees every CPU write to cartridge space. The mixer polls every channel
+// once per CPU cycle and adds it linearly on top of the 2A03, scaled so the
+// chips sit at their usual loudness next to the APU.
+// Only the VRC6 is emulated so far, and only NSF files install it.
+
+use crate::vrc6::Vrc6;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExpansionChip {
+    Vrc6,
+    Vrc7,
+    Mmc5,
+    Namco163,
+    Sunsoft5B,
+    Fds,
+}
+
+pub const EXPANSION_CHIPS: [ExpansionChip; 6] = [
+    ExpansionChip::Vrc6,
+    ExpansionChip::Vrc7,
+    ExpansionChip::Mmc5,
+    ExpansionChip::Namco163,
+    ExpansionChip::Sunsoft5B,
+    ExpansionChip::Fds,
+];
+
+impl ExpansionChip {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "vrc6" => Some(ExpansionChip::Vrc6),
+            "vrc7" => Some(ExpansionChip::Vrc7),
+            "mmc5" => Some(ExpansionChip::Mmc5),
+            "n163" | "namco163" => Some(ExpansionChip::Namco163),
+            "5b" | "sunsoft5b" => Some(ExpansionChip::Sunsoft5B),
+            "fds" => Some(ExpansionChip::Fds),
+            _ => None,
+        }
+    }
+
+    // The emulated chip, None for those that aren't yet
+    pub fn create(&self) -> Option<Box<dyn ExpansionAudio>> {
+        match self {
+            ExpansionChip::Vrc6 => Some(Box::new(Vrc6::new())),
+            _ => None,
+        }
+    }
+
+    // Index into per chip settings
+    pub fn index(&self) -> usize {
+        *self as usize
+    }
+
+    // One channel at full volume, relative to one 2A03 pulse at full volume
+    // Approximate levels measured on hardware, as collected on the NESdev wiki
+    pub fn relative_volume(&self) -> f32 {
+        match self {
+            ExpansionChip::Vrc6 => 1.0,
+            ExpansionChip::Vrc7 => 1.5,
+            ExpansionChip::Mmc5 => 1.0,
+            ExpansionChip::Namco163 => 1.6,
+            ExpansionChip::Sunsoft5B => 1.1,
+            ExpansionChip::Fds => 2.4,
+        }
+    }
+}
+
+pub trait ExpansionAudio {
+    fn chip(&self) -> ExpansionChip;
+
+    // CPU write to cartridge space, $4020-$FFFF
+    fn write(&mut self, addr: u16, data: u8);
+
+    // Advances the chip by one CPU cycle
+    fn clock(&mut self);
+
+    fn channel_count(&self) -> usize;
+
+    // Current level of a channel, 0.0 (silent) to 1.0 (full volume), or
+    // above for channels louder than the chip's others
+    fn output(&self, channel: usize) -> f32;
+}
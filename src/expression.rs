@@ -0,0 +1,222 @@
+// Debugger expressions, written the way 6502 assembly reads:
+//   A == #$10          $0324 >= #3 && C == 1          PC != $C000 || X
+// Registers are A X Y SP P PC and flags N V D I Z C (0 or 1). $ADDR reads
+// memory without side effects, #$10 and #16 are numbers. Symbol names read
+// memory like an address, and #name is the address itself. Comparisons can
+// be joined with && and ||, && binding tighter.
+
+use crate::nes::Nes;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operand {
+    A,
+    X,
+    Y,
+    Sp,
+    P,
+    Pc,
+    Flag(u8),
+    Memory(u16),
+    Number(u16),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Compare {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Comparison {
+    left: Operand,
+    right: Option<(Compare, Operand)>,
+}
+
+// Any of the groups being true, where a group is all of its comparisons
+#[derive(Clone, Debug, PartialEq)]
+pub struct Expression {
+    groups: Vec<Vec<Comparison>>,
+}
+
+// Hex with $ or 0x, otherwise decimal
+pub fn parse_number(text: &str) -> Option<u16> {
+    if let Some(hex) = text.strip_prefix('$').or(text.strip_prefix("0x")) {
+        u16::from_str_radix(hex, 16).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_alphanumeric() || matches!(c, '$' | '#' | '_') {
+            let mut token = String::new();
+            while let Some(&c) = chars.peek().filter(|c| c.is_ascii_alphanumeric() || matches!(c, '$' | '#' | '_')) {
+                token.push(c);
+                chars.next();
+            }
+            tokens.push(token);
+        } else {
+            let mut token = String::from(c);
+            chars.next();
+            if let Some(&next) = chars.peek().filter(|&&next| matches!((c, next), ('=' | '!' | '<' | '>', '=') | ('&', '&') | ('|', '|'))) {
+                token.push(next);
+                chars.next();
+            }
+            tokens.push(token);
+        }
+    }
+    tokens
+}
+
+fn parse_operand(token: &str, resolve: &dyn Fn(&str) -> Option<u16>) -> Result<Operand, String> {
+    let operand = match token.to_uppercase().as_str() {
+        "A" => Operand::A,
+        "X" => Operand::X,
+        "Y" => Operand::Y,
+        "SP" | "S" => Operand::Sp,
+        "P" => Operand::P,
+        "PC" => Operand::Pc,
+        "N" => Operand::Flag(0x80),
+        "V" => Operand::Flag(0x40),
+        "D" => Operand::Flag(0x08),
+        "I" => Operand::Flag(0x04),
+        "Z" => Operand::Flag(0x02),
+        "C" => Operand::Flag(0x01),
+        _ => {
+            if let Some(number) = token.strip_prefix('#') {
+                let value = parse_number(number).or_else(|| resolve(number));
+                Operand::Number(value.ok_or(format!("Invalid number {}", token))?)
+            } else if token.starts_with('$') {
+                Operand::Memory(parse_number(token).ok_or(format!("Invalid address {}", token))?)
+            } else if let Some(addr) = resolve(token) {
+                Operand::Memory(addr)
+            } else {
+                return Err(format!("Unknown operand {}", token));
+            }
+        }
+    };
+    Ok(operand)
+}
+
+impl Expression {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        Self::parse_with_symbols(text, &|_| None)
+    }
+
+    // Names are looked up through resolve as the expression is parsed
+    pub fn parse_with_symbols(text: &str, resolve: &dyn Fn(&str) -> Option<u16>) -> Result<Self, String> {
+        let tokens = tokenize(text);
+        let mut groups = vec![Vec::new()];
+        let mut tokens = tokens.iter().map(String::as_str);
+        loop {
+            let left = parse_operand(tokens.next().ok_or(format!("Incomplete expression {}", text))?, resolve)?;
+            let mut comparison = Comparison { left, right: None };
+            let mut joiner = tokens.next();
+            let compare = match joiner {
+                Some("==") => Some(Compare::Equal),
+                Some("!=") => Some(Compare::NotEqual),
+                Some("<") => Some(Compare::Less),
+                Some("<=") => Some(Compare::LessEqual),
+                Some(">") => Some(Compare::Greater),
+                Some(">=") => Some(Compare::GreaterEqual),
+                _ => None,
+            };
+            if let Some(compare) = compare {
+                let right = parse_operand(tokens.next().ok_or(format!("Incomplete expression {}", text))?, resolve)?;
+                comparison.right = Some((compare, right));
+                joiner = tokens.next();
+            }
+            groups.last_mut().unwrap().push(comparison);
+            match joiner {
+                None => break,
+                Some("&&") => {}
+                Some("||") => groups.push(Vec::new()),
+                Some(token) => return Err(format!("Unexpected {} in {}", token, text)),
+            }
+        }
+        Ok(Self { groups })
+    }
+
+    fn operand(operand: Operand, nes: &Nes) -> u16 {
+        let registers = nes.cpu.registers();
+        match operand {
+            Operand::A => registers.a as u16,
+            Operand::X => registers.x as u16,
+            Operand::Y => registers.y as u16,
+            Operand::Sp => registers.sp as u16,
+            Operand::P => registers.p as u16,
+            Operand::Pc => registers.pc,
+            Operand::Flag(mask) => (registers.p & mask != 0) as u16,
+            Operand::Memory(addr) => nes.bus.peek(addr as usize) as u16,
+            Operand::Number(value) => value,
+        }
+    }
+
+    fn comparison(comparison: &Comparison, nes: &Nes) -> u16 {
+        let left = Self::operand(comparison.left, nes);
+        let Some((compare, right)) = comparison.right else {
+            return left;
+        };
+        let right = Self::operand(right, nes);
+        let result = match compare {
+            Compare::Equal => left == right,
+            Compare::NotEqual => left != right,
+            Compare::Less => left < right,
+            Compare::LessEqual => left <= right,
+            Compare::Greater => left > right,
+            Compare::GreaterEqual => left >= right,
+        };
+        result as u16
+    }
+
+    // A lone operand gives its value, anything else 1 or 0
+    pub fn evaluate(&self, nes: &Nes) -> u16 {
+        if let [group] = self.groups.as_slice() {
+            if let [comparison] = group.as_slice() {
+                return Self::comparison(comparison, nes);
+            }
+        }
+        self.is_true(nes) as u16
+    }
+
+    pub fn is_true(&self, nes: &Nes) -> bool {
+        self.groups
+            .iter()
+            .any(|group| group.iter().all(|comparison| Self::comparison(comparison, nes) != 0))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    #[test]
+    fn expressions() {
+        use crate::expression::*;
+        let mut nes = Nes::new();
+        nes.bus.write(0x0324, 0x05);
+        nes.cpu.x = 0x10;
+
+        assert_eq!(Expression::parse("$0324").unwrap().evaluate(&nes), 5);
+        assert!(Expression::parse("X == #$10").unwrap().is_true(&nes));
+        assert!(Expression::parse("X==#16&&$0324<#6").unwrap().is_true(&nes));
+        assert!(!Expression::parse("X != #$10 && C").unwrap().is_true(&nes));
+        assert!(Expression::parse("A == #1 || $0324 >= #$05").unwrap().is_true(&nes));
+        assert!(Expression::parse("A ==").is_err());
+        assert!(Expression::parse("Q == #1").is_err());
+        assert!(Expression::parse("A #1").is_err());
+
+        let resolve = |name: &str| (name == "player_x").then_some(0x0324);
+        assert!(Expression::parse_with_symbols("player_x == #5 && #player_x == #$0324", &resolve).unwrap().is_true(&nes));
+        assert!(Expression::parse_with_symbols("player_y", &resolve).is_err());
+    }
+}
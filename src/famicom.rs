@@ -0,0 +1,300 @@
+// Famicom only peripherals: the Family BASIC keyboard and the Data Recorder
+// that plugs into its cassette jacks
+// $4016 write: bit 0 resets the keyboard to row 0, bit 1 selects the column
+// (the row advances when it goes from 1 to 0), bit 2 enables the keyboard and
+// is also the tape output.
+// $4016 read D1 is the tape input, $4017 read D1-D4 are the four keys of the
+// selected row and column, 0 while pressed.
+
+use std::any::Any;
+
+use crate::input::InputDevice;
+use crate::ppu::Ppu;
+use crate::savestate::State;
+
+// Key names by row and column, in bit order D1-D4
+const KEYBOARD_MATRIX: [[[&str; 4]; 2]; 9] = [
+    [["]", "[", "RETURN", "F8"], ["STOP", "YEN", "RSHIFT", "KANA"]],
+    [[";", ":", "@", "F7"], ["^", "-", "/", "_"]],
+    [["K", "L", "O", "F6"], ["0", "P", ",", "."]],
+    [["J", "U", "I", "F5"], ["8", "9", "N", "M"]],
+    [["H", "G", "Y", "F4"], ["6", "7", "V", "B"]],
+    [["D", "R", "T", "F3"], ["4", "5", "C", "F"]],
+    [["A", "S", "W", "F2"], ["3", "E", "Z", "X"]],
+    [["CTR", "Q", "ESC", "F1"], ["2", "1", "GRPH", "LSHIFT"]],
+    [["LEFT", "RIGHT", "UP", "CLR"], ["INS", "DEL", "SPACE", "DOWN"]],
+];
+
+// Host keys that don't share a name with a Famicom key
+const HOST_KEYS: [(&str, &str); 14] = [
+    ("ENTER", "RETURN"),
+    ("BACKSPACE", "DEL"),
+    ("DELETE", "DEL"),
+    ("INSERT", "INS"),
+    ("HOME", "CLR"),
+    ("ESCAPE", "ESC"),
+    ("CONTROL", "CTR"),
+    ("LCONTROL", "CTR"),
+    ("ALT", "GRPH"),
+    ("LALT", "GRPH"),
+    ("RALT", "KANA"),
+    ("BACKSLASH", "YEN"),
+    ("END", "STOP"),
+    ("`", "KANA"),
+];
+
+// Looks a key up by its Famicom name or a host key name, case insensitive
+pub fn keyboard_key(name: &str) -> Option<(usize, usize, usize)> {
+    let name = name.to_uppercase();
+    let name = HOST_KEYS.iter().find(|(host, _)| *host == name).map_or(name.as_str(), |(_, key)| key);
+    for (row, columns) in KEYBOARD_MATRIX.iter().enumerate() {
+        for (column, keys) in columns.iter().enumerate() {
+            if let Some(bit) = keys.iter().position(|&key| key == name) {
+                return Some((row, column, bit));
+            }
+        }
+    }
+    None
+}
+
+// Tape sample rate used for recording
+const TAPE_SAMPLE_RATE: usize = 44_100;
+const TAPE_LEVEL: i16 = 0x4000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TapeMode {
+    Stopped,
+    Playing,
+    Recording,
+}
+
+// Cassette deck: records the output bit as a square wave and plays a
+// recording back as the input bit, both at the tape's sample rate
+pub struct DataRecorder {
+    pub tape: Vec<i16>,
+    pub sample_rate: usize,
+    mode: TapeMode,
+    position: usize,
+    cpu_clock_hz: f64,
+    phase: f64, // Fraction of a tape sample elapsed
+    output: bool,
+}
+
+impl DataRecorder {
+    pub fn new(cpu_clock_hz: f64) -> Self {
+        Self {
+            tape: Vec::new(),
+            sample_rate: TAPE_SAMPLE_RATE,
+            mode: TapeMode::Stopped,
+            position: 0,
+            cpu_clock_hz,
+            phase: 0.0,
+            output: false,
+        }
+    }
+
+    pub fn mode(&self) -> TapeMode {
+        self.mode
+    }
+
+    // Plays a tape from the start, as loaded from a WAV file
+    pub fn play(&mut self, sample_rate: usize, tape: Vec<i16>) {
+        self.tape = tape;
+        self.sample_rate = sample_rate;
+        self.position = 0;
+        self.mode = TapeMode::Playing;
+    }
+
+    // Starts a blank recording
+    pub fn record(&mut self) {
+        self.tape.clear();
+        self.sample_rate = TAPE_SAMPLE_RATE;
+        self.mode = TapeMode::Recording;
+    }
+
+    pub fn stop(&mut self) {
+        self.mode = TapeMode::Stopped;
+    }
+
+    // The tape and whether it's playing or recording stay as they are
+    fn sync_state(&mut self, state: &mut State) {
+        state.usize(&mut self.position);
+        state.f64(&mut self.phase);
+        state.bool(&mut self.output);
+    }
+
+    // Level of the tape under the head, as seen on $4016 D1
+    fn input(&self) -> bool {
+        self.mode == TapeMode::Playing && self.tape.get(self.position).is_some_and(|&sample| sample > 0)
+    }
+
+    // Advances the tape by one CPU cycle
+    fn clock(&mut self) {
+        if self.mode == TapeMode::Stopped {
+            return;
+        }
+        self.phase += self.sample_rate as f64 / self.cpu_clock_hz;
+        while self.phase >= 1.0 {
+            self.phase -= 1.0;
+            match self.mode {
+                TapeMode::Playing => {
+                    self.position += 1;
+                    if self.position >= self.tape.len() {
+                        self.mode = TapeMode::Stopped;
+                    }
+                }
+                TapeMode::Recording => self.tape.push(if self.output { TAPE_LEVEL } else { -TAPE_LEVEL }),
+                TapeMode::Stopped => {}
+            }
+        }
+    }
+}
+
+// Family BASIC keyboard on the expansion port, with the Data Recorder
+pub struct FamilyKeyboard {
+    keys: [[u8; 2]; 9], // Held keys per row and column, bits 1-4 as read
+    row: usize,
+    column: usize,
+    enabled: bool,
+    pub recorder: DataRecorder,
+}
+
+impl FamilyKeyboard {
+    pub fn new(cpu_clock_hz: f64) -> Self {
+        Self {
+            keys: [[0; 2]; 9],
+            row: 0,
+            column: 0,
+            enabled: false,
+            recorder: DataRecorder::new(cpu_clock_hz),
+        }
+    }
+
+    // Takes a Famicom or host key name, returns false if it isn't mapped
+    pub fn set_key(&mut self, name: &str, pressed: bool) -> bool {
+        let Some((row, column, bit)) = keyboard_key(name) else {
+            return false;
+        };
+        let mask = 0x02 << bit;
+        if pressed {
+            self.keys[row][column] |= mask;
+        } else {
+            self.keys[row][column] &= !mask;
+        }
+        true
+    }
+
+    pub fn release_all(&mut self) {
+        self.keys = [[0; 2]; 9];
+    }
+}
+
+impl InputDevice for FamilyKeyboard {
+    fn write(&mut self, data: u8) {
+        let column = (data >> 1) as usize & 0x01;
+        if data & 0x01 != 0 {
+            self.row = 0;
+        } else if self.column == 1 && column == 0 {
+            self.row += 1;
+        }
+        self.column = column;
+        self.enabled = data & 0x04 != 0;
+        self.recorder.output = data & 0x04 != 0;
+    }
+
+    fn read(&mut self, port: usize, _ppu: &Ppu) -> u8 {
+        if port == 0 {
+            return (self.recorder.input() as u8) << 1;
+        }
+        match self.keys.get(self.row) {
+            _ if !self.enabled => 0,
+            Some(columns) => !columns[self.column] & 0x1E,
+            // Past the last row nothing reads as pressed
+            None => 0x1E,
+        }
+    }
+
+    fn clock(&mut self) {
+        self.recorder.clock();
+    }
+
+    fn sync_state(&mut self, state: &mut State) {
+        state.usize(&mut self.row);
+        state.usize(&mut self.column);
+        state.bool(&mut self.enabled);
+        self.recorder.sync_state(state);
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    #[test]
+    fn keyboard_scan() {
+        use crate::famicom::*;
+        let ppu = Ppu::new();
+        let mut keyboard = FamilyKeyboard::new(1_789_773.0);
+        assert!(keyboard.set_key("enter", true));
+        assert!(keyboard.set_key("x", true));
+        assert!(!keyboard.set_key("F12", true));
+
+        // Family BASIC's scan: reset and enable, then both columns of each row
+        keyboard.write(0x05);
+        let mut matrix = Vec::new();
+        for _ in 0..9 {
+            keyboard.write(0x04);
+            matrix.push(keyboard.read(1, &ppu));
+            keyboard.write(0x06);
+            matrix.push(keyboard.read(1, &ppu));
+        }
+        assert_eq!(matrix[0], 0x1E & !0x08); // RETURN is row 0 column 0 D3
+        assert_eq!(matrix[13], 0x1E & !0x10); // X is row 6 column 1 D4
+        assert_eq!(matrix.iter().filter(|&&bits| bits == 0x1E).count(), 16);
+
+        keyboard.write(0x04);
+        assert_eq!(keyboard.read(1, &ppu), 0x1E);
+        keyboard.write(0x00);
+        assert_eq!(keyboard.read(1, &ppu), 0x00);
+    }
+
+    #[test]
+    fn tape_round_trip() {
+        use crate::famicom::*;
+        let ppu = Ppu::new();
+        let clock_hz = 1_789_773.0;
+        let mut keyboard = FamilyKeyboard::new(clock_hz);
+
+        // Record a 1 kHz square wave on the output bit for 10 ms
+        keyboard.recorder.record();
+        let half_period = (clock_hz / 2000.0) as usize;
+        for cycle in 0..half_period * 20 {
+            if cycle.is_multiple_of(half_period) {
+                let level = (cycle / half_period).is_multiple_of(2);
+                keyboard.write(if level { 0x04 } else { 0x00 });
+            }
+            keyboard.clock();
+        }
+        keyboard.recorder.stop();
+        let tape = keyboard.recorder.tape.clone();
+        assert!((439..=441).contains(&tape.len()));
+
+        // Playback reproduces the wave on $4016 D1
+        keyboard.recorder.play(TAPE_SAMPLE_RATE, tape);
+        let mut edges = 0;
+        let mut last = keyboard.read(0, &ppu);
+        for _ in 0..half_period * 20 {
+            keyboard.clock();
+            let bit = keyboard.read(0, &ppu);
+            assert!(bit == 0x00 || bit == 0x02);
+            edges += (bit != last) as usize;
+            last = bit;
+        }
+        assert_eq!(edges, 19);
+        assert_eq!(keyboard.recorder.mode(), TapeMode::Stopped);
+    }
+}
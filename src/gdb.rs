@@ -0,0 +1,476 @@
+// GDB remote serial protocol stub, for debugging over TCP from gdb or an IDE.
+// Registers are a x y pc sp p, with pc little endian, described to the
+// client through target.xml. Memory reads peek so they don't disturb the PPU
+// or controllers, writes go through the bus. Z0/Z1 set execute breakpoints
+// and Z2/Z3/Z4 watch writes, reads or both, all checked before each
+// instruction by the command-line debugger's core. X writes binary data.
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+
+use crate::cpu::Registers;
+use crate::debugger::{Access, Breakpoint, Debugger, Stop};
+use crate::nes::Nes;
+use crate::watch::Space;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.nebulous.6502">
+    <reg name="a" bitsize="8" type="uint8"/>
+    <reg name="x" bitsize="8" type="uint8"/>
+    <reg name="y" bitsize="8" type="uint8"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="8" type="uint8"/>
+    <reg name="p" bitsize="8" type="uint8"/>
+  </feature>
+</target>
+"#;
+
+// Instructions run between checks for Ctrl-C from the client
+const INTERRUPT_CHECK: usize = 10_000;
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_hex_bytes(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn parse_hex(text: &str) -> Option<u16> {
+    u16::from_str_radix(text, 16).ok()
+}
+
+// ADDR,LEN as used by m, M and Z
+fn parse_range(text: &str) -> Option<(u16, u16)> {
+    let (addr, length) = text.split_once(',')?;
+    Some((parse_hex(addr)?, parse_hex(length)?))
+}
+
+// Packet framing over the socket: $data#checksum, acknowledged with + or -
+struct Connection<'a> {
+    stream: &'a mut TcpStream,
+    buffer: Vec<u8>,
+    last_packet: Vec<u8>,
+    hung_up: bool, // Seen while the target was running
+}
+
+impl Connection<'_> {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        if self.buffer.is_empty() {
+            let mut chunk = [0; 1024];
+            let count = self.stream.read(&mut chunk)?;
+            if count == 0 {
+                return Ok(None);
+            }
+            self.buffer.extend_from_slice(&chunk[..count]);
+        }
+        Ok(Some(self.buffer.remove(0)))
+    }
+
+    // Next packet, None when the client hangs up
+    fn read_packet(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => {}
+                // The client lost our last reply
+                Some(b'-') => {
+                    let packet = self.last_packet.clone();
+                    self.stream.write_all(&packet)?;
+                    continue;
+                }
+                // Acks, and Ctrl-C while already stopped
+                Some(_) => continue,
+            }
+            // The checksum covers the bytes as sent, escapes included
+            let mut raw = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => raw.push(byte),
+                }
+            }
+            let sum = [self.read_byte()?.unwrap_or(0), self.read_byte()?.unwrap_or(0)];
+            let expected = std::str::from_utf8(&sum).ok().and_then(|sum| u8::from_str_radix(sum, 16).ok());
+            if expected != Some(checksum(&raw)) {
+                self.stream.write_all(b"-")?;
+                continue;
+            }
+            self.stream.write_all(b"+")?;
+            // } escapes the next byte in binary data, which is XORed with 0x20
+            let mut data = Vec::with_capacity(raw.len());
+            let mut bytes = raw.into_iter();
+            while let Some(byte) = bytes.next() {
+                data.push(if byte == b'}' { bytes.next().unwrap_or(0) ^ 0x20 } else { byte });
+            }
+            return Ok(Some(data));
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        self.last_packet = format!("${}#{:02x}", data, checksum(data.as_bytes())).into_bytes();
+        self.stream.write_all(&self.last_packet)
+    }
+
+    // Whether Ctrl-C (0x03) arrived or the client hung up, without waiting
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut chunk = [0; 1024];
+        let result = self.stream.read(&mut chunk);
+        self.stream.set_nonblocking(false)?;
+        match result {
+            Ok(0) => {
+                self.hung_up = true;
+                return Ok(true);
+            }
+            Ok(count) => self.buffer.extend_from_slice(&chunk[..count]),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+            Err(err) => return Err(err),
+        }
+        match self.buffer.iter().position(|&byte| byte == 0x03) {
+            Some(index) => {
+                self.buffer.remove(index);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+pub struct GdbStub {
+    pub debugger: Debugger,
+}
+
+impl GdbStub {
+    pub fn new() -> Self {
+        Self { debugger: Debugger::new() }
+    }
+
+    // Serves one client until it detaches, kills the target or hangs up
+    pub fn serve(&mut self, nes: &mut Nes, stream: &mut TcpStream) -> io::Result<()> {
+        stream.set_nodelay(true)?;
+        let mut connection = Connection { stream, buffer: Vec::new(), last_packet: Vec::new(), hung_up: false };
+        // Let the reset sequence finish so the client sees an instruction boundary
+        nes.finish_instruction();
+        while let Some(packet) = connection.read_packet()? {
+            match packet.first() {
+                Some(b'k') => return Ok(()),
+                Some(b'D') => return connection.send("OK"),
+                Some(&kind @ (b'c' | b's')) => {
+                    let addr = std::str::from_utf8(&packet[1..]).unwrap_or_default();
+                    let reply = self.resume(nes, &mut connection, kind == b's', addr)?;
+                    // Nobody is left to tell why the target stopped
+                    if connection.hung_up {
+                        return Ok(());
+                    }
+                    connection.send(&reply)?;
+                }
+                _ => {
+                    // Unsupported packets get an empty reply
+                    let reply = self.handle(nes, &packet).unwrap_or_default();
+                    connection.send(&reply)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Replies to everything but the run control packets, None if unsupported
+    fn handle(&mut self, nes: &mut Nes, packet: &[u8]) -> Option<String> {
+        // X ADDR,LEN:DATA carries raw bytes, every other packet is text
+        if let Some(rest) = packet.strip_prefix(b"X") {
+            let colon = rest.iter().position(|&byte| byte == b':')?;
+            let (addr, length) = parse_range(std::str::from_utf8(&rest[..colon]).ok()?)?;
+            let bytes = &rest[colon + 1..];
+            if bytes.len() != length as usize {
+                return Some(String::from("E00"));
+            }
+            for (i, &byte) in bytes.iter().enumerate() {
+                nes.bus.write(addr.wrapping_add(i as u16) as usize, byte);
+            }
+            return Some(String::from("OK"));
+        }
+        let packet = std::str::from_utf8(packet).ok()?;
+        let registers = nes.cpu.registers();
+        let reply = match packet.as_bytes().first()? {
+            b'?' => String::from("S05"),
+            b'g' => {
+                let [pc_lo, pc_hi] = registers.pc.to_le_bytes();
+                hex_bytes(&[registers.a, registers.x, registers.y, pc_lo, pc_hi, registers.sp, registers.p])
+            }
+            b'G' => {
+                let bytes = parse_hex_bytes(&packet[1..]).filter(|bytes| bytes.len() == 7)?;
+                let pc = u16::from_le_bytes([bytes[3], bytes[4]]);
+                nes.cpu.set_registers(Registers { a: bytes[0], x: bytes[1], y: bytes[2], pc, sp: bytes[5], p: bytes[6] });
+                String::from("OK")
+            }
+            b'p' => match parse_hex(&packet[1..])? {
+                0 => hex_bytes(&[registers.a]),
+                1 => hex_bytes(&[registers.x]),
+                2 => hex_bytes(&[registers.y]),
+                3 => hex_bytes(&registers.pc.to_le_bytes()),
+                4 => hex_bytes(&[registers.sp]),
+                5 => hex_bytes(&[registers.p]),
+                _ => String::from("E00"),
+            },
+            b'P' => {
+                let (index, value) = packet[1..].split_once('=')?;
+                let bytes = parse_hex_bytes(value)?;
+                let mut registers = registers;
+                match (parse_hex(index)?, bytes.as_slice()) {
+                    (0, [value]) => registers.a = *value,
+                    (1, [value]) => registers.x = *value,
+                    (2, [value]) => registers.y = *value,
+                    (3, [lo, hi]) => registers.pc = u16::from_le_bytes([*lo, *hi]),
+                    (4, [value]) => registers.sp = *value,
+                    (5, [value]) => registers.p = *value,
+                    _ => return Some(String::from("E00")),
+                }
+                nes.cpu.set_registers(registers);
+                String::from("OK")
+            }
+            b'm' => {
+                let (addr, length) = parse_range(&packet[1..])?;
+                let bytes: Vec<u8> = (0..length).map(|i| nes.bus.peek(addr.wrapping_add(i) as usize)).collect();
+                hex_bytes(&bytes)
+            }
+            b'M' => {
+                let (range, data) = packet[1..].split_once(':')?;
+                let (addr, length) = parse_range(range)?;
+                let bytes = parse_hex_bytes(data).filter(|bytes| bytes.len() == length as usize)?;
+                for (i, &byte) in bytes.iter().enumerate() {
+                    nes.bus.write(addr.wrapping_add(i as u16) as usize, byte);
+                }
+                String::from("OK")
+            }
+            b'Z' | b'z' => self.breakpoint(packet)?,
+            b'H' => String::from("OK"),
+            b'q' => Self::query(packet)?,
+            _ => return None,
+        };
+        Some(reply)
+    }
+
+    fn query(packet: &str) -> Option<String> {
+        if packet.starts_with("qSupported") {
+            return Some(String::from("PacketSize=1000;qXfer:features:read+;swbreak+;hwbreak+"));
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let (offset, length) = parse_range(range)?;
+            let (offset, length) = (offset as usize, length as usize);
+            let rest = TARGET_XML.get(offset.min(TARGET_XML.len())..)?;
+            return Some(match rest.len() <= length {
+                true => format!("l{}", rest),
+                false => format!("m{}", &rest[..length]),
+            });
+        }
+        let reply = match packet {
+            "qAttached" => "1",
+            "qC" => "QC1",
+            "qfThreadInfo" => "m1",
+            "qsThreadInfo" => "l",
+            _ => return None,
+        };
+        Some(String::from(reply))
+    }
+
+    // Z/z TYPE,ADDR,KIND where KIND is the length for watchpoints
+    fn breakpoint(&mut self, packet: &str) -> Option<String> {
+        let mut fields = packet[1..].split(',');
+        let kind = fields.next()?;
+        let addr = parse_hex(fields.next()?)?;
+        let length = parse_hex(fields.next()?.split(';').next()?)?;
+        let (accesses, end): (&[Access], u16) = match kind {
+            "0" | "1" => (&[Access::Execute], addr),
+            "2" => (&[Access::Write], addr.wrapping_add(length.max(1) - 1)),
+            "3" => (&[Access::Read], addr.wrapping_add(length.max(1) - 1)),
+            "4" => (&[Access::Read, Access::Write], addr.wrapping_add(length.max(1) - 1)),
+            _ => return None,
+        };
+        let breakpoints = &mut self.debugger.breakpoints;
+        for &access in accesses {
+            let existing = breakpoints
+                .iter()
+                .position(|breakpoint| breakpoint.access == access && breakpoint.start == addr && breakpoint.end == end);
+            match (packet.starts_with('Z'), existing) {
+                (true, None) => breakpoints.push(Breakpoint { access, start: addr, end, condition: None }),
+                (false, Some(index)) => {
+                    breakpoints.remove(index);
+                }
+                _ => {}
+            }
+        }
+        Some(String::from("OK"))
+    }
+
+    // c/s [ADDR], returning the stop reply
+    fn resume(&mut self, nes: &mut Nes, connection: &mut Connection, step: bool, addr: &str) -> io::Result<String> {
+        if let Some(addr) = parse_hex(addr) {
+            nes.cpu.jump(addr);
+        }
+        let stop = loop {
+            let mut left = if step { 1 } else { INTERRUPT_CHECK };
+            let stop = self.debugger.run(nes, |_, _| {
+                left -= 1;
+                left == 0
+            });
+            if step || stop != Stop::Done {
+                break stop;
+            }
+            if connection.interrupted()? {
+                return Ok(String::from("S02"));
+            }
+        };
+        let reply = match stop {
+            Stop::Done => String::from("S05"),
+            Stop::Breakpoint(index) => {
+                let breakpoint = &self.debugger.breakpoints[index];
+                match breakpoint.access {
+                    Access::Execute => String::from("T05swbreak:;"),
+                    Access::Read => format!("T05rwatch:{:04x};", breakpoint.start),
+                    Access::Write => format!("T05watch:{:04x};", breakpoint.start),
+                }
+            }
+            Stop::Watchpoint(hit) if hit.space == Space::Cpu && hit.access == Access::Write => format!("T05watch:{:04x};", hit.addr),
+            Stop::Watchpoint(hit) if hit.space == Space::Cpu && hit.access == Access::Read => format!("T05rwatch:{:04x};", hit.addr),
+            Stop::Watchpoint(_) => String::from("S05"),
+            // SIGILL
+            Stop::Jammed => String::from("S04"),
+        };
+        Ok(reply)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    // Sends a packet and returns the reply, checking both acks
+    fn exchange(stream: &mut std::net::TcpStream, data: impl AsRef<[u8]>) -> String {
+        use std::io::{Read, Write};
+        let data = data.as_ref();
+        let sum = data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        stream.write_all(b"$").unwrap();
+        stream.write_all(data).unwrap();
+        write!(stream, "#{:02x}", sum).unwrap();
+        let mut byte = [0];
+        stream.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], b'+');
+        stream.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], b'$');
+        let mut reply = Vec::new();
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            if byte[0] == b'#' {
+                break;
+            }
+            reply.push(byte[0]);
+        }
+        let mut sum = [0; 2];
+        stream.read_exact(&mut sum).unwrap();
+        stream.write_all(b"+").unwrap();
+        String::from_utf8(reply).unwrap()
+    }
+
+    #[test]
+    fn scripted_session() {
+        use crate::gdb::*;
+        use std::net::TcpListener;
+        use std::thread;
+
+        // JSR $C010 / LDA #$10 / STA $0300 / KIL, with INX / RTS at $C010
+        let mut nes = Nes::new();
+        let mut prg_rom = vec![0xEA; 0x4000];
+        prg_rom[..9].copy_from_slice(&[0x20, 0x10, 0xC0, 0xA9, 0x10, 0x8D, 0x00, 0x03, 0x02]);
+        prg_rom[0x10..0x12].copy_from_slice(&[0xE8, 0x60]);
+        prg_rom[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0xC0]);
+        nes.bus.rom.prg_rom = prg_rom;
+        nes.reset();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            assert!(exchange(&mut stream, "qSupported:swbreak+").contains("qXfer:features:read+"));
+            assert!(exchange(&mut stream, "qXfer:features:read:target.xml:0,800").starts_with("l<?xml"));
+            assert_eq!(exchange(&mut stream, "?"), "S05");
+            assert_eq!(exchange(&mut stream, "g"), "00000000c0fd24");
+            assert_eq!(exchange(&mut stream, "mc000,3"), "2010c0");
+            assert_eq!(exchange(&mut stream, "M300,2:1234"), "OK");
+            assert_eq!(exchange(&mut stream, "m300,2"), "1234");
+            assert_eq!(exchange(&mut stream, "P1=05"), "OK");
+
+            assert_eq!(exchange(&mut stream, "s"), "S05");
+            assert_eq!(exchange(&mut stream, "p3"), "10c0");
+            assert_eq!(exchange(&mut stream, "Z0,c003,1"), "OK");
+            assert_eq!(exchange(&mut stream, "c"), "T05swbreak:;");
+            assert_eq!(exchange(&mut stream, "g"), "00060003c0fd24");
+            assert_eq!(exchange(&mut stream, "z0,c003,1"), "OK");
+            assert_eq!(exchange(&mut stream, "Z2,300,1"), "OK");
+            assert_eq!(exchange(&mut stream, "c"), "T05watch:0300;");
+            assert_eq!(exchange(&mut stream, "z2,300,1"), "OK");
+            assert_eq!(exchange(&mut stream, "c"), "S04");
+            assert_eq!(exchange(&mut stream, "m300,1"), "10");
+            assert_eq!(exchange(&mut stream, "vMustReplyEmpty"), "");
+
+            // Binary writes: } escapes itself and #, 0xFF isn't valid UTF-8
+            assert_eq!(exchange(&mut stream, b"X300,3:}]}\x03\xFF"), "OK");
+            assert_eq!(exchange(&mut stream, "m300,3"), "7d23ff");
+            assert_eq!(exchange(&mut stream, b"X300,2:}]"), "E00");
+
+            // Ctrl-C stops JMP $0200 looping forever
+            assert_eq!(exchange(&mut stream, "M200,3:4c0002"), "OK");
+            let mut interrupter = stream.try_clone().unwrap();
+            let interrupt = thread::spawn(move || {
+                thread::sleep(std::time::Duration::from_millis(50));
+                interrupter.write_all(&[0x03]).unwrap();
+            });
+            assert_eq!(exchange(&mut stream, "c200"), "S02");
+            interrupt.join().unwrap();
+            assert_eq!(exchange(&mut stream, "D"), "OK");
+        });
+        let (mut stream, _) = listener.accept().unwrap();
+        GdbStub::new().serve(&mut nes, &mut stream).unwrap();
+        client.join().unwrap();
+        assert_eq!(nes.cpu.x, 6);
+    }
+
+    #[test]
+    fn hang_up_while_running() {
+        use crate::gdb::*;
+        use std::net::TcpListener;
+        use std::thread;
+
+        // JMP $C000 forever
+        let mut nes = Nes::new();
+        let mut prg_rom = vec![0xEA; 0x4000];
+        prg_rom[..3].copy_from_slice(&[0x4C, 0x00, 0xC0]);
+        prg_rom[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0xC0]);
+        nes.bus.rom.prg_rom = prg_rom;
+        nes.reset();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(b"$c#63").unwrap();
+            let mut ack = [0];
+            stream.read_exact(&mut ack).unwrap();
+            assert_eq!(ack[0], b'+');
+        });
+        let (mut stream, _) = listener.accept().unwrap();
+        // Returns once the client is gone instead of running forever
+        GdbStub::new().serve(&mut nes, &mut stream).unwrap();
+        client.join().unwrap();
+    }
+}
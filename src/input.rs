@@ -0,0 +1,460 @@
+// Devices on the two controller ports and the Famicom expansion port
+// A $4016 write reaches every device (bit 0 is the strobe). A read of $4016
+// or $4017 ORs together what the device on that port and the expansion port
+// drive on D0-D4.
+// Protocols as documented on the NESdev wiki (Input devices).
+
+use std::any::Any;
+
+use crate::controller::Controller;
+use crate::famicom::FamilyKeyboard;
+use crate::ppu::{Ppu, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::region::Region;
+use crate::savestate::State;
+use crate::screenshot::pixel_rgb;
+
+pub trait InputDevice {
+    // $4016 write
+    fn write(&mut self, data: u8);
+
+    // Bits driven for a read of $4016 (port 0) or $4017 (port 1)
+    fn read(&mut self, port: usize, ppu: &Ppu) -> u8;
+
+    // Joypad state for a pad on the device, devices without pads ignore it
+    // Slot 0 is the first pad, slot 1 the second on a multitap
+    fn set_buttons(&mut self, _slot: usize, _buttons: u8) {}
+
+    fn set_allow_opposite(&mut self, _allow: bool) {}
+
+    // Called every CPU cycle for devices that keep time, only on the expansion port
+    fn clock(&mut self) {}
+
+    // Savestate fields, the shift registers and latches but not the held input
+    fn sync_state(&mut self, _state: &mut State) {}
+
+    // Lets the frontend reach device specific state
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+pub struct Unplugged;
+
+impl InputDevice for Unplugged {
+    fn write(&mut self, _data: u8) {}
+
+    fn read(&mut self, _port: usize, _ppu: &Ppu) -> u8 {
+        0
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+// NES Four Score, one per port. 8 bits of each pad, then an 8 bit
+// signature, then 1s.
+pub struct FourScore {
+    pub pads: [Controller; 2],
+    signature: u8,
+    reads: usize,
+    strobe: bool,
+}
+
+impl FourScore {
+    pub fn new(port: usize) -> Self {
+        Self {
+            pads: [Controller::new(), Controller::new()],
+            signature: [0x10, 0x20][port],
+            reads: 0,
+            strobe: false,
+        }
+    }
+}
+
+impl InputDevice for FourScore {
+    fn write(&mut self, data: u8) {
+        self.strobe = data & 0x01 != 0;
+        self.pads.iter_mut().for_each(|pad| pad.write(data));
+        if self.strobe {
+            self.reads = 0;
+        }
+    }
+
+    fn read(&mut self, _port: usize, _ppu: &Ppu) -> u8 {
+        let bit = match self.reads {
+            0..=7 => self.pads[0].read(),
+            8..=15 => self.pads[1].read(),
+            16..=23 => (self.signature >> (self.reads - 16)) & 0x01,
+            _ => 1,
+        };
+        if !self.strobe {
+            self.reads = (self.reads + 1).min(24);
+        }
+        bit
+    }
+
+    fn set_buttons(&mut self, slot: usize, buttons: u8) {
+        self.pads[slot].set_buttons(buttons);
+    }
+
+    fn set_allow_opposite(&mut self, allow: bool) {
+        self.pads.iter_mut().for_each(|pad| pad.allow_opposite = allow);
+    }
+
+    fn sync_state(&mut self, state: &mut State) {
+        self.pads.iter_mut().for_each(|pad| pad.sync_state(state));
+        state.usize(&mut self.reads);
+        state.bool(&mut self.strobe);
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+// Famicom Four Players Adapter in its simple mode, on the expansion port.
+// Players 3 and 4 are plain pads read through $4016 and $4017 D1, with no
+// signature after them.
+pub struct FourPlayerAdapter {
+    pub pads: [Controller; 2],
+}
+
+impl FourPlayerAdapter {
+    pub fn new() -> Self {
+        Self { pads: [Controller::new(), Controller::new()] }
+    }
+}
+
+impl InputDevice for FourPlayerAdapter {
+    fn write(&mut self, data: u8) {
+        self.pads.iter_mut().for_each(|pad| pad.write(data));
+    }
+
+    fn read(&mut self, port: usize, _ppu: &Ppu) -> u8 {
+        self.pads[port].read() << 1
+    }
+
+    // Slot 0 is player 3 and slot 1 player 4
+    fn set_buttons(&mut self, slot: usize, buttons: u8) {
+        self.pads[slot].set_buttons(buttons);
+    }
+
+    fn set_allow_opposite(&mut self, allow: bool) {
+        self.pads.iter_mut().for_each(|pad| pad.allow_opposite = allow);
+    }
+
+    fn sync_state(&mut self, state: &mut State) {
+        self.pads.iter_mut().for_each(|pad| pad.sync_state(state));
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+// Lines the photodiode stays lit for after the beam passes
+const ZAPPER_PERSISTENCE: usize = 26;
+// Average RGB level the sensor reacts to
+const ZAPPER_THRESHOLD: u16 = 0x80;
+
+// NES Zapper: D3 low while light is seen, D4 high while the trigger is pulled
+pub struct Zapper {
+    pub x: usize, // Aim point in framebuffer pixels
+    pub y: usize,
+    pub trigger: bool,
+}
+
+impl Zapper {
+    pub fn new() -> Self {
+        Self { x: 0, y: 0, trigger: false }
+    }
+
+    // Bright pixels near the aim point that the beam drew in the last few lines
+    fn senses_light(&self, ppu: &Ppu) -> bool {
+        if self.x >= SCREEN_WIDTH || self.y >= SCREEN_HEIGHT {
+            return false;
+        }
+        let framebuffer = ppu.framebuffer();
+        let top = self.y.saturating_sub(2);
+        let bottom = (self.y + 2).min(SCREEN_HEIGHT - 1);
+        let left = self.x.saturating_sub(2);
+        let right = (self.x + 2).min(SCREEN_WIDTH - 1);
+        (top..=bottom).any(|y| {
+            // Dot 1 draws pixel 0
+            let drawn = |x: usize| ppu.scanline > y || (ppu.scanline == y && ppu.dot > x + 1);
+            let recent = ppu.scanline < y + ZAPPER_PERSISTENCE;
+            (left..=right).any(|x| {
+                let [r, g, b] = pixel_rgb(framebuffer[y * SCREEN_WIDTH + x]);
+                drawn(x) && recent && (r as u16 + g as u16 + b as u16) / 3 >= ZAPPER_THRESHOLD
+            })
+        })
+    }
+}
+
+impl InputDevice for Zapper {
+    fn write(&mut self, _data: u8) {}
+
+    fn read(&mut self, _port: usize, ppu: &Ppu) -> u8 {
+        let dark = !self.senses_light(ppu) as u8;
+        (dark << 3) | ((self.trigger as u8) << 4)
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+// Arkanoid Vaus controller: a knob position shifted out MSB first (inverted)
+// and a fire button. The NES version uses D4 and D3 on one port, the Famicom
+// version sits on the expansion port with the button on $4016 D1 and the
+// position on $4017 D1.
+pub struct ArkanoidPaddle {
+    pub position: u8, // About 98 (left) to 242 (right) on hardware
+    pub button: bool,
+    famicom: bool,
+    shift: u8,
+    strobe: bool,
+}
+
+impl ArkanoidPaddle {
+    pub fn new(famicom: bool) -> Self {
+        Self {
+            position: 170,
+            button: false,
+            famicom,
+            shift: 0,
+            strobe: false,
+        }
+    }
+
+    fn next_bit(&mut self) -> u8 {
+        if self.strobe {
+            self.shift = !self.position;
+        }
+        let bit = self.shift >> 7;
+        self.shift <<= 1;
+        bit
+    }
+}
+
+impl InputDevice for ArkanoidPaddle {
+    fn write(&mut self, data: u8) {
+        self.strobe = data & 0x01 != 0;
+        if self.strobe {
+            self.shift = !self.position;
+        }
+    }
+
+    fn read(&mut self, port: usize, _ppu: &Ppu) -> u8 {
+        let button = self.button as u8;
+        match (self.famicom, port) {
+            (false, _) => (self.next_bit() << 4) | (button << 3),
+            (true, 0) => button << 1,
+            (true, _) => self.next_bit() << 1,
+        }
+    }
+
+    fn sync_state(&mut self, state: &mut State) {
+        state.u8(&mut self.shift);
+        state.bool(&mut self.strobe);
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+// Buttons (numbered 1-12 on side B) in the order they come out on D3 and D4
+const POWER_PAD_D3: [u16; 8] = [2, 1, 5, 9, 6, 10, 11, 7];
+const POWER_PAD_D4: [u16; 4] = [4, 3, 12, 8];
+// Side A is the same mat turned over, so its buttons 1-8 are these side B ones
+const POWER_PAD_SIDE_A: [u16; 8] = [3, 2, 8, 7, 6, 5, 11, 10];
+
+// Power Pad / Family Trainer mat on D3 and D4
+pub struct PowerPad {
+    pub buttons: u16, // Bit n-1 for button n, as numbered on the side in use
+    side_a: bool,
+    shift_d3: u8,
+    shift_d4: u8,
+    strobe: bool,
+}
+
+impl PowerPad {
+    pub fn new(side_a: bool) -> Self {
+        Self { buttons: 0, side_a, shift_d3: 0, shift_d4: 0, strobe: false }
+    }
+
+    // Pressed buttons numbered as on side B, which is how the mat is wired
+    fn side_b_buttons(&self) -> u16 {
+        if !self.side_a {
+            return self.buttons;
+        }
+        POWER_PAD_SIDE_A.iter().enumerate().fold(0, |bits, (i, &b)| bits | ((self.buttons >> i) & 0x01) << (b - 1))
+    }
+
+    fn latch(&mut self) {
+        let buttons = self.side_b_buttons();
+        let pressed = |button: u16| ((buttons >> (button - 1)) & 0x01) as u8;
+        self.shift_d3 = POWER_PAD_D3.iter().enumerate().fold(0, |bits, (i, &b)| bits | pressed(b) << i);
+        // D4 only has four buttons, the rest of its stream reads 1
+        self.shift_d4 = POWER_PAD_D4.iter().enumerate().fold(0xF0, |bits, (i, &b)| bits | pressed(b) << i);
+    }
+}
+
+impl InputDevice for PowerPad {
+    fn write(&mut self, data: u8) {
+        self.strobe = data & 0x01 != 0;
+        if self.strobe {
+            self.latch();
+        }
+    }
+
+    fn read(&mut self, _port: usize, _ppu: &Ppu) -> u8 {
+        if self.strobe {
+            self.latch();
+        }
+        let bits = ((self.shift_d3 & 0x01) << 3) | ((self.shift_d4 & 0x01) << 4);
+        self.shift_d3 = (self.shift_d3 >> 1) | 0x80;
+        self.shift_d4 = (self.shift_d4 >> 1) | 0x80;
+        bits
+    }
+
+    fn sync_state(&mut self, state: &mut State) {
+        state.u8(&mut self.shift_d3);
+        state.u8(&mut self.shift_d4);
+        state.bool(&mut self.strobe);
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+// Port 1, port 2 and expansion port devices for an NES 2.0 default
+// expansion device (header byte 15). Anything not emulated gets standard pads.
+pub fn default_devices(device: u8, region: Region) -> [Box<dyn InputDevice>; 3] {
+    let pad = || -> Box<dyn InputDevice> { Box::new(Controller::new()) };
+    match device {
+        0x02 => [Box::new(FourScore::new(0)), Box::new(FourScore::new(1)), Box::new(Unplugged)],
+        0x03 => [pad(), pad(), Box::new(FourPlayerAdapter::new())],
+        0x08 => [pad(), Box::new(Zapper::new()), Box::new(Unplugged)],
+        0x09 => [Box::new(Zapper::new()), Box::new(Zapper::new()), Box::new(Unplugged)],
+        0x0B => [pad(), Box::new(PowerPad::new(true)), Box::new(Unplugged)],
+        0x0C => [pad(), Box::new(PowerPad::new(false)), Box::new(Unplugged)],
+        0x0F => [pad(), Box::new(ArkanoidPaddle::new(false)), Box::new(Unplugged)],
+        0x10 => [pad(), pad(), Box::new(ArkanoidPaddle::new(true))],
+        0x23 => [pad(), pad(), Box::new(FamilyKeyboard::new(region.cpu_clock_hz()))],
+        _ => [pad(), pad(), Box::new(Unplugged)],
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    fn strobe(bus: &mut crate::bus::Bus) {
+        bus.write(0x4016, 1);
+        bus.write(0x4016, 0);
+    }
+
+    #[test]
+    fn four_score_signature() {
+        use crate::bus::Bus;
+        use crate::input::*;
+        let mut bus = Bus::new();
+        let [port1, port2, expansion] = default_devices(0x02, Region::Ntsc);
+        bus.ports = [port1, port2];
+        bus.expansion_port = expansion;
+        bus.ports[0].set_buttons(0, 0x01); // Player 1 A
+        bus.ports[0].set_buttons(1, 0x80); // Player 3 Right
+        bus.ports[1].set_buttons(1, 0x02); // Player 4 B
+
+        strobe(&mut bus);
+        let port1: Vec<u8> = (0..26).map(|_| bus.read(0x4016) & 0x01).collect();
+        let port2: Vec<u8> = (0..24).map(|_| bus.read(0x4017) & 0x01).collect();
+        assert_eq!(&port1[0..8], &[1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&port1[8..16], &[0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(&port1[16..26], &[0, 0, 0, 0, 1, 0, 0, 0, 1, 1]);
+        assert_eq!(&port2[8..16], &[0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&port2[16..24], &[0, 0, 0, 0, 0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn zapper_light() {
+        use crate::input::*;
+        let mut ppu = Ppu::new();
+        let mut zapper = Zapper::new();
+        zapper.x = 100;
+        zapper.y = 50;
+        zapper.trigger = true;
+
+        // The framebuffer starts at colour $00, too dark to register
+        ppu.scanline = 60;
+        assert_eq!(zapper.read(1, &ppu), 0x18);
+
+        ppu.framebuffer_mut()[50 * SCREEN_WIDTH + 101] = 0x30;
+        assert_eq!(zapper.read(1, &ppu), 0x10);
+
+        // The beam hasn't reached the target yet, or passed it too long ago
+        ppu.scanline = 49;
+        assert_eq!(zapper.read(1, &ppu) & 0x08, 0x08);
+        ppu.scanline = 50 + ZAPPER_PERSISTENCE;
+        assert_eq!(zapper.read(1, &ppu) & 0x08, 0x08);
+    }
+
+    #[test]
+    fn paddle_and_power_pad() {
+        use crate::bus::Bus;
+        use crate::input::*;
+        let mut bus = Bus::new();
+        let mut paddle = ArkanoidPaddle::new(false);
+        paddle.position = 0b1010_0000;
+        paddle.button = true;
+        bus.ports[1] = Box::new(paddle);
+
+        strobe(&mut bus);
+        let bits: Vec<u8> = (0..4).map(|_| bus.read(0x4017) & 0x18).collect();
+        assert_eq!(bits, vec![0x08, 0x18, 0x08, 0x18]);
+
+        let mut pad = PowerPad::new(false);
+        pad.buttons = 1 << (1 - 1) | 1 << (12 - 1);
+        bus.ports[1] = Box::new(pad);
+        strobe(&mut bus);
+        let bits: Vec<u8> = (0..8).map(|_| bus.read(0x4017) & 0x18).collect();
+        assert_eq!(bits, vec![0x00, 0x08, 0x10, 0x00, 0x10, 0x10, 0x10, 0x10]);
+
+        // Side A buttons 1 and 3 are side B buttons 3 and 8, both on D4
+        let [_, side_a, _] = default_devices(0x0B, Region::Ntsc);
+        bus.ports[1] = side_a;
+        bus.ports[1].as_any_mut().downcast_mut::<PowerPad>().unwrap().buttons = 1 << (1 - 1) | 1 << (3 - 1);
+        strobe(&mut bus);
+        let bits: Vec<u8> = (0..4).map(|_| bus.read(0x4017) & 0x18).collect();
+        assert_eq!(bits, vec![0x00, 0x10, 0x00, 0x10]);
+    }
+
+    #[test]
+    fn famicom_expansion_devices() {
+        use crate::bus::Bus;
+        use crate::input::*;
+        let mut bus = Bus::new();
+
+        // Players 3 and 4 on D1 as plain pads, 1s after the eighth read
+        bus.expansion_port = default_devices(0x03, Region::Ntsc).into_iter().nth(2).unwrap();
+        bus.expansion_port.set_buttons(0, 0x01); // Player 3 A
+        bus.expansion_port.set_buttons(1, 0x80); // Player 4 Right
+        strobe(&mut bus);
+        let port1: Vec<u8> = (0..10).map(|_| (bus.read(0x4016) >> 1) & 0x01).collect();
+        let port2: Vec<u8> = (0..10).map(|_| (bus.read(0x4017) >> 1) & 0x01).collect();
+        assert_eq!(port1, vec![1, 0, 0, 0, 0, 0, 0, 0, 1, 1]);
+        assert_eq!(port2, vec![0, 0, 0, 0, 0, 0, 0, 1, 1, 1]);
+
+        // Famicom Arkanoid: the button on $4016 D1, the position on $4017 D1
+        bus.expansion_port = default_devices(0x10, Region::Ntsc).into_iter().nth(2).unwrap();
+        let paddle = bus.expansion_port.as_any_mut().downcast_mut::<ArkanoidPaddle>().unwrap();
+        paddle.position = 0b0101_1111;
+        paddle.button = true;
+        strobe(&mut bus);
+        assert_eq!(bus.read(0x4016) & 0x02, 0x02);
+        let bits: Vec<u8> = (0..4).map(|_| (bus.read(0x4017) >> 1) & 0x01).collect();
+        assert_eq!(bits, vec![1, 0, 1, 0]);
+    }
+}
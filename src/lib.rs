@@ -0,0 +1,40 @@
+// Types follow the hardware with a plain new() rather than Default
+#![allow(clippy::new_without_default)]
+
+pub mod apu;
+pub mod audio;
+pub mod cpu;
+pub mod bus;
+pub mod cdl;
+pub mod checksum;
+pub mod controller;
+pub mod dap;
+pub mod dbginfo;
+pub mod debugger;
+pub mod disasm;
+pub mod expansion;
+pub mod expression;
+pub mod famicom;
+pub mod gdb;
+pub mod input;
+pub mod json;
+pub mod movie;
+pub mod nes;
+pub mod nsf;
+pub mod ntsc;
+pub mod options;
+pub mod ppu;
+pub mod ppuview;
+pub mod profiler;
+pub mod ram;
+pub mod region;
+pub mod rom;
+pub mod savestate;
+pub mod screenshot;
+pub mod symbols;
+pub mod testrom;
+pub mod trace;
+pub mod vrc6;
+pub mod watch;
+pub mod wav;
+pub mod zip;
@@ -1,82 +1,480 @@
-// Route ROM buffer to RAM mapping? (Is ROM buffer directly accessible...
-//    or only accessible through RAM mapping?)
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufWriter, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process;
 
-mod cpu;
-mod bus;
-mod ram;
-mod rom;
-
-use cpu::Cpu;
-use bus::Bus;
-use ram::Ram;
-use rom::Rom;
+use nebulous::cdl::CodeDataLog;
+use nebulous::dap::DebugAdapter;
+use nebulous::dbginfo::DebugInfo;
+use nebulous::debugger::Debugger;
+use nebulous::disasm;
+use nebulous::expansion::ExpansionChip;
+use nebulous::famicom::FamilyKeyboard;
+use nebulous::gdb::GdbStub;
+use nebulous::input;
+use nebulous::movie::Movie;
+use nebulous::nes::Nes;
+use nebulous::nsf::{Nsf, NsfPlayer};
+use nebulous::ntsc::{NtscFilter, NTSC_WIDTH};
+use nebulous::options::Options;
+use nebulous::ppu::SCREEN_HEIGHT;
+use nebulous::ppuview;
+use nebulous::profiler::Profiler;
+use nebulous::region::{self, Region};
+use nebulous::savestate;
+use nebulous::screenshot;
+use nebulous::trace;
+use nebulous::watch::Watcher;
+use nebulous::wav;
 
 fn main() {
-    let mut cpu = Cpu::new();
-    let bus = Bus::new();
-    let mut ram = Ram::new();
-    let mut rom = Rom::new();
-    rom.load_rom();
-
-    cpu.x = 0x01;
-    cpu.y = 0x02;
-
-    // Load test bytes to RAM
-    // Will change main loop testing method after full LDA implementation
-    bus.write(&mut ram, 0x0000, 0xA9); // LDA IMM
-    bus.write(&mut ram, 0x0001, 0x2B); // Random data
-    bus.write(&mut ram, 0x0002, 0xA5); // LDA ZPG
-    bus.write(&mut ram, 0x0003, 0x24); // ZPG addr
-    bus.write(&mut ram, 0x0024, 0x57); // Random data
-    bus.write(&mut ram, 0x0004, 0xB5); // LDA ZPX
-    bus.write(&mut ram, 0x0005, 0x1F); // ZPX base addr
-    bus.write(&mut ram, 0x0020, 0x34); // Random data
-    bus.write(&mut ram, 0x0006, 0xAD); // LDA ABS
-    bus.write(&mut ram, 0x0007, 0x27); // ABS addr lo
-    bus.write(&mut ram, 0x0008, 0x00); // ABS addr hi
-    bus.write(&mut ram, 0x0027, 0x17); // Random data
-    bus.write(&mut ram, 0x0009, 0xBD); // LDA ABX
-    bus.write(&mut ram, 0x000A, 0x2C); // ABX base addr lo
-    bus.write(&mut ram, 0x000B, 0x00); // ABX base addr hi
-    bus.write(&mut ram, 0x002D, 0x27); // Random data
-    bus.write(&mut ram, 0x000C, 0xB9); // LDA ABY
-    bus.write(&mut ram, 0x000D, 0x2A); // ABY base addr lo
-    bus.write(&mut ram, 0x000E, 0x00); // ABY base addr hi
-    bus.write(&mut ram, 0x002C, 0x14); // Random data
-    bus.write(&mut ram, 0x000F, 0xBD); // LDA ABX
-    bus.write(&mut ram, 0x0010, 0xFF); // ABX base addr lo
-    bus.write(&mut ram, 0x0011, 0x01); // ABX base addr hi
-    bus.write(&mut ram, 0x0200, 0xAC); // Random data
-    bus.write(&mut ram, 0x0012, 0xB9); // LDA ABY
-    bus.write(&mut ram, 0x0013, 0xFF); // ABY base addr lo
-    bus.write(&mut ram, 0x0014, 0x01); // ABY base addr hi
-    bus.write(&mut ram, 0x0201, 0xBF); // Random data
-    bus.write(&mut ram, 0x0015, 0xA1); // LDA INX
-    bus.write(&mut ram, 0x0016, 0xA4); // INX zpg addr lo
-    bus.write(&mut ram, 0x00A5, 0x04); // INX addr lo
-    bus.write(&mut ram, 0x00A6, 0x03); // INX addr hi
-    bus.write(&mut ram, 0x0304, 0x38); // Random data
-    bus.write(&mut ram, 0x0017, 0xB1); // LDA INY
-    bus.write(&mut ram, 0x0018, 0xC7); // INY zpg addr lo
-    bus.write(&mut ram, 0x00C7, 0xFF); // INY addr lo
-    bus.write(&mut ram, 0x00C8, 0x03); // INY addr hi
-    bus.write(&mut ram, 0x0401, 0x29); // Random data
-    bus.write(&mut ram, 0x0019, 0xA2); // LDX IMM
-    bus.write(&mut ram, 0x001A, 0x03); // Random data
-
-    let mut loop_limit = 11;
-
-    cpu.debug_print();
-
-    while loop_limit > 0 {
-        if cpu.cycles == 0 {
-            println!("Cycles: {:?}, Executing", cpu.cycles);
-            println!("--------------------");
-            cpu.clock(&bus, &ram);
-            cpu.debug_print();
-            loop_limit -= 1;
-        }
-        println!("Cycles: {:?}", cpu.cycles);
-        cpu.cycles -= 1;
-    }
-}
\ No newline at end of file
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = Options::parse(&args).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    });
+
+    if options.is_nsf() {
+        play_nsf(&options);
+        return;
+    }
+
+    let mut nes = Nes::new();
+    if let Err(err) = nes.bus.rom.load_rom(&options.rom_path) {
+        eprintln!("Failed to load {}: {}", options.rom_path, err);
+        process::exit(1);
+    }
+    for path in &options.symbols {
+        if let Err(err) = nes.bus.symbols.load(Path::new(path)) {
+            eprintln!("Failed to load {}: {}", path, err);
+            process::exit(1);
+        }
+    }
+    if let Some(path) = &options.disassemble {
+        // PRG ROM ends at $FFFF, so a 16 KB bank is listed at $C000
+        let prg_rom = &nes.bus.rom.prg_rom;
+        let base = (0x10000 - prg_rom.len().min(0x8000)) as u16;
+        let labels = nes.bus.symbols.labels(&nes.bus.rom);
+        if let Err(err) = disasm::save_bank(Path::new(path), prg_rom, base, Some(&labels)) {
+            eprintln!("Failed to write {}: {}", path, err);
+            process::exit(1);
+        }
+        return;
+    }
+
+    let movie = options.play_movie.as_ref().map(|path| load_movie(path, &nes));
+    // A movie only replays in sync with the settings it was recorded with
+    let region = movie.as_ref().map_or_else(|| select_region(&options, &nes), |movie| movie.region);
+    nes.set_region(region);
+    nes.set_sample_rate(options.sample_rate);
+    for &channel in &options.muted {
+        nes.mixer.set_muted(channel, true);
+    }
+    for &channel in &options.soloed {
+        nes.mixer.set_solo(channel, true);
+    }
+    for &(chip, volume) in &options.chip_volumes {
+        nes.mixer.set_chip_volume(chip, volume);
+    }
+    if let Some(device) = nes.bus.rom.input_device {
+        let [port1, port2, expansion] = input::default_devices(device, region);
+        nes.bus.ports = [port1, port2];
+        nes.bus.expansion_port = expansion;
+    }
+    let four_score = movie.as_ref().is_some_and(|movie| movie.four_score);
+    if four_score {
+        let [port1, port2, _] = input::default_devices(0x02, region);
+        nes.bus.ports = [port1, port2];
+    }
+    for device in nes.bus.ports.iter_mut() {
+        device.set_allow_opposite(options.allow_opposite);
+    }
+    nes.bus.expansion_port.set_allow_opposite(options.allow_opposite);
+    if options.tape_in.is_some() || options.tape_out.is_some() {
+        insert_tape(&options, &mut nes);
+    }
+    nes.reset();
+    // Played movies start from their own savestate, if they have one
+    let start_state = match &movie {
+        Some(movie) => movie.savestate.clone(),
+        None => options.load_state.as_ref().map(|path| {
+            fs::read(path).unwrap_or_else(|err| {
+                eprintln!("Failed to load {}: {}", path, err);
+                process::exit(1);
+            })
+        }),
+    };
+    if let Some(data) = &start_state {
+        if let Err(err) = savestate::load(&mut nes, data) {
+            eprintln!("Can't start from the savestate: {}", err);
+            process::exit(1);
+        }
+    }
+    if let Some(addr) = options.start_pc {
+        nes.cpu.jump(addr);
+    }
+    if let Some(path) = &options.trace {
+        let file = File::create(path).unwrap_or_else(|err| {
+            eprintln!("Failed to create {}: {}", path, err);
+            process::exit(1);
+        });
+        nes.cpu.tracer = Some(Box::new(BufWriter::new(file)));
+    }
+    if !options.watchpoints.is_empty() {
+        let mut watcher = Watcher::new();
+        watcher.watchpoints = options.watchpoints.clone();
+        nes.bus.watch = Some(Box::new(watcher));
+    }
+    if let Some(path) = &options.cdl {
+        let cdl = if Path::new(path).exists() {
+            CodeDataLog::load(Path::new(path), &nes.bus.rom).unwrap_or_else(|err| {
+                eprintln!("Failed to load {}: {}", path, err);
+                process::exit(1);
+            })
+        } else {
+            CodeDataLog::new(&nes.bus.rom)
+        };
+        nes.bus.cdl = Some(Box::new(cdl));
+    }
+    if options.debug || options.gdb.is_some() || options.dap || options.dap_port.is_some() {
+        if let Some(port) = options.gdb {
+            serve_gdb(&mut nes, port);
+        } else if options.dap || options.dap_port.is_some() {
+            serve_dap(&options, &mut nes);
+        } else {
+            debug(&mut nes);
+        }
+        if let Some(mut tracer) = nes.cpu.tracer.take() {
+            tracer.flush().ok();
+        }
+        save_cdl(&options, &nes);
+        return;
+    }
+
+    if !options.dump_frames.is_empty() || options.ppu_views.is_some() {
+        if let Err(err) = fs::create_dir_all(&options.out_dir) {
+            eprintln!("Failed to create {}: {}", options.out_dir, err);
+            process::exit(1);
+        }
+    }
+
+    let ntsc = options.ntsc_filter.then(|| NtscFilter::new(NTSC_WIDTH));
+    let mut profiler = (options.profile.is_some() || options.flamegraph.is_some()).then(Profiler::new);
+    let mut audio = Vec::new();
+    nes.audio_enabled = options.record_audio.is_some();
+    let mut recording = options.record_movie.as_ref().map(|path| {
+        let file_name = Path::new(&options.rom_path).file_name().unwrap_or_default().to_string_lossy();
+        let mut recording = Movie::new(&nes.bus.rom, &file_name, region, four_score);
+        if let Some(movie) = &movie {
+            recording.rerecord_count = movie.rerecord_count;
+            recording.comments = movie.comments.clone();
+        }
+        recording.savestate = start_state.clone();
+        (path, recording)
+    });
+
+    // Headless: nothing is displayed, requested frames are written to disk
+    // A movie without --frames runs to its end
+    let last_frame = match (options.last_frame(), &movie) {
+        (None, Some(movie)) => Some(movie.frames.len()),
+        (last, _) => last,
+    };
+    while last_frame.is_none_or(|last| nes.frame_count() < last) {
+        // Past the end of a movie the controllers are released
+        let input = movie.as_ref().and_then(|movie| movie.frames.get(nes.frame_count())).cloned().unwrap_or_default();
+        input.apply(&mut nes);
+        if let Some((_, recording)) = &mut recording {
+            recording.frames.push(input);
+        }
+        // Mid-frame views stop the PPU on the way through the frame
+        if let Some((frame, Some((scanline, dot)))) = options.ppu_views {
+            if nes.frame_count() + 1 == frame {
+                nes.run_to(scanline, dot);
+                save_ppu_views(&options, &nes);
+            }
+        }
+        match &mut profiler {
+            Some(profiler) => profiler.run_frame(&mut nes),
+            None => nes.run_frame(),
+        }
+        if let Some(hit) = nes.bus.watch.as_ref().and_then(|watch| watch.halted) {
+            println!("Watchpoint {} hit on frame {}: {}", hit.watchpoint + 1, nes.frame_count(), hit.report(&nes.bus));
+            break;
+        }
+        let samples = nes.audio_samples();
+        if options.record_audio.is_some() {
+            audio.extend_from_slice(&samples);
+        }
+        let frame = nes.frame_count();
+        if options.ppu_views == Some((frame, None)) {
+            save_ppu_views(&options, &nes);
+        }
+        if options.dump_frames.contains(&frame) {
+            let file_name = format!("frame_{:05}.{}", frame, options.format);
+            let path = Path::new(&options.out_dir).join(file_name);
+            let result = match &ntsc {
+                Some(filter) => {
+                    let rgb = filter.apply(nes.framebuffer(), nes.bus.ppu.frame_phase);
+                    screenshot::save_rgb(&path, filter.width(), SCREEN_HEIGHT, &rgb)
+                }
+                None => screenshot::save(&path, nes.framebuffer()),
+            };
+            if let Err(err) = result {
+                eprintln!("Failed to write {}: {}", path.display(), err);
+                process::exit(1);
+            }
+        }
+    }
+
+    if let Some(path) = &options.record_audio {
+        if let Err(err) = wav::save_wav(Path::new(path), nes.sample_rate(), &audio) {
+            eprintln!("Failed to write {}: {}", path, err);
+            process::exit(1);
+        }
+    }
+    if let Some((path, recording)) = &recording {
+        if let Err(err) = recording.save(Path::new(path)) {
+            eprintln!("Failed to write {}: {}", path, err);
+            process::exit(1);
+        }
+    }
+    if let Some(path) = &options.save_state {
+        if let Err(err) = fs::write(path, savestate::save(&mut nes)) {
+            eprintln!("Failed to write {}: {}", path, err);
+            process::exit(1);
+        }
+    }
+    if let Some(mut tracer) = nes.cpu.tracer.take() {
+        if let Err(err) = tracer.flush() {
+            eprintln!("Failed to write {}: {}", options.trace.as_ref().unwrap(), err);
+            process::exit(1);
+        }
+    }
+    save_cdl(&options, &nes);
+    if let Some(profiler) = &profiler {
+        let outputs = [(&options.profile, profiler.report(&nes)), (&options.flamegraph, profiler.collapsed(&nes))];
+        for (path, text) in outputs {
+            if let Some(path) = path {
+                if let Err(err) = fs::write(path, text) {
+                    eprintln!("Failed to write {}: {}", path, err);
+                    process::exit(1);
+                }
+            }
+        }
+    }
+    if let Some(path) = &options.compare_log {
+        compare_log(options.trace.as_ref().unwrap(), path);
+    }
+    if let Some(path) = &options.tape_out {
+        let keyboard = nes.bus.expansion_port.as_any_mut().downcast_mut::<FamilyKeyboard>().unwrap();
+        let recorder = &keyboard.recorder;
+        if let Err(err) = wav::save_wav(Path::new(path), recorder.sample_rate, &recorder.tape) {
+            eprintln!("Failed to write {}: {}", path, err);
+            process::exit(1);
+        }
+    }
+}
+
+fn save_cdl(options: &Options, nes: &Nes) {
+    let (Some(path), Some(cdl)) = (&options.cdl, &nes.bus.cdl) else {
+        return;
+    };
+    if let Err(err) = cdl.save(Path::new(path)) {
+        eprintln!("Failed to write {}: {}", path, err);
+        process::exit(1);
+    }
+    println!("Code/data log: {}", cdl.summary());
+}
+
+// Writes the --ppu-views pictures for the PPU as it is now
+fn save_ppu_views(options: &Options, nes: &Nes) {
+    let ppu = &nes.bus.ppu;
+    let frame = options.ppu_views.map_or(0, |(frame, _)| frame);
+    let prefix = format!("ppu_{:05}_{:03}_{:03}", frame, ppu.scanline, ppu.dot);
+    let result = ppuview::save_all(Path::new(&options.out_dir), &prefix, &options.format, ppu, &nes.bus.rom, options.view_palette);
+    if let Err(err) = result {
+        eprintln!("Failed to write PPU views to {}: {}", options.out_dir, err);
+        process::exit(1);
+    }
+    println!("PPU views written to {} as {}_*", options.out_dir, prefix);
+}
+
+// Loads --play-movie and makes sure it was recorded on this ROM
+fn load_movie(path: &str, nes: &Nes) -> Movie {
+    let movie = Movie::load(Path::new(path)).unwrap_or_else(|err| {
+        eprintln!("Failed to load {}: {}", path, err);
+        process::exit(1);
+    });
+    if let Err(err) = movie.check_rom(&nes.bus.rom) {
+        eprintln!("Can't play {}: {}", path, err);
+        process::exit(1);
+    }
+    movie
+}
+
+// Reads debugger commands from stdin until quit or end of input
+fn debug(nes: &mut Nes) {
+    let mut debugger = Debugger::new();
+    // Let the reset sequence finish so the first stop shows real cycle counts
+    nes.finish_instruction();
+    println!("{}", debugger.status(nes));
+    let mut lines = io::stdin().lock().lines();
+    loop {
+        print!("> ");
+        io::stdout().flush().ok();
+        let Some(Ok(line)) = lines.next() else {
+            break;
+        };
+        match debugger.execute(nes, &line) {
+            Some(output) if output.is_empty() => {}
+            Some(output) => println!("{}", output),
+            None => break,
+        }
+    }
+}
+
+// Waits for one GDB client on localhost and serves it until it detaches
+fn serve_gdb(nes: &mut Nes, port: u16) {
+    let listener = TcpListener::bind(("127.0.0.1", port)).unwrap_or_else(|err| {
+        eprintln!("Failed to listen on port {}: {}", port, err);
+        process::exit(1);
+    });
+    eprintln!("Waiting for GDB on 127.0.0.1:{}", port);
+    let result = listener.accept().and_then(|(mut stream, _)| GdbStub::new().serve(nes, &mut stream));
+    if let Err(err) = result {
+        eprintln!("GDB connection failed: {}", err);
+        process::exit(1);
+    }
+}
+
+// Serves one debug adapter client on stdio or localhost. Debug info comes
+// from --debug-info, or the ROM's name with .dbg when that exists.
+fn serve_dap(options: &Options, nes: &mut Nes) {
+    let default_path = Path::new(&options.rom_path).with_extension("dbg");
+    let info_path = options.debug_info.as_ref().map(PathBuf::from).or(default_path.exists().then_some(default_path));
+    let info = info_path.as_ref().map(|path| {
+        DebugInfo::load(path).unwrap_or_else(|err| {
+            eprintln!("Failed to load {}: {}", path.display(), err);
+            process::exit(1);
+        })
+    });
+    let source_root = info_path.as_ref().and_then(|path| path.parent()).unwrap_or(Path::new(""));
+    let mut adapter = DebugAdapter::new(info, source_root);
+
+    let result = match options.dap_port {
+        Some(port) => {
+            let listener = TcpListener::bind(("127.0.0.1", port)).unwrap_or_else(|err| {
+                eprintln!("Failed to listen on port {}: {}", port, err);
+                process::exit(1);
+            });
+            eprintln!("Waiting for a debug adapter client on 127.0.0.1:{}", port);
+            listener.accept().and_then(|(stream, _)| {
+                let mut output = stream.try_clone()?;
+                adapter.serve(nes, stream, &mut output)
+            })
+        }
+        None => adapter.serve(nes, io::stdin(), &mut io::stdout()),
+    };
+    if let Err(err) = result {
+        eprintln!("Debug adapter connection failed: {}", err);
+        process::exit(1);
+    }
+}
+
+// Checks the trace against a reference log, exiting with an error at the
+// first line that differs
+fn compare_log(trace_path: &str, reference_path: &str) {
+    let read = |path: &str| {
+        fs::read_to_string(path).unwrap_or_else(|err| {
+            eprintln!("Failed to read {}: {}", path, err);
+            process::exit(1);
+        })
+    };
+    match trace::first_divergence(&read(trace_path), &read(reference_path)) {
+        Some(divergence) => {
+            eprintln!("{}", divergence.report());
+            process::exit(1);
+        }
+        None => println!("Trace matches {}", reference_path),
+    }
+}
+
+// Starts the Data Recorder playing --tape-in or recording for --tape-out
+fn insert_tape(options: &Options, nes: &mut Nes) {
+    let Some(keyboard) = nes.bus.expansion_port.as_any_mut().downcast_mut::<FamilyKeyboard>() else {
+        eprintln!("The Data Recorder needs a ROM that uses the Family BASIC keyboard");
+        process::exit(1);
+    };
+    if let Some(path) = &options.tape_in {
+        let (sample_rate, tape) = wav::load_wav(Path::new(path)).unwrap_or_else(|err| {
+            eprintln!("Failed to load {}: {}", path, err);
+            process::exit(1);
+        });
+        keyboard.recorder.play(sample_rate, tape);
+    } else {
+        keyboard.recorder.record();
+    }
+}
+
+// Renders one track of an NSF to WAV
+// Length comes from --duration, then the NSFe track time, then a default
+fn play_nsf(options: &Options) {
+    let Some(wav_path) = &options.record_audio else {
+        eprintln!("NSF playback needs --record-audio FILE");
+        process::exit(1);
+    };
+    let nsf = Nsf::load(&options.rom_path).unwrap_or_else(|err| {
+        eprintln!("Failed to load {}: {}", options.rom_path, err);
+        process::exit(1);
+    });
+
+    let track = options.track.map_or(nsf.starting_song, |track| track - 1);
+    if track >= nsf.total_songs {
+        eprintln!("Track {} is out of range, the file has {}", track + 1, nsf.total_songs);
+        process::exit(1);
+    }
+    let silent: Vec<ExpansionChip> = nsf.expansion_chips.iter().copied().filter(|chip| chip.create().is_none()).collect();
+    if !silent.is_empty() {
+        eprintln!("Expansion audio {:?} is not emulated and will be silent", silent);
+    }
+    let (length_ms, fade_ms) = match options.duration {
+        Some(seconds) => ((seconds * 1000.0) as u32, 0),
+        None => (nsf.track_time(track).unwrap_or(150_000), nsf.track_fade(track).unwrap_or(10_000)),
+    };
+    let name = nsf.track_name(track).map(String::from).unwrap_or(format!("Track {}", track + 1));
+    println!("{} - {}: {}", nsf.artist, nsf.title, name);
+
+    let region = options.region.unwrap_or(nsf.region());
+    let mut player = NsfPlayer::new(nsf, region, options.sample_rate);
+    for &channel in &options.muted {
+        player.nes.mixer.set_muted(channel, true);
+    }
+    for &channel in &options.soloed {
+        player.nes.mixer.set_solo(channel, true);
+    }
+    for &(chip, volume) in &options.chip_volumes {
+        player.nes.mixer.set_chip_volume(chip, volume);
+    }
+    let samples = player.render_track(track, length_ms, fade_ms);
+    if let Err(err) = wav::save_wav(Path::new(wav_path), options.sample_rate, &samples) {
+        eprintln!("Failed to write {}: {}", wav_path, err);
+        process::exit(1);
+    }
+}
+
+// Command line first, then an NES 2.0 header, then the ROM database
+fn select_region(options: &Options, nes: &Nes) -> Region {
+    if let Some(region) = options.region.or(nes.bus.rom.region) {
+        return region;
+    }
+    if let Some(path) = &options.rom_db {
+        match region::lookup_database(path, nes.bus.rom.crc32()) {
+            Ok(Some(region)) => return region,
+            Ok(None) => {}
+            Err(err) => eprintln!("Failed to read ROM database {}: {}", path, err),
+        }
+    }
+    Region::Ntsc
+}
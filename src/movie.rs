@@ -0,0 +1,519 @@
+// Input movies: the buttons held on every frame from power on
+// Saved and loaded as FCEUX .fm2 text or BizHawk .bk2 archives. Each movie
+// carries a hash of the ROM it was recorded on (MD5 for FM2, SHA-1 for BK2)
+// and playback refuses to start on any other ROM.
+// A movie may start from one of our savestates instead of power on, embedded
+// as a base64 savestate header in FM2 and as Core.bin in BK2. Savestates from
+// other emulators can't be loaded.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::checksum::to_hex;
+use crate::nes::Nes;
+use crate::region::Region;
+use crate::rom::Rom;
+use crate::savestate;
+use crate::zip;
+
+// FM2 gamepad field, left to right from Right (bit 7) down to A (bit 0)
+const FM2_BUTTONS: &[u8; 8] = b"RLDUTSBA";
+// BK2 button names and their controller::Button bits
+const BK2_BUTTONS: [(&str, u8); 8] = [
+    ("Up", 4),
+    ("Down", 5),
+    ("Left", 6),
+    ("Right", 7),
+    ("Start", 3),
+    ("Select", 2),
+    ("B", 1),
+    ("A", 0),
+];
+const BK2_MNEMONICS: &[u8; 8] = b"UDLRSsBA";
+
+// FM2 command bits
+const COMMAND_RESET: u32 = 1;
+const COMMAND_POWER: u32 = 2;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MovieFrame {
+    pub reset: bool,
+    pub power: bool,
+    pub buttons: [u8; 4], // Players 1-4, one bit per controller::Button
+}
+
+impl MovieFrame {
+    // Applies the frame's input before it runs
+    pub fn apply(&self, nes: &mut Nes) {
+        if self.power {
+            nes.power_cycle();
+        } else if self.reset {
+            nes.reset();
+        }
+        for (player, &buttons) in self.buttons.iter().enumerate() {
+            nes.set_buttons(player, buttons);
+        }
+    }
+}
+
+pub struct Movie {
+    pub rom_filename: String,
+    pub md5: Option<[u8; 16]>,
+    pub sha1: Option<[u8; 20]>,
+    pub region: Region,
+    pub four_score: bool,
+    pub rerecord_count: usize,
+    pub comments: Vec<String>,
+    pub savestate: Option<Vec<u8>>, // Restored before the first frame
+    pub frames: Vec<MovieFrame>,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+impl Movie {
+    // Empty movie for a new recording on the loaded ROM
+    pub fn new(rom: &Rom, rom_filename: &str, region: Region, four_score: bool) -> Self {
+        Self {
+            rom_filename: rom_filename.to_string(),
+            md5: Some(rom.md5()),
+            sha1: Some(rom.sha1()),
+            region,
+            four_score,
+            rerecord_count: 0,
+            comments: Vec::new(),
+            savestate: None,
+            frames: Vec::new(),
+        }
+    }
+
+    // Fails unless every hash the movie carries matches the ROM
+    pub fn check_rom(&self, rom: &Rom) -> Result<(), String> {
+        if let Some(md5) = self.md5 {
+            if md5 != rom.md5() {
+                return Err(format!(
+                    "Movie was recorded on a ROM with MD5 {}, the loaded ROM has {}",
+                    to_hex(&md5),
+                    to_hex(&rom.md5())
+                ));
+            }
+        }
+        if let Some(sha1) = self.sha1 {
+            if sha1 != rom.sha1() {
+                return Err(format!(
+                    "Movie was recorded on a ROM with SHA-1 {}, the loaded ROM has {}",
+                    to_hex(&sha1),
+                    to_hex(&rom.sha1())
+                ));
+            }
+        }
+        if self.md5.is_none() && self.sha1.is_none() {
+            return Err(String::from("Movie doesn't record which ROM it was made on"));
+        }
+        Ok(())
+    }
+
+    // Picks the format from the file extension
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let data = fs::read(path)?;
+        if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("bk2")) {
+            Self::parse_bk2(&data)
+        } else {
+            Self::parse_fm2(&String::from_utf8_lossy(&data))
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let data = if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("bk2")) {
+            self.encode_bk2()
+        } else {
+            self.encode_fm2().into_bytes()
+        };
+        fs::write(path, data)
+    }
+
+    // Key/value header lines, then one |commands|port0|port1|port2| line per frame
+    pub fn parse_fm2(text: &str) -> io::Result<Self> {
+        let mut movie = Self {
+            rom_filename: String::new(),
+            md5: None,
+            sha1: None,
+            region: Region::Ntsc,
+            four_score: false,
+            rerecord_count: 0,
+            comments: Vec::new(),
+            savestate: None,
+            frames: Vec::new(),
+        };
+
+        for line in text.lines() {
+            if let Some(fields) = line.strip_prefix('|') {
+                movie.frames.push(movie.parse_fm2_frame(fields)?);
+                continue;
+            }
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            match key {
+                "version" if value != "3" => return Err(invalid(&format!("Unsupported FM2 version {}", value))),
+                "binary" if value == "1" => return Err(invalid("Binary FM2 input isn't supported")),
+                "savestate" => {
+                    let encoded = value.strip_prefix("base64:").ok_or(invalid("Unknown savestate format"))?;
+                    let data = base64_decode(encoded).ok_or(invalid("Invalid savestate"))?;
+                    movie.savestate = Some(check_savestate(data)?);
+                }
+                "romFilename" => movie.rom_filename = value.to_string(),
+                "romChecksum" => {
+                    let encoded = value.strip_prefix("base64:").ok_or(invalid("Unknown romChecksum format"))?;
+                    let md5 = base64_decode(encoded).ok_or(invalid("Invalid romChecksum"))?;
+                    movie.md5 = Some(md5.try_into().map_err(|_| invalid("romChecksum isn't an MD5"))?);
+                }
+                "palFlag" if value == "1" => movie.region = Region::Pal,
+                "dendy" if value == "1" => movie.region = Region::Dendy,
+                "fourscore" => movie.four_score = value == "1",
+                "rerecordCount" => movie.rerecord_count = value.parse().unwrap_or(0),
+                "comment" => movie.comments.push(value.to_string()),
+                _ => {}
+            }
+        }
+        Ok(movie)
+    }
+
+    fn parse_fm2_frame(&self, fields: &str) -> io::Result<MovieFrame> {
+        let fields: Vec<&str> = fields.split('|').collect();
+        let bad_frame = || invalid(&format!("Invalid FM2 input line |{}", fields.join("|")));
+        let commands: u32 = fields.first().and_then(|c| c.trim().parse().ok()).ok_or_else(bad_frame)?;
+        let players = if self.four_score { 4 } else { 2 };
+
+        let mut frame = MovieFrame {
+            reset: commands & COMMAND_RESET != 0,
+            power: commands & COMMAND_POWER != 0,
+            buttons: [0; 4],
+        };
+        for player in 0..players {
+            let field = fields.get(player + 1).ok_or_else(bad_frame)?.as_bytes();
+            // Ports without a gamepad leave the field empty
+            for (i, &c) in field.iter().take(8).enumerate() {
+                if c != b'.' && c != b' ' {
+                    frame.buttons[player] |= 0x80 >> i;
+                }
+            }
+        }
+        Ok(frame)
+    }
+
+    pub fn encode_fm2(&self) -> String {
+        let mut text = String::from("version 3\nemuVersion 22020\n");
+        text += &format!("rerecordCount {}\n", self.rerecord_count);
+        text += &format!("palFlag {}\n", (self.region == Region::Pal) as u8);
+        if self.region == Region::Dendy {
+            text += "dendy 1\n";
+        }
+        text += &format!("romFilename {}\n", self.rom_filename);
+        if let Some(md5) = self.md5 {
+            text += &format!("romChecksum base64:{}\n", base64_encode(&md5));
+            text += &format!("guid {}\n", guid(&md5));
+        }
+        text += &format!("fourscore {}\n", self.four_score as u8);
+        let port = if self.four_score { 0 } else { 1 };
+        text += &format!("port0 {}\nport1 {}\nport2 0\n", port, port);
+        for comment in &self.comments {
+            text += &format!("comment {}\n", comment);
+        }
+        if let Some(data) = &self.savestate {
+            text += &format!("savestate base64:{}\n", base64_encode(data));
+        }
+
+        let players = if self.four_score { 4 } else { 2 };
+        for frame in &self.frames {
+            let commands = (frame.reset as u32 * COMMAND_RESET) | (frame.power as u32 * COMMAND_POWER);
+            text += &format!("|{}|", commands);
+            for &buttons in &frame.buttons[..players] {
+                for (i, &c) in FM2_BUTTONS.iter().enumerate() {
+                    text.push(if buttons & (0x80 >> i) != 0 { c as char } else { '.' });
+                }
+                text.push('|');
+            }
+            text += "|\n";
+        }
+        text
+    }
+
+    // ZIP with Header.txt (key value lines) and Input Log.txt, whose LogKey
+    // names the buttons in each |field| of the frame lines
+    pub fn parse_bk2(data: &[u8]) -> io::Result<Self> {
+        let entries = zip::read_entries(data).map_err(|err| invalid(&err))?;
+        let entry = |name: &str| {
+            entries
+                .iter()
+                .find(|(entry, _)| entry == name)
+                .map(|(_, body)| String::from_utf8_lossy(body).into_owned())
+                .ok_or(invalid(&format!("BK2 archive has no {}", name)))
+        };
+
+        let mut movie = Self::parse_fm2("").unwrap();
+        for line in entry("Header.txt")?.lines() {
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            match key {
+                "Platform" if value != "NES" => return Err(invalid(&format!("BK2 is for {}, not NES", value))),
+                "StartsFromSavestate" if value == "True" => {
+                    let data = entries.iter().find(|(entry, _)| entry == "Core.bin").map(|(_, body)| body.clone());
+                    movie.savestate = Some(check_savestate(data.ok_or(invalid("BK2 archive has no Core.bin"))?)?);
+                }
+                "GameName" => movie.rom_filename = value.to_string(),
+                "SHA1" => {
+                    let sha1 = from_hex(value).ok_or(invalid("Invalid SHA1 header"))?;
+                    movie.sha1 = Some(sha1.try_into().map_err(|_| invalid("SHA1 header isn't a SHA-1"))?);
+                }
+                "PAL" => movie.region = if value == "True" { Region::Pal } else { Region::Ntsc },
+                "rerecordCount" => movie.rerecord_count = value.parse().unwrap_or(0),
+                _ => {}
+            }
+        }
+
+        // Button names for each field, from LogKey:#Reset|Power|#P1 Up|...|
+        let mut groups: Vec<Vec<String>> = Vec::new();
+        for line in entry("Input Log.txt")?.lines() {
+            if let Some(key) = line.strip_prefix("LogKey:") {
+                groups = key
+                    .split('#')
+                    .filter(|group| !group.is_empty())
+                    .map(|group| group.split('|').filter(|name| !name.is_empty()).map(String::from).collect())
+                    .collect();
+                movie.four_score = groups.iter().flatten().any(|name| name.starts_with("P3 "));
+            } else if let Some(fields) = line.strip_prefix('|') {
+                let mut frame = MovieFrame::default();
+                for (group, field) in groups.iter().zip(fields.split('|')) {
+                    for (name, &c) in group.iter().zip(field.as_bytes()) {
+                        if c == b'.' {
+                            continue;
+                        }
+                        match name.as_str() {
+                            "Reset" => frame.reset = true,
+                            "Power" => frame.power = true,
+                            name => {
+                                let Some((player, button)) = name.strip_prefix('P').and_then(|n| n.split_once(' ')) else {
+                                    continue;
+                                };
+                                let player: usize = player.parse().map_err(|_| invalid("Invalid BK2 LogKey"))?;
+                                if let Some(&(_, bit)) = BK2_BUTTONS.iter().find(|(b, _)| *b == button) {
+                                    if (1..=4).contains(&player) {
+                                        frame.buttons[player - 1] |= 1 << bit;
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+                movie.frames.push(frame);
+            }
+        }
+        Ok(movie)
+    }
+
+    pub fn encode_bk2(&self) -> Vec<u8> {
+        let mut header = String::from("MovieVersion BizHawk v2.0.0\nPlatform NES\nCore NesHawk\n");
+        header += &format!("GameName {}\n", self.rom_filename);
+        if let Some(sha1) = self.sha1 {
+            header += &format!("SHA1 {}\n", to_hex(&sha1).to_uppercase());
+        }
+        if self.region == Region::Pal {
+            header += "PAL True\n";
+        }
+        header += &format!("rerecordCount {}\n", self.rerecord_count);
+        if self.savestate.is_some() {
+            header += "StartsFromSavestate True\n";
+        }
+
+        let players = if self.four_score { 4 } else { 2 };
+        let mut log = String::from("[Input]\nLogKey:#Reset|Power|");
+        for player in 1..=players {
+            log.push('#');
+            for (name, _) in BK2_BUTTONS {
+                log += &format!("P{} {}|", player, name);
+            }
+        }
+        log.push('\n');
+        for frame in &self.frames {
+            log.push('|');
+            log.push(if frame.reset { 'r' } else { '.' });
+            log.push(if frame.power { 'P' } else { '.' });
+            log.push('|');
+            for &buttons in &frame.buttons[..players] {
+                for (&(_, bit), &c) in BK2_BUTTONS.iter().zip(BK2_MNEMONICS) {
+                    log.push(if buttons & (1 << bit) != 0 { c as char } else { '.' });
+                }
+                log.push('|');
+            }
+            log.push('\n');
+        }
+        log += "[/Input]\n";
+
+        let mut files = vec![("Header.txt", header.as_bytes()), ("Input Log.txt", log.as_bytes())];
+        if let Some(data) = &self.savestate {
+            files.push(("Core.bin", data));
+        }
+        zip::write_stored(&files)
+    }
+}
+
+// Movies made by other emulators embed their own savestates
+fn check_savestate(data: Vec<u8>) -> io::Result<Vec<u8>> {
+    if !savestate::is_savestate(&data) {
+        return Err(invalid("Movie starts from a savestate made by another emulator"));
+    }
+    Ok(data)
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut text = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, &b)| bits | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(BASE64[(bits >> (18 - 6 * i)) as usize & 0x3F] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    let (mut bits, mut count) = (0u32, 0);
+    for c in text.trim().bytes().filter(|&c| c != b'=') {
+        bits = (bits << 6) | BASE64.iter().position(|&b| b == c)? as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            data.push((bits >> count) as u8);
+        }
+    }
+    Some(data)
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim();
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+// FCEUX wants a GUID per movie, derived here from the ROM hash
+fn guid(md5: &[u8; 16]) -> String {
+    let hex = to_hex(md5).to_uppercase();
+    format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    fn test_rom() -> crate::rom::Rom {
+        let mut rom = crate::rom::Rom::new();
+        rom.prg_rom = vec![0xEA; 0x4000];
+        rom
+    }
+
+    fn test_movie() -> crate::movie::Movie {
+        use crate::movie::*;
+        let mut movie = Movie::new(&test_rom(), "test.nes", Region::Ntsc, false);
+        movie.frames.push(MovieFrame { reset: false, power: true, buttons: [0, 0, 0, 0] });
+        movie.frames.push(MovieFrame { reset: false, power: false, buttons: [0x81, 0x10, 0, 0] });
+        movie.frames.push(MovieFrame { reset: true, power: false, buttons: [0x08, 0, 0, 0] });
+        movie
+    }
+
+    #[test]
+    fn fm2_round_trip() {
+        use crate::movie::*;
+        let movie = test_movie();
+        let text = movie.encode_fm2();
+        assert!(text.contains("|0|R......A|...U....||\n"));
+        assert!(text.contains("|1|....T...|........||\n"));
+
+        let loaded = Movie::parse_fm2(&text).unwrap();
+        assert_eq!(loaded.frames, movie.frames);
+        assert_eq!(loaded.md5, movie.md5);
+        assert!(loaded.check_rom(&test_rom()).is_ok());
+
+        // A different ROM is refused
+        let mut other = test_rom();
+        other.prg_rom[0] = 0x00;
+        assert!(loaded.check_rom(&other).unwrap_err().contains("MD5"));
+
+        assert!(Movie::parse_fm2("version 3\nsavestate foo\n").is_err());
+    }
+
+    #[test]
+    fn bk2_round_trip() {
+        use crate::movie::*;
+        let mut movie = test_movie();
+        movie.four_score = true;
+        movie.frames[1].buttons[3] = 0x04;
+        let loaded = Movie::parse_bk2(&movie.encode_bk2()).unwrap();
+        assert_eq!(loaded.frames, movie.frames);
+        assert!(loaded.four_score);
+        assert_eq!(loaded.md5, None);
+        assert!(loaded.check_rom(&test_rom()).is_ok());
+
+        let mut other = test_rom();
+        other.prg_rom[0] = 0x00;
+        assert!(loaded.check_rom(&other).unwrap_err().contains("SHA-1"));
+    }
+
+    #[test]
+    fn savestate_round_trip() {
+        use crate::movie::*;
+        let mut movie = test_movie();
+        let mut nes = Nes::new();
+        nes.bus.rom = test_rom();
+        nes.reset();
+        nes.run_frame();
+        movie.savestate = Some(savestate::save(&mut nes));
+
+        let fm2 = Movie::parse_fm2(&movie.encode_fm2()).unwrap();
+        let bk2 = Movie::parse_bk2(&movie.encode_bk2()).unwrap();
+        for loaded in [fm2, bk2] {
+            assert_eq!(loaded.savestate, movie.savestate);
+            assert_eq!(loaded.frames, movie.frames);
+        }
+
+        // FCEUX's own savestates are refused
+        let fceux = format!("version 3\nsavestate base64:{}\n", base64_encode(b"FCSX\x00\x00"));
+        assert!(Movie::parse_fm2(&fceux).err().unwrap().to_string().contains("another emulator"));
+    }
+
+    #[test]
+    fn power_clears_ram() {
+        use crate::movie::*;
+        let mut nes = Nes::new();
+        nes.bus.rom = test_rom();
+        nes.bus.write(0x0010, 0x55);
+        nes.bus.write(0x4015, 0x01);
+        nes.bus.write(0x4003, 0x08);
+
+        // Reset keeps RAM, power cycling clears it and silences the APU
+        MovieFrame { reset: true, power: false, buttons: [0; 4] }.apply(&mut nes);
+        assert_eq!(nes.bus.peek(0x0010), 0x55);
+        nes.bus.write(0x4015, 0x01);
+        nes.bus.write(0x4003, 0x08);
+        MovieFrame { reset: false, power: true, buttons: [0; 4] }.apply(&mut nes);
+        assert_eq!(nes.bus.peek(0x0010), 0x00);
+        assert_eq!(nes.bus.read(0x4015) & 0x01, 0x00);
+    }
+
+    #[test]
+    fn base64() {
+        use crate::movie::*;
+        assert_eq!(base64_encode(b"Man"), "TWFu");
+        assert_eq!(base64_encode(b"Ma"), "TWE=");
+        assert_eq!(base64_decode("TWE=").unwrap(), b"Ma");
+        assert_eq!(base64_decode(&base64_encode(&[0xFF; 16])).unwrap(), vec![0xFF; 16]);
+    }
+}
@@ -0,0 +1,393 @@
+// Ties the CPU to the bus and steps the whole console one CPU cycle at a time
+
+use crate::apu::Apu;
+use crate::audio::{Mixer, Resampler};
+use crate::bus::Bus;
+use crate::cpu::Cpu;
+use crate::disasm::OPCODES;
+use crate::ppu::Ppu;
+use crate::ram::Ram;
+use crate::region::Region;
+use crate::savestate::State;
+
+pub struct Nes {
+    pub cpu: Cpu,
+    pub bus: Bus,
+    region: Region,
+    master_clock: usize, // Master clocks the PPU still has to catch up on
+    oam_dma_end: usize,  // CPU cycle the current OAM DMA finishes on
+    pub mixer: Mixer,
+    resampler: Resampler,
+    pub audio_enabled: bool, // Only set when something drains audio_samples, or the buffer grows forever
+}
+
+pub const DEFAULT_SAMPLE_RATE: usize = 44_100;
+
+impl Nes {
+    pub fn new() -> Self {
+        Self {
+            cpu: Cpu::new(),
+            bus: Bus::new(),
+            region: Region::Ntsc,
+            master_clock: 0,
+            oam_dma_end: 0,
+            mixer: Mixer::new(),
+            resampler: Resampler::new(Region::Ntsc.cpu_clock_hz(), DEFAULT_SAMPLE_RATE),
+            audio_enabled: false,
+        }
+    }
+
+    // Where the PPU and DMA stand against the CPU, savestate::save adds the rest
+    pub fn sync_state(&mut self, state: &mut State) {
+        state.usize(&mut self.master_clock);
+        state.usize(&mut self.oam_dma_end);
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.bus.ppu.region = region;
+        self.bus.apu.region = region;
+        self.set_sample_rate(self.resampler.sample_rate());
+    }
+
+    // Host audio rate, discards samples not yet read
+    pub fn set_sample_rate(&mut self, sample_rate: usize) {
+        self.resampler = Resampler::new(self.region.cpu_clock_hz(), sample_rate);
+    }
+
+    pub fn sample_rate(&self) -> usize {
+        self.resampler.sample_rate()
+    }
+
+    // Audio produced since the last call, read once per frame
+    pub fn audio_samples(&mut self) -> Vec<i16> {
+        self.resampler.read_samples()
+    }
+
+    // Output samples buffered until the next audio_samples call
+    pub fn audio_buffered(&self) -> usize {
+        self.resampler.buffered()
+    }
+
+    pub fn reset(&mut self) {
+        self.bus.apu.reset();
+        self.cpu.reset(&mut self.bus);
+    }
+
+    // Unlike reset, power cycling clears RAM and the CPU registers and puts
+    // the PPU and APU back in their power up state. The cartridge, input
+    // devices and the frame and cycle counts carry on.
+    pub fn power_cycle(&mut self) {
+        self.bus.ram = Ram::new();
+        let frame_count = self.bus.ppu.frame_count;
+        self.bus.ppu = Ppu::new();
+        self.bus.ppu.region = self.region;
+        self.bus.ppu.frame_count = frame_count;
+        self.bus.apu = Apu::new();
+        self.bus.apu.region = self.region;
+        self.bus.oam_dma = None;
+        self.master_clock = 0;
+        self.cpu.power_on(&mut self.bus);
+    }
+
+    // Runs one CPU cycle and the PPU dots that fit inside it
+    // (3 on NTSC and Dendy, 3.2 on average on PAL)
+    // Instructions execute whole on their first cycle, the rest are counted down
+    pub fn clock(&mut self) {
+        if self.cpu.cycles == 0 {
+            // A halted watchpoint holds the CPU at the boundary
+            if self.bus.watch.is_some() && !self.begin_watched_instruction() {
+                return;
+            }
+            // NMI is sampled at the instruction boundary, so a $2002 read that
+            // races vblank can still withdraw it
+            if self.bus.ppu.nmi {
+                self.bus.ppu.nmi = false;
+                self.cpu.nmi = true;
+            }
+            // IRQ is level triggered, it stays asserted until acknowledged
+            self.cpu.irq = self.bus.apu.irq();
+            if self.bus.cdl.is_some() {
+                self.begin_logged_instruction();
+            }
+            // Register reads and writes land on the last cycle, before page
+            // crossing penalties
+            let opcode = self.bus.peek(self.cpu.pc() as usize);
+            let cycles = OPCODES[opcode as usize].cycles as usize;
+            self.bus.access_delay = cycles.saturating_sub(1);
+            self.bus.ppu_catch_up = self.dots_in(self.bus.access_delay);
+            self.cpu.clock(&mut self.bus);
+            self.bus.access_delay = 0;
+            self.bus.ppu_catch_up = 0;
+
+            // The $4014 write is the last cycle of the instruction
+            if let Some(page) = self.bus.oam_dma.take() {
+                let write_cycle = self.cpu.total_cycles + self.cpu.cycles - 1;
+                self.oam_dma(page, write_cycle);
+            }
+        }
+        self.cpu.cycles -= 1;
+        self.cpu.total_cycles += 1;
+        self.bus.apu.clock();
+        self.bus.expansion_port.clock();
+        if let Some(chip) = self.bus.rom.expansion_audio.as_mut() {
+            chip.clock();
+        }
+        if self.audio_enabled {
+            let level = self.mixer.mix(&self.bus.apu.outputs(), self.bus.rom.expansion_audio.as_deref());
+            self.resampler.clock(level);
+        }
+        if let Some(addr) = self.bus.apu.dmc.dma_request.take() {
+            self.dmc_dma(addr);
+        }
+
+        self.master_clock += self.region.cpu_divider();
+        while self.master_clock >= self.region.ppu_divider() {
+            self.master_clock -= self.region.ppu_divider();
+            if self.bus.ppu_ahead > 0 {
+                self.bus.ppu_ahead -= 1;
+            } else {
+                self.bus.ppu.clock(&self.bus.rom, self.bus.cdl.as_deref_mut());
+            }
+        }
+    }
+
+    // PPU dots that the next CPU cycles will run
+    fn dots_in(&self, cycles: usize) -> usize {
+        (self.master_clock + cycles * self.region.cpu_divider()) / self.region.ppu_divider()
+    }
+
+    fn begin_watched_instruction(&mut self) -> bool {
+        let (pc, cycle) = (self.cpu.pc(), self.cpu.total_cycles);
+        let opcode = self.bus.peek(pc as usize);
+        self.bus.watch.as_mut().unwrap().begin_instruction(pc, opcode, cycle)
+    }
+
+    fn begin_logged_instruction(&mut self) {
+        let pc = self.cpu.pc();
+        let opcode = self.bus.peek(pc as usize);
+        let interrupt = self.cpu.interrupt_pending(false, self.cpu.irq);
+        let cdl = self.bus.cdl.as_mut().unwrap();
+        if interrupt {
+            cdl.begin_interrupt();
+        } else {
+            cdl.begin_instruction(&self.bus.rom, pc, opcode);
+        }
+    }
+
+    // Copies $XX00-$XXFF into OAM through $2004
+    // Takes 513 cycles, plus one to align when the write landed on an odd cycle
+    fn oam_dma(&mut self, page: u8, write_cycle: usize) {
+        let base = (page as usize) << 8;
+        for offset in 0..256 {
+            let data = self.bus.read(base + offset);
+            self.bus.write(0x2004, data);
+        }
+        self.cpu.stall(513 + write_cycle % 2);
+        self.oam_dma_end = self.cpu.total_cycles + self.cpu.cycles;
+    }
+
+    // Fetches a DMC sample byte, halting the CPU for up to 4 cycles
+    fn dmc_dma(&mut self, addr: u16) {
+        // Halting on a controller read repeats the read, which clocks the
+        // shift register again and drops a button bit
+        if self.cpu.cycles == 0 && matches!(self.bus.last_read, 0x4016 | 0x4017) {
+            self.bus.read(self.bus.last_read);
+        }
+        // Logged as a sample rather than as a data read
+        let cdl = self.bus.cdl.take();
+        let data = self.bus.read(addr as usize);
+        self.bus.cdl = cdl;
+        if let Some(cdl) = self.bus.cdl.as_mut() {
+            cdl.log_sample(&self.bus.rom, addr);
+        }
+        self.bus.apu.dmc.fill_buffer(data);
+
+        // Inside OAM DMA only the DMC's own get and put cycles are lost
+        let stall = match self.oam_dma_end.saturating_sub(self.cpu.total_cycles) {
+            0 => 4,
+            1 => 3,
+            2 => 1,
+            _ => 2,
+        };
+        self.cpu.stall(stall);
+    }
+
+    // Counts down the rest of an instruction that has already executed,
+    // as run_frame can stop part way through one
+    pub fn finish_instruction(&mut self) {
+        while self.cpu.cycles != 0 {
+            self.clock();
+        }
+    }
+
+    // Executes one instruction or enters an interrupt handler
+    pub fn step(&mut self) {
+        self.finish_instruction();
+        loop {
+            self.clock();
+            if self.cpu.cycles == 0 {
+                break;
+            }
+        }
+    }
+
+    // Whether the next step enters an NMI or IRQ handler
+    pub fn interrupt_pending(&self) -> bool {
+        self.cpu.interrupt_pending(self.bus.ppu.nmi, self.bus.apu.irq())
+    }
+
+    // Runs until the PPU finishes the next picture
+    // or a watchpoint halts at an instruction boundary
+    pub fn run_frame(&mut self) {
+        self.bus.ppu.frame_complete = false;
+        while !self.bus.ppu.frame_complete {
+            self.clock();
+            if self.cpu.cycles == 0 && self.bus.halted() {
+                break;
+            }
+        }
+    }
+
+    // Runs until the PPU next reaches a dot on a scanline, to within the 3 dots
+    // each CPU cycle covers, or a watchpoint halts at an instruction boundary
+    // Scanlines 0-239 draw the picture, 240 and up are vblank and pre-render
+    pub fn run_to(&mut self, scanline: usize, dot: usize) {
+        let target = (scanline, dot);
+        let frame = self.frame_count();
+        // Gives up after two frames on a position the region doesn't have
+        while self.frame_count() < frame + 2 {
+            let before = (self.bus.ppu.scanline, self.bus.ppu.dot);
+            self.clock();
+            let after = (self.bus.ppu.scanline, self.bus.ppu.dot);
+            let reached = if before <= after {
+                before < target && target <= after
+            } else {
+                before < target || target <= after
+            };
+            if reached || (self.cpu.cycles == 0 && self.bus.halted()) {
+                break;
+            }
+        }
+    }
+
+    // Buttons held by a player (0-3) until changed, one bit per
+    // controller::Button, usually set before each run_frame
+    // Players 3 and 4 need a Four Score or a Famicom 4 player adapter
+    pub fn set_buttons(&mut self, player: usize, buttons: u8) {
+        let port = player % 2;
+        if player < 2 {
+            self.bus.ports[port].set_buttons(0, buttons);
+        } else {
+            self.bus.ports[port].set_buttons(1, buttons);
+            self.bus.expansion_port.set_buttons(port, buttons);
+        }
+    }
+
+    // Number of pictures completed since power on
+    pub fn frame_count(&self) -> usize {
+        self.bus.ppu.frame_count
+    }
+
+    pub fn framebuffer(&self) -> &[u16] {
+        self.bus.ppu.framebuffer()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    #[test]
+    fn oam_dma() {
+        use crate::nes::Nes;
+        let mut nes = Nes::new();
+
+        // Copy from internal RAM on an even write cycle
+        for i in 0..256 {
+            nes.bus.write(0x0200 + i, i as u8);
+        }
+        nes.bus.write(0x4014, 0x02);
+        let page = nes.bus.oam_dma.take().unwrap();
+        nes.oam_dma(page, 10);
+        assert_eq!(nes.bus.ppu.oam[0], 0x00);
+        assert_eq!(nes.bus.ppu.oam[0x7F], 0x7F);
+        assert_eq!(nes.cpu.cycles, 513);
+
+        // Copy from PRG ROM on an odd write cycle
+        nes.cpu.cycles = 0;
+        nes.bus.rom.prg_rom = vec![0xEA; 0x4000];
+        nes.oam_dma(0xC0, 11);
+        assert_eq!(nes.bus.ppu.oam[0xFF], 0xEA);
+        assert_eq!(nes.cpu.cycles, 514);
+        assert_eq!(nes.cpu.stall_cycles, 513 + 514);
+    }
+
+    #[test]
+    fn dmc_dma_stall() {
+        use crate::nes::Nes;
+        let mut nes = Nes::new();
+        nes.bus.rom.prg_rom = vec![0x55; 0x4000];
+        nes.bus.apu.dmc.bytes_remaining = 2;
+
+        // On its own a DMC fetch halts the CPU for 4 cycles
+        nes.dmc_dma(0xC000);
+        assert_eq!(nes.cpu.cycles, 4);
+
+        // In the middle of OAM DMA it only takes 2
+        nes.cpu.cycles = 0;
+        nes.oam_dma(0x00, 0);
+        nes.cpu.total_cycles += 100;
+        nes.cpu.cycles -= 100;
+        nes.dmc_dma(0xC000);
+        assert_eq!(nes.cpu.cycles, 513 - 100 + 2);
+    }
+
+    // LDA $2002 / STA $00 / JMP $C005, started at a PPU position on line 240
+    // with NMI enabled, returning the value read
+    fn race_vblank(dot: usize) -> (u8, crate::nes::Nes) {
+        use crate::nes::Nes;
+        let mut nes = Nes::new();
+        let mut prg_rom = vec![0xEA; 0x4000];
+        prg_rom[..8].copy_from_slice(&[0xAD, 0x02, 0x20, 0x85, 0x00, 0x4C, 0x05, 0xC0]);
+        prg_rom[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0xC0]);
+        nes.bus.rom.prg_rom = prg_rom;
+        nes.reset();
+        nes.bus.write(0x2000, 0x80);
+        while nes.cpu.cycles != 0 {
+            nes.clock();
+        }
+        (nes.bus.ppu.scanline, nes.bus.ppu.dot) = (240, dot);
+        // The STA finishes before vblank's NMI can be taken
+        for _ in 0..7 {
+            nes.clock();
+        }
+        (nes.bus.ram.read(0x00), nes)
+    }
+
+    #[test]
+    fn vblank_race_on_read_cycle() {
+        // The read cycle is 9 dots after the instruction starts, landing on
+        // the dot vblank sets: the flag reads clear and never sets
+        let (data, mut nes) = race_vblank(333);
+        assert_eq!(data & 0x80, 0);
+        assert!(!nes.bus.ppu.nmi && !nes.cpu.nmi);
+        assert_eq!(nes.bus.read(0x2002) & 0x80, 0);
+
+        // One dot later it reads set and the NMI is cancelled
+        let (data, nes) = race_vblank(334);
+        assert_eq!(data & 0x80, 0x80);
+        assert!(!nes.bus.ppu.nmi && !nes.cpu.nmi);
+
+        // Starting before the flag sets is too early to race it
+        let (data, mut nes) = race_vblank(320);
+        assert_eq!(data & 0x80, 0);
+        nes.clock();
+        assert!(nes.bus.ppu.nmi);
+        assert_eq!(nes.bus.read(0x2002) & 0x80, 0x80);
+    }
+}
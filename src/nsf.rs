@@ -0,0 +1,449 @@
+// NSF and NSFe music files
+// An NSF is 6502 code plus data with two entry points: INIT selects a song and
+// PLAY advances it by one tick. The player loads the data as a cartridge, calls
+// INIT once, then calls PLAY at the rate given in the header. Between calls the
+// CPU idles in a small driver loop at NSF_DRIVER_ADDR.
+// Formats as documented on the NESdev wiki (NSF, NSFe).
+
+use std::fs;
+use std::io;
+
+use crate::expansion::ExpansionChip;
+use crate::nes::Nes;
+use crate::region::Region;
+use crate::rom::NSF_DRIVER_ADDR;
+
+const NSF_HEADER_SIZE: usize = 0x80;
+
+// Header expansion byte, bit order
+const NSF_CHIPS: [ExpansionChip; 6] = [
+    ExpansionChip::Vrc6,
+    ExpansionChip::Vrc7,
+    ExpansionChip::Fds,
+    ExpansionChip::Mmc5,
+    ExpansionChip::Namco163,
+    ExpansionChip::Sunsoft5B,
+];
+
+pub struct Nsf {
+    pub total_songs: usize,
+    pub starting_song: usize, // 0 based
+    pub load_addr: u16,
+    pub init_addr: u16,
+    pub play_addr: u16,
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+    pub ntsc_speed: u16, // PLAY period in microseconds
+    pub pal_speed: u16,
+    pub bankswitch: Option<[u8; 8]>, // Initial banks for $8000-$FFFF in 4 KB slots
+    pub pal: bool,                   // Written for PAL
+    pub dual_region: bool,
+    pub expansion_chips: Vec<ExpansionChip>,
+    pub data: Vec<u8>,
+    // NSFe metadata, empty or None when the file doesn't say
+    pub track_names: Vec<String>,
+    pub track_times: Vec<Option<u32>>, // Milliseconds
+    pub track_fades: Vec<Option<u32>>, // Milliseconds
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+// Zero terminated (or zero padded) text
+fn read_string(data: &[u8]) -> String {
+    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).into_owned()
+}
+
+impl Nsf {
+    fn new() -> Self {
+        Self {
+            total_songs: 1,
+            starting_song: 0,
+            load_addr: 0x8000,
+            init_addr: 0x8000,
+            play_addr: 0x8000,
+            title: String::new(),
+            artist: String::new(),
+            copyright: String::new(),
+            ntsc_speed: 16_639,
+            pal_speed: 19_997,
+            bankswitch: None,
+            pal: false,
+            dual_region: false,
+            expansion_chips: Vec::new(),
+            data: Vec::new(),
+            track_names: Vec::new(),
+            track_times: Vec::new(),
+            track_fades: Vec::new(),
+        }
+    }
+
+    pub fn load(path: &str) -> io::Result<Self> {
+        Self::parse(&fs::read(path)?)
+    }
+
+    // Accepts either format, told apart by the magic number
+    pub fn parse(data: &[u8]) -> io::Result<Self> {
+        if data.starts_with(b"NESM\x1A") {
+            Self::parse_nsf(data)
+        } else if data.starts_with(b"NSFE") {
+            Self::parse_nsfe(data)
+        } else {
+            Err(invalid("Not an NSF or NSFe file"))
+        }
+    }
+
+    fn parse_nsf(data: &[u8]) -> io::Result<Self> {
+        if data.len() < NSF_HEADER_SIZE {
+            return Err(invalid("NSF header is truncated"));
+        }
+        let mut nsf = Self::new();
+        nsf.total_songs = data[0x06] as usize;
+        nsf.starting_song = (data[0x07] as usize).saturating_sub(1);
+        nsf.load_addr = read_u16(data, 0x08);
+        nsf.init_addr = read_u16(data, 0x0A);
+        nsf.play_addr = read_u16(data, 0x0C);
+        nsf.title = read_string(&data[0x0E..0x2E]);
+        nsf.artist = read_string(&data[0x2E..0x4E]);
+        nsf.copyright = read_string(&data[0x4E..0x6E]);
+        nsf.ntsc_speed = read_u16(data, 0x6E);
+        nsf.set_bankswitch(&data[0x70..0x78]);
+        nsf.pal_speed = read_u16(data, 0x78);
+        nsf.set_region_flags(data[0x7A]);
+        nsf.set_expansion_flags(data[0x7B]);
+
+        // NSF2 may give the program length, metadata chunks follow it
+        let length = data[0x7D] as usize | (data[0x7E] as usize) << 8 | (data[0x7F] as usize) << 16;
+        let end = if data[0x05] >= 2 && length != 0 { NSF_HEADER_SIZE + length } else { data.len() };
+        nsf.data = data.get(NSF_HEADER_SIZE..end).ok_or(invalid("NSF data is truncated"))?.to_vec();
+        nsf.validate()?;
+        Ok(nsf)
+    }
+
+    // Chunks of: length (u32), four character id, body
+    // Ids starting with an upper case letter must be understood to play the file
+    fn parse_nsfe(data: &[u8]) -> io::Result<Self> {
+        let mut nsf = Self::new();
+        let mut has_info = false;
+        let mut pos = 4;
+        while pos + 8 <= data.len() {
+            let length = u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+            let id = &data[pos + 4..pos + 8];
+            let body = data.get(pos + 8..pos + 8 + length).ok_or(invalid("NSFe chunk is truncated"))?;
+            pos += 8 + length;
+
+            match id {
+                b"INFO" => {
+                    if body.len() < 9 {
+                        return Err(invalid("NSFe INFO chunk is too short"));
+                    }
+                    nsf.load_addr = read_u16(body, 0);
+                    nsf.init_addr = read_u16(body, 2);
+                    nsf.play_addr = read_u16(body, 4);
+                    nsf.set_region_flags(body[6]);
+                    nsf.set_expansion_flags(body[7]);
+                    nsf.total_songs = body[8] as usize;
+                    nsf.starting_song = body.get(9).copied().unwrap_or(0) as usize;
+                    has_info = true;
+                }
+                b"DATA" => nsf.data = body.to_vec(),
+                b"BANK" => {
+                    let mut banks = [0; 8];
+                    banks[..body.len().min(8)].copy_from_slice(&body[..body.len().min(8)]);
+                    nsf.set_bankswitch(&banks);
+                }
+                b"RATE" => {
+                    if body.len() >= 2 {
+                        nsf.ntsc_speed = read_u16(body, 0);
+                    }
+                    if body.len() >= 4 {
+                        nsf.pal_speed = read_u16(body, 2);
+                    }
+                }
+                b"auth" => {
+                    let mut fields = body.split(|&b| b == 0).map(read_string);
+                    nsf.title = fields.next().unwrap_or_default();
+                    nsf.artist = fields.next().unwrap_or_default();
+                    nsf.copyright = fields.next().unwrap_or_default();
+                }
+                b"tlbl" => {
+                    nsf.track_names = body.split(|&b| b == 0).map(read_string).collect();
+                    nsf.track_names.truncate(nsf.total_songs);
+                }
+                b"time" => nsf.track_times = Self::read_track_times(body),
+                b"fade" => nsf.track_fades = Self::read_track_times(body),
+                b"NEND" => break,
+                _ if id[0].is_ascii_uppercase() => {
+                    return Err(invalid(&format!("Unsupported NSFe chunk {}", String::from_utf8_lossy(id))));
+                }
+                _ => {}
+            }
+        }
+
+        if !has_info || nsf.data.is_empty() {
+            return Err(invalid("NSFe file is missing its INFO or DATA chunk"));
+        }
+        nsf.validate()?;
+        Ok(nsf)
+    }
+
+    // Signed milliseconds per track, negative means unset
+    fn read_track_times(body: &[u8]) -> Vec<Option<u32>> {
+        body.chunks_exact(4)
+            .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .map(|ms| u32::try_from(ms).ok())
+            .collect()
+    }
+
+    fn set_bankswitch(&mut self, banks: &[u8]) {
+        // All zero means the tune isn't bankswitched
+        self.bankswitch = banks.iter().any(|&b| b != 0).then(|| banks.try_into().unwrap());
+    }
+
+    fn set_region_flags(&mut self, flags: u8) {
+        self.pal = flags & 0x01 != 0;
+        self.dual_region = flags & 0x02 != 0;
+    }
+
+    fn set_expansion_flags(&mut self, flags: u8) {
+        self.expansion_chips = NSF_CHIPS
+            .iter()
+            .enumerate()
+            .filter(|(bit, _)| flags & (1 << bit) != 0)
+            .map(|(_, &chip)| chip)
+            .collect();
+    }
+
+    fn validate(&self) -> io::Result<()> {
+        if self.total_songs == 0 {
+            return Err(invalid("NSF has no songs"));
+        }
+        // FDS tunes may load into RAM below $8000, which isn't mapped
+        if self.load_addr < 0x8000 {
+            return Err(invalid(&format!("Unsupported NSF load address {:04X}", self.load_addr)));
+        }
+        Ok(())
+    }
+
+    // Region the tune was written for, PAL only if it doesn't also support NTSC
+    pub fn region(&self) -> Region {
+        if self.pal && !self.dual_region { Region::Pal } else { Region::Ntsc }
+    }
+
+    pub fn track_name(&self, track: usize) -> Option<&str> {
+        self.track_names.get(track).map(String::as_str).filter(|name| !name.is_empty())
+    }
+
+    pub fn track_time(&self, track: usize) -> Option<u32> {
+        self.track_times.get(track).copied().flatten()
+    }
+
+    pub fn track_fade(&self, track: usize) -> Option<u32> {
+        self.track_fades.get(track).copied().flatten()
+    }
+}
+
+// Runs an NSF on an otherwise empty console
+pub struct NsfPlayer {
+    pub nes: Nes,
+    pub nsf: Nsf,
+    play_period: f64, // CPU cycles between PLAY calls
+    next_play: f64,
+}
+
+impl NsfPlayer {
+    pub fn new(nsf: Nsf, region: Region, sample_rate: usize) -> Self {
+        let mut nes = Nes::new();
+        nes.set_region(region);
+        nes.set_sample_rate(sample_rate);
+        nes.audio_enabled = true;
+        nes.bus.rom.load_nsf(&nsf);
+
+        let speed = if region == Region::Ntsc { nsf.ntsc_speed } else { nsf.pal_speed };
+        let play_period = speed as f64 * region.cpu_clock_hz() / 1_000_000.0;
+        Self { nes, nsf, play_period, next_play: 0.0 }
+    }
+
+    // Sets the console up as the NSF spec describes and calls INIT
+    pub fn start_track(&mut self, track: usize) {
+        self.nes.bus.rom.load_nsf(&self.nsf);
+        for addr in 0x0000..0x0800 {
+            self.nes.bus.write(addr, 0);
+        }
+        for addr in 0x4000..=0x4013 {
+            self.nes.bus.write(addr, 0x00);
+        }
+        self.nes.bus.write(0x4015, 0x0F);
+        self.nes.bus.write(0x4017, 0x40);
+        self.nes.reset();
+
+        // A holds the song, X the region (0 NTSC, 1 PAL)
+        let pal = (self.nes.region() != Region::Ntsc) as u8;
+        self.nes.cpu.call(&mut self.nes.bus, self.nsf.init_addr, NSF_DRIVER_ADDR as u16, track as u8, pal);
+        self.next_play = self.nes.cpu.total_cycles as f64 + self.play_period;
+    }
+
+    // Runs for a number of CPU cycles, calling PLAY whenever it's due and
+    // the previous call has returned
+    pub fn run(&mut self, cycles: usize) -> Vec<i16> {
+        let end = self.nes.cpu.total_cycles + cycles;
+        while self.nes.cpu.total_cycles < end {
+            if self.nes.cpu.cycles == 0
+                && self.nes.cpu.pc() as usize == NSF_DRIVER_ADDR
+                && self.nes.cpu.total_cycles as f64 >= self.next_play
+            {
+                self.nes.cpu.call(&mut self.nes.bus, self.nsf.play_addr, NSF_DRIVER_ADDR as u16, 0, 0);
+                self.next_play += self.play_period;
+            }
+            self.nes.clock();
+        }
+        self.nes.audio_samples()
+    }
+
+    // Renders a track from the start, fading out over the last fade_ms
+    pub fn render_track(&mut self, track: usize, length_ms: u32, fade_ms: u32) -> Vec<i16> {
+        self.start_track(track);
+        let cycles = (length_ms + fade_ms) as f64 / 1000.0 * self.nes.region().cpu_clock_hz();
+        let mut samples = self.run(cycles as usize);
+
+        let fade = (fade_ms as usize * self.nes.sample_rate() / 1000).min(samples.len());
+        let start = samples.len() - fade;
+        for (i, sample) in samples[start..].iter_mut().enumerate() {
+            *sample = (*sample as f32 * (fade - i) as f32 / fade as f32) as i16;
+        }
+        samples
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    fn nsf_header(bankswitch: [u8; 8]) -> Vec<u8> {
+        let mut data = vec![0; 0x80];
+        data[0..5].copy_from_slice(b"NESM\x1A");
+        data[0x05] = 1;
+        data[0x06] = 3;
+        data[0x07] = 2;
+        data[0x08..0x0E].copy_from_slice(&[0x00, 0x80, 0x03, 0x80, 0x06, 0x80]);
+        data[0x0E..0x13].copy_from_slice(b"Title");
+        data[0x6E..0x70].copy_from_slice(&16_639u16.to_le_bytes());
+        data[0x70..0x78].copy_from_slice(&bankswitch);
+        data[0x7A] = 0x02;
+        data[0x7B] = 0x05;
+        data
+    }
+
+    #[test]
+    fn parse_nsf() {
+        use crate::expansion::ExpansionChip;
+        use crate::nsf::*;
+        let mut data = nsf_header([0; 8]);
+        data.extend_from_slice(&[0xEA; 0x100]);
+
+        let nsf = Nsf::parse(&data).unwrap();
+        assert_eq!(nsf.total_songs, 3);
+        assert_eq!(nsf.starting_song, 1);
+        assert_eq!((nsf.load_addr, nsf.init_addr, nsf.play_addr), (0x8000, 0x8003, 0x8006));
+        assert_eq!(nsf.title, "Title");
+        assert_eq!(nsf.bankswitch, None);
+        assert_eq!(nsf.region(), Region::Ntsc);
+        assert!(nsf.dual_region);
+        assert_eq!(nsf.expansion_chips, vec![ExpansionChip::Vrc6, ExpansionChip::Fds]);
+        assert_eq!(nsf.data.len(), 0x100);
+
+        assert!(Nsf::parse(&data[..0x40]).is_err());
+    }
+
+    #[test]
+    fn parse_nsfe() {
+        use crate::nsf::*;
+        let mut data = b"NSFE".to_vec();
+        let mut chunk = |id: &[u8; 4], body: &[u8]| {
+            data.extend_from_slice(&(body.len() as u32).to_le_bytes());
+            data.extend_from_slice(id);
+            data.extend_from_slice(body);
+        };
+        chunk(b"INFO", &[0x00, 0x80, 0x00, 0x80, 0x03, 0x80, 0x01, 0x00, 0x02, 0x00]);
+        chunk(b"DATA", &[0x60; 16]);
+        chunk(b"auth", b"Game\0Composer\0Year\0Ripper\0");
+        chunk(b"tlbl", b"Overworld\0Castle\0");
+        let mut times = 90_000i32.to_le_bytes().to_vec();
+        times.extend_from_slice(&(-1i32).to_le_bytes());
+        chunk(b"time", &times);
+        chunk(b"fade", &5_000i32.to_le_bytes());
+        chunk(b"xtra", &[1, 2, 3]);
+        chunk(b"NEND", &[]);
+
+        let nsf = Nsf::parse(&data).unwrap();
+        assert_eq!(nsf.region(), Region::Pal);
+        assert_eq!(nsf.total_songs, 2);
+        assert_eq!(nsf.play_addr, 0x8003);
+        assert_eq!((nsf.title.as_str(), nsf.artist.as_str()), ("Game", "Composer"));
+        assert_eq!(nsf.track_name(1), Some("Castle"));
+        assert_eq!(nsf.track_time(0), Some(90_000));
+        assert_eq!(nsf.track_time(1), None);
+        assert_eq!(nsf.track_fade(0), Some(5_000));
+        assert_eq!(nsf.track_fade(1), None);
+
+        // Unknown chunks are only fatal when marked as required
+        let mut bad = data.clone();
+        let end = bad.len() - 8;
+        bad.splice(end..end, [0, 0, 0, 0, b'X', b'T', b'R', b'A']);
+        assert!(Nsf::parse(&bad).is_err());
+    }
+
+    #[test]
+    fn bankswitch_and_driver_call() {
+        use crate::nsf::*;
+        // Load address $8123 pads the first bank with $123 bytes
+        let mut data = nsf_header([0, 1, 2, 3, 4, 5, 6, 2]);
+        data[0x08..0x0A].copy_from_slice(&0x8123u16.to_le_bytes());
+        let mut program = vec![0; 3 * 0x1000 - 0x123];
+        program[0] = 0x11;
+        program[0x2000 - 0x123] = 0x22;
+        data.extend_from_slice(&program);
+
+        let mut player = NsfPlayer::new(Nsf::parse(&data).unwrap(), Region::Ntsc, 44_100);
+        let bus = &mut player.nes.bus;
+        assert_eq!(bus.read(0x8123), 0x11);
+        assert_eq!(bus.read(0xF000), 0x22);
+        bus.write(0x5FFF, 0x00);
+        assert_eq!(bus.read(0xF123), 0x11);
+        // Banks past the end of the data read as 0
+        assert_eq!(bus.read(0xD000), 0x00);
+
+        // INIT is entered with the song in A and returns into the driver loop
+        player.start_track(1);
+        assert_eq!(player.nes.cpu.pc(), 0x8003);
+        assert_eq!(player.nes.bus.read(0xF000), 0x22);
+        assert_eq!(player.nes.bus.read(0x01FD), 0x4E);
+        assert_eq!(player.nes.bus.read(0x01FC), 0xFF);
+        assert_eq!(player.nes.bus.read(0x4F00), 0x4C);
+    }
+
+    #[test]
+    fn vrc6_registers() {
+        use crate::nsf::*;
+        // INIT sets VRC6 pulse 1 to a constant 15 and enables it
+        let mut data = nsf_header([0; 8]);
+        let mut program = vec![0xEA; 3];
+        program.extend_from_slice(&[0xA9, 0x8F, 0x8D, 0x00, 0x90, 0xA9, 0x80, 0x8D, 0x02, 0x90, 0x60]);
+        data.extend_from_slice(&program);
+
+        let mut player = NsfPlayer::new(Nsf::parse(&data).unwrap(), Region::Ntsc, 44_100);
+        player.start_track(0);
+        player.run(100);
+        let chip = player.nes.bus.rom.expansion_audio.as_ref().unwrap();
+        assert_eq!(chip.chip(), ExpansionChip::Vrc6);
+        assert_eq!(chip.output(0), 1.0);
+        assert_eq!(chip.output(1), 0.0);
+    }
+}
@@ -0,0 +1,165 @@
+// Composite NTSC signal simulation
+// Each PPU dot becomes 8 samples of a square wave whose phase follows the
+// 12 sample colour subcarrier, which is then decoded back to YIQ per output
+// column. Colour bleed between neighbouring dots produces the artifact colours,
+// and the subcarrier phase drifting between frames produces dot crawl.
+// Based on the signal levels documented on the NESdev wiki (NTSC video).
+
+use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+// Output width matching nes_ntsc for a 256 pixel picture
+pub const NTSC_WIDTH: usize = 602;
+
+const SAMPLES_PER_DOT: usize = 8;
+const SAMPLES_PER_LINE: usize = SCREEN_WIDTH * SAMPLES_PER_DOT;
+const SUBCARRIER_PERIOD: usize = 12;
+
+// Voltages relative to sync
+const BLACK: f32 = 0.518;
+const WHITE: f32 = 1.962;
+const ATTENUATION: f32 = 0.746;
+const LEVELS_LOW: [f32; 4] = [0.350, 0.518, 0.962, 1.550];
+const LEVELS_HIGH: [f32; 4] = [1.094, 1.506, 1.962, 1.962];
+
+// Decoder hue adjustment, in samples
+const HUE_OFFSET: f32 = 3.9;
+const GAMMA: f32 = 2.0;
+
+pub struct NtscFilter {
+    width: usize,
+    cos_table: [f32; SUBCARRIER_PERIOD],
+    sin_table: [f32; SUBCARRIER_PERIOD],
+}
+
+fn in_colour_phase(hue: usize, phase: usize) -> bool {
+    (hue + phase) % SUBCARRIER_PERIOD < 6
+}
+
+// Normalised signal level of one sample of a pixel
+fn signal(pixel: u16, phase: usize) -> f32 {
+    let hue = (pixel & 0x0F) as usize;
+    let mut level = ((pixel >> 4) & 0x03) as usize;
+    let emphasis = (pixel >> 6) & 0x07;
+
+    // $xE/$xF are forced to level 1 black
+    if hue > 13 {
+        level = 1;
+    }
+    let mut low = LEVELS_LOW[level];
+    let mut high = LEVELS_HIGH[level];
+    if hue == 0 {
+        low = high;
+    }
+    if hue > 12 {
+        high = low;
+    }
+
+    let mut signal = if in_colour_phase(hue, phase) { high } else { low };
+    // Emphasis attenuates the wave during the red, green and blue phases
+    if (emphasis & 0x01 != 0 && in_colour_phase(0, phase))
+        || (emphasis & 0x02 != 0 && in_colour_phase(4, phase))
+        || (emphasis & 0x04 != 0 && in_colour_phase(8, phase))
+    {
+        signal *= ATTENUATION;
+    }
+    (signal - BLACK) / (WHITE - BLACK)
+}
+
+fn gamma_fix(value: f32) -> u8 {
+    let value = if value <= 0.0 { 0.0 } else { value.powf(2.2 / GAMMA) };
+    (value * 255.95).clamp(0.0, 255.0) as u8
+}
+
+impl NtscFilter {
+    pub fn new(width: usize) -> Self {
+        let mut cos_table = [0.0; SUBCARRIER_PERIOD];
+        let mut sin_table = [0.0; SUBCARRIER_PERIOD];
+        for phase in 0..SUBCARRIER_PERIOD {
+            let angle = std::f32::consts::PI * (phase as f32 + HUE_OFFSET) / 6.0;
+            cos_table[phase] = angle.cos();
+            sin_table[phase] = angle.sin();
+        }
+        Self { width, cos_table, sin_table }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    // Converts a framebuffer to RGB, frame_phase is the subcarrier phase at the
+    // start of the frame as tracked by the PPU
+    pub fn apply(&self, framebuffer: &[u16], frame_phase: usize) -> Vec<u8> {
+        let mut rgb = Vec::with_capacity(self.width * SCREEN_HEIGHT * 3);
+        let mut samples = [0.0f32; SAMPLES_PER_LINE];
+
+        for (line, pixels) in framebuffer.chunks(SCREEN_WIDTH).enumerate() {
+            // Each line is 341 dots of 8 samples, and the picture starts at dot 1
+            let line_phase = (frame_phase + (line * 341 + 1) * SAMPLES_PER_DOT) % SUBCARRIER_PERIOD;
+
+            for (x, &pixel) in pixels.iter().enumerate() {
+                for p in 0..SAMPLES_PER_DOT {
+                    let sample = x * SAMPLES_PER_DOT + p;
+                    samples[sample] = signal(pixel, line_phase + sample);
+                }
+            }
+
+            for column in 0..self.width {
+                // Average one subcarrier period around the column's centre
+                let center = column * SAMPLES_PER_LINE / self.width;
+                let begin = center.saturating_sub(6);
+                let end = (center + 6).min(SAMPLES_PER_LINE);
+                let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+                for (p, &level) in samples.iter().enumerate().take(end).skip(begin) {
+                    let level = level / SUBCARRIER_PERIOD as f32;
+                    let phase = (line_phase + p) % SUBCARRIER_PERIOD;
+                    y += level;
+                    i += level * self.cos_table[phase];
+                    q += level * self.sin_table[phase];
+                }
+
+                rgb.push(gamma_fix(y + 0.946882 * i + 0.623557 * q));
+                rgb.push(gamma_fix(y - 0.274788 * i - 0.635691 * q));
+                rgb.push(gamma_fix(y - 1.108545 * i + 1.709007 * q));
+            }
+        }
+        rgb
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    #[test]
+    fn greys_stay_grey() {
+        use crate::ntsc::*;
+        let filter = NtscFilter::new(NTSC_WIDTH);
+
+        // $20 and $0F have no chroma, so every phase decodes to the same grey
+        let mut framebuffer = vec![0x20; SCREEN_WIDTH * SCREEN_HEIGHT];
+        framebuffer[SCREEN_WIDTH..].fill(0x0F);
+        let rgb = filter.apply(&framebuffer, 0);
+        assert_eq!(rgb.len(), NTSC_WIDTH * SCREEN_HEIGHT * 3);
+
+        let white = &rgb[NTSC_WIDTH / 2 * 3..NTSC_WIDTH / 2 * 3 + 3];
+        assert!(white.iter().all(|&c| c > 240));
+        assert!(white.iter().all(|&c| c.abs_diff(white[0]) < 3));
+        let black = &rgb[(NTSC_WIDTH + 100) * 3..(NTSC_WIDTH + 100) * 3 + 3];
+        assert_eq!(black, &[0, 0, 0]);
+    }
+
+    #[test]
+    fn dot_crawl() {
+        use crate::ntsc::*;
+        let filter = NtscFilter::new(NTSC_WIDTH);
+
+        // Alternating columns blend into artifact colours that shift with the phase
+        let framebuffer: Vec<u16> = (0..SCREEN_WIDTH * SCREEN_HEIGHT)
+            .map(|i| if i % 2 == 0 { 0x16 } else { 0x30 })
+            .collect();
+        let first = filter.apply(&framebuffer, 0);
+        let second = filter.apply(&framebuffer, 4);
+        assert_ne!(first, second);
+        assert_eq!(first, filter.apply(&framebuffer, 12));
+    }
+}
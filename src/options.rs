@@ -0,0 +1,304 @@
+// Command line parsing
+// nebulous <rom> [--frames N] [--dump-frames N,N,...] [--out DIR] [--format ppm|png]
+//                [--region ntsc|pal|dendy] [--rom-db FILE] [--ntsc]
+//                [--record-audio FILE] [--sample-rate HZ]
+//                [--mute CH,CH,...] [--solo CH,CH,...] [--chip-volume CHIP=VOL,...]
+//                [--allow-opposite] [--tape-in FILE] [--tape-out FILE]
+//                [--play-movie FILE] [--record-movie FILE] [--load-state FILE] [--save-state FILE]
+//                [--disassemble FILE]
+//                [--trace FILE] [--start-pc ADDR] [--compare-log FILE] [--debug] [--gdb PORT]
+//                [--dap] [--dap-port PORT] [--debug-info FILE] [--watch SPEC]
+//                [--cdl FILE] [--symbols FILE]... [--profile FILE] [--flamegraph FILE]
+//                [--ppu-views FRAME[,SCANLINE,DOT]] [--view-palette N]
+// nebulous <nsf|nsfe> --record-audio FILE [--track N] [--duration SECONDS]
+
+use crate::audio::Channel;
+use crate::expansion::ExpansionChip;
+use crate::nes::DEFAULT_SAMPLE_RATE;
+use crate::region::Region;
+use crate::watch::Watchpoint;
+
+pub struct Options {
+    pub rom_path: String,
+    pub frames: Option<usize>,    // Stop after this many frames
+    pub dump_frames: Vec<usize>,  // Frame numbers to save as images
+    pub out_dir: String,
+    pub format: String,           // Image file extension
+    pub region: Option<Region>,   // Overrides the header and database
+    pub rom_db: Option<String>,
+    pub ntsc_filter: bool,        // Run dumped frames through the composite filter
+    pub record_audio: Option<String>, // WAV file for everything played
+    pub sample_rate: usize,
+    pub muted: Vec<Channel>,
+    pub soloed: Vec<Channel>,
+    pub chip_volumes: Vec<(ExpansionChip, f32)>,
+    pub allow_opposite: bool,    // Pass Left+Right and Up+Down through to the game
+    pub tape_in: Option<String>,  // WAV played into the Data Recorder
+    pub tape_out: Option<String>, // WAV recorded from the Data Recorder
+    pub play_movie: Option<String>,   // .fm2 or .bk2 input to replay
+    pub record_movie: Option<String>, // .fm2 or .bk2 to save the input to
+    pub load_state: Option<String>,   // Savestate to start from instead of power on
+    pub save_state: Option<String>,   // Savestate written when the run ends
+    pub disassemble: Option<String>,  // Write a PRG listing here instead of running
+    pub trace: Option<String>,        // nestest.log style CPU trace
+    pub start_pc: Option<u16>,        // Overrides the reset vector
+    pub compare_log: Option<String>,  // Reference log the trace must match
+    pub debug: bool,             // Interactive debugger on stdin instead of running
+    pub gdb: Option<u16>,        // Wait for a GDB remote client on this port instead of running
+    pub dap: bool,               // Debug adapter protocol on stdio instead of running
+    pub dap_port: Option<u16>,   // Debug adapter protocol over TCP instead
+    pub debug_info: Option<String>, // ld65 .dbg file, otherwise the ROM's name with .dbg
+    pub watchpoints: Vec<Watchpoint>, // Halt the run and report the access
+    pub cdl: Option<String>,     // FCEUX code/data log, continued if it exists and saved on exit
+    pub symbols: Vec<String>,    // Label files for disassembly, traces and the debugger
+    pub profile: Option<String>, // Cycles by routine, written when the run ends
+    pub flamegraph: Option<String>, // The same profile as collapsed stacks
+    pub ppu_views: Option<(usize, Option<(usize, usize)>)>, // Frame, and scanline and dot, to picture PPU memory at
+    pub view_palette: usize,     // Palette 0-7 for pattern tables in the PPU views
+    pub track: Option<usize>,    // NSF track, 1 based
+    pub duration: Option<f64>,   // NSF render length in seconds
+}
+
+impl Options {
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut options = Self {
+            rom_path: String::new(),
+            frames: None,
+            dump_frames: Vec::new(),
+            out_dir: String::from("."),
+            format: String::from("png"),
+            region: None,
+            rom_db: None,
+            ntsc_filter: false,
+            record_audio: None,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            muted: Vec::new(),
+            soloed: Vec::new(),
+            chip_volumes: Vec::new(),
+            allow_opposite: false,
+            tape_in: None,
+            tape_out: None,
+            play_movie: None,
+            record_movie: None,
+            load_state: None,
+            save_state: None,
+            disassemble: None,
+            trace: None,
+            start_pc: None,
+            compare_log: None,
+            debug: false,
+            gdb: None,
+            dap: false,
+            dap_port: None,
+            debug_info: None,
+            watchpoints: Vec::new(),
+            cdl: None,
+            symbols: Vec::new(),
+            profile: None,
+            flamegraph: None,
+            ppu_views: None,
+            view_palette: 0,
+            track: None,
+            duration: None,
+        };
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("Missing value for {}", arg));
+            match arg.as_str() {
+                "--frames" => options.frames = Some(parse_number(value()?)?),
+                "--dump-frames" => {
+                    for frame in value()?.split(',') {
+                        let frame = parse_number(frame)?;
+                        if frame == 0 {
+                            return Err(String::from("Frames are numbered from 1"));
+                        }
+                        options.dump_frames.push(frame);
+                    }
+                    options.dump_frames.sort_unstable();
+                }
+                "--out" => options.out_dir = value()?.clone(),
+                "--format" => {
+                    options.format = value()?.to_lowercase();
+                    if options.format != "ppm" && options.format != "png" {
+                        return Err(format!("Unknown image format {}", options.format));
+                    }
+                }
+                "--region" => {
+                    let name = value()?;
+                    options.region = Some(Region::parse(name).ok_or(format!("Unknown region {}", name))?);
+                }
+                "--rom-db" => options.rom_db = Some(value()?.clone()),
+                "--ntsc" => options.ntsc_filter = true,
+                "--record-audio" => options.record_audio = Some(value()?.clone()),
+                "--sample-rate" => {
+                    options.sample_rate = parse_number(value()?)?;
+                    if options.sample_rate == 0 {
+                        return Err(String::from("Sample rate must be above 0"));
+                    }
+                }
+                "--mute" => options.muted.extend(parse_channels(value()?)?),
+                "--solo" => options.soloed.extend(parse_channels(value()?)?),
+                "--chip-volume" => {
+                    for setting in value()?.split(',') {
+                        let (chip, volume) = setting.split_once('=').ok_or(format!("Expected CHIP=VOLUME, got {}", setting))?;
+                        let chip = ExpansionChip::parse(chip).ok_or(format!("Unknown sound chip {}", chip))?;
+                        let volume = volume.trim().parse().map_err(|_| format!("Invalid volume {}", volume))?;
+                        options.chip_volumes.push((chip, volume));
+                    }
+                }
+                "--allow-opposite" => options.allow_opposite = true,
+                "--tape-in" => options.tape_in = Some(value()?.clone()),
+                "--tape-out" => options.tape_out = Some(value()?.clone()),
+                "--play-movie" => options.play_movie = Some(value()?.clone()),
+                "--record-movie" => options.record_movie = Some(value()?.clone()),
+                "--load-state" => options.load_state = Some(value()?.clone()),
+                "--save-state" => options.save_state = Some(value()?.clone()),
+                "--disassemble" => options.disassemble = Some(value()?.clone()),
+                "--trace" => options.trace = Some(value()?.clone()),
+                "--start-pc" => {
+                    let text = value()?;
+                    let addr = u16::from_str_radix(text.trim_start_matches('$'), 16);
+                    options.start_pc = Some(addr.map_err(|_| format!("Invalid address {}", text))?);
+                }
+                "--compare-log" => options.compare_log = Some(value()?.clone()),
+                "--debug" => options.debug = true,
+                "--gdb" => {
+                    let text = value()?;
+                    options.gdb = Some(text.trim().parse().map_err(|_| format!("Invalid port {}", text))?);
+                }
+                "--dap" => options.dap = true,
+                "--dap-port" => {
+                    let text = value()?;
+                    options.dap_port = Some(text.trim().parse().map_err(|_| format!("Invalid port {}", text))?);
+                }
+                "--debug-info" => options.debug_info = Some(value()?.clone()),
+                "--watch" => options.watchpoints.push(Watchpoint::parse(value()?)?),
+                "--cdl" => options.cdl = Some(value()?.clone()),
+                "--symbols" => options.symbols.push(value()?.clone()),
+                "--profile" => options.profile = Some(value()?.clone()),
+                "--flamegraph" => options.flamegraph = Some(value()?.clone()),
+                "--ppu-views" => options.ppu_views = Some(parse_view_position(value()?)?),
+                "--view-palette" => {
+                    options.view_palette = parse_number(value()?)?;
+                    if options.view_palette > 7 {
+                        return Err(String::from("Palettes are numbered 0-7"));
+                    }
+                }
+                "--track" => {
+                    let track = parse_number(value()?)?;
+                    if track == 0 {
+                        return Err(String::from("Tracks are numbered from 1"));
+                    }
+                    options.track = Some(track);
+                }
+                "--duration" => {
+                    let text = value()?;
+                    let seconds: f64 = text.trim().parse().map_err(|_| format!("Invalid duration {}", text))?;
+                    if seconds.is_nan() || seconds <= 0.0 {
+                        return Err(format!("Invalid duration {}", text));
+                    }
+                    options.duration = Some(seconds);
+                }
+                _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+                _ => options.rom_path = arg.clone(),
+            }
+        }
+
+        if options.tape_in.is_some() && options.tape_out.is_some() {
+            return Err(String::from("The Data Recorder can't play and record at once"));
+        }
+        if options.load_state.is_some() && options.play_movie.is_some() {
+            return Err(String::from("--load-state can't be used with --play-movie, the movie says where it starts"));
+        }
+        if options.compare_log.is_some() && options.trace.is_none() {
+            return Err(String::from("--compare-log needs --trace FILE"));
+        }
+        if options.rom_path.is_empty() {
+            return Err(String::from("No ROM file given"));
+        }
+        Ok(options)
+    }
+
+    // Music files run in the NSF player instead of as a cartridge
+    pub fn is_nsf(&self) -> bool {
+        let path = self.rom_path.to_lowercase();
+        path.ends_with(".nsf") || path.ends_with(".nsfe")
+    }
+
+    // Frame after which the emulator can stop, None runs forever
+    pub fn last_frame(&self) -> Option<usize> {
+        let capture = self.dump_frames.last().copied().max(self.ppu_views.map(|(frame, _)| frame));
+        match (self.frames, capture) {
+            (Some(frames), Some(capture)) => Some(frames.max(capture)),
+            (Some(frames), None) => Some(frames),
+            (None, capture) => capture,
+        }
+    }
+}
+
+fn parse_channels(text: &str) -> Result<Vec<Channel>, String> {
+    text.split(',')
+        .map(|name| Channel::parse(name.trim()).ok_or(format!("Unknown channel {}", name)))
+        .collect()
+}
+
+// "FRAME" for the end of a frame or "FRAME,SCANLINE,DOT" for a point in it
+fn parse_view_position(text: &str) -> Result<(usize, Option<(usize, usize)>), String> {
+    let numbers = text.split(',').map(parse_number).collect::<Result<Vec<usize>, String>>()?;
+    match numbers[..] {
+        [0, ..] => Err(String::from("Frames are numbered from 1")),
+        [frame] => Ok((frame, None)),
+        [_, scanline, dot] if scanline >= 312 || dot >= 341 => Err(format!("No scanline {} dot {}", scanline, dot)),
+        [frame, scanline, dot] => Ok((frame, Some((scanline, dot)))),
+        _ => Err(format!("Invalid PPU view position {}", text)),
+    }
+}
+
+fn parse_number(text: &str) -> Result<usize, String> {
+    text.trim().parse().map_err(|_| format!("Invalid number {}", text))
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    fn parse(args: &str) -> Result<crate::options::Options, String> {
+        let args: Vec<String> = args.split_whitespace().map(String::from).collect();
+        crate::options::Options::parse(&args)
+    }
+
+    #[test]
+    fn dump_frames() {
+        let options = parse("game.nes --dump-frames 30,5,12").unwrap();
+        assert_eq!(options.dump_frames, vec![5, 12, 30]);
+        assert_eq!(options.rom_path, "game.nes");
+
+        assert_eq!(parse("game.nes --dump-frames 0").err().unwrap(), "Frames are numbered from 1");
+        assert_eq!(parse("game.nes --dump-frames 3,x").err().unwrap(), "Invalid number x");
+        assert_eq!(parse("game.nes --dump-frames").err().unwrap(), "Missing value for --dump-frames");
+    }
+
+    #[test]
+    fn conflicting_options() {
+        assert_eq!(parse("game.nes --compare-log nestest.log").err().unwrap(), "--compare-log needs --trace FILE");
+        assert!(parse("game.nes --trace out.log --compare-log nestest.log").is_ok());
+        assert_eq!(
+            parse("game.nes --tape-in in.wav --tape-out out.wav").err().unwrap(),
+            "The Data Recorder can't play and record at once"
+        );
+        assert!(parse("game.nes --load-state a.sav --record-movie a.fm2").is_ok());
+        assert!(parse("game.nes --load-state a.sav --play-movie a.fm2").is_err());
+        assert_eq!(parse("--frames 10").err().unwrap(), "No ROM file given");
+    }
+
+    #[test]
+    fn last_frame() {
+        assert_eq!(parse("game.nes").unwrap().last_frame(), None);
+        assert_eq!(parse("game.nes --frames 10").unwrap().last_frame(), Some(10));
+        // Runs on to the last frame that has to be captured
+        assert_eq!(parse("game.nes --frames 10 --dump-frames 4,20").unwrap().last_frame(), Some(20));
+        assert_eq!(parse("game.nes --frames 10 --dump-frames 4").unwrap().last_frame(), Some(10));
+        assert_eq!(parse("game.nes --dump-frames 4 --ppu-views 7,100,5").unwrap().last_frame(), Some(7));
+    }
+}
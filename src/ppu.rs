@@ -0,0 +1,892 @@
+// 2C02 Picture Processing Unit
+// 341 dots per scanline, 262 scanlines per frame (312 on PAL and Dendy)
+// Scanlines 0-239 visible, 240 post-render, 241-260 vblank, 261 pre-render
+
+use crate::cdl::{self, CodeDataLog};
+use crate::region::Region;
+use crate::rom::{Mirroring, Rom};
+use crate::savestate::State;
+
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+
+const DOTS_PER_SCANLINE: usize = 341;
+
+#[allow(dead_code)]
+enum PpuCtrl {
+    Increment = 1 << 2,       // VRAM increment (0: across, 1: down)
+    SpriteTable = 1 << 3,     // 8x8 sprite pattern table
+    BackgroundTable = 1 << 4, // Background pattern table
+    SpriteSize = 1 << 5,      // 8x16 sprites
+    NmiEnable = 1 << 7,       // NMI at start of vblank
+}
+
+enum PpuMask {
+    Greyscale = 1 << 0,
+    BackgroundLeft = 1 << 1, // Show background in leftmost 8 pixels
+    SpritesLeft = 1 << 2,    // Show sprites in leftmost 8 pixels
+    Background = 1 << 3,
+    Sprites = 1 << 4,
+}
+
+enum PpuStatus {
+    Overflow = 1 << 5,
+    SpriteZeroHit = 1 << 6,
+    VBlank = 1 << 7,
+}
+
+pub struct Ppu {
+    ctrl: u8,
+    mask: u8,
+    status: u8,
+    oam_addr: u8,
+    pub oam: [u8; 256],
+    vram: [u8; 0x1000], // 2 KB on the console, 4 KB for four-screen carts
+    pub palette: [u8; 32],
+
+    // Loopy scroll registers
+    v: u16,  // Current VRAM address
+    t: u16,  // Temporary VRAM address
+    x: u8,   // Fine X scroll
+    w: bool, // Write toggle for $2005/$2006
+
+    read_buffer: u8, // $2007 read buffer
+    data_latch: u8,  // Last value written to any register
+
+    pub dot: usize,
+    pub scanline: usize,
+    pub frame_count: usize,
+    pub frame_complete: bool,
+    pub nmi: bool, // NMI request for the CPU
+    pub region: Region,
+    odd_frame: bool,
+    skipped_dot: bool,
+    pub frame_phase: usize, // Colour subcarrier phase (0-11) at the start of the frame
+    suppress_vblank: bool,           // $2002 read raced the vblank flag
+    oam_corrupt_row: Option<usize>, // Set when rendering is cut during sprite evaluation
+
+    // Background pipeline
+    bg_next_tile: u8,
+    bg_next_attr: u8,
+    bg_next_lo: u8,
+    bg_next_hi: u8,
+    bg_shift_lo: u16,
+    bg_shift_hi: u16,
+    bg_attr_shift_lo: u16,
+    bg_attr_shift_hi: u16,
+
+    // Sprites selected for the current scanline
+    sprite_count: usize,
+    sprite_x: [u8; 8],
+    sprite_attr: [u8; 8],
+    sprite_lo: [u8; 8],
+    sprite_hi: [u8; 8],
+    sprite_zero_on_line: bool,
+
+    // Palette index (bits 0-5) plus PPUMASK emphasis (bits 6-8) per pixel
+    framebuffer: Vec<u16>,
+}
+
+impl Ppu {
+    pub fn new() -> Self {
+        Self {
+            ctrl: 0,
+            mask: 0,
+            status: 0,
+            oam_addr: 0,
+            oam: [0; 256],
+            vram: [0; 0x1000],
+            palette: [0; 32],
+
+            v: 0,
+            t: 0,
+            x: 0,
+            w: false,
+
+            read_buffer: 0,
+            data_latch: 0,
+
+            dot: 0,
+            scanline: 0,
+            frame_count: 0,
+            frame_complete: false,
+            nmi: false,
+            region: Region::Ntsc,
+            odd_frame: false,
+            skipped_dot: false,
+            frame_phase: 0,
+            suppress_vblank: false,
+            oam_corrupt_row: None,
+
+            bg_next_tile: 0,
+            bg_next_attr: 0,
+            bg_next_lo: 0,
+            bg_next_hi: 0,
+            bg_shift_lo: 0,
+            bg_shift_hi: 0,
+            bg_attr_shift_lo: 0,
+            bg_attr_shift_hi: 0,
+
+            sprite_count: 0,
+            sprite_x: [0; 8],
+            sprite_attr: [0; 8],
+            sprite_lo: [0; 8],
+            sprite_hi: [0; 8],
+            sprite_zero_on_line: false,
+
+            framebuffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+        }
+    }
+
+    // The region is a setting, the picture is redrawn every frame and the
+    // frame count restarts
+    pub fn sync_state(&mut self, state: &mut State) {
+        state.u8(&mut self.ctrl);
+        state.u8(&mut self.mask);
+        state.u8(&mut self.status);
+        state.u8(&mut self.oam_addr);
+        state.bytes(&mut self.oam);
+        state.bytes(&mut self.vram);
+        state.bytes(&mut self.palette);
+
+        state.u16(&mut self.v);
+        state.u16(&mut self.t);
+        state.u8(&mut self.x);
+        state.bool(&mut self.w);
+
+        state.u8(&mut self.read_buffer);
+        state.u8(&mut self.data_latch);
+
+        state.usize(&mut self.dot);
+        state.usize(&mut self.scanline);
+        state.bool(&mut self.frame_complete);
+        state.bool(&mut self.nmi);
+        state.bool(&mut self.odd_frame);
+        state.bool(&mut self.skipped_dot);
+        state.usize(&mut self.frame_phase);
+        state.bool(&mut self.suppress_vblank);
+        state.option(&mut self.oam_corrupt_row, State::usize);
+
+        state.u8(&mut self.bg_next_tile);
+        state.u8(&mut self.bg_next_attr);
+        state.u8(&mut self.bg_next_lo);
+        state.u8(&mut self.bg_next_hi);
+        state.u16(&mut self.bg_shift_lo);
+        state.u16(&mut self.bg_shift_hi);
+        state.u16(&mut self.bg_attr_shift_lo);
+        state.u16(&mut self.bg_attr_shift_hi);
+
+        state.usize(&mut self.sprite_count);
+        state.bytes(&mut self.sprite_x);
+        state.bytes(&mut self.sprite_attr);
+        state.bytes(&mut self.sprite_lo);
+        state.bytes(&mut self.sprite_hi);
+        state.bool(&mut self.sprite_zero_on_line);
+    }
+
+    // Last completed picture, one entry per pixel in row-major order
+    pub fn framebuffer(&self) -> &[u16] {
+        &self.framebuffer
+    }
+
+    // For tools and tests that draw straight into the picture
+    pub fn framebuffer_mut(&mut self) -> &mut [u16] {
+        &mut self.framebuffer
+    }
+
+    fn ctrl(&self, flag: PpuCtrl) -> bool {
+        self.ctrl & flag as u8 != 0
+    }
+
+    fn mask(&self, flag: PpuMask) -> bool {
+        self.mask & flag as u8 != 0
+    }
+
+    fn set_status(&mut self, flag: PpuStatus, flag_set: bool) {
+        if flag_set {
+            self.status |= flag as u8;
+        } else {
+            self.status &= !(flag as u8);
+        }
+    }
+
+    pub fn rendering_enabled(&self) -> bool {
+        self.mask(PpuMask::Background) || self.mask(PpuMask::Sprites)
+    }
+
+    // Pre-render and visible scanlines, where fetches happen if rendering is on
+    fn render_line(&self) -> bool {
+        self.scanline < SCREEN_HEIGHT || self.scanline == self.pre_render_scanline()
+    }
+
+    fn pre_render_scanline(&self) -> usize {
+        self.region.scanlines_per_frame() - 1
+    }
+
+    fn rendering_now(&self) -> bool {
+        self.render_line() && self.rendering_enabled()
+    }
+
+    // $2007 access moves v by 1 or 32, except during rendering where the
+    // coarse X and Y incrementers both fire instead
+    fn increment_vram_addr(&mut self) {
+        if self.rendering_now() {
+            self.increment_x();
+            self.increment_y();
+        } else {
+            let increment = if self.ctrl(PpuCtrl::Increment) { 32 } else { 1 };
+            self.v = self.v.wrapping_add(increment) & 0x7FFF;
+        }
+    }
+
+    // PPU address the next $2007 access uses
+    pub fn vram_addr(&self) -> u16 {
+        self.v & 0x3FFF
+    }
+
+    // OAM byte the next $2004 access uses
+    pub fn oam_addr(&self) -> u8 {
+        self.oam_addr
+    }
+
+    // Pattern table base address for the background
+    pub fn background_table(&self) -> usize {
+        if self.ctrl(PpuCtrl::BackgroundTable) { 0x1000 } else { 0x0000 }
+    }
+
+    // Pattern table base address for 8x8 sprites, 8x16 sprites pick theirs by tile
+    pub fn sprite_table(&self) -> usize {
+        if self.ctrl(PpuCtrl::SpriteTable) { 0x1000 } else { 0x0000 }
+    }
+
+    // Scroll position the game last set through $2000, $2005 and $2006, in
+    // pixels across the four nametables (0-511, 0-479)
+    pub fn scroll(&self) -> (usize, usize) {
+        let t = self.t as usize;
+        let x = (t >> 10 & 1) * 256 + (t & 0x1F) * 8 + self.x as usize;
+        let y = (t >> 11 & 1) * 240 + (t >> 5 & 0x1F) * 8 + (t >> 12 & 7);
+        (x, y)
+    }
+
+    // CPU side register access, addr is already folded into $2000-$2007
+    pub fn cpu_read(&mut self, addr: usize, rom: &Rom) -> u8 {
+        match addr {
+            0x2002 => {
+                // Reading on the dot vblank sets hides the flag for the whole frame,
+                // reading just after it still sees the flag but cancels the NMI
+                if self.scanline == self.region.vblank_scanline() {
+                    match self.dot {
+                        1 => self.suppress_vblank = true,
+                        2 | 3 => self.nmi = false,
+                        _ => {}
+                    }
+                }
+                let data = (self.status & 0xE0) | (self.data_latch & 0x1F);
+                self.set_status(PpuStatus::VBlank, false);
+                self.w = false;
+                data
+            }
+            0x2004 => {
+                // Secondary OAM clear drives the bus high for the first 64 dots
+                if self.rendering_now() && (1..=64).contains(&self.dot) {
+                    return 0xFF;
+                }
+                let data = self.oam[self.oam_addr as usize];
+                // Attribute bits 2-4 are not implemented in OAM
+                if self.oam_addr & 0x03 == 0x02 { data & 0xE3 } else { data }
+            }
+            0x2007 => {
+                let addr = (self.v & 0x3FFF) as usize;
+                // Palette reads are immediate, the buffer gets the nametable underneath
+                let data = if addr >= 0x3F00 {
+                    self.read_buffer = self.ppu_read(addr - 0x1000, rom);
+                    self.ppu_read(addr, rom)
+                } else {
+                    let data = self.read_buffer;
+                    self.read_buffer = self.ppu_read(addr, rom);
+                    data
+                };
+                self.increment_vram_addr();
+                data
+            }
+            // Write-only registers return the stale bus value
+            _ => self.data_latch,
+        }
+    }
+
+    pub fn cpu_write(&mut self, addr: usize, data: u8, rom: &mut Rom) {
+        self.data_latch = data;
+        match addr {
+            0x2000 => {
+                let nmi_was_enabled = self.ctrl(PpuCtrl::NmiEnable);
+                self.ctrl = data;
+                self.t = (self.t & 0xF3FF) | ((data as u16 & 0x03) << 10);
+                // Enabling NMI during vblank triggers one immediately
+                if !nmi_was_enabled && self.ctrl(PpuCtrl::NmiEnable) && self.status & PpuStatus::VBlank as u8 != 0 {
+                    self.nmi = true;
+                }
+            }
+            0x2001 => {
+                let was_rendering = self.rendering_now();
+                self.mask = data;
+                // Evaluation reads a sprite every 2 dots from dot 65, so the row
+                // is the one holding the sprite it had reached
+                if was_rendering && !self.rendering_enabled() && (65..=256).contains(&self.dot) {
+                    let sprite = (self.dot - 65) / 2;
+                    self.oam_corrupt_row = Some((sprite * 4 % 256) & 0xF8);
+                }
+            }
+            0x2003 => self.oam_addr = data,
+            0x2004 => {
+                // Writes during rendering are dropped but still bump the sprite index
+                if self.rendering_now() {
+                    self.oam_addr = self.oam_addr.wrapping_add(4);
+                    return;
+                }
+                self.oam[self.oam_addr as usize] = data;
+                self.oam_addr = self.oam_addr.wrapping_add(1);
+            }
+            0x2005 => {
+                if !self.w {
+                    self.t = (self.t & 0xFFE0) | (data as u16 >> 3);
+                    self.x = data & 0x07;
+                } else {
+                    self.t = (self.t & 0x8C1F) | ((data as u16 & 0x07) << 12) | ((data as u16 & 0xF8) << 2);
+                }
+                self.w = !self.w;
+            }
+            0x2006 => {
+                if !self.w {
+                    self.t = (self.t & 0x80FF) | ((data as u16 & 0x3F) << 8);
+                } else {
+                    self.t = (self.t & 0xFF00) | data as u16;
+                    self.v = self.t;
+                }
+                self.w = !self.w;
+            }
+            0x2007 => {
+                self.ppu_write((self.v & 0x3FFF) as usize, data, rom);
+                self.increment_vram_addr();
+            }
+            _ => {}
+        }
+    }
+
+    // Maps a $2000-$3EFF address onto the internal nametable RAM
+    fn nametable_addr(addr: usize, mirroring: Mirroring) -> usize {
+        let addr = addr & 0x0FFF;
+        let table = addr / 0x400;
+        let offset = addr & 0x3FF;
+        match mirroring {
+            Mirroring::Vertical => (table & 1) * 0x400 + offset,
+            Mirroring::Horizontal => (table >> 1) * 0x400 + offset,
+            Mirroring::FourScreen => addr,
+        }
+    }
+
+    // $3F10/$3F14/$3F18/$3F1C mirror the backdrop entries
+    fn palette_addr(addr: usize) -> usize {
+        let addr = addr & 0x1F;
+        if addr & 0x13 == 0x10 { addr & 0x0F } else { addr }
+    }
+
+    pub fn ppu_read(&self, addr: usize, rom: &Rom) -> u8 {
+        let addr = addr & 0x3FFF;
+        match addr {
+            0x0000..=0x1FFF => rom.ppu_read(addr),
+            0x2000..=0x3EFF => self.vram[Self::nametable_addr(addr, rom.mirroring)],
+            _ => self.palette[Self::palette_addr(addr)],
+        }
+    }
+
+    pub fn ppu_write(&mut self, addr: usize, data: u8, rom: &mut Rom) {
+        let addr = addr & 0x3FFF;
+        match addr {
+            0x0000..=0x1FFF => rom.ppu_write(addr, data),
+            0x2000..=0x3EFF => self.vram[Self::nametable_addr(addr, rom.mirroring)] = data,
+            _ => self.palette[Self::palette_addr(addr)] = data & 0x3F,
+        }
+    }
+
+    // Coarse X increment, wrapping into the horizontally adjacent nametable
+    fn increment_x(&mut self) {
+        if self.v & 0x001F == 31 {
+            self.v &= !0x001F;
+            self.v ^= 0x0400;
+        } else {
+            self.v += 1;
+        }
+    }
+
+    // Fine Y increment, carrying into coarse Y and the vertical nametable
+    fn increment_y(&mut self) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+            return;
+        }
+        self.v &= !0x7000;
+        let mut coarse_y = (self.v & 0x03E0) >> 5;
+        if coarse_y == 29 {
+            coarse_y = 0;
+            self.v ^= 0x0800;
+        } else if coarse_y == 31 {
+            coarse_y = 0;
+        } else {
+            coarse_y += 1;
+        }
+        self.v = (self.v & !0x03E0) | (coarse_y << 5);
+    }
+
+    fn transfer_x(&mut self) {
+        self.v = (self.v & !0x041F) | (self.t & 0x041F);
+    }
+
+    fn transfer_y(&mut self) {
+        self.v = (self.v & !0x7BE0) | (self.t & 0x7BE0);
+    }
+
+    fn load_bg_shifters(&mut self) {
+        self.bg_shift_lo = (self.bg_shift_lo & 0xFF00) | self.bg_next_lo as u16;
+        self.bg_shift_hi = (self.bg_shift_hi & 0xFF00) | self.bg_next_hi as u16;
+        let attr_lo = if self.bg_next_attr & 0x01 != 0 { 0xFF } else { 0x00 };
+        let attr_hi = if self.bg_next_attr & 0x02 != 0 { 0xFF } else { 0x00 };
+        self.bg_attr_shift_lo = (self.bg_attr_shift_lo & 0xFF00) | attr_lo;
+        self.bg_attr_shift_hi = (self.bg_attr_shift_hi & 0xFF00) | attr_hi;
+    }
+
+    fn update_bg_shifters(&mut self) {
+        if self.mask(PpuMask::Background) {
+            self.bg_shift_lo <<= 1;
+            self.bg_shift_hi <<= 1;
+            self.bg_attr_shift_lo <<= 1;
+            self.bg_attr_shift_hi <<= 1;
+        }
+    }
+
+    // One step of the 8 dot nametable/attribute/pattern fetch cycle
+    fn fetch_background(&mut self, rom: &Rom, cdl: Option<&mut CodeDataLog>) {
+        let table = self.background_table();
+        let fine_y = ((self.v >> 12) & 0x07) as usize;
+        match (self.dot - 1) % 8 {
+            0 => {
+                self.load_bg_shifters();
+                self.bg_next_tile = self.ppu_read(0x2000 | (self.v & 0x0FFF) as usize, rom);
+            }
+            2 => {
+                let v = self.v as usize;
+                let addr = 0x23C0 | (v & 0x0C00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07);
+                let mut attr = self.ppu_read(addr, rom);
+                if v & 0x0040 != 0 {
+                    attr >>= 4;
+                }
+                if v & 0x0002 != 0 {
+                    attr >>= 2;
+                }
+                self.bg_next_attr = attr & 0x03;
+            }
+            4 => {
+                let addr = table + self.bg_next_tile as usize * 16 + fine_y;
+                self.bg_next_lo = self.ppu_read(addr, rom);
+                if let Some(cdl) = cdl {
+                    cdl.log_chr(rom, addr, cdl::RENDERED);
+                }
+            }
+            6 => {
+                let addr = table + self.bg_next_tile as usize * 16 + fine_y + 8;
+                self.bg_next_hi = self.ppu_read(addr, rom);
+                if let Some(cdl) = cdl {
+                    cdl.log_chr(rom, addr, cdl::RENDERED);
+                }
+            }
+            7 => self.increment_x(),
+            _ => {}
+        }
+    }
+
+    pub fn sprite_height(&self) -> usize {
+        if self.ctrl(PpuCtrl::SpriteSize) { 16 } else { 8 }
+    }
+
+    // Selects up to 8 sprites for the next scanline and fetches their patterns
+    fn evaluate_sprites(&mut self, rom: &Rom, mut cdl: Option<&mut CodeDataLog>) {
+        let height = self.sprite_height();
+        self.sprite_count = 0;
+        self.sprite_zero_on_line = false;
+
+        for i in 0..64 {
+            let y = self.oam[i * 4] as usize;
+            if self.scanline < y || self.scanline - y >= height {
+                continue;
+            }
+            if self.sprite_count == 8 {
+                self.set_status(PpuStatus::Overflow, true);
+                break;
+            }
+            let tile = self.oam[i * 4 + 1] as usize;
+            let attr = self.oam[i * 4 + 2];
+            let mut row = self.scanline - y;
+            if attr & 0x80 != 0 {
+                row = height - 1 - row;
+            }
+
+            let addr = if height == 16 {
+                let table = (tile & 0x01) * 0x1000;
+                let tile = (tile & 0xFE) + row / 8;
+                table + tile * 16 + row % 8
+            } else {
+                let table = self.sprite_table();
+                table + tile * 16 + row
+            };
+            let mut lo = self.ppu_read(addr, rom);
+            let mut hi = self.ppu_read(addr + 8, rom);
+            if let Some(cdl) = cdl.as_deref_mut() {
+                cdl.log_chr(rom, addr, cdl::RENDERED);
+                cdl.log_chr(rom, addr + 8, cdl::RENDERED);
+            }
+            if attr & 0x40 != 0 {
+                lo = lo.reverse_bits();
+                hi = hi.reverse_bits();
+            }
+
+            let n = self.sprite_count;
+            self.sprite_x[n] = self.oam[i * 4 + 3];
+            self.sprite_attr[n] = attr;
+            self.sprite_lo[n] = lo;
+            self.sprite_hi[n] = hi;
+            if i == 0 {
+                self.sprite_zero_on_line = true;
+            }
+            self.sprite_count += 1;
+        }
+    }
+
+    // Composes background and sprite pixels for the current dot
+    fn render_pixel(&mut self) {
+        let x = self.dot - 1;
+        let left_edge = x < 8;
+
+        let mut bg_pixel = 0;
+        let mut bg_palette = 0;
+        if self.mask(PpuMask::Background) && (!left_edge || self.mask(PpuMask::BackgroundLeft)) {
+            let bit = 0x8000 >> self.x;
+            bg_pixel = ((self.bg_shift_hi & bit != 0) as u8) << 1 | (self.bg_shift_lo & bit != 0) as u8;
+            bg_palette = ((self.bg_attr_shift_hi & bit != 0) as u8) << 1 | (self.bg_attr_shift_lo & bit != 0) as u8;
+        }
+
+        let mut sprite_pixel = 0;
+        let mut sprite_palette = 0;
+        let mut sprite_behind = false;
+        if self.mask(PpuMask::Sprites) && (!left_edge || self.mask(PpuMask::SpritesLeft)) {
+            for i in 0..self.sprite_count {
+                let column = x as isize - self.sprite_x[i] as isize;
+                if !(0..8).contains(&column) {
+                    continue;
+                }
+                let bit = 0x80 >> column;
+                let pixel = ((self.sprite_hi[i] & bit != 0) as u8) << 1 | (self.sprite_lo[i] & bit != 0) as u8;
+                if pixel == 0 {
+                    continue;
+                }
+                if i == 0 && self.sprite_zero_on_line && bg_pixel != 0 && x != 255 {
+                    self.set_status(PpuStatus::SpriteZeroHit, true);
+                }
+                sprite_pixel = pixel;
+                sprite_palette = (self.sprite_attr[i] & 0x03) + 4;
+                sprite_behind = self.sprite_attr[i] & 0x20 != 0;
+                break;
+            }
+        }
+
+        let palette_index = match (bg_pixel, sprite_pixel) {
+            (0, 0) => 0,
+            (0, _) => sprite_palette << 2 | sprite_pixel,
+            (_, 0) => bg_palette << 2 | bg_pixel,
+            _ if sprite_behind => bg_palette << 2 | bg_pixel,
+            _ => sprite_palette << 2 | sprite_pixel,
+        };
+
+        let mut colour = self.palette[Self::palette_addr(palette_index as usize)] as u16;
+        if self.mask(PpuMask::Greyscale) {
+            colour &= 0x30;
+        }
+        let emphasis = (self.mask as u16 >> 5) << 6;
+        self.framebuffer[self.scanline * SCREEN_WIDTH + x] = colour | emphasis;
+    }
+
+    // Advances the PPU by one dot, logging pattern fetches to cdl
+    pub fn clock(&mut self, rom: &Rom, mut cdl: Option<&mut CodeDataLog>) {
+        let visible = self.scanline < SCREEN_HEIGHT;
+        let pre_render = self.scanline == self.pre_render_scanline();
+
+        if pre_render && self.dot == 1 {
+            self.set_status(PpuStatus::VBlank, false);
+            self.set_status(PpuStatus::SpriteZeroHit, false);
+            self.set_status(PpuStatus::Overflow, false);
+        }
+
+        if self.rendering_now() {
+            // Re-enabling rendering after cutting it mid evaluation copies
+            // the first OAM row over the row that was being read
+            if self.dot == 1 {
+                if let Some(row) = self.oam_corrupt_row.take() {
+                    self.oam.copy_within(0..8, row);
+                }
+            }
+            if (2..=257).contains(&self.dot) || (321..=337).contains(&self.dot) {
+                self.update_bg_shifters();
+                self.fetch_background(rom, cdl.as_deref_mut());
+            }
+            if self.dot == 256 {
+                self.increment_y();
+            }
+            if self.dot == 257 {
+                self.load_bg_shifters();
+                self.transfer_x();
+            }
+            if pre_render && (280..=304).contains(&self.dot) {
+                self.transfer_y();
+            }
+            if (257..=320).contains(&self.dot) {
+                self.oam_addr = 0;
+            }
+        }
+
+        if visible && (1..=256).contains(&self.dot) {
+            self.render_pixel();
+        }
+
+        // Sprites are evaluated a line ahead of where they are drawn, and none
+        // are on the pre-render line for the first line to draw
+        if visible && self.dot == 257 {
+            if self.rendering_enabled() {
+                self.evaluate_sprites(rom, cdl);
+            } else {
+                self.sprite_count = 0;
+            }
+        }
+        if pre_render && self.dot == 257 {
+            self.sprite_count = 0;
+            self.sprite_zero_on_line = false;
+        }
+
+        if self.scanline == self.region.vblank_scanline() && self.dot == 1 {
+            if !self.suppress_vblank {
+                self.set_status(PpuStatus::VBlank, true);
+                if self.ctrl(PpuCtrl::NmiEnable) {
+                    self.nmi = true;
+                }
+            }
+            self.suppress_vblank = false;
+        }
+
+        self.dot += 1;
+        // With rendering on, odd frames drop the last dot of the pre-render line
+        if pre_render && self.dot == DOTS_PER_SCANLINE - 1 && self.odd_frame
+            && self.rendering_enabled() && self.region.skips_odd_frame_dot()
+        {
+            self.dot += 1;
+            self.skipped_dot = true;
+        }
+        if self.dot == DOTS_PER_SCANLINE {
+            self.dot = 0;
+            self.scanline += 1;
+            if self.scanline == SCREEN_HEIGHT {
+                self.frame_count += 1;
+                self.frame_complete = true;
+            }
+            if self.scanline == self.region.scanlines_per_frame() {
+                self.scanline = 0;
+                self.odd_frame = !self.odd_frame;
+                // Every dot is 8 of the 12 subcarrier samples, so a 341 dot line
+                // shifts the phase by 4 and whole frames leave a dot crawl
+                let dots = DOTS_PER_SCANLINE * self.region.scanlines_per_frame() - self.skipped_dot as usize;
+                self.frame_phase = (self.frame_phase + dots * 8) % 12;
+                self.skipped_dot = false;
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    #[test]
+    fn render_background_tile() {
+        use crate::bus::Bus;
+        use crate::ppu::SCREEN_WIDTH;
+        let mut bus = Bus::new();
+
+        // Tile 1 is a solid block of colour 3
+        for row in 0..8 {
+            bus.rom.chr[16 + row] = 0xFF;
+            bus.rom.chr[24 + row] = 0xFF;
+        }
+
+        // Put tile 1 at the top left of nametable 0
+        bus.write(0x2006, 0x20);
+        bus.write(0x2006, 0x00);
+        bus.write(0x2007, 0x01);
+
+        // Backdrop and background palette 0 colour 3
+        bus.write(0x2006, 0x3F);
+        bus.write(0x2006, 0x00);
+        bus.write(0x2007, 0x0F);
+        bus.write(0x2006, 0x3F);
+        bus.write(0x2006, 0x03);
+        bus.write(0x2007, 0x21);
+
+        // Reset scroll and enable background in the left column
+        bus.write(0x2000, 0x00);
+        bus.write(0x2005, 0x00);
+        bus.write(0x2005, 0x00);
+        bus.write(0x2001, 0b0000_1010);
+
+        // First frame primes the pre-render line, second is clean
+        for _ in 0..2 {
+            bus.ppu.frame_complete = false;
+            while !bus.ppu.frame_complete {
+                bus.ppu.clock(&bus.rom, None);
+            }
+        }
+
+        let frame = bus.ppu.framebuffer();
+        assert_eq!(frame[0], 0x21);
+        assert_eq!(frame[7 * SCREEN_WIDTH + 7], 0x21);
+        assert_eq!(frame[8], 0x0F);
+        assert_eq!(frame[8 * SCREEN_WIDTH], 0x0F);
+    }
+
+    #[test]
+    fn no_sprites_on_first_line() {
+        use crate::bus::Bus;
+        use crate::ppu::SCREEN_WIDTH;
+        let mut bus = Bus::new();
+
+        // Tile 1 is a solid block of colour 1, sprite 0 uses it on lines 237-244
+        for row in 0..8 {
+            bus.rom.chr[16 + row] = 0xFF;
+        }
+        bus.ppu.oam[..4].copy_from_slice(&[236, 0x01, 0x00, 0x00]);
+        bus.write(0x2006, 0x3F);
+        bus.write(0x2006, 0x11);
+        bus.write(0x2007, 0x16);
+        bus.write(0x2001, 0b0001_0100);
+
+        for _ in 0..2 {
+            bus.ppu.frame_complete = false;
+            while !bus.ppu.frame_complete {
+                bus.ppu.clock(&bus.rom, None);
+            }
+        }
+
+        // Drawn at the bottom, but not carried over to the top of the next frame
+        let frame = bus.ppu.framebuffer();
+        assert_eq!(frame[239 * SCREEN_WIDTH], 0x16);
+        assert_eq!(frame[0], 0x00);
+    }
+
+    // Counts dots from the current position until the next frame starts
+    fn dots_to_next_frame(bus: &mut crate::bus::Bus) -> usize {
+        let mut dots = 0;
+        loop {
+            bus.ppu.clock(&bus.rom, None);
+            dots += 1;
+            if bus.ppu.scanline == 0 && bus.ppu.dot == 0 {
+                return dots;
+            }
+        }
+    }
+
+    #[test]
+    fn odd_frame_skip() {
+        use crate::bus::Bus;
+        let mut bus = Bus::new();
+        dots_to_next_frame(&mut bus);
+
+        // Rendering disabled, every frame is full length
+        assert_eq!(dots_to_next_frame(&mut bus), 341 * 262);
+        assert_eq!(dots_to_next_frame(&mut bus), 341 * 262);
+
+        // Rendering enabled, odd frames are one dot short
+        bus.write(0x2001, 0b0000_1000);
+        let first = dots_to_next_frame(&mut bus);
+        let second = dots_to_next_frame(&mut bus);
+        assert_eq!(first + second, 2 * 341 * 262 - 1);
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn pal_frame_length() {
+        use crate::bus::Bus;
+        use crate::region::Region;
+        let mut bus = Bus::new();
+        bus.ppu.region = Region::Pal;
+        dots_to_next_frame(&mut bus);
+
+        // No odd frame skip on PAL
+        bus.write(0x2001, 0b0000_1000);
+        assert_eq!(dots_to_next_frame(&mut bus), 341 * 312);
+        assert_eq!(dots_to_next_frame(&mut bus), 341 * 312);
+    }
+
+    #[test]
+    fn vblank_read_race() {
+        use crate::bus::Bus;
+        let mut bus = Bus::new();
+        bus.write(0x2000, 0x80);
+
+        // Read on the dot the flag would set: flag reads clear and no NMI all frame
+        while !(bus.ppu.scanline == 241 && bus.ppu.dot == 1) {
+            bus.ppu.clock(&bus.rom, None);
+        }
+        assert_eq!(bus.read(0x2002) & 0x80, 0);
+        while bus.ppu.scanline != 250 {
+            bus.ppu.clock(&bus.rom, None);
+        }
+        assert_eq!(bus.read(0x2002) & 0x80, 0);
+        assert!(!bus.ppu.nmi);
+
+        // Read one dot after: flag reads set but the NMI is cancelled
+        while !(bus.ppu.scanline == 241 && bus.ppu.dot == 2) {
+            bus.ppu.clock(&bus.rom, None);
+        }
+        assert!(bus.ppu.nmi);
+        assert_eq!(bus.read(0x2002) & 0x80, 0x80);
+        assert!(!bus.ppu.nmi);
+
+        // Later reads leave the NMI alone
+        dots_to_next_frame(&mut bus);
+        while !(bus.ppu.scanline == 241 && bus.ppu.dot == 10) {
+            bus.ppu.clock(&bus.rom, None);
+        }
+        assert!(bus.ppu.nmi);
+        assert_eq!(bus.read(0x2002) & 0x80, 0x80);
+        assert!(bus.ppu.nmi);
+    }
+
+    #[test]
+    fn oam_corruption() {
+        use crate::bus::Bus;
+        let mut bus = Bus::new();
+        for i in 0..256 {
+            bus.ppu.oam[i] = i as u8;
+        }
+        bus.write(0x2001, 0b0001_1000);
+
+        // Cutting rendering at dot 101 stops evaluation 18 sprites in, row $48
+        while !(bus.ppu.scanline == 10 && bus.ppu.dot == 101) {
+            bus.ppu.clock(&bus.rom, None);
+        }
+        bus.write(0x2001, 0x00);
+        bus.write(0x2001, 0b0001_1000);
+        while bus.ppu.scanline != 11 {
+            bus.ppu.clock(&bus.rom, None);
+        }
+        bus.ppu.clock(&bus.rom, None);
+        bus.ppu.clock(&bus.rom, None);
+        assert_eq!(bus.ppu.oam[0x48..0x50], [0, 1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(bus.ppu.oam[0x40..0x48], [0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47]);
+        assert_eq!(bus.ppu.oam[0x50], 0x50);
+    }
+}
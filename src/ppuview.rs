@@ -0,0 +1,280 @@
+// Debug pictures of PPU memory: both pattern tables in a chosen palette, the
+// four nametables with the scroll window outlined, every OAM sprite and
+// palette RAM, plus a text listing of sprite attributes. They show whatever
+// the PPU holds when drawn, so Nes::run_to can stop mid-frame first.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::ppu::{Ppu, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::rom::Rom;
+use crate::screenshot;
+
+const SPRITE_CELL: (usize, usize) = (24, 40); // Grid cell for one sprite at 2x
+const BACKDROP: [u8; 3] = [24, 24, 24];       // Around sprites in the grid
+const TRANSPARENT: [u8; 3] = [72, 72, 72];    // Colour 0 inside a sprite
+
+pub struct View {
+    pub width: usize,
+    pub height: usize,
+    pub rgb: Vec<u8>,
+}
+
+impl View {
+    fn new(width: usize, height: usize, fill: [u8; 3]) -> Self {
+        Self { width, height, rgb: fill.repeat(width * height) }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> [u8; 3] {
+        let i = (y * self.width + x) * 3;
+        [self.rgb[i], self.rgb[i + 1], self.rgb[i + 2]]
+    }
+
+    fn set_pixel(&mut self, x: usize, y: usize, rgb: [u8; 3]) {
+        let i = (y * self.width + x) * 3;
+        self.rgb[i..i + 3].copy_from_slice(&rgb);
+    }
+
+    fn fill(&mut self, left: usize, top: usize, width: usize, height: usize, rgb: [u8; 3]) {
+        for y in top..top + height {
+            for x in left..left + width {
+                self.set_pixel(x, y, rgb);
+            }
+        }
+    }
+
+    // Picks the format from the file extension like screenshot::save
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        screenshot::save_rgb(path, self.width, self.height, &self.rgb)
+    }
+}
+
+// Palette RAM entry 0-31 as RGB, without emphasis
+fn colour(ppu: &Ppu, rom: &Rom, entry: usize) -> [u8; 3] {
+    screenshot::pixel_rgb(ppu.ppu_read(0x3F00 + entry, rom) as u16)
+}
+
+// 2 bit colour of a pattern pixel, col 0 the leftmost
+fn pattern_pixel(ppu: &Ppu, rom: &Rom, tile_addr: usize, row: usize, col: usize) -> usize {
+    let low = ppu.ppu_read(tile_addr + row, rom) >> (7 - col) & 1;
+    let high = ppu.ppu_read(tile_addr + row + 8, rom) >> (7 - col) & 1;
+    (high << 1 | low) as usize
+}
+
+// $0000 on the left and $1000 on the right, tiles in rows of 16, drawn with
+// palette 0-3 for the background or 4-7 for sprites
+pub fn pattern_tables(ppu: &Ppu, rom: &Rom, palette: usize) -> View {
+    let colours: Vec<[u8; 3]> = (0..4).map(|i| colour(ppu, rom, if i == 0 { 0 } else { (palette & 7) * 4 + i })).collect();
+    let mut view = View::new(256, 128, BACKDROP);
+    for tile in 0..512 {
+        let (left, top) = ((tile / 256) * 128 + (tile % 16) * 8, (tile % 256 / 16) * 8);
+        for row in 0..8 {
+            for col in 0..8 {
+                let pixel = pattern_pixel(ppu, rom, tile * 16, row, col);
+                view.set_pixel(left + col, top + row, colours[pixel]);
+            }
+        }
+    }
+    view
+}
+
+// $2000 top left through $2C00 bottom right as the current mirroring maps
+// them, with the 256x240 window at the scroll position outlined in inverted
+// colours, wrapping like the hardware
+pub fn nametables(ppu: &Ppu, rom: &Rom) -> View {
+    let (width, height) = (SCREEN_WIDTH * 2, SCREEN_HEIGHT * 2);
+    let mut view = View::new(width, height, BACKDROP);
+    let table = ppu.background_table();
+    for nametable in 0..4 {
+        let base = 0x2000 + nametable * 0x400;
+        let (left, top) = ((nametable & 1) * SCREEN_WIDTH, (nametable >> 1) * SCREEN_HEIGHT);
+        for tile_y in 0..30 {
+            for tile_x in 0..32 {
+                let tile = ppu.ppu_read(base + tile_y * 32 + tile_x, rom) as usize;
+                let attribute = ppu.ppu_read(base + 0x3C0 + (tile_y / 4) * 8 + tile_x / 4, rom) as usize;
+                let palette = attribute >> ((tile_y & 2) * 2 + (tile_x & 2)) & 3;
+                for row in 0..8 {
+                    for col in 0..8 {
+                        let pixel = pattern_pixel(ppu, rom, table + tile * 16, row, col);
+                        let entry = if pixel == 0 { 0 } else { palette * 4 + pixel };
+                        view.set_pixel(left + tile_x * 8 + col, top + tile_y * 8 + row, colour(ppu, rom, entry));
+                    }
+                }
+            }
+        }
+    }
+
+    let (scroll_x, scroll_y) = ppu.scroll();
+    let mut outline = Vec::new();
+    for dx in 0..SCREEN_WIDTH {
+        outline.push((dx, 0));
+        outline.push((dx, SCREEN_HEIGHT - 1));
+    }
+    for dy in 1..SCREEN_HEIGHT - 1 {
+        outline.push((0, dy));
+        outline.push((SCREEN_WIDTH - 1, dy));
+    }
+    for (dx, dy) in outline {
+        let (x, y) = ((scroll_x + dx) % width, (scroll_y + dy) % height);
+        let [r, g, b] = view.pixel(x, y);
+        view.set_pixel(x, y, [255 - r, 255 - g, 255 - b]);
+    }
+    view
+}
+
+// OAM sprites 0-63 in rows of 8 at twice their size, flipped as they would
+// be drawn, with colour 0 shown grey
+pub fn sprites(ppu: &Ppu, rom: &Rom) -> View {
+    let (cell_width, cell_height) = SPRITE_CELL;
+    let mut view = View::new(cell_width * 8, cell_height * 8, BACKDROP);
+    let height = ppu.sprite_height();
+    for (sprite, entry) in ppu.oam.chunks(4).enumerate() {
+        let (tile, attributes) = (entry[1] as usize, entry[2]);
+        let palette = 4 + (attributes & 3) as usize;
+        let (flip_h, flip_v) = (attributes & 0x40 != 0, attributes & 0x80 != 0);
+        let left = (sprite % 8) * cell_width + (cell_width - 16) / 2;
+        let top = (sprite / 8) * cell_height + (cell_height - height * 2) / 2;
+        for row in 0..height {
+            let source_row = if flip_v { height - 1 - row } else { row };
+            let tile_addr = if height == 16 {
+                (tile & 1) * 0x1000 + ((tile & 0xFE) + source_row / 8) * 16
+            } else {
+                ppu.sprite_table() + tile * 16
+            };
+            for col in 0..8 {
+                let source_col = if flip_h { 7 - col } else { col };
+                let pixel = pattern_pixel(ppu, rom, tile_addr, source_row % 8, source_col);
+                let rgb = if pixel == 0 { TRANSPARENT } else { colour(ppu, rom, palette * 4 + pixel) };
+                view.fill(left + col * 2, top + row * 2, 2, 2, rgb);
+            }
+        }
+    }
+    view
+}
+
+// Palette RAM as 16x16 swatches, background palettes on the top row and
+// sprite palettes below
+pub fn palette(ppu: &Ppu, rom: &Rom) -> View {
+    let mut view = View::new(256, 32, BACKDROP);
+    for entry in 0..32 {
+        view.fill((entry % 16) * 16, (entry / 16) * 16, 16, 16, colour(ppu, rom, entry));
+    }
+    view
+}
+
+// PPU position and settings, palette RAM in hex and every sprite's attributes
+pub fn listing(ppu: &Ppu, rom: &Rom) -> String {
+    let (scroll_x, scroll_y) = ppu.scroll();
+    let height = ppu.sprite_height();
+    let mut text = format!(
+        "Scanline {}, dot {}\nScroll {},{}, background ${:04X}, sprites {}\n",
+        ppu.scanline,
+        ppu.dot,
+        scroll_x,
+        scroll_y,
+        ppu.background_table(),
+        if height == 16 { String::from("8x16") } else { format!("8x8 ${:04X}", ppu.sprite_table()) }
+    );
+    for (name, start) in [("Background", 0x3F00), ("Sprites", 0x3F10)] {
+        let entries: Vec<String> = (start..start + 16).map(|addr| format!("{:02X}", ppu.ppu_read(addr, rom))).collect();
+        text += &format!("{:<11} {}\n", name, entries.join(" "));
+    }
+    text += "\nSprite   X   Y  Tile  Palette  Priority  Flip\n";
+    for (sprite, entry) in ppu.oam.chunks(4).enumerate() {
+        let (y, tile, attributes, x) = (entry[0], entry[1], entry[2], entry[3]);
+        let flip = match (attributes & 0x40 != 0, attributes & 0x80 != 0) {
+            (false, false) => "-",
+            (true, false) => "H",
+            (false, true) => "V",
+            (true, true) => "HV",
+        };
+        let priority = if attributes & 0x20 != 0 { "behind" } else { "front" };
+        text += &format!("{:>6} {:>3} {:>3}   ${:02X} {:>8}  {:<8}  {}\n", sprite, x, y, tile, attributes & 3, priority, flip);
+    }
+    text
+}
+
+// Writes every view into dir as <prefix>_patterns, _nametables, _sprites and
+// _palette with the image extension given, and the listing as <prefix>_oam.txt
+pub fn save_all(dir: &Path, prefix: &str, extension: &str, ppu: &Ppu, rom: &Rom, pattern_palette: usize) -> io::Result<()> {
+    let views = [
+        ("patterns", pattern_tables(ppu, rom, pattern_palette)),
+        ("nametables", nametables(ppu, rom)),
+        ("sprites", sprites(ppu, rom)),
+        ("palette", palette(ppu, rom)),
+    ];
+    for (name, view) in views {
+        view.save(&dir.join(format!("{}_{}.{}", prefix, name, extension)))?;
+    }
+    fs::write(dir.join(format!("{}_oam.txt", prefix)), listing(ppu, rom))
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    // One solid tile in colour 1, placed in the nametable and in OAM
+    fn load_scene() -> crate::nes::Nes {
+        let mut nes = crate::nes::Nes::new();
+        let mut prg_rom = vec![0xEA; 0x4000];
+        prg_rom[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0xC0]);
+        nes.bus.rom.prg_rom = prg_rom;
+        nes.reset();
+        let (ppu, rom) = (&mut nes.bus.ppu, &mut nes.bus.rom);
+        for row in 0..8 {
+            ppu.ppu_write(0x10 + row, 0xFF, rom);
+        }
+        for (addr, data) in [(0x3F00, 0x0F), (0x3F01, 0x30), (0x3F11, 0x16), (0x2000, 0x01)] {
+            ppu.ppu_write(addr, data, rom);
+        }
+        ppu.oam[..4].copy_from_slice(&[0x20, 0x01, 0x40, 0x30]);
+        // Scroll to 16,8
+        ppu.cpu_write(0x2005, 16, rom);
+        ppu.cpu_write(0x2005, 8, rom);
+        nes
+    }
+
+    #[test]
+    fn draws_ppu_memory() {
+        use crate::ppuview::*;
+        use crate::screenshot::pixel_rgb;
+        let nes = load_scene();
+        let (ppu, rom) = (&nes.bus.ppu, &nes.bus.rom);
+        let (black, white, red) = (pixel_rgb(0x0F), pixel_rgb(0x30), pixel_rgb(0x16));
+
+        let patterns = pattern_tables(ppu, rom, 0);
+        assert_eq!((patterns.width, patterns.height), (256, 128));
+        assert_eq!((patterns.pixel(8, 0), patterns.pixel(0, 0)), (white, black));
+        assert_eq!(pattern_tables(ppu, rom, 4).pixel(8, 0), red);
+
+        let nametables = nametables(ppu, rom);
+        assert_eq!((nametables.width, nametables.height), (512, 480));
+        assert_eq!((nametables.pixel(1, 1), nametables.pixel(9, 1)), (white, black));
+        // The scroll window's corner is inverted
+        assert_eq!(nametables.pixel(16, 8), black.map(|channel| 255 - channel));
+        assert_eq!(nametables.pixel(17, 9), black);
+
+        // Sprite 0 is centred in the first cell at twice its size
+        let sprites = sprites(ppu, rom);
+        assert_eq!((sprites.pixel(4, 12), sprites.pixel(19, 27), sprites.pixel(0, 0)), (red, red, BACKDROP));
+        assert_eq!(sprites.pixel(28, 12), TRANSPARENT);
+
+        assert_eq!(palette(ppu, rom).pixel(16, 0), white);
+        let text = listing(ppu, rom);
+        assert!(text.starts_with("Scanline 0, dot 0\nScroll 16,8, background $0000, sprites 8x8 $0000\n"));
+        assert!(text.contains("\nBackground  0F 30 00"));
+        assert!(text.contains("\n     0  48  32   $01        0  front     H\n"));
+    }
+
+    #[test]
+    fn runs_to_scanline_and_dot() {
+        let mut nes = load_scene();
+        nes.run_to(100, 50);
+        assert_eq!(nes.bus.ppu.scanline, 100);
+        assert!((50..53).contains(&nes.bus.ppu.dot));
+        let frame = nes.frame_count();
+        nes.run_to(10, 0);
+        assert_eq!((nes.frame_count(), nes.bus.ppu.scanline), (frame + 1, 10));
+    }
+}
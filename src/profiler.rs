@@ -0,0 +1,218 @@
+// Cycle profiler. Every instruction's cycles, plus DMA stalls that land
+// during it, go to the routine running it, with routines told apart by entry
+// address through the debugger's call stack. Inclusive cycles also count the
+// routines a routine calls, exclusive cycles only its own instructions.
+// Interrupt entry is charged to the handler, and code outside any tracked
+// call to the top level.
+
+use std::collections::HashMap;
+
+use crate::debugger::{CallStack, FrameKind};
+use crate::nes::Nes;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RoutineStats {
+    pub calls: usize,
+    pub inclusive: usize,
+    pub exclusive: usize,
+    pub dma: usize,       // Stall cycles included in exclusive
+    pub frame_max: usize, // Most inclusive cycles in one frame
+    frame: usize,         // Inclusive cycles in the current frame
+}
+
+pub struct Profiler {
+    pub call_stack: CallStack,
+    pub routines: HashMap<Option<u16>, RoutineStats>, // By entry, None for the top level
+    pub frames: Vec<(usize, usize)>,                   // Each frame profiled and its cycles
+    stacks: HashMap<Vec<u16>, usize>,                  // Exclusive cycles by call stack
+    frame: Option<usize>,                              // Frame number being profiled
+    frame_cycles: usize,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self {
+            call_stack: CallStack::new(),
+            routines: HashMap::new(),
+            frames: Vec::new(),
+            stacks: HashMap::new(),
+            frame: None,
+            frame_cycles: 0,
+        }
+    }
+
+    fn entries(&self) -> Vec<u16> {
+        self.call_stack.frames.iter().map(|frame| frame.entry).collect()
+    }
+
+    // Steps one instruction and charges its cycles
+    pub fn step(&mut self, nes: &mut Nes) {
+        nes.finish_instruction();
+        let frame = *self.frame.get_or_insert(nes.frame_count());
+        let before = self.entries();
+        let (cycle, stall) = (nes.cpu.total_cycles, nes.cpu.stall_cycles);
+        self.call_stack.step(nes);
+        let (cycles, dma) = (nes.cpu.total_cycles - cycle, nes.cpu.stall_cycles - stall);
+        if cycles == 0 {
+            return;
+        }
+
+        let after = self.entries();
+        let entered = after.len() > before.len();
+        let interrupt = entered && self.call_stack.frames.last().is_some_and(|frame| frame.kind != FrameKind::Subroutine);
+        let stack = if interrupt { after.clone() } else { before };
+        if entered {
+            self.routines.entry(after.last().copied()).or_default().calls += 1;
+        }
+
+        let own = self.routines.entry(stack.last().copied()).or_default();
+        own.exclusive += cycles;
+        own.dma += dma;
+        // A routine that calls itself is only charged once
+        let mut charged: Vec<Option<u16>> = vec![None];
+        for &entry in &stack {
+            if !charged.contains(&Some(entry)) {
+                charged.push(Some(entry));
+            }
+        }
+        for entry in charged {
+            let routine = self.routines.entry(entry).or_default();
+            routine.inclusive += cycles;
+            routine.frame += cycles;
+        }
+        *self.stacks.entry(stack).or_default() += cycles;
+
+        self.frame_cycles += cycles;
+        if nes.frame_count() != frame {
+            self.end_frame(frame);
+            self.frame = Some(nes.frame_count());
+        }
+    }
+
+    fn end_frame(&mut self, frame: usize) {
+        self.frames.push((frame, self.frame_cycles));
+        self.frame_cycles = 0;
+        for routine in self.routines.values_mut() {
+            routine.frame_max = routine.frame_max.max(routine.frame);
+            routine.frame = 0;
+        }
+    }
+
+    // Profiles until the PPU finishes the next picture, in place of
+    // Nes::run_frame
+    pub fn run_frame(&mut self, nes: &mut Nes) {
+        let frame = nes.frame_count();
+        while nes.frame_count() == frame && !nes.bus.halted() {
+            self.step(nes);
+        }
+    }
+
+    fn name(nes: &Nes, entry: Option<u16>) -> String {
+        match entry {
+            None => String::from("[top]"),
+            Some(addr) => match nes.bus.symbols.name(addr, &nes.bus.rom) {
+                Some(name) => name.to_string(),
+                None => format!("${:04X}", addr),
+            },
+        }
+    }
+
+    // Frame totals, then routines by inclusive cycles
+    pub fn report(&self, nes: &Nes) -> String {
+        let mut text = String::new();
+        if let Some(&(worst, max)) = self.frames.iter().max_by_key(|&&(_, cycles)| cycles) {
+            let average = self.frames.iter().map(|&(_, cycles)| cycles).sum::<usize>() / self.frames.len();
+            text += &format!("{} frames, {} cycles on average, {} at most in frame {}\n\n", self.frames.len(), average, max, worst);
+        }
+        let total = self.routines.get(&None).map_or(0, |top| top.inclusive).max(1);
+        let mut routines: Vec<(&Option<u16>, &RoutineStats)> = self.routines.iter().collect();
+        routines.sort_by_key(|&(entry, stats)| (std::cmp::Reverse(stats.inclusive), *entry));
+        text += &format!(
+            "{:<24} {:>8} {:>12} {:>6} {:>12} {:>10} {:>10}\n",
+            "Routine", "Calls", "Inclusive", "%", "Exclusive", "DMA", "Frame max"
+        );
+        for (&entry, stats) in routines {
+            text += &format!(
+                "{:<24} {:>8} {:>12} {:>6.2} {:>12} {:>10} {:>10}\n",
+                Self::name(nes, entry),
+                stats.calls,
+                stats.inclusive,
+                stats.inclusive as f64 * 100.0 / total as f64,
+                stats.exclusive,
+                stats.dma,
+                stats.frame_max.max(stats.frame)
+            );
+        }
+        text
+    }
+
+    // Collapsed stacks for flame graph tools, "[top];nmi;update_player 1234"
+    // with exclusive cycles
+    pub fn collapsed(&self, nes: &Nes) -> String {
+        let mut lines: Vec<String> = self
+            .stacks
+            .iter()
+            .map(|(stack, cycles)| {
+                let names: Vec<String> =
+                    std::iter::once(None).chain(stack.iter().map(|&entry| Some(entry))).map(|entry| Self::name(nes, entry)).collect();
+                format!("{} {}", names.join(";"), cycles)
+            })
+            .collect();
+        lines.sort();
+        lines.join("\n") + "\n"
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    // Main loop JSR $C010 / JMP $C000, with $C010 doing JSR $C020 / NOP / RTS
+    // and $C020 doing STA $4014 / RTS
+    fn load_program() -> crate::nes::Nes {
+        let mut nes = crate::nes::Nes::new();
+        let mut prg_rom = vec![0xEA; 0x4000];
+        prg_rom[..6].copy_from_slice(&[0x20, 0x10, 0xC0, 0x4C, 0x00, 0xC0]);
+        prg_rom[0x10..0x15].copy_from_slice(&[0x20, 0x20, 0xC0, 0xEA, 0x60]);
+        prg_rom[0x20..0x24].copy_from_slice(&[0x8D, 0x14, 0x40, 0x60]);
+        prg_rom[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0xC0]);
+        nes.bus.rom.prg_rom = prg_rom;
+        nes.reset();
+        nes
+    }
+
+    #[test]
+    fn inclusive_and_exclusive_cycles() {
+        use crate::profiler::*;
+        use crate::symbols::Location;
+        let mut nes = load_program();
+        nes.bus.symbols.add("update", Location::Prg(0x10), 1);
+        let mut profiler = Profiler::new();
+        // One pass through the loop
+        for _ in 0..7 {
+            profiler.step(&mut nes);
+        }
+        let outer = &profiler.routines[&Some(0xC010)];
+        let inner = &profiler.routines[&Some(0xC020)];
+        // STA $4014 is 4 cycles plus 513 or 514 for the DMA, then RTS is 6
+        assert_eq!((inner.calls, inner.exclusive, inner.inclusive), (1, 10 + inner.dma, 10 + inner.dma));
+        assert!(inner.dma == 513 || inner.dma == 514);
+        // JSR 6, NOP 2, RTS 6
+        assert_eq!((outer.calls, outer.exclusive, outer.inclusive), (1, 14, 14 + inner.inclusive));
+        let top = &profiler.routines[&None];
+        assert_eq!((top.exclusive, top.inclusive), (9, 9 + outer.inclusive));
+
+        let collapsed = profiler.collapsed(&nes);
+        assert_eq!(collapsed, format!("[top] 9\n[top];update 14\n[top];update;$C020 {}\n", inner.inclusive));
+        let report = profiler.report(&nes);
+        assert!(report.lines().nth(1).unwrap().starts_with("[top]"));
+        assert!(report.lines().nth(2).unwrap().starts_with("update"));
+
+        // A frame later every routine has a per frame maximum
+        profiler.run_frame(&mut nes);
+        profiler.run_frame(&mut nes);
+        assert_eq!(profiler.frames.len(), 2);
+        assert!(profiler.report(&nes).starts_with("2 frames, "));
+        assert!(profiler.routines[&Some(0xC020)].frame_max >= 523);
+    }
+}
@@ -1,3 +1,5 @@
+use crate::savestate::State;
+
 pub struct Ram {
     ram: [u8; 0xFFFF],
 }
@@ -9,6 +11,11 @@ impl Ram {
         }
     }
 
+    pub fn sync_state(&mut self, state: &mut State) {
+        // Only the first 2 KB is wired up
+        state.bytes(&mut self.ram[..0x800]);
+    }
+
     pub fn read(&self, addr: usize) -> u8 {
         self.ram[addr]
     }
@@ -0,0 +1,131 @@
+// Console timing variants
+// CPU and PPU both derive from the master clock through fixed dividers:
+//          Master clock   CPU   PPU   Dots per CPU cycle
+// NTSC     21.477272 MHz  /12   /4    3
+// PAL      26.601712 MHz  /16   /5    3.2
+// Dendy    26.601712 MHz  /15   /5    3
+
+use std::fs;
+use std::io;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Region {
+    Ntsc,
+    Pal,
+    Dendy,
+}
+
+// APU frame sequencer steps in CPU cycles, 4-step mode then 5-step mode
+// The last step of each sequence restarts it
+const NTSC_FRAME_STEPS: [[usize; 6]; 2] = [
+    [7457, 14913, 22371, 29828, 29829, 29830],
+    [7457, 14913, 22371, 29829, 37281, 37282],
+];
+const PAL_FRAME_STEPS: [[usize; 6]; 2] = [
+    [8313, 16627, 24939, 33252, 33253, 33254],
+    [8313, 16627, 24939, 33253, 41565, 41566],
+];
+
+const NTSC_NOISE_PERIODS: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+const PAL_NOISE_PERIODS: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+
+const NTSC_DMC_RATES: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+const PAL_DMC_RATES: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
+
+impl Region {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "ntsc" => Some(Region::Ntsc),
+            "pal" => Some(Region::Pal),
+            "dendy" => Some(Region::Dendy),
+            _ => None,
+        }
+    }
+
+    pub fn master_clock_hz(&self) -> f64 {
+        match self {
+            Region::Ntsc => 21_477_272.0,
+            Region::Pal | Region::Dendy => 26_601_712.0,
+        }
+    }
+
+    // Master clocks per CPU cycle
+    pub fn cpu_divider(&self) -> usize {
+        match self {
+            Region::Ntsc => 12,
+            Region::Pal => 16,
+            Region::Dendy => 15,
+        }
+    }
+
+    // Master clocks per PPU dot
+    pub fn ppu_divider(&self) -> usize {
+        match self {
+            Region::Ntsc => 4,
+            Region::Pal | Region::Dendy => 5,
+        }
+    }
+
+    pub fn cpu_clock_hz(&self) -> f64 {
+        self.master_clock_hz() / self.cpu_divider() as f64
+    }
+
+    pub fn scanlines_per_frame(&self) -> usize {
+        match self {
+            Region::Ntsc => 262,
+            Region::Pal | Region::Dendy => 312,
+        }
+    }
+
+    // Dendy keeps the 20 line vblank but pushes it 50 lines down
+    pub fn vblank_scanline(&self) -> usize {
+        match self {
+            Region::Ntsc | Region::Pal => 241,
+            Region::Dendy => 291,
+        }
+    }
+
+    // Only the NTSC PPU drops a dot on odd frames
+    pub fn skips_odd_frame_dot(&self) -> bool {
+        *self == Region::Ntsc
+    }
+
+    // The Dendy APU runs on NTSC tables
+    pub fn frame_counter_steps(&self, five_step: bool) -> [usize; 6] {
+        let steps = if *self == Region::Pal { PAL_FRAME_STEPS } else { NTSC_FRAME_STEPS };
+        steps[five_step as usize]
+    }
+
+    pub fn noise_periods(&self) -> &'static [u16; 16] {
+        if *self == Region::Pal { &PAL_NOISE_PERIODS } else { &NTSC_NOISE_PERIODS }
+    }
+
+    pub fn dmc_rates(&self) -> &'static [u16; 16] {
+        if *self == Region::Pal { &PAL_DMC_RATES } else { &NTSC_DMC_RATES }
+    }
+}
+
+// ROM database: one "<crc32 in hex> <region>" pair per line, # starts a comment
+// The checksum covers PRG and CHR data without the iNES header
+pub fn lookup_database(path: &str, crc: u32) -> io::Result<Option<Region>> {
+    let text = fs::read_to_string(path)?;
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        let mut fields = line.split_whitespace();
+        let (Some(entry_crc), Some(region)) = (fields.next(), fields.next()) else {
+            continue;
+        };
+        if u32::from_str_radix(entry_crc, 16).ok() == Some(crc) {
+            return Ok(Region::parse(region));
+        }
+    }
+    Ok(None)
+}
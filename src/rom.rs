@@ -1,21 +1,215 @@
-// Build in error handling for ROM loading
+// Only mapper 0 (NROM) is supported so far, plus the NSF bankswitching layout
 
-use std::env;
 use std::fs;
+use std::io;
+
+use crate::checksum::{crc32, md5, sha1};
+use crate::expansion::ExpansionAudio;
+use crate::nsf::Nsf;
+use crate::region::Region;
+use crate::savestate::State;
+
+// Idle loop the NSF player returns to between INIT and PLAY calls
+pub const NSF_DRIVER_ADDR: usize = 0x4F00;
+const NSF_DRIVER: [u8; 3] = [0x4C, 0x00, 0x4F]; // JMP $4F00
+
+// Nametable layout wired by the cartridge
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    FourScreen,
+}
 
 pub struct Rom {
-    pub buffer: Vec<u8>
+    pub buffer: Vec<u8>,
+    pub prg_rom: Vec<u8>,
+    pub chr: Vec<u8>,
+    pub prg_ram: Vec<u8>, // $6000-$7FFF
+    pub mapper: u8,
+    pub mirroring: Mirroring,
+    pub region: Option<Region>, // Only NES 2.0 headers carry a reliable timing field
+    pub input_device: Option<u8>, // NES 2.0 default expansion device
+    chr_is_ram: bool,
+    pub expansion_audio: Option<Box<dyn ExpansionAudio>>, // Sound chip on the cartridge
+    nsf_banks: Option<[u8; 8]>, // 4 KB PRG banks at $8000-$FFFF, set through $5FF8-$5FFF
 }
 
 impl Rom {
     pub fn new() -> Self {
         Self {
-            buffer: Vec::new()
+            buffer: Vec::new(),
+            prg_rom: Vec::new(),
+            chr: vec![0; 0x2000],
+            prg_ram: vec![0; 0x2000],
+            mapper: 0,
+            mirroring: Mirroring::Horizontal,
+            region: None,
+            input_device: None,
+            chr_is_ram: true,
+            expansion_audio: None,
+            nsf_banks: None,
+        }
+    }
+
+    // Cartridge RAM, the ROM itself comes from the file
+    pub fn sync_state(&mut self, state: &mut State) {
+        state.bytes(&mut self.prg_ram);
+        if self.chr_is_ram {
+            state.bytes(&mut self.chr);
+        }
+    }
+
+    pub fn load_rom(&mut self, path: &str) -> io::Result<()> {
+        self.buffer = fs::read(path)?;
+        self.parse_ines()
+    }
+
+    // Splits the iNES buffer into PRG ROM and CHR ROM/RAM
+    pub fn parse_ines(&mut self) -> io::Result<()> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+        if self.buffer.len() < 16 || &self.buffer[0..4] != b"NES\x1A" {
+            return Err(invalid("Missing iNES header"));
+        }
+        let prg_size = self.buffer[4] as usize * 0x4000;
+        let chr_size = self.buffer[5] as usize * 0x2000;
+        let flags_6 = self.buffer[6];
+        let flags_7 = self.buffer[7];
+
+        self.mapper = (flags_7 & 0xF0) | (flags_6 >> 4);
+        if self.mapper != 0 {
+            return Err(invalid(&format!("Unsupported mapper {}", self.mapper)));
+        }
+        self.mirroring = if flags_6 & 0x08 != 0 {
+            Mirroring::FourScreen
+        } else if flags_6 & 0x01 != 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        };
+
+        // NES 2.0 byte 12: 0 NTSC, 1 PAL, 2 multi-region, 3 Dendy
+        let nes2 = flags_7 & 0x0C == 0x08;
+        self.region = match self.buffer[12] & 0x03 {
+            _ if !nes2 => None,
+            1 => Some(Region::Pal),
+            3 => Some(Region::Dendy),
+            _ => Some(Region::Ntsc),
+        };
+        self.input_device = nes2.then_some(self.buffer[15] & 0x3F);
+
+        // Skip the 512 byte trainer if present
+        let prg_start = if flags_6 & 0x04 != 0 { 16 + 512 } else { 16 };
+        let chr_start = prg_start + prg_size;
+        if self.buffer.len() < chr_start + chr_size {
+            return Err(invalid("ROM file is shorter than its header claims"));
         }
+        self.prg_rom = self.buffer[prg_start..chr_start].to_vec();
+        self.chr_is_ram = chr_size == 0;
+        self.chr = if self.chr_is_ram {
+            vec![0; 0x2000]
+        } else {
+            self.buffer[chr_start..chr_start + chr_size].to_vec()
+        };
+        Ok(())
     }
 
-    pub fn load_rom(&mut self) {
-        let rom_file_path = env::args().nth(1).unwrap();
-        self.buffer = fs::read(rom_file_path).unwrap();
+    // Maps NSF data as PRG ROM in 4 KB banks
+    // Bankswitched tunes are padded by the load address offset within a bank,
+    // the others are placed at the load address with fixed banks 0-7
+    pub fn load_nsf(&mut self, nsf: &Nsf) {
+        let padding = match nsf.bankswitch {
+            Some(_) => nsf.load_addr as usize & 0x0FFF,
+            None => nsf.load_addr as usize - 0x8000,
+        };
+        self.prg_rom = vec![0; padding];
+        self.prg_rom.extend_from_slice(&nsf.data);
+        self.prg_ram.fill(0);
+        self.nsf_banks = Some(nsf.bankswitch.unwrap_or([0, 1, 2, 3, 4, 5, 6, 7]));
+        // One sound chip at a time, the first the file uses that is emulated
+        self.expansion_audio = nsf.expansion_chips.iter().find_map(|chip| chip.create());
     }
-}
\ No newline at end of file
+
+    // PRG and CHR ROM without the header, which is what databases and movie
+    // files hash to identify a game
+    fn rom_data(&self) -> Vec<u8> {
+        let mut data = self.prg_rom.clone();
+        if !self.chr_is_ram {
+            data.extend_from_slice(&self.chr);
+        }
+        data
+    }
+
+    pub fn crc32(&self) -> u32 {
+        crc32(&self.rom_data())
+    }
+
+    pub fn md5(&self) -> [u8; 16] {
+        md5(&self.rom_data())
+    }
+
+    pub fn sha1(&self) -> [u8; 20] {
+        sha1(&self.rom_data())
+    }
+
+    pub fn cpu_read(&self, addr: usize) -> u8 {
+        match addr {
+            0x6000..=0x7FFF => self.prg_ram[addr - 0x6000],
+            0x4F00..=0x4F02 if self.nsf_banks.is_some() => NSF_DRIVER[addr - NSF_DRIVER_ADDR],
+            0x8000..=0xFFFF => self.prg_offset(addr).and_then(|offset| self.prg_rom.get(offset)).copied().unwrap_or(0),
+            _ => 0,
+        }
+    }
+
+    // Offset into PRG ROM that a CPU address in $8000-$FFFF reads
+    pub fn prg_offset(&self, addr: usize) -> Option<usize> {
+        match addr {
+            0x8000..=0xFFFF if self.nsf_banks.is_some() => {
+                let bank = self.nsf_banks.unwrap()[(addr - 0x8000) >> 12] as usize;
+                Some(bank << 12 | (addr & 0x0FFF))
+            }
+            // 16 KB PRG ROM is mirrored into $C000-$FFFF
+            0x8000..=0xFFFF if !self.prg_rom.is_empty() => Some((addr - 0x8000) % self.prg_rom.len()),
+            _ => None,
+        }
+    }
+
+    // Offset into CHR ROM that a PPU address reads, None for CHR RAM
+    pub fn chr_offset(&self, addr: usize) -> Option<usize> {
+        match addr {
+            0x0000..=0x1FFF if !self.chr_is_ram => Some(addr),
+            _ => None,
+        }
+    }
+
+    pub fn chr_rom_len(&self) -> usize {
+        if self.chr_is_ram { 0 } else { self.chr.len() }
+    }
+
+    pub fn cpu_write(&mut self, addr: usize, data: u8) {
+        match addr {
+            0x5FF8..=0x5FFF => {
+                if let Some(banks) = self.nsf_banks.as_mut() {
+                    banks[addr - 0x5FF8] = data;
+                }
+            }
+            0x6000..=0x7FFF => self.prg_ram[addr - 0x6000] = data,
+            _ => {
+                if let Some(chip) = self.expansion_audio.as_mut() {
+                    chip.write(addr as u16, data);
+                }
+            }
+        }
+    }
+
+    pub fn ppu_read(&self, addr: usize) -> u8 {
+        self.chr[addr & 0x1FFF]
+    }
+
+    pub fn ppu_write(&mut self, addr: usize, data: u8) {
+        if self.chr_is_ram {
+            self.chr[addr & 0x1FFF] = data;
+        }
+    }
+}
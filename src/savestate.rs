@@ -0,0 +1,189 @@
+// Savestates: a snapshot of everything in the console that changes as it runs
+// Each component has a sync_state function that writes its fields when saving
+// and reads them back in the same order when loading, so the two can't drift
+// apart. Settings, debugging state, audio output, the picture (redrawn every
+// frame) and the frame count (restarted so frames number from the state) are
+// left out.
+// Layout: "NEBS", a version byte, the region, then every field little endian.
+
+use crate::nes::Nes;
+use crate::region::Region;
+
+const MAGIC: &[u8; 4] = b"NEBS";
+const VERSION: u8 = 1;
+
+pub struct State {
+    data: Vec<u8>,
+    pos: usize,
+    loading: bool,
+    truncated: bool, // A read ran past the end, the value was left alone
+}
+
+impl State {
+    fn saving() -> Self {
+        let mut data = MAGIC.to_vec();
+        data.push(VERSION);
+        Self { data, pos: 0, loading: false, truncated: false }
+    }
+
+    fn loading(data: &[u8]) -> Result<Self, String> {
+        if !is_savestate(data) {
+            return Err(String::from("Not a savestate"));
+        }
+        if data[4] != VERSION {
+            return Err(format!("Unsupported savestate version {}", data[4]));
+        }
+        Ok(Self { data: data.to_vec(), pos: 5, loading: true, truncated: false })
+    }
+
+    fn raw<const N: usize>(&mut self, bytes: [u8; N]) -> [u8; N] {
+        if !self.loading {
+            self.data.extend_from_slice(&bytes);
+            return bytes;
+        }
+        match self.data.get(self.pos..self.pos + N) {
+            Some(read) => {
+                self.pos += N;
+                read.try_into().unwrap()
+            }
+            None => {
+                self.truncated = true;
+                bytes
+            }
+        }
+    }
+
+    pub fn u8(&mut self, value: &mut u8) {
+        *value = self.raw([*value])[0];
+    }
+
+    pub fn bool(&mut self, value: &mut bool) {
+        *value = self.raw([*value as u8])[0] != 0;
+    }
+
+    pub fn u16(&mut self, value: &mut u16) {
+        *value = u16::from_le_bytes(self.raw(value.to_le_bytes()));
+    }
+
+    pub fn usize(&mut self, value: &mut usize) {
+        *value = u64::from_le_bytes(self.raw((*value as u64).to_le_bytes())) as usize;
+    }
+
+    pub fn f64(&mut self, value: &mut f64) {
+        *value = f64::from_le_bytes(self.raw(value.to_le_bytes()));
+    }
+
+    // Memory of a size fixed by the console or cartridge
+    pub fn bytes(&mut self, value: &mut [u8]) {
+        value.iter_mut().for_each(|byte| self.u8(byte));
+    }
+
+    pub fn option<T: Default>(&mut self, value: &mut Option<T>, sync: impl FnOnce(&mut Self, &mut T)) {
+        let mut present = value.is_some();
+        self.bool(&mut present);
+        let mut inner = value.take().unwrap_or_default();
+        if present {
+            sync(self, &mut inner);
+            *value = Some(inner);
+        }
+    }
+}
+
+pub fn is_savestate(data: &[u8]) -> bool {
+    data.len() >= 5 && &data[..4] == MAGIC
+}
+
+fn sync(nes: &mut Nes, state: &mut State) {
+    nes.sync_state(state);
+    nes.cpu.sync_state(state);
+    nes.bus.sync_state(state);
+}
+
+pub fn save(nes: &mut Nes) -> Vec<u8> {
+    let mut state = State::saving();
+    let mut region = nes.region() as u8;
+    state.u8(&mut region);
+    sync(nes, &mut state);
+    state.data
+}
+
+// Loads with the same cartridge and input devices the state was saved with
+// A state that doesn't fit leaves the console as it was
+pub fn load(nes: &mut Nes, data: &[u8]) -> Result<(), String> {
+    let mut state = State::loading(data)?;
+    let mut region = 0;
+    state.u8(&mut region);
+    let region = match region {
+        0 => Region::Ntsc,
+        1 => Region::Pal,
+        2 => Region::Dendy,
+        _ => return Err(format!("Unknown region {} in savestate", region)),
+    };
+
+    let backup = save(nes);
+    let previous_region = nes.region();
+    if region != previous_region {
+        nes.set_region(region);
+    }
+    sync(nes, &mut state);
+    if state.truncated || state.pos != state.data.len() {
+        let mut restore = State::loading(&backup).unwrap();
+        restore.u8(&mut 0);
+        nes.set_region(previous_region);
+        sync(nes, &mut restore);
+        return Err(String::from("Savestate doesn't match this cartridge and its input devices"));
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    #[test]
+    fn round_trip() {
+        use crate::nes::Nes;
+        use crate::savestate::*;
+        // INX / STX $00,X / JMP $C000 with pulse 1 playing
+        let new_nes = || {
+            let mut nes = Nes::new();
+            let mut prg_rom = vec![0xEA; 0x4000];
+            prg_rom[..6].copy_from_slice(&[0xE8, 0x96, 0x00, 0x4C, 0x00, 0xC0]);
+            prg_rom[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0xC0]);
+            nes.bus.rom.prg_rom = prg_rom;
+            nes.reset();
+            nes
+        };
+        let mut nes = new_nes();
+        for (addr, data) in [(0x4015, 0x01), (0x4000, 0xBF), (0x4002, 0x40), (0x4003, 0x08), (0x2001, 0x1E)] {
+            nes.bus.write(addr, data);
+        }
+        nes.run_frame();
+        nes.run_to(100, 200);
+        let state = save(&mut nes);
+
+        // Restored into a fresh console, both run on identically
+        let mut copy = new_nes();
+        assert_ne!(save(&mut copy), state);
+        load(&mut copy, &state).unwrap();
+        assert_eq!(save(&mut copy), state);
+        assert_eq!((copy.bus.ppu.scanline, copy.bus.ppu.dot), (100, 200));
+        for nes in [&mut nes, &mut copy] {
+            nes.run_frame();
+            nes.run_frame();
+        }
+        assert_eq!(save(&mut copy), save(&mut nes));
+        assert_eq!(copy.cpu.registers(), nes.cpu.registers());
+        assert_eq!((0..0x800).map(|addr| copy.bus.peek(addr)).collect::<Vec<u8>>(), (0..0x800).map(|addr| nes.bus.peek(addr)).collect::<Vec<u8>>());
+        assert_eq!(copy.framebuffer(), nes.framebuffer());
+
+        // Anything else is refused and changes nothing
+        let before = save(&mut copy);
+        assert_eq!(load(&mut copy, b"NES\x1A").unwrap_err(), "Not a savestate");
+        assert!(load(&mut copy, &state[..state.len() - 1]).is_err());
+        let mut longer = state.clone();
+        longer.push(0);
+        assert!(load(&mut copy, &longer).is_err());
+        assert_eq!(save(&mut copy), before);
+    }
+}
@@ -0,0 +1,168 @@
+// Converts PPU framebuffers to RGB and writes them as PPM or PNG
+// PNG output uses stored (uncompressed) deflate blocks to avoid a zlib dependency
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::checksum::{adler32, crc32};
+use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+// 2C02 colours for palette indices $00-$3F
+const SYSTEM_PALETTE: [(u8, u8, u8); 64] = [
+    (84, 84, 84), (0, 30, 116), (8, 16, 144), (48, 0, 136),
+    (68, 0, 100), (92, 0, 48), (84, 4, 0), (60, 24, 0),
+    (32, 42, 0), (8, 58, 0), (0, 64, 0), (0, 60, 0),
+    (0, 50, 60), (0, 0, 0), (0, 0, 0), (0, 0, 0),
+    (152, 150, 152), (8, 76, 196), (48, 50, 236), (92, 30, 228),
+    (136, 20, 176), (160, 20, 100), (152, 34, 32), (120, 60, 0),
+    (84, 90, 0), (40, 114, 0), (8, 124, 0), (0, 118, 40),
+    (0, 102, 120), (0, 0, 0), (0, 0, 0), (0, 0, 0),
+    (236, 238, 236), (76, 154, 236), (120, 124, 236), (176, 98, 236),
+    (228, 84, 236), (236, 88, 180), (236, 106, 100), (212, 136, 32),
+    (160, 170, 0), (116, 196, 0), (76, 208, 32), (56, 204, 108),
+    (56, 180, 204), (60, 60, 60), (0, 0, 0), (0, 0, 0),
+    (236, 238, 236), (168, 204, 236), (188, 188, 236), (212, 178, 236),
+    (236, 174, 236), (236, 174, 212), (236, 180, 176), (228, 196, 144),
+    (204, 210, 120), (180, 222, 120), (168, 226, 144), (152, 226, 180),
+    (160, 214, 228), (160, 162, 160), (0, 0, 0), (0, 0, 0),
+];
+
+// Looks up a framebuffer pixel, dimming the channels that aren't emphasised
+pub fn pixel_rgb(pixel: u16) -> [u8; 3] {
+    let (r, g, b) = SYSTEM_PALETTE[(pixel & 0x3F) as usize];
+    let mut rgb = [r, g, b];
+    let emphasis = (pixel >> 6) & 0x07;
+    if emphasis != 0 {
+        for (channel, value) in rgb.iter_mut().enumerate() {
+            if emphasis & (1 << channel) == 0 {
+                *value = (*value as u16 * 13 / 16) as u8;
+            }
+        }
+    }
+    rgb
+}
+
+pub fn to_rgb(framebuffer: &[u16]) -> Vec<u8> {
+    framebuffer.iter().flat_map(|&pixel| pixel_rgb(pixel)).collect()
+}
+
+// Picks the format from the file extension, anything but .png is written as PPM
+pub fn save(path: &Path, framebuffer: &[u16]) -> io::Result<()> {
+    save_rgb(path, SCREEN_WIDTH, SCREEN_HEIGHT, &to_rgb(framebuffer))
+}
+
+pub fn save_rgb(path: &Path, width: usize, height: usize, rgb: &[u8]) -> io::Result<()> {
+    let is_png = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("png"));
+    let data = if is_png {
+        encode_png(width, height, rgb)
+    } else {
+        encode_ppm(width, height, rgb)
+    };
+    fs::write(path, data)
+}
+
+// Binary (P6) PPM
+pub fn encode_ppm(width: usize, height: usize, rgb: &[u8]) -> Vec<u8> {
+    let mut data = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+    data.extend_from_slice(rgb);
+    data
+}
+
+// Reads back a binary PPM, used to compare frames against golden images
+pub fn decode_ppm(data: &[u8]) -> Option<(usize, usize, Vec<u8>)> {
+    // Header is four whitespace separated fields: magic, width, height, max value
+    let mut fields = Vec::new();
+    let mut pos = 0;
+    while fields.len() < 4 {
+        while data.get(pos)?.is_ascii_whitespace() {
+            pos += 1;
+        }
+        let start = pos;
+        while !data.get(pos)?.is_ascii_whitespace() {
+            pos += 1;
+        }
+        fields.push(std::str::from_utf8(&data[start..pos]).ok()?);
+    }
+    pos += 1;
+
+    if fields[0] != "P6" || fields[3] != "255" {
+        return None;
+    }
+    let width: usize = fields[1].parse().ok()?;
+    let height: usize = fields[2].parse().ok()?;
+    let rgb = data.get(pos..pos + width * height * 3)?.to_vec();
+    Some((width, height, rgb))
+}
+
+fn png_chunk(png: &mut Vec<u8>, kind: &[u8; 4], body: &[u8]) {
+    png.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(body);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+// 8 bit RGB PNG
+pub fn encode_png(width: usize, height: usize, rgb: &[u8]) -> Vec<u8> {
+    // Every scanline is prefixed with filter type 0 (none)
+    let mut raw = Vec::with_capacity(height * (width * 3 + 1));
+    for row in rgb.chunks(width * 3) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    // zlib stream made of stored deflate blocks
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = raw.chunks(0xFFFF).peekable();
+    while let Some(block) = blocks.next() {
+        zlib.push(if blocks.peek().is_none() { 1 } else { 0 });
+        let len = block.len() as u16;
+        zlib.extend_from_slice(&len.to_le_bytes());
+        zlib.extend_from_slice(&(!len).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+    let mut ihdr = Vec::new();
+    ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+    ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    png_chunk(&mut png, b"IHDR", &ihdr);
+    png_chunk(&mut png, b"IDAT", &zlib);
+    png_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    #[test]
+    fn ppm_round_trip() {
+        use crate::screenshot::*;
+        let mut framebuffer = vec![0x0F; SCREEN_WIDTH * SCREEN_HEIGHT];
+        framebuffer[1] = 0x30;
+        let rgb = to_rgb(&framebuffer);
+
+        let (width, height, decoded) = decode_ppm(&encode_ppm(SCREEN_WIDTH, SCREEN_HEIGHT, &rgb)).unwrap();
+        assert_eq!((width, height), (SCREEN_WIDTH, SCREEN_HEIGHT));
+        assert_eq!(&decoded[0..6], &[0, 0, 0, 236, 238, 236]);
+        assert_eq!(decoded, rgb);
+    }
+
+    #[test]
+    fn png_checksums() {
+        use crate::checksum::*;
+        use crate::screenshot::*;
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+
+        let png = encode_png(2, 1, &[255, 0, 0, 0, 0, 255]);
+        assert_eq!(&png[0..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&png[png.len() - 12..], b"\x00\x00\x00\x00IEND\xAE\x42\x60\x82");
+    }
+}
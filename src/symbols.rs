@@ -0,0 +1,295 @@
+// Symbol files, giving names to addresses in disassembly, traces and the
+// debugger. Supported formats, picked by extension:
+//   .dbg  ld65 debug info (ld65 --dbgfile)
+//   .nl   FCEUX name lists, GAME.nes.ram.nl for RAM and GAME.nes.N.nl for
+//         16 KB PRG bank N (hex), lines $C000#Name#Comment or $0300/10#Name#
+//   .mlb  Mesen labels, lines P:1F00:Name:Comment with P (PRG ROM offset),
+//         R (internal RAM), W or S (PRG RAM) and G (registers), or their
+//         Mesen 2 names NesPrgRom, NesInternalRam, NesWorkRam, NesSaveRam and
+//         NesMemory
+//   anything else, VICE labels (ld65 -Ln), lines al 00C000 .Name
+// Labels in PRG ROM are kept by ROM offset where the file says which bank
+// they belong to, so they follow bank switching.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::dbginfo::DebugInfo;
+use crate::disasm::LabelLookup;
+use crate::rom::Rom;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Location {
+    Cpu(u16),   // Fixed CPU address: RAM, registers, or ROM with no bank given
+    Prg(usize), // PRG ROM offset
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub location: Location,
+    pub size: u16, // Bytes covered, at least 1
+}
+
+pub struct SymbolTable {
+    pub symbols: Vec<Symbol>,
+    cpu: HashMap<u16, usize>, // Index of the first symbol at each location
+    prg: HashMap<usize, usize>,
+}
+
+fn invalid(path: &Path, number: usize) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid symbol on line {} of {}", number + 1, path.display()))
+}
+
+fn parse_hex(text: &str) -> Option<usize> {
+    usize::from_str_radix(text.trim().trim_start_matches('$'), 16).ok()
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self { symbols: Vec::new(), cpu: HashMap::new(), prg: HashMap::new() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    pub fn add(&mut self, name: &str, location: Location, size: u16) {
+        let index = self.symbols.len();
+        match location {
+            Location::Cpu(addr) => self.cpu.entry(addr).or_insert(index),
+            Location::Prg(offset) => self.prg.entry(offset).or_insert(index),
+        };
+        self.symbols.push(Symbol { name: name.to_string(), location, size: size.max(1) });
+    }
+
+    // Adds the symbols from a file, returning how many there were
+    pub fn load(&mut self, path: &Path) -> io::Result<usize> {
+        let before = self.symbols.len();
+        let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or_default().to_lowercase();
+        match extension.as_str() {
+            "dbg" => self.add_debug_info(&DebugInfo::load(path)?),
+            "nl" => self.load_name_list(path)?,
+            "mlb" => self.load_mesen(path)?,
+            _ => self.load_vice(path)?,
+        }
+        Ok(self.symbols.len() - before)
+    }
+
+    // Labels, plus equates in the register range so PPUCTRL and friends
+    // show up, but not constants that happen to look like addresses
+    pub fn add_debug_info(&mut self, info: &DebugInfo) {
+        // Segment file offsets count the iNES header when it is linked in
+        let header = if info.segments.iter().any(|segment| segment.rom_offset == Some(0) && segment.size == 16) { 16 } else { 0 };
+        for symbol in &info.symbols {
+            if !symbol.label && !(0x2000..0x4020).contains(&symbol.value) {
+                continue;
+            }
+            let segment = symbol.segment.map(|index| &info.segments[index]);
+            let location = match segment.and_then(|segment| segment.rom_offset.map(|offset| (segment, offset))) {
+                Some((segment, offset)) if symbol.value >= 0x8000 && offset >= header => {
+                    Location::Prg(offset - header + symbol.value.wrapping_sub(segment.start) as usize)
+                }
+                _ => Location::Cpu(symbol.value),
+            };
+            self.add(&symbol.name, location, symbol.size);
+        }
+    }
+
+    fn load_name_list(&mut self, path: &Path) -> io::Result<()> {
+        // GAME.nes.3.nl is bank 3, GAME.nes.ram.nl RAM
+        let stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default();
+        let bank = stem.rsplit_once('.').and_then(|(_, bank)| parse_hex(bank));
+        for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if !line.starts_with('$') {
+                continue;
+            }
+            let mut fields = line.splitn(3, '#');
+            let (addr, name) = (fields.next().unwrap_or_default(), fields.next().unwrap_or_default());
+            if name.is_empty() {
+                continue;
+            }
+            let (addr, size) = match addr.split_once('/') {
+                Some((addr, size)) => (addr, parse_hex(size).ok_or_else(|| invalid(path, number))?),
+                None => (addr, 1),
+            };
+            let addr = parse_hex(addr).filter(|&addr| addr <= 0xFFFF).ok_or_else(|| invalid(path, number))?;
+            let location = match bank {
+                Some(bank) if addr >= 0x8000 => Location::Prg(bank * 0x4000 + (addr & 0x3FFF)),
+                _ => Location::Cpu(addr as u16),
+            };
+            self.add(name, location, size as u16);
+        }
+        Ok(())
+    }
+
+    fn load_mesen(&mut self, path: &Path) -> io::Result<()> {
+        for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let mut fields = line.trim().splitn(4, ':');
+            let (Some(kind), Some(range), Some(name)) = (fields.next(), fields.next(), fields.next()) else {
+                continue;
+            };
+            if name.is_empty() {
+                continue;
+            }
+            let (start, end) = match range.split_once('-') {
+                Some((start, end)) => (parse_hex(start), parse_hex(end)),
+                None => (parse_hex(range), parse_hex(range)),
+            };
+            let (Some(start), Some(end)) = (start, end) else {
+                return Err(invalid(path, number));
+            };
+            let location = match kind {
+                "P" | "NesPrgRom" => Location::Prg(start),
+                "R" | "NesInternalRam" | "G" | "NesMemory" => Location::Cpu(start as u16),
+                "W" | "S" | "NesWorkRam" | "NesSaveRam" => Location::Cpu(0x6000 + start as u16),
+                _ => continue,
+            };
+            self.add(name, location, (end.saturating_sub(start) + 1) as u16);
+        }
+        Ok(())
+    }
+
+    fn load_vice(&mut self, path: &Path) -> io::Result<()> {
+        for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let ["al", addr, name] = words.as_slice() else {
+                continue;
+            };
+            // VICE allows a memory space prefix, C: for the CPU
+            let addr = addr.rsplit(':').next().and_then(parse_hex).ok_or_else(|| invalid(path, number))?;
+            self.add(name.trim_start_matches('.'), Location::Cpu(addr as u16), 1);
+        }
+        Ok(())
+    }
+
+    fn at(&self, addr: u16, rom: &Rom) -> Option<&Symbol> {
+        let banked = rom.prg_offset(addr as usize).and_then(|offset| self.prg.get(&offset));
+        banked.or_else(|| self.cpu.get(&addr)).map(|&index| &self.symbols[index])
+    }
+
+    // Name of the symbol at addr with the banks mapped right now
+    pub fn name(&self, addr: u16, rom: &Rom) -> Option<&str> {
+        self.at(addr, rom).map(|symbol| symbol.name.as_str())
+    }
+
+    // CPU address a symbol is visible at with the banks mapped right now, the
+    // highest one when a bank is mirrored, as the vectors use the top copy
+    pub fn address(&self, symbol: &Symbol, rom: &Rom) -> Option<u16> {
+        match symbol.location {
+            Location::Cpu(addr) => Some(addr),
+            Location::Prg(offset) => (0x8000..=0xFFFFu16)
+                .rev()
+                .step_by(0x1000)
+                .map(|window| window & 0xF000)
+                .find_map(|window| {
+                    let base = rom.prg_offset(window as usize)?;
+                    (base..base + 0x1000).contains(&offset).then(|| window + (offset - base) as u16)
+                }),
+        }
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+
+    // The address a name refers to, with its size, for breakpoints and
+    // expressions
+    pub fn lookup(&self, name: &str, rom: &Rom) -> Option<(u16, u16)> {
+        let symbol = self.find(name)?;
+        Some((self.address(symbol, rom)?, symbol.size))
+    }
+
+    // For the disassembler, resolved through the current bank mapping
+    pub fn labels<'a>(&'a self, rom: &'a Rom) -> MappedLabels<'a> {
+        MappedLabels { symbols: self, rom }
+    }
+}
+
+pub struct MappedLabels<'a> {
+    symbols: &'a SymbolTable,
+    rom: &'a Rom,
+}
+
+impl LabelLookup for MappedLabels<'_> {
+    fn label(&self, addr: u16) -> Option<&str> {
+        self.symbols.name(addr, self.rom)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    fn write(name: &str, text: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn symbol_formats() {
+        use crate::symbols::*;
+        let mut rom = Rom::new();
+        rom.prg_rom = vec![0; 0x8000];
+        let mut symbols = SymbolTable::new();
+        let files = [
+            write("nebulous_symbols.nes.ram.nl", "$0324#player_x#Horizontal position\n$0300/10#enemies#\n$0010##Just a comment\n"),
+            write("nebulous_symbols.nes.1.nl", "$C010#update_sound#\n"),
+            write("nebulous_symbols.mlb", "P:0100:reset:Entry point\nR:0020-0021:score\nG:2000:PPUCTRL\nS:0000:save_slot\n"),
+            write("nebulous_symbols.lbl", "al 00E000 .nmi\nal C:E010 .irq\n"),
+        ];
+        let counts: Vec<usize> = files.iter().map(|path| symbols.load(path).unwrap()).collect();
+        for path in &files {
+            std::fs::remove_file(path).ok();
+        }
+        assert_eq!(counts, [2, 1, 4, 2]);
+
+        assert_eq!(symbols.name(0x0324, &rom), Some("player_x"));
+        assert_eq!(symbols.lookup("enemies", &rom), Some((0x0300, 0x10)));
+        // Bank 1 of a 32 KB ROM sits at $C000
+        assert_eq!(symbols.find("update_sound").unwrap().location, Location::Prg(0x4010));
+        assert_eq!(symbols.lookup("update_sound", &rom), Some((0xC010, 1)));
+        assert_eq!(symbols.name(0x8100, &rom), Some("reset"));
+        assert_eq!(symbols.lookup("score", &rom), Some((0x0020, 2)));
+        assert_eq!(symbols.lookup("save_slot", &rom), Some((0x6000, 1)));
+        assert_eq!((symbols.name(0x2000, &rom), symbols.name(0xE010, &rom)), (Some("PPUCTRL"), Some("irq")));
+
+        // A 16 KB ROM mirrors, and its labels are found at the top copy
+        rom.prg_rom.truncate(0x4000);
+        assert_eq!(symbols.lookup("reset", &rom), Some((0xC100, 1)));
+        assert_eq!(symbols.name(0x8100, &rom), Some("reset"));
+        assert_eq!(symbols.labels(&rom).label(0xC100), Some("reset"));
+        assert!(symbols.load(&write("nebulous_symbols_bad.mlb", "P:zz:oops\n")).is_err());
+        std::fs::remove_file(std::env::temp_dir().join("nebulous_symbols_bad.mlb")).ok();
+    }
+
+    #[test]
+    fn debug_info_banks() {
+        use crate::dbginfo::DebugInfo;
+        use crate::symbols::*;
+        let info = DebugInfo::parse(
+            "version\tmajor=2,minor=0
+seg\tid=0,name=\"HEADER\",start=0x000000,size=0x0010,addrsize=absolute,type=ro,oname=\"game.nes\",ooffs=0
+seg\tid=1,name=\"CODE\",start=0x00C000,size=0x0100,addrsize=absolute,type=ro,oname=\"game.nes\",ooffs=16
+seg\tid=2,name=\"BSS\",start=0x000300,size=0x0010,addrsize=absolute,type=bss
+sym\tid=0,name=\"reset\",addrsize=absolute,scope=0,def=0,val=0xC020,seg=1,type=lab
+sym\tid=1,name=\"buffer\",addrsize=absolute,size=16,scope=0,def=1,val=0x300,seg=2,type=lab
+sym\tid=2,name=\"PPUMASK\",addrsize=absolute,scope=0,def=2,val=0x2001,type=equ
+sym\tid=3,name=\"SPEED\",addrsize=zeropage,scope=0,def=3,val=0x03,type=equ
+",
+        )
+        .unwrap();
+        let mut symbols = SymbolTable::new();
+        symbols.add_debug_info(&info);
+        let locations: Vec<(&str, Location, u16)> =
+            symbols.symbols.iter().map(|symbol| (symbol.name.as_str(), symbol.location, symbol.size)).collect();
+        assert_eq!(
+            locations,
+            [("reset", Location::Prg(0x20), 1), ("buffer", Location::Cpu(0x0300), 16), ("PPUMASK", Location::Cpu(0x2001), 1)]
+        );
+    }
+}
@@ -0,0 +1,140 @@
+// Runs test ROMs that report through PRG RAM the way blargg's suites do:
+// $DE $B0 $61 at $6001-$6003 once the test has started, $80 at $6000 while
+// it runs, $81 when it wants the reset button pressed, then the result code,
+// 0 for a pass, with a message from $6004 on
+
+use std::path::Path;
+
+use crate::nes::Nes;
+
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const RUNNING: u8 = 0x80;
+const RESET_REQUESTED: u8 = 0x81;
+
+pub struct TestResult {
+    pub code: u8,
+    pub message: String,
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        self.code == 0
+    }
+}
+
+fn started(nes: &Nes) -> bool {
+    (0..3).all(|i| nes.bus.peek(0x6001 + i) == SIGNATURE[i])
+}
+
+fn message(nes: &Nes) -> String {
+    let mut text = Vec::new();
+    let mut addr = 0x6004;
+    while addr < 0x8000 && nes.bus.peek(addr) != 0 {
+        text.push(nes.bus.peek(addr));
+        addr += 1;
+    }
+    String::from_utf8_lossy(&text).trim().to_string()
+}
+
+// Runs a ROM until it reports, giving up after max_frames
+pub fn run(path: &Path, max_frames: usize) -> Result<TestResult, String> {
+    let mut nes = Nes::new();
+    nes.bus.rom.load_rom(&path.to_string_lossy()).map_err(|err| format!("{}: {}", path.display(), err))?;
+    nes.reset();
+    let mut reset_at = None;
+    for frame in 0..max_frames {
+        nes.run_frame();
+        if !started(&nes) {
+            continue;
+        }
+        match nes.bus.peek(0x6000) {
+            RUNNING => {}
+            // The button has to be held for a moment, 6 frames is about 100 ms
+            RESET_REQUESTED => match reset_at {
+                None => reset_at = Some(frame + 6),
+                Some(at) if frame >= at => {
+                    nes.reset();
+                    reset_at = None;
+                }
+                Some(_) => {}
+            },
+            code => return Ok(TestResult { code, message: message(&nes) }),
+        }
+    }
+    Err(format!("{}: no result after {} frames", path.display(), max_frames))
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    // Runs every ROM in a suite directory under roms/, or under TEST_ROMS_DIR,
+    // and fails on the first one that doesn't pass or can't be found
+    fn run_suite(suite: &str) {
+        use crate::testrom::*;
+        use std::env;
+        use std::fs;
+        use std::path::PathBuf;
+        let dir = env::var("TEST_ROMS_DIR").map(PathBuf::from).unwrap_or(PathBuf::from("roms")).join(suite);
+        let entries = fs::read_dir(&dir).unwrap_or_else(|err| panic!("{}: {}", dir.display(), err));
+        let mut roms: Vec<PathBuf> =
+            entries.filter_map(|entry| entry.ok().map(|entry| entry.path())).filter(|path| path.extension().is_some_and(|ext| ext == "nes")).collect();
+        roms.sort();
+        assert!(!roms.is_empty(), "No ROMs in {}", dir.display());
+        for rom in roms {
+            let result = run(&rom, 3600).unwrap();
+            assert!(result.passed(), "{} failed with {}: {}", rom.display(), result.code, result.message);
+        }
+    }
+
+    #[test]
+    fn reports_result() {
+        use crate::testrom::*;
+        // Writes the signature, "ok" and result 3, then loops
+        let mut program = Vec::new();
+        for (addr, data) in [(0x6001u16, 0xDE), (0x6002, 0xB0), (0x6003, 0x61), (0x6004, b'o'), (0x6005, b'k'), (0x6000, 3)] {
+            program.extend_from_slice(&[0xA9, data, 0x8D, addr as u8, (addr >> 8) as u8]);
+        }
+        let jump = 0xC000 + program.len() as u16;
+        program.extend_from_slice(&[0x4C, jump as u8, (jump >> 8) as u8]);
+        let mut rom = b"NES\x1A\x01\x01".to_vec();
+        rom.resize(16, 0);
+        let mut prg = vec![0xEA; 0x4000];
+        prg[..program.len()].copy_from_slice(&program);
+        prg[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0xC0]);
+        rom.extend_from_slice(&prg);
+        rom.resize(16 + 0x4000 + 0x2000, 0);
+
+        let path = std::env::temp_dir().join("nebulous_testrom_test.nes");
+        std::fs::write(&path, rom).unwrap();
+        let result = run(&path, 10).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert_eq!((result.code, result.message.as_str(), result.passed()), (3, "ok", false));
+    }
+
+    // These need the ROMs, run them with cargo test -- --ignored
+
+    #[test]
+    #[ignore]
+    fn ppu_vbl_nmi() {
+        run_suite("ppu_vbl_nmi/rom_singles");
+    }
+
+    #[test]
+    #[ignore]
+    fn ppu_sprite_hit() {
+        run_suite("ppu_sprite_hit/rom_singles");
+    }
+
+    #[test]
+    #[ignore]
+    fn apu_test() {
+        run_suite("apu_test/rom_singles");
+    }
+
+    #[test]
+    #[ignore]
+    fn apu_reset() {
+        run_suite("apu_reset");
+    }
+}
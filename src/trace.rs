@@ -0,0 +1,138 @@
+// CPU trace logs in nestest.log format, and comparing them against a
+// reference log. nestest.nes runs in automation mode by starting at $C000,
+// which tests every instruction without needing the PPU.
+
+use crate::nes::Nes;
+
+pub const NESTEST_START: u16 = 0xC000;
+
+// First line (1 based) where a trace differs from the reference, with both versions
+#[derive(Debug, PartialEq)]
+pub struct Divergence {
+    pub line: usize,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl Divergence {
+    pub fn report(&self) -> String {
+        format!("Trace diverges at line {}\nexpected: {}\n  actual: {}", self.line, self.expected, self.actual)
+    }
+}
+
+// Compares every reference line, so a trace that stops early diverges too
+pub fn first_divergence(actual: &str, expected: &str) -> Option<Divergence> {
+    let mut actual_lines = actual.lines();
+    for (i, expected_line) in expected.lines().enumerate() {
+        let actual_line = actual_lines.next().unwrap_or("<end of trace>");
+        if actual_line.trim_end() != expected_line.trim_end() {
+            return Some(Divergence {
+                line: i + 1,
+                expected: expected_line.trim_end().to_string(),
+                actual: actual_line.trim_end().to_string(),
+            });
+        }
+    }
+    None
+}
+
+// Runs from addr until count instructions have started, returning their trace
+pub fn trace_from(nes: &mut Nes, addr: u16, count: usize) -> String {
+    nes.cpu.jump(addr);
+    let mut trace = String::new();
+    let mut traced = 0;
+    while traced < count {
+        if nes.cpu.cycles == 0 {
+            trace += &nes.cpu.trace_line(&nes.bus);
+            trace.push('\n');
+            traced += 1;
+        }
+        nes.clock();
+    }
+    trace
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    // Builds an NROM image from a program placed at $C000
+    fn load_program(program: &[u8]) -> crate::nes::Nes {
+        let mut nes = crate::nes::Nes::new();
+        let mut prg_rom = vec![0xEA; 0x4000];
+        prg_rom[..program.len()].copy_from_slice(program);
+        prg_rom[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0xC0]);
+        nes.bus.rom.prg_rom = prg_rom;
+        nes.reset();
+        nes
+    }
+
+    #[test]
+    fn trace_format() {
+        use crate::trace::*;
+        // The first lines of nestest.log
+        let mut program = vec![0xEA; 0x600];
+        program[..3].copy_from_slice(&[0x4C, 0xF5, 0xC5]);
+        program[0x5F5..0x5FA].copy_from_slice(&[0xA2, 0x00, 0x86, 0x00, 0x04]);
+        program[0x5FA] = 0xA9;
+        let mut nes = load_program(&program);
+        let reference = "\
+C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+C5F5  A2 00     LDX #$00                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 30 CYC:10
+C5F7  86 00     STX $00 = 00                    A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 36 CYC:12
+C5F9  04 A9    *NOP $A9 = 00                    A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 45 CYC:15
+";
+        let trace = trace_from(&mut nes, NESTEST_START, 4);
+        assert_eq!(first_divergence(&trace, reference), None);
+
+        let divergence = first_divergence("C000\nC5F5\n", "C000\nC5F6\nC5F7\n").unwrap();
+        assert_eq!(divergence.line, 2);
+        assert_eq!(divergence.actual, "C5F5");
+        assert_eq!(first_divergence("C000\n", "C000\nC5F5\n").unwrap().actual, "<end of trace>");
+    }
+
+    #[test]
+    fn arithmetic_and_branches() {
+        use crate::trace::*;
+        let program = [
+            0xA9, 0x50, // LDA #$50
+            0x69, 0x50, // ADC #$50, signed overflow
+            0x70, 0x02, // BVS +2
+            0xA9, 0xFF, // (skipped)
+            0x38,       // SEC
+            0xE9, 0xF0, // SBC #$F0, borrows
+            0x6C, 0xFF, 0x02, // JMP ($02FF), hi byte from $0200
+        ];
+        let mut nes = load_program(&program);
+        nes.bus.write(0x02FF, 0x00);
+        nes.bus.write(0x0200, 0xC0);
+        let trace = trace_from(&mut nes, NESTEST_START, 7);
+        let lines: Vec<&str> = trace.lines().collect();
+        assert!(lines[2].contains("A:A0 X:00 Y:00 P:E4"));
+        assert!(lines[3].starts_with("C008  38"));
+        assert!(lines[5].contains("A:B0 X:00 Y:00 P:A4"));
+        assert!(lines[5].starts_with("C00B  6C FF 02  JMP ($02FF) = C000"));
+        assert!(lines[6].starts_with("C000"));
+    }
+
+    // Needs nestest.nes and nestest.log in roms/, or NESTEST_DIR pointing at
+    // them, run it with cargo test -- --ignored
+    #[test]
+    #[ignore]
+    fn nestest() {
+        use crate::trace::*;
+        use std::env;
+        use std::fs;
+        use std::path::PathBuf;
+        let dir = env::var("NESTEST_DIR").map(PathBuf::from).unwrap_or(PathBuf::from("roms"));
+        let log = dir.join("nestest.log");
+        let reference = fs::read_to_string(&log).unwrap_or_else(|err| panic!("{}: {}", log.display(), err));
+        let mut nes = Nes::new();
+        nes.bus.rom.load_rom(dir.join("nestest.nes").to_str().unwrap()).unwrap();
+        nes.reset();
+        let trace = trace_from(&mut nes, NESTEST_START, reference.lines().count());
+        if let Some(divergence) = first_divergence(&trace, &reference) {
+            panic!("{}", divergence.report());
+        }
+    }
+}
@@ -0,0 +1,214 @@
+// Konami VRC6 sound: two pulses with 8 duty settings and a sawtooth
+// Registers sit at $9000-$9003 (pulse 1 and frequency control), $A000-$A002
+// (pulse 2) and $B000-$B002 (saw), as on mapper 24 and in NSF files.
+// Each channel's 12 bit period counts down once per CPU cycle.
+
+use crate::expansion::{ExpansionAudio, ExpansionChip};
+
+struct Pulse {
+    volume: u8,
+    duty: u8,           // Output is high for duty + 1 of 16 steps
+    ignore_duty: bool,  // Constant output at the volume
+    enabled: bool,
+    period: u16,
+    timer: u16,
+    step: u8,           // Counts down from 15
+}
+
+impl Pulse {
+    fn new() -> Self {
+        Self { volume: 0, duty: 0, ignore_duty: false, enabled: false, period: 0, timer: 0, step: 15 }
+    }
+
+    fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                self.ignore_duty = data & 0x80 != 0;
+                self.duty = (data >> 4) & 0x07;
+                self.volume = data & 0x0F;
+            }
+            1 => self.period = (self.period & 0x0F00) | data as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((data as u16 & 0x0F) << 8);
+                self.enabled = data & 0x80 != 0;
+                // Disabling resets the duty position
+                if !self.enabled {
+                    self.step = 15;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.step = self.step.wrapping_sub(1) & 0x0F;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && (self.ignore_duty || self.step <= self.duty) { self.volume } else { 0 }
+    }
+}
+
+struct Saw {
+    rate: u8,           // Added to the accumulator every other step
+    enabled: bool,
+    period: u16,
+    timer: u16,
+    step: u8,           // 0-13, the accumulator resets on the 14th
+    accumulator: u8,
+}
+
+impl Saw {
+    fn new() -> Self {
+        Self { rate: 0, enabled: false, period: 0, timer: 0, step: 0, accumulator: 0 }
+    }
+
+    fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => self.rate = data & 0x3F,
+            1 => self.period = (self.period & 0x0F00) | data as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((data as u16 & 0x0F) << 8);
+                self.enabled = data & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period >> shift;
+        self.step += 1;
+        if self.step == 14 {
+            self.step = 0;
+            self.accumulator = 0;
+        } else if self.step.is_multiple_of(2) {
+            self.accumulator = self.accumulator.wrapping_add(self.rate);
+        }
+    }
+
+    // The top 5 bits, 0-31
+    fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+}
+
+pub struct Vrc6 {
+    pulse1: Pulse,
+    pulse2: Pulse,
+    saw: Saw,
+    halt: bool,
+    shift: u8, // $9003 bits 1-2 speed every period up by 16 or 256
+}
+
+impl Vrc6 {
+    pub fn new() -> Self {
+        Self { pulse1: Pulse::new(), pulse2: Pulse::new(), saw: Saw::new(), halt: false, shift: 0 }
+    }
+}
+
+impl ExpansionAudio for Vrc6 {
+    fn chip(&self) -> ExpansionChip {
+        ExpansionChip::Vrc6
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x9003 => {
+                self.halt = data & 0x01 != 0;
+                self.shift = if data & 0x04 != 0 { 8 } else if data & 0x02 != 0 { 4 } else { 0 };
+            }
+            0x9000..=0x9002 => self.pulse1.write(addr - 0x9000, data),
+            0xA000..=0xA002 => self.pulse2.write(addr - 0xA000, data),
+            0xB000..=0xB002 => self.saw.write(addr - 0xB000, data),
+            _ => {}
+        }
+    }
+
+    fn clock(&mut self) {
+        if self.halt {
+            return;
+        }
+        self.pulse1.clock(self.shift);
+        self.pulse2.clock(self.shift);
+        self.saw.clock(self.shift);
+    }
+
+    fn channel_count(&self) -> usize {
+        3
+    }
+
+    // The saw's 5 bit output peaks at about twice a pulse
+    fn output(&self, channel: usize) -> f32 {
+        match channel {
+            0 => self.pulse1.output() as f32 / 15.0,
+            1 => self.pulse2.output() as f32 / 15.0,
+            _ => self.saw.output() as f32 / 15.0,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    #[test]
+    fn pulse_and_saw() {
+        use crate::expansion::ExpansionAudio;
+        use crate::vrc6::*;
+        let mut chip = Vrc6::new();
+
+        // Pulse 1 at volume 15 with duty 7, half high and half low, period 1
+        chip.write(0x9000, 0x7F);
+        chip.write(0x9001, 0x01);
+        chip.write(0x9002, 0x80);
+        let mut levels = Vec::new();
+        for _ in 0..32 {
+            chip.clock();
+            levels.push(chip.output(0));
+        }
+        assert_eq!(levels.iter().filter(|&&level| level == 1.0).count(), 16);
+        assert_eq!(levels.iter().filter(|&&level| level == 0.0).count(), 16);
+
+        // Ignoring the duty holds the volume
+        chip.write(0x9000, 0x85);
+        chip.clock();
+        assert_eq!(chip.output(0), 5.0 / 15.0);
+
+        // The saw adds its rate every other step and resets on the 14th
+        chip.write(0xB000, 0x20);
+        chip.write(0xB001, 0x00);
+        chip.write(0xB002, 0x80);
+        let mut saw = Vec::new();
+        for _ in 0..14 {
+            chip.clock();
+            saw.push(chip.output(2) * 15.0);
+        }
+        assert_eq!(saw, [0.0, 4.0, 4.0, 8.0, 8.0, 12.0, 12.0, 16.0, 16.0, 20.0, 20.0, 24.0, 24.0, 0.0]);
+
+        // Halting stops every channel where it is
+        chip.clock();
+        chip.clock();
+        chip.write(0x9003, 0x01);
+        for _ in 0..10 {
+            chip.clock();
+        }
+        assert_eq!(chip.output(2) * 15.0, 4.0);
+    }
+}
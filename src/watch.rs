@@ -0,0 +1,282 @@
+// Memory access watchpoints, checked by Bus on every read and write once a
+// Watcher is installed. Without one the bus pays a single None check.
+//
+// Watchpoints cover the CPU address space, PPU memory (pattern tables,
+// nametables and palette, reached through $2007) and OAM (through $2004 and
+// OAM DMA). A hit calls the callback, and a halting watchpoint stops the CPU
+// at the next instruction boundary, or before the instruction for execute.
+//
+// Specs are [cpu:|ppu:|oam:]ACCESS:ADDR[-END][=VALUE] with ACCESS any of r, w
+// and x, and hex numbers: w:0300-03FF, ppu:w:3F00-3F1F=0F, x:C000.
+
+use crate::bus::Bus;
+use crate::debugger::Access;
+use crate::disasm;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Space {
+    Cpu,
+    Ppu,
+    Oam,
+}
+
+impl Space {
+    fn name(self) -> &'static str {
+        match self {
+            Space::Cpu => "cpu",
+            Space::Ppu => "ppu",
+            Space::Oam => "oam",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Watchpoint {
+    pub space: Space,
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+    pub start: u16,
+    pub end: u16,
+    pub value: Option<u8>, // Only accesses of this value
+    pub halt: bool,        // Stop emulation, rather than only calling back
+}
+
+impl Watchpoint {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid watchpoint {}, expected [cpu:|ppu:|oam:]rwx:ADDR[-END][=VALUE]", spec);
+        let mut parts: Vec<&str> = spec.split(':').collect();
+        let space = match parts.first().map(|part| part.to_lowercase()).as_deref() {
+            Some("cpu") => Space::Cpu,
+            Some("ppu") => Space::Ppu,
+            Some("oam") => Space::Oam,
+            _ => {
+                parts.insert(0, "cpu");
+                Space::Cpu
+            }
+        };
+        let [_, access, range] = parts.as_slice() else {
+            return Err(invalid());
+        };
+        let access = access.to_lowercase();
+        if access.is_empty() || !access.chars().all(|c| matches!(c, 'r' | 'w' | 'x')) {
+            return Err(invalid());
+        }
+        let (range, value) = match range.split_once('=') {
+            Some((range, value)) => (range, Some(parse_hex(value).filter(|&value| value <= 0xFF).ok_or_else(invalid)? as u8)),
+            None => (*range, None),
+        };
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (parse_hex(start).ok_or_else(invalid)?, parse_hex(end).ok_or_else(invalid)?),
+            None => (parse_hex(range).ok_or_else(invalid)?, parse_hex(range).ok_or_else(invalid)?),
+        };
+        if end < start || (access.contains('x') && space != Space::Cpu) {
+            return Err(invalid());
+        }
+        Ok(Self {
+            space,
+            read: access.contains('r'),
+            write: access.contains('w'),
+            execute: access.contains('x'),
+            start,
+            end,
+            value,
+            halt: true,
+        })
+    }
+
+    pub fn describe(&self) -> String {
+        let access: String = [(self.read, 'r'), (self.write, 'w'), (self.execute, 'x')]
+            .iter()
+            .filter(|(set, _)| *set)
+            .map(|(_, c)| c)
+            .collect();
+        let mut text = format!("{}:{}:{:04X}", self.space.name(), access, self.start);
+        if self.end != self.start {
+            text += &format!("-{:04X}", self.end);
+        }
+        if let Some(value) = self.value {
+            text += &format!("={:02X}", value);
+        }
+        text
+    }
+
+    fn matches(&self, space: Space, access: Access, addr: u16, value: u8) -> bool {
+        let access_matches = match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+            Access::Execute => self.execute,
+        };
+        access_matches
+            && self.space == space
+            && (self.start..=self.end).contains(&addr)
+            && self.value.is_none_or(|expected| expected == value)
+    }
+}
+
+fn parse_hex(text: &str) -> Option<u16> {
+    u16::from_str_radix(text.trim().trim_start_matches('$'), 16).ok()
+}
+
+// One access that matched a watchpoint
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Hit {
+    pub watchpoint: usize,
+    pub space: Space,
+    pub access: Access,
+    pub addr: u16,
+    pub value: u8,
+    pub pc: u16,      // Instruction that made the access, or was about to run for execute
+    pub cycle: usize, // CPU cycle that instruction started on
+}
+
+impl Hit {
+    // "write ppu $3F00 = $0F by C123 STA $2007 on cycle 1234"
+    pub fn report(&self, bus: &Bus) -> String {
+        let peek = |addr: u16| bus.peek(addr as usize);
+        let access = match self.access {
+            Access::Read => "read",
+            Access::Write => "write",
+            Access::Execute => "execute",
+        };
+        format!(
+            "{} {} ${:04X} = ${:02X} by {:04X} {} on cycle {}",
+            access,
+            self.space.name(),
+            self.addr,
+            self.value,
+            self.pc,
+            disasm::format_instruction(&peek, self.pc, None, None),
+            self.cycle
+        )
+    }
+}
+
+// Called for every hit, halting or not
+pub type Callback = Box<dyn FnMut(&Hit)>;
+
+pub struct Watcher {
+    pub watchpoints: Vec<Watchpoint>,
+    pub callback: Option<Callback>,
+    pub halted: Option<Hit>, // First halting hit, until resume
+    instruction: (u16, usize), // pc and start cycle of the running instruction
+    resume_pc: Option<u16>,    // Execute watchpoint to step past after resuming
+}
+
+impl Watcher {
+    pub fn new() -> Self {
+        Self { watchpoints: Vec::new(), callback: None, halted: None, instruction: (0, 0), resume_pc: None }
+    }
+
+    // Lets emulation continue after a halt
+    pub fn resume(&mut self) {
+        if let Some(hit) = self.halted.take() {
+            if hit.access == Access::Execute {
+                self.resume_pc = Some(hit.pc);
+            }
+        }
+    }
+
+    // Called at each instruction boundary, false when the CPU must not start
+    // the instruction at pc
+    pub fn begin_instruction(&mut self, pc: u16, opcode: u8, cycle: usize) -> bool {
+        if self.halted.is_some() {
+            return false;
+        }
+        self.instruction = (pc, cycle);
+        if self.resume_pc.take() == Some(pc) {
+            return true;
+        }
+        self.check(Space::Cpu, Access::Execute, pc, opcode);
+        self.halted.is_none()
+    }
+
+    pub fn check(&mut self, space: Space, access: Access, addr: u16, value: u8) {
+        for (index, watchpoint) in self.watchpoints.iter().enumerate() {
+            if !watchpoint.matches(space, access, addr, value) {
+                continue;
+            }
+            let (pc, cycle) = self.instruction;
+            let hit = Hit { watchpoint: index, space, access, addr, value, pc, cycle };
+            if let Some(callback) = &mut self.callback {
+                callback(&hit);
+            }
+            if watchpoint.halt && self.halted.is_none() {
+                self.halted = Some(hit);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    #[test]
+    fn parse_specs() {
+        use crate::watch::*;
+        let watchpoint = Watchpoint::parse("ppu:w:3F00-3F1F=0F").unwrap();
+        assert_eq!((watchpoint.space, watchpoint.read, watchpoint.write), (Space::Ppu, false, true));
+        assert_eq!((watchpoint.start, watchpoint.end, watchpoint.value), (0x3F00, 0x3F1F, Some(0x0F)));
+        assert_eq!(watchpoint.describe(), "ppu:w:3F00-3F1F=0F");
+        assert_eq!(Watchpoint::parse("rx:$C000").unwrap().describe(), "cpu:rx:C000");
+        assert!(Watchpoint::parse("oam:x:00").is_err());
+        assert!(Watchpoint::parse("q:0300").is_err());
+        assert!(Watchpoint::parse("w:0300-0200").is_err());
+        assert!(Watchpoint::parse("w:0300=100").is_err());
+    }
+
+    // LDA #$3F / STA $2006 / LDA #$00 / STA $2006 / LDA #$0F / STA $2007 /
+    // STA $0300 / NOP...
+    fn load_program() -> crate::nes::Nes {
+        let mut nes = crate::nes::Nes::new();
+        let mut prg_rom = vec![0xEA; 0x4000];
+        let program = [
+            0xA9, 0x3F, 0x8D, 0x06, 0x20, 0xA9, 0x00, 0x8D, 0x06, 0x20, 0xA9, 0x0F, 0x8D, 0x07, 0x20, 0x8D, 0x00, 0x03,
+        ];
+        prg_rom[..program.len()].copy_from_slice(&program);
+        prg_rom[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0xC0]);
+        nes.bus.rom.prg_rom = prg_rom;
+        nes.reset();
+        nes
+    }
+
+    #[test]
+    fn halts_at_instruction_boundary() {
+        use crate::watch::*;
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        let mut nes = load_program();
+        let mut watcher = Watcher::new();
+        watcher.watchpoints.push(Watchpoint::parse("ppu:w:3F00=0F").unwrap());
+        let mut logged = Watchpoint::parse("w:0300").unwrap();
+        logged.halt = false;
+        watcher.watchpoints.push(logged);
+        let hits = Rc::new(RefCell::new(Vec::new()));
+        let log = hits.clone();
+        watcher.callback = Some(Box::new(move |hit: &Hit| log.borrow_mut().push(*hit)));
+        nes.bus.watch = Some(Box::new(watcher));
+
+        nes.run_frame();
+        let hit = nes.bus.watch.as_ref().unwrap().halted.unwrap();
+        assert_eq!(hit.report(&nes.bus), format!("write ppu $3F00 = $0F by C00C STA $2007 on cycle {}", hit.cycle));
+        assert_eq!((nes.cpu.pc(), nes.cpu.cycles), (0xC00F, 0));
+        assert_eq!(nes.frame_count(), 0);
+        // Halted emulation stays put until resumed
+        nes.run_frame();
+        assert_eq!(nes.cpu.pc(), 0xC00F);
+
+        nes.bus.watch.as_mut().unwrap().resume();
+        nes.step();
+        assert_eq!(hits.borrow().len(), 2);
+        assert_eq!(hits.borrow()[1].report(&nes.bus), format!("write cpu $0300 = $0F by C00F STA $0300 on cycle {}", hit.cycle + 4));
+
+        // Execute stops before the instruction, and resuming runs it
+        nes.bus.watch.as_mut().unwrap().watchpoints.push(Watchpoint::parse("x:C013").unwrap());
+        nes.run_frame();
+        assert_eq!(nes.cpu.pc(), 0xC013);
+        nes.bus.watch.as_mut().unwrap().resume();
+        nes.step();
+        assert_eq!(nes.cpu.pc(), 0xC014);
+    }
+}
@@ -0,0 +1,100 @@
+// 16 bit PCM WAV files
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Mono 16 bit PCM
+pub fn encode_wav(sample_rate: usize, samples: &[i16]) -> Vec<u8> {
+    let data_len = samples.len() as u32 * 2;
+    let mut wav = Vec::with_capacity(44 + data_len as usize);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+    wav.extend_from_slice(b"WAVE");
+
+    wav.extend_from_slice(b"fmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    wav.extend_from_slice(&1u16.to_le_bytes()); // Channels
+    wav.extend_from_slice(&(sample_rate as u32).to_le_bytes());
+    wav.extend_from_slice(&(sample_rate as u32 * 2).to_le_bytes()); // Bytes per second
+    wav.extend_from_slice(&2u16.to_le_bytes()); // Bytes per frame
+    wav.extend_from_slice(&16u16.to_le_bytes()); // Bits per sample
+
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_len.to_le_bytes());
+    for sample in samples {
+        wav.extend_from_slice(&sample.to_le_bytes());
+    }
+    wav
+}
+
+pub fn save_wav(path: &Path, sample_rate: usize, samples: &[i16]) -> io::Result<()> {
+    fs::write(path, encode_wav(sample_rate, samples))
+}
+
+// Reads 8 or 16 bit PCM, keeping only the first channel
+pub fn decode_wav(data: &[u8]) -> Option<(usize, Vec<i16>)> {
+    if data.get(0..4)? != b"RIFF" || data.get(8..12)? != b"WAVE" {
+        return None;
+    }
+    let mut format = None;
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let id = &data[pos..pos + 4];
+        let length = u32::from_le_bytes(data[pos + 4..pos + 8].try_into().ok()?) as usize;
+        let body = data.get(pos + 8..pos + 8 + length)?;
+        // Chunks are padded to an even length
+        pos += 8 + length + (length & 1);
+
+        match id {
+            b"fmt " => {
+                let field = |offset: usize| Some(u16::from_le_bytes(body.get(offset..offset + 2)?.try_into().ok()?));
+                let sample_rate = u32::from_le_bytes(body.get(4..8)?.try_into().ok()?) as usize;
+                if field(0)? != 1 {
+                    return None;
+                }
+                format = Some((field(2)? as usize, sample_rate, field(14)?));
+            }
+            b"data" => {
+                let (channels, sample_rate, bits) = format?;
+                if channels == 0 {
+                    return None;
+                }
+                let width = bits as usize / 8;
+                let samples = match bits {
+                    8 => body.chunks_exact(width * channels).map(|frame| (frame[0] as i16 - 128) << 8).collect(),
+                    16 => body.chunks_exact(width * channels).map(|frame| i16::from_le_bytes([frame[0], frame[1]])).collect(),
+                    _ => return None,
+                };
+                return Some((sample_rate, samples));
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+pub fn load_wav(path: &Path) -> io::Result<(usize, Vec<i16>)> {
+    let data = fs::read(path)?;
+    decode_wav(&data).ok_or(io::Error::new(io::ErrorKind::InvalidData, "Unsupported WAV file"))
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    #[test]
+    fn wav_header() {
+        use crate::wav::*;
+        let wav = encode_wav(44_100, &[0, -1, 0x1234]);
+        assert_eq!(wav.len(), 44 + 6);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(&wav[4..8], &42u32.to_le_bytes());
+        assert_eq!(&wav[24..28], &44_100u32.to_le_bytes());
+        assert_eq!(&wav[40..44], &6u32.to_le_bytes());
+        assert_eq!(&wav[44..], &[0x00, 0x00, 0xFF, 0xFF, 0x34, 0x12]);
+        assert_eq!(decode_wav(&wav), Some((44_100, vec![0, -1, 0x1234])));
+        assert_eq!(decode_wav(&wav[..40]), None);
+    }
+}
@@ -0,0 +1,332 @@
+// Minimal ZIP archives for BK2 movies
+// Entries are written stored (uncompressed). Reading handles stored and
+// deflated entries, the two methods BizHawk uses.
+
+use crate::checksum::crc32;
+
+const LOCAL_HEADER: u32 = 0x0403_4B50;
+const CENTRAL_HEADER: u32 = 0x0201_4B50;
+const END_OF_DIRECTORY: u32 = 0x0605_4B50;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+
+// Deflate length and distance codes: base values and extra bits (RFC 1951 3.2.5)
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+// Order the code length code lengths are stored in
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+// Deflate stream bits, least significant first
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize, // In bits
+}
+
+impl BitReader<'_> {
+    fn bits(&mut self, count: u8) -> Result<u32, String> {
+        let mut value = 0;
+        for i in 0..count {
+            let byte = self.data.get(self.pos / 8).ok_or("Truncated deflate stream")?;
+            value |= (((byte >> (self.pos % 8)) & 0x01) as u32) << i;
+            self.pos += 1;
+        }
+        Ok(value)
+    }
+
+    // Stored blocks start on a byte boundary
+    fn align(&mut self) {
+        self.pos = self.pos.div_ceil(8) * 8;
+    }
+}
+
+// Canonical Huffman code, as symbol counts per length and symbols by code
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0; 16];
+        lengths.iter().for_each(|&len| counts[len as usize] += 1);
+        counts[0] = 0;
+        let mut offsets = [0; 16];
+        for len in 1..16 {
+            offsets[len] = offsets[len - 1] + counts[len - 1];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Self { counts, symbols }
+    }
+
+    // Codes are stored most significant bit first, one bit at a time
+    fn decode(&self, input: &mut BitReader) -> Result<u16, String> {
+        let (mut code, mut first, mut index) = (0, 0, 0);
+        for len in 1..16 {
+            code |= input.bits(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(String::from("Invalid deflate code"))
+    }
+}
+
+// Literal/length and distance codes of a dynamic block
+fn dynamic_codes(input: &mut BitReader) -> Result<(Huffman, Huffman), String> {
+    let literals = input.bits(5)? as usize + 257;
+    let distances = input.bits(5)? as usize + 1;
+    let code_lengths = input.bits(4)? as usize + 4;
+    let mut lengths = [0; 19];
+    for &i in &CODE_LENGTH_ORDER[..code_lengths] {
+        lengths[i] = input.bits(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&lengths);
+
+    let mut lengths = Vec::with_capacity(literals + distances);
+    while lengths.len() < literals + distances {
+        let (len, repeat) = match code_length_code.decode(input)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => (*lengths.last().ok_or("Deflate length repeat with nothing before it")?, 3 + input.bits(2)?),
+            17 => (0, 3 + input.bits(3)?),
+            _ => (0, 11 + input.bits(7)?),
+        };
+        lengths.extend((0..repeat).map(|_| len));
+    }
+    if lengths.len() > literals + distances {
+        return Err(String::from("Deflate code lengths overrun"));
+    }
+    Ok((Huffman::new(&lengths[..literals]), Huffman::new(&lengths[literals..])))
+}
+
+// Decompresses a raw deflate stream (RFC 1951), as ZIP method 8 stores it
+pub fn inflate(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut input = BitReader { data, pos: 0 };
+    let mut output = Vec::new();
+    loop {
+        let last = input.bits(1)? == 1;
+        match input.bits(2)? {
+            0 => {
+                input.align();
+                let start = input.pos / 8;
+                let len = u16_at(data, start).ok_or("Truncated deflate stream")? as usize;
+                let block = data.get(start + 4..start + 4 + len).ok_or("Truncated deflate stream")?;
+                output.extend_from_slice(block);
+                input.pos = (start + 4 + len) * 8;
+            }
+            kind @ (1 | 2) => {
+                let (literal_code, distance_code) = if kind == 1 {
+                    // Fixed codes
+                    let mut lengths = [8; 288];
+                    lengths[144..256].fill(9);
+                    lengths[256..280].fill(7);
+                    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+                } else {
+                    dynamic_codes(&mut input)?
+                };
+                loop {
+                    let symbol = literal_code.decode(&mut input)? as usize;
+                    if symbol < 256 {
+                        output.push(symbol as u8);
+                        continue;
+                    }
+                    if symbol == 256 {
+                        break;
+                    }
+                    let index = symbol - 257;
+                    let len = *LENGTH_BASE.get(index).ok_or("Invalid deflate length")? as usize
+                        + input.bits(LENGTH_EXTRA[index])? as usize;
+                    let index = distance_code.decode(&mut input)? as usize;
+                    let distance = *DISTANCE_BASE.get(index).ok_or("Invalid deflate distance")? as usize
+                        + input.bits(DISTANCE_EXTRA[index])? as usize;
+                    let start = output.len().checked_sub(distance).ok_or("Deflate distance before the start")?;
+                    // The copy can overlap what it writes
+                    for i in 0..len {
+                        output.push(output[start + i]);
+                    }
+                }
+            }
+            _ => return Err(String::from("Invalid deflate block type")),
+        }
+        if last {
+            return Ok(output);
+        }
+    }
+}
+
+pub fn write_stored(entries: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut zip = Vec::new();
+    let mut directory = Vec::new();
+    for &(name, data) in entries {
+        let offset = zip.len() as u32;
+        let crc = crc32(data);
+
+        // Fields shared by the local and central headers, from version needed on
+        let mut common = Vec::new();
+        common.extend_from_slice(&20u16.to_le_bytes()); // Version needed
+        common.extend_from_slice(&0u16.to_le_bytes()); // Flags
+        common.extend_from_slice(&0u16.to_le_bytes()); // Stored
+        common.extend_from_slice(&0u32.to_le_bytes()); // Time and date
+        common.extend_from_slice(&crc.to_le_bytes());
+        common.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        common.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        common.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        common.extend_from_slice(&0u16.to_le_bytes()); // Extra field length
+
+        zip.extend_from_slice(&LOCAL_HEADER.to_le_bytes());
+        zip.extend_from_slice(&common);
+        zip.extend_from_slice(name.as_bytes());
+        zip.extend_from_slice(data);
+
+        directory.extend_from_slice(&CENTRAL_HEADER.to_le_bytes());
+        directory.extend_from_slice(&20u16.to_le_bytes()); // Version made by
+        directory.extend_from_slice(&common);
+        directory.extend_from_slice(&[0; 6]); // Comment length, disk, internal attributes
+        directory.extend_from_slice(&0u32.to_le_bytes()); // External attributes
+        directory.extend_from_slice(&offset.to_le_bytes());
+        directory.extend_from_slice(name.as_bytes());
+    }
+
+    let directory_offset = zip.len() as u32;
+    zip.extend_from_slice(&directory);
+    zip.extend_from_slice(&END_OF_DIRECTORY.to_le_bytes());
+    zip.extend_from_slice(&[0; 4]); // Disk numbers
+    zip.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    zip.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    zip.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+    zip.extend_from_slice(&directory_offset.to_le_bytes());
+    zip.extend_from_slice(&0u16.to_le_bytes()); // Comment length
+    zip
+}
+
+// Names and contents of every entry, through the central directory
+pub fn read_entries(data: &[u8]) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let corrupt = || String::from("Corrupt ZIP archive");
+    let end = (0..data.len().saturating_sub(21))
+        .rev()
+        .find(|&pos| u32_at(data, pos) == Some(END_OF_DIRECTORY))
+        .ok_or("Not a ZIP archive")?;
+    let count = u16_at(data, end + 10).ok_or_else(corrupt)? as usize;
+    let mut pos = u32_at(data, end + 16).ok_or_else(corrupt)? as usize;
+
+    let mut entries = Vec::with_capacity(count);
+    for _ in 0..count {
+        if u32_at(data, pos) != Some(CENTRAL_HEADER) {
+            return Err(corrupt());
+        }
+        let method = u16_at(data, pos + 10).ok_or_else(corrupt)?;
+        let crc = u32_at(data, pos + 16).ok_or_else(corrupt)?;
+        let size = u32_at(data, pos + 20).ok_or_else(corrupt)? as usize;
+        let name_len = u16_at(data, pos + 28).ok_or_else(corrupt)? as usize;
+        let extra_len = u16_at(data, pos + 30).ok_or_else(corrupt)? as usize;
+        let comment_len = u16_at(data, pos + 32).ok_or_else(corrupt)? as usize;
+        let local = u32_at(data, pos + 42).ok_or_else(corrupt)? as usize;
+        let name = data.get(pos + 46..pos + 46 + name_len).ok_or_else(corrupt)?;
+        let name = String::from_utf8_lossy(name).into_owned();
+        pos += 46 + name_len + extra_len + comment_len;
+
+        let local_name_len = u16_at(data, local + 26).ok_or_else(corrupt)? as usize;
+        let local_extra_len = u16_at(data, local + 28).ok_or_else(corrupt)? as usize;
+        let start = local + 30 + local_name_len + local_extra_len;
+        let body = data.get(start..start + size).ok_or_else(corrupt)?;
+        let body = match method {
+            METHOD_STORED => body.to_vec(),
+            METHOD_DEFLATED => inflate(body).map_err(|err| format!("{}: {}", name, err))?,
+            _ => return Err(format!("{} uses compression method {}, only stored and deflated are supported", name, method)),
+        };
+        if crc32(&body) != crc {
+            return Err(format!("{} fails its CRC check", name));
+        }
+        entries.push((name, body));
+    }
+    Ok(entries)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    #[test]
+    fn stored_round_trip() {
+        use crate::zip::*;
+        let zip = write_stored(&[("Header.txt", b"Platform NES\n"), ("Input Log.txt", b"")]);
+        assert_eq!(&zip[0..4], b"PK\x03\x04");
+
+        let entries = read_entries(&zip).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0], (String::from("Header.txt"), b"Platform NES\n".to_vec()));
+        assert_eq!(entries[1].0, "Input Log.txt");
+        assert!(read_entries(b"not a zip file at all").is_err());
+    }
+
+    #[test]
+    fn deflated() {
+        use crate::checksum::crc32;
+        use crate::zip::*;
+        // Stored, fixed code and dynamic code blocks, made with zlib
+        assert_eq!(inflate(&[0x01, 0x06, 0x00, 0xF9, 0xFF, b's', b't', b'o', b'r', b'e', b'd']).unwrap(), b"stored");
+        let fixed = [0x4B, 0x4C, 0x4A, 0x4E, 0x84, 0x21, 0x00];
+        assert_eq!(inflate(&fixed).unwrap(), b"abcabcabcabc");
+        let dynamic = [
+            0x55, 0x93, 0xBB, 0x11, 0xC3, 0x20, 0x10, 0x05, 0x73, 0x17, 0xB3, 0x3D, 0x98, 0xB9, 0x90, 0x08,
+            0x46, 0x95, 0x90, 0xAA, 0x78, 0xC3, 0x8D, 0x19, 0x16, 0x22, 0x10, 0x88, 0xD5, 0xFB, 0xE8, 0x85,
+            0x97, 0xFF, 0x78, 0x3F, 0x73, 0xFA, 0x3E, 0x54, 0x18, 0x7B, 0x45, 0xD0, 0xA0, 0xEC, 0xBD, 0xA8,
+            0x8D, 0xB1, 0x57, 0xCC, 0x93, 0x1D, 0xBE, 0xFB, 0x3D, 0x5A, 0x1F, 0x7B, 0xC5, 0x3C, 0xD9, 0x29,
+            0x7B, 0x2F, 0x4C, 0x98, 0x93, 0x76, 0x08, 0x93, 0x27, 0xC2, 0xE4, 0xD1, 0x87, 0x78, 0x22, 0x4C,
+            0x9E, 0x08, 0xCF, 0xBA, 0x70, 0x94, 0xC3, 0xB3, 0x86, 0x30, 0x61, 0x3D, 0xEF, 0x47, 0xC3, 0xFA,
+            0xEA, 0x21, 0x5E, 0x93, 0x86, 0x30, 0x81, 0x74, 0xA2, 0x48, 0x9F, 0x34, 0xA4, 0x13, 0xE2, 0x89,
+            0x40, 0x3A, 0x21, 0x9E, 0x08, 0xA4, 0x13, 0xC7, 0x17, 0x13, 0x48, 0x27, 0x8E, 0x2F, 0xCB, 0x09,
+            0xF1, 0xAC, 0x21, 0x4C, 0x48, 0xE1, 0xF2, 0xA5, 0x8A, 0x90, 0xF9, 0x0D, 0xF1, 0xAC, 0x21, 0x9D,
+            0x10, 0xAF, 0x49, 0x43, 0x3A, 0x21, 0x9E, 0x08, 0x99, 0xDF, 0xD1, 0x40, 0x6D, 0xCE, 0x61, 0x39,
+            0x81, 0xF3, 0x53, 0x0E, 0xB5, 0x39, 0x87, 0x1C, 0xCE, 0xEF, 0x68, 0x08, 0x13, 0x32, 0x3F, 0xE5,
+            0x50, 0x45, 0xC8, 0xFC, 0x94, 0x83, 0x09, 0x99, 0xDF, 0xD5, 0x4F, 0xAE, 0x7E, 0x72, 0xF5, 0xB3,
+            0x2B, 0x87, 0xEC, 0x84, 0xF3, 0xF3, 0xFF, 0x00, 0x57, 0x3F, 0xB9, 0xFA, 0xC9, 0xD5, 0xCF, 0x8E,
+            0xFB, 0x29, 0x0D, 0xB1, 0x09, 0x3F,
+        ];
+        let log: String = (0..64)
+            .map(|i| {
+                let buttons: String = "UDLRSsBA".chars().enumerate().map(|(j, c)| if ((i * 37) >> j) & 1 != 0 { c } else { '.' }).collect();
+                format!("|..|{}|\n", buttons)
+            })
+            .collect();
+        assert_eq!(inflate(&dynamic).unwrap(), log.as_bytes());
+        assert!(inflate(&fixed[..4]).is_err());
+
+        // The same entry marked deflated, with the CRC and size of what it inflates to
+        let mut zip = write_stored(&[("a.txt", &fixed)]);
+        let central = zip.len() - 22 - 46 - 5;
+        for header in [0, central + 2] {
+            zip[header + 8..header + 10].copy_from_slice(&8u16.to_le_bytes());
+            zip[header + 14..header + 18].copy_from_slice(&crc32(b"abcabcabcabc").to_le_bytes());
+            zip[header + 22..header + 26].copy_from_slice(&12u32.to_le_bytes());
+        }
+        assert_eq!(read_entries(&zip).unwrap(), vec![(String::from("a.txt"), b"abcabcabcabc".to_vec())]);
+        zip[central + 18] ^= 0xFF;
+        assert!(read_entries(&zip).unwrap_err().contains("CRC"));
+    }
+}